@@ -0,0 +1,409 @@
+//! Command line interface of the app binary.
+//!
+//! Everything but `serve` runs once and exits, so deploys and lookups work
+//! from an ssh session without the browser.
+
+use config::{CheckedConfig, config_path, get_config};
+use deploy::{DeployError, DeployEvent, DeployPlan, StartError, hub};
+use model::{DeploymentFilter, DeploymentStatus, LogStream, SqliteDeploymentModel};
+use std::{
+    env,
+    fmt::{self, Display, Formatter},
+    fs,
+    io::{self, Write},
+    process::ExitCode,
+    sync::mpsc,
+};
+use view::{format_duration, format_timestamp};
+
+pub const USAGE: &str = "\
+usage: app [--config <path>] [command]
+
+commands:
+  serve                               run the http and websocket servers (default)
+  check-config                        validate the config file and report every problem
+  migrate                             apply pending database migrations
+  deploy <service> --env <env> [--build-of <deployment>]
+                                      deploy a service, streaming its output, shipping
+                                      the stored build of an earlier deployment if given
+  promote <service> --from <env>      deploy what an environment runs to the one it promotes to
+  list services|nodes|environments    show what the config defines
+  history [--service <name>] [--status <status>] [--limit <n>]
+                                      show recent deployments
+
+without --config the path in PIPELINE_CONFIG is used, then ./config.toml";
+
+const DEFAULT_HISTORY_LIMIT: u32 = 20;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Cli {
+    pub config: Option<String>,
+    pub command: Command,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Serve,
+    CheckConfig,
+    Migrate,
+    Deploy { service: String, environment: String, build_of: Option<u64> },
+    Promote { service: String, from: String },
+    List(ListKind),
+    History { service: Option<String>, status: Option<DeploymentStatus>, limit: u32 },
+    Help,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ListKind {
+    Services,
+    Nodes,
+    Environments,
+}
+
+/// The command line didn't make sense; shown above the usage text.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UsageError(pub String);
+
+impl Display for UsageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// Parse the arguments after the program name.
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Cli, UsageError> {
+    let mut config = None;
+    let mut positional: Vec<String> = Vec::new();
+    let mut options: Vec<(String, String)> = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(Cli { config, command: Command::Help });
+        }
+        let Some(flag) = arg.strip_prefix("--") else {
+            positional.push(arg);
+            continue;
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => {
+                let value = args.next().ok_or_else(|| UsageError(format!("--{flag} requires a value")))?;
+                (flag.to_string(), value)
+            }
+        };
+        if value.is_empty() {
+            return Err(UsageError(format!("--{name} requires a value")));
+        }
+        if name == "config" {
+            config = Some(value);
+        } else {
+            options.push((name, value));
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match positional.next().as_deref() {
+        None | Some("serve") => Command::Serve,
+        Some("check-config") => Command::CheckConfig,
+        Some("migrate") => Command::Migrate,
+        Some("deploy") => {
+            let service = positional.next().ok_or_else(|| UsageError("deploy requires a service".to_string()))?;
+            let environment = take_option(&mut options, "env")?
+                .ok_or_else(|| UsageError("deploy requires --env <env>".to_string()))?;
+            let build_of = match take_option(&mut options, "build-of")? {
+                Some(id) => Some(
+                    id.trim_start_matches('#')
+                        .parse::<u64>()
+                        .map_err(|_| UsageError(format!("--build-of must be a deployment number, got '{id}'")))?,
+                ),
+                None => None,
+            };
+            Command::Deploy { service, environment, build_of }
+        }
+        Some("promote") => {
+            let service = positional.next().ok_or_else(|| UsageError("promote requires a service".to_string()))?;
+            let from = take_option(&mut options, "from")?
+                .ok_or_else(|| UsageError("promote requires --from <env>".to_string()))?;
+            Command::Promote { service, from }
+        }
+        Some("list") => match positional.next().as_deref() {
+            Some("services") => Command::List(ListKind::Services),
+            Some("nodes") => Command::List(ListKind::Nodes),
+            Some("environments") => Command::List(ListKind::Environments),
+            Some(other) => return Err(UsageError(format!("cannot list '{other}'"))),
+            None => return Err(UsageError("list requires services, nodes or environments".to_string())),
+        },
+        Some("history") => {
+            let service = take_option(&mut options, "service")?;
+            let status = match take_option(&mut options, "status")? {
+                Some(status) => Some(
+                    DeploymentStatus::parse(&status)
+                        .ok_or_else(|| UsageError(format!("unknown status '{status}'")))?,
+                ),
+                None => None,
+            };
+            let limit = match take_option(&mut options, "limit")? {
+                Some(limit) => limit
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|limit| *limit > 0)
+                    .ok_or_else(|| UsageError(format!("--limit must be a positive number, got '{limit}'")))?,
+                None => DEFAULT_HISTORY_LIMIT,
+            };
+            Command::History { service, status, limit }
+        }
+        Some(other) => return Err(UsageError(format!("unknown command '{other}'"))),
+    };
+
+    if let Some(extra) = positional.next() {
+        return Err(UsageError(format!("unexpected argument '{extra}'")));
+    }
+    if let Some((name, _)) = options.first() {
+        return Err(UsageError(format!("unexpected option --{name}")));
+    }
+    Ok(Cli { config, command })
+}
+
+fn take_option(options: &mut Vec<(String, String)>, name: &str) -> Result<Option<String>, UsageError> {
+    let mut values = options.extract_if(.., |(n, _)| n == name);
+    let value = values.next().map(|(_, value)| value);
+    if values.next().is_some() {
+        return Err(UsageError(format!("--{name} given more than once")));
+    }
+    Ok(value)
+}
+
+/// Report every problem with the config file without starting anything.
+pub fn check_config() -> ExitCode {
+    let path = config_path();
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("error, when reading config {}. Error: {err}", path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    match config::check_config(&contents) {
+        Ok(CheckedConfig { warnings, .. }) => {
+            for warning in &warnings {
+                eprintln!("{}: {}", path.display(), warning);
+            }
+            println!("{}: ok, {} warning(s)", path.display(), warnings.len());
+            ExitCode::SUCCESS
+        }
+        Err(problems) => {
+            for problem in &problems {
+                eprintln!("{}: {}", path.display(), problem);
+            }
+            let errors = problems.iter().filter(|p| p.is_error()).count();
+            println!("{}: {errors} error(s)", path.display());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+pub fn migrate() -> ExitCode {
+    match db::migrate() {
+        Ok(applied) if applied.is_empty() => {
+            println!("database is up to date");
+            ExitCode::SUCCESS
+        }
+        Ok(applied) => {
+            for name in applied {
+                println!("applied {name}");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("error, when applying migrations. Error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Run a deploy through the same hub the websocket uses, printing its output
+/// as it arrives. Succeeds only if the deploy does. With `build_of` the
+/// stored build of that deployment is shipped instead of building.
+pub fn deploy(service: &str, environment: &str, build_of: Option<u64>) -> ExitCode {
+    run_deploy(DeployPlan::resolve(get_config(), service, environment), |plan, initiated_by| match build_of {
+        Some(build_of) => hub().redeploy(plan, build_of, initiated_by),
+        None => hub().start(plan, initiated_by),
+    })
+}
+
+/// Deploy what `from` runs to the environment it promotes to, like [`deploy`].
+pub fn promote(service: &str, from: &str) -> ExitCode {
+    run_deploy(DeployPlan::resolve_promotion(get_config(), service, from), |plan, initiated_by| {
+        hub().promote(plan, from, initiated_by)
+    })
+}
+
+fn run_deploy(
+    plan: Result<DeployPlan, DeployError>,
+    start: impl FnOnce(&DeployPlan, &str) -> Result<u64, StartError>,
+) -> ExitCode {
+    let plan = match plan {
+        Ok(plan) => plan,
+        Err(err) => {
+            eprintln!("deploy rejected: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let initiated_by = match env::var("USER") {
+        Ok(user) if !user.is_empty() => format!("cli:{user}"),
+        _ => "cli".to_string(),
+    };
+
+    // subscribe first so nothing the deploy prints is missed
+    let (sender, receiver) = mpsc::channel::<DeployEvent>();
+    let subscription = hub().subscribe(&plan.service, Box::new(move |event| sender.send(event.clone()).is_ok()));
+    let deployment_id = match start(&plan, &initiated_by) {
+        Ok(id) => id,
+        Err(err) => {
+            hub().unsubscribe(subscription);
+            eprintln!("deploy failed: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("deployment #{deployment_id}: {} to {}", plan.service, plan.environment);
+
+    for event in receiver {
+        if event.deployment_id() != deployment_id {
+            continue;
+        }
+        match event {
+            DeployEvent::Started { .. } | DeployEvent::Step { .. } => {}
+            DeployEvent::Line { stream: LogStream::Stdout, line, .. } => println!("{line}"),
+            DeployEvent::Line { stream: LogStream::Stderr, line, .. } => eprintln!("{line}"),
+            DeployEvent::Finished { status, exit, .. } => {
+                hub().unsubscribe(subscription);
+                println!("deployment #{deployment_id} {status} ({exit})");
+                return match status {
+                    DeploymentStatus::Succeeded => ExitCode::SUCCESS,
+                    _ => ExitCode::FAILURE,
+                };
+            }
+        }
+    }
+    eprintln!("error, deploy hub stopped before deployment #{deployment_id} finished");
+    ExitCode::FAILURE
+}
+
+pub fn list(kind: &ListKind) -> ExitCode {
+    let config = get_config();
+    let mut rows: Vec<(&str, String)> = Vec::new();
+    match kind {
+        ListKind::Services => {
+            for (name, service) in &config.services {
+                let environments: Vec<&str> = service.environments.keys().map(String::as_str).collect();
+                rows.push((name, environments.join(", ")));
+            }
+        }
+        ListKind::Nodes => {
+            for (name, node) in &config.nodes {
+                rows.push((name, format!("{}@{}:{}", node.user, node.host_name, node.port)));
+            }
+        }
+        ListKind::Environments => {
+            for (name, environment) in &config.environments {
+                rows.push((name, environment.nodes.join(", ")));
+            }
+        }
+    }
+    print_lines(rows.iter().map(|(name, detail)| format!("{name}\t{detail}")))
+}
+
+pub fn history(service: Option<&str>, status: Option<DeploymentStatus>, limit: u32) -> ExitCode {
+    let filter = DeploymentFilter { service, status };
+    let deployments = match SqliteDeploymentModel::new().list_deployments(&filter, limit) {
+        Ok(deployments) => deployments,
+        Err(err) => {
+            eprintln!("error, when listing deployments. Error: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if deployments.is_empty() {
+        println!("no deployments found");
+        return ExitCode::SUCCESS;
+    }
+    print_lines(deployments.iter().map(|deployment| {
+        format!(
+            "#{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            deployment.id(),
+            format_timestamp(deployment.started_at()),
+            deployment.service(),
+            deployment.environment(),
+            deployment.status(),
+            deployment.duration_ms().map(format_duration).unwrap_or_else(|| "-".to_string()),
+            deployment.initiated_by(),
+        )
+    }))
+}
+
+/// Write listing output, stopping quietly if the reader (e.g. `head`) goes away.
+fn print_lines(lines: impl Iterator<Item = String>) -> ExitCode {
+    let mut out = io::stdout().lock();
+    for line in lines {
+        if writeln!(out, "{line}").is_err() {
+            break;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, UsageError> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults_to_serve() {
+        assert_eq!(parse(&[]), Ok(Cli { config: None, command: Command::Serve }));
+        let cli = parse(&["--config", "/etc/pipeline.toml"]).unwrap();
+        assert_eq!(cli.config.as_deref(), Some("/etc/pipeline.toml"));
+        assert_eq!(cli.command, Command::Serve);
+    }
+
+    #[test]
+    fn parses_deploy_with_options_anywhere() {
+        let expected = Command::Deploy { service: "api".to_string(), environment: "staging".to_string(), build_of: None };
+        assert_eq!(parse(&["deploy", "api", "--env", "staging"]).unwrap().command, expected);
+        let cli = parse(&["--env=staging", "deploy", "--config=dev.toml", "api"]).unwrap();
+        assert_eq!(cli.command, expected);
+        assert_eq!(cli.config.as_deref(), Some("dev.toml"));
+        assert!(parse(&["deploy", "api"]).is_err());
+        let cli = parse(&["deploy", "api", "--env", "production", "--build-of", "#12"]).unwrap();
+        assert!(matches!(cli.command, Command::Deploy { build_of: Some(12), .. }));
+        assert!(parse(&["deploy", "api", "--env", "production", "--build-of", "latest"]).is_err());
+        let cli = parse(&["promote", "api", "--from", "staging"]).unwrap();
+        assert_eq!(cli.command, Command::Promote { service: "api".to_string(), from: "staging".to_string() });
+        assert!(parse(&["promote", "api"]).is_err());
+    }
+
+    #[test]
+    fn parses_history_filters() {
+        let cli = parse(&["history", "--service", "api", "--status", "failed", "--limit", "5"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Command::History {
+                service: Some("api".to_string()),
+                status: Some(DeploymentStatus::Failed),
+                limit: 5,
+            },
+        );
+        assert!(parse(&["history", "--status", "exploded"]).is_err());
+    }
+
+    #[test]
+    fn rejects_stray_arguments() {
+        assert!(parse(&["list", "pies"]).is_err());
+        assert!(parse(&["migrate", "now"]).is_err());
+        assert!(parse(&["list", "nodes", "--env", "dev"]).is_err());
+        assert!(parse(&["launch"]).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+pub mod cli;
+
 pub use view::{get_landing_page, get_not_found};
 
 use std::{
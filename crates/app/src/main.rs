@@ -3,14 +3,43 @@ use http::handle_http_connection;
 use model::SqliteUserModel;
 use view::render_user_profile;
 use app::{ThreadPool};
-use config::get_config;
+use app::cli::{self, Command, USAGE};
+use config::{get_config, set_config_path};
 use std::{
-    thread,
+    env,
     net::TcpListener,
+    process::ExitCode,
+    sync::Arc,
+    thread,
 };
-use ws::handle_websocket_connection;
 
-fn main() {
+fn main() -> ExitCode {
+    let cli = match cli::parse_args(env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("error, {err}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    if let Some(path) = cli.config {
+        set_config_path(path);
+    }
+    match cli.command {
+        Command::Help => {
+            println!("{USAGE}");
+            ExitCode::SUCCESS
+        }
+        Command::Serve => serve(),
+        Command::CheckConfig => cli::check_config(),
+        Command::Migrate => cli::migrate(),
+        Command::Deploy { service, environment, build_of } => cli::deploy(&service, &environment, build_of),
+        Command::Promote { service, from } => cli::promote(&service, &from),
+        Command::List(kind) => cli::list(&kind),
+        Command::History { service, status, limit } => cli::history(service.as_deref(), status, limit),
+    }
+}
+
+fn serve() -> ExitCode {
     let _ = db::pool();
     let model = SqliteUserModel::new();
     let controller = UserController::new(model);
@@ -23,26 +52,38 @@ fn main() {
         None => eprintln!("User not found"),
     };
 
-    // websocket threads
-    thread::spawn(move || {
-        let listener = TcpListener::bind("127.0.0.1:8787").unwrap();
-        let config = get_config();
-        let pool = ThreadPool::new(config.max_users); 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(s) => {
-                    pool.execute(|| {
-                        handle_websocket_connection(s);
-                    });
-                }
-                Err(e) => eprintln!("websocket connection from browser failed. {e}"),
-            }
-        }
-    });
+    let config = get_config();
 
-    // http threads
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4); // for serving http files needed to get the websocket setup
+    // websocket event loop, fed upgraded connections by the http threads
+    if let Err(err) = ws::start(config.max_users, config.server.ws_max_message_size) {
+        panic!("error, when starting websocket server. Error: {err}");
+    }
+
+    // pulls the repos and builds and deploys services their new commits touch
+    if let Err(err) = deploy::poller::start(config, deploy::hub()) {
+        panic!("error, when starting repo poller. Error: {err}");
+    }
+
+    // http threads, for serving http files and handing websocket upgrades to the event loop
+    let pool = Arc::new(ThreadPool::new(config.server.http_workers));
+    let listeners: Vec<TcpListener> = config.server.listen.iter()
+        .map(|address| TcpListener::bind(address)
+            .unwrap_or_else(|e| panic!("error, when binding {address}. Error: {e}")))
+        .collect();
+    for address in &config.server.listen {
+        println!("listening on http://{address}");
+    }
+    let mut listeners = listeners.into_iter();
+    let first = listeners.next().expect("config validation requires a listen address");
+    for listener in listeners {
+        let pool = Arc::clone(&pool);
+        thread::spawn(move || accept_http(listener, &pool));
+    }
+    accept_http(first, &pool);
+    ExitCode::SUCCESS
+}
+
+fn accept_http(listener: TcpListener, pool: &ThreadPool) {
     for stream in listener.incoming() {
         match stream {
             Ok(s) => {
@@ -54,6 +95,3 @@ fn main() {
         }
     }
 }
-
-
-
@@ -0,0 +1,241 @@
+//! Problems found while loading a config file, with where they are in the TOML.
+
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+use toml_edit::{ImDocument, Item, TableLike, Value};
+
+/// Whether a problem stops the config from loading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// Position of a value in the config, e.g. `services.api.staging[0].nodes[1]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyPath(Vec<PathSegment>);
+
+impl KeyPath {
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    pub fn key(&self, key: &str) -> Self {
+        let mut path = self.clone();
+        path.0.push(PathSegment::Key(key.to_string()));
+        path
+    }
+
+    pub fn index(&self, index: usize) -> Self {
+        let mut path = self.clone();
+        path.0.push(PathSegment::Index(index));
+        path
+    }
+
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.0
+    }
+}
+
+impl Display for KeyPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "(top level)");
+        }
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) if i == 0 => write!(f, "{key}")?,
+                PathSegment::Key(key) => write!(f, ".{key}")?,
+                PathSegment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 1-based line and column in the config file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    fn from_offset(source: &str, offset: usize) -> Self {
+        let before = &source[..offset.min(source.len())];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigErrorKind {
+    /// The file isn't valid TOML or doesn't match the config's shape.
+    Parse(String),
+    Required,
+    MustBePositive,
+    Empty,
+    InvalidAddress(String),
+    InvalidUrl(String),
+    MissingDbFile,
+    UnknownNode(String),
+    UnknownEnvironment(String),
+    /// Promoting from the environment eventually promotes back to it.
+    PromotionCycle(String),
+    UnknownRepo(String),
+    InvalidGlob(String, &'static str),
+    OutsideRepo(String),
+    InvalidChoice(String, &'static [&'static str]),
+    InvalidEnvName(String),
+    DuplicateNode(String),
+    DuplicateStep(String),
+    UnusedNode(String),
+    UnusedRepo(String),
+}
+
+impl Display for ConfigErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(message) => write!(f, "{message}"),
+            Self::Required => write!(f, "is required"),
+            Self::MustBePositive => write!(f, "must be greater than zero"),
+            Self::Empty => write!(f, "requires at least one entry"),
+            Self::InvalidAddress(address) => write!(f, "address '{address}' must be host:port"),
+            Self::InvalidUrl(url) => write!(f, "url '{url}' must be http://host[:port][/path]"),
+            Self::MissingDbFile => write!(f, "fossil repos require a db_file"),
+            Self::UnknownNode(node) => write!(f, "references unknown node '{node}'"),
+            Self::UnknownEnvironment(env) => write!(f, "references unknown environment '{env}'"),
+            Self::PromotionCycle(env) => write!(f, "promotes builds of '{env}' back to '{env}'"),
+            Self::UnknownRepo(repo) => write!(f, "references unknown repo '{repo}'"),
+            Self::InvalidGlob(pattern, reason) => write!(f, "path glob '{pattern}' {reason}"),
+            Self::OutsideRepo(repo) => {
+                write!(f, "is not inside repo '{repo}', set paths to choose which changes rebuild the service")
+            }
+            Self::InvalidChoice(value, choices) => write!(f, "'{value}' must be one of {}", choices.join(", ")),
+            Self::InvalidEnvName(name) => {
+                write!(f, "environment variable '{name}' may only contain letters, digits and '_'")
+            }
+            Self::DuplicateNode(node) => write!(f, "lists node '{node}' more than once"),
+            Self::DuplicateStep(name) => write!(f, "step '{name}' is already a step of this stage, give it another name"),
+            Self::UnusedNode(node) => write!(f, "node '{node}' is not used by ci, any environment or any service"),
+            Self::UnusedRepo(repo) => write!(f, "repo '{repo}' is not the source of any service"),
+        }
+    }
+}
+
+/// One problem with the config, pointing at the key it was found under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub severity: Severity,
+    pub path: KeyPath,
+    pub location: Option<Location>,
+    pub kind: ConfigErrorKind,
+}
+
+impl ConfigError {
+    pub fn error(path: KeyPath, kind: ConfigErrorKind) -> Self {
+        Self { severity: Severity::Error, path, location: None, kind }
+    }
+
+    pub fn warning(path: KeyPath, kind: ConfigErrorKind) -> Self {
+        Self { severity: Severity::Warning, path, location: None, kind }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.path)?;
+        if let Some(location) = self.location {
+            write!(f, " (line {}, column {})", location.line, location.column)?;
+        }
+        write!(f, ": {}", self.kind)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A parse failure, located by the span the TOML parser reported.
+pub(crate) fn parse_error(source: &str, err: &toml::de::Error) -> ConfigError {
+    ConfigError {
+        location: err.span().map(|span| Location::from_offset(source, span.start)),
+        ..ConfigError::error(KeyPath::root(), ConfigErrorKind::Parse(err.message().to_string()))
+    }
+}
+
+/// Fill in line and column for each problem from the TOML it was found in.
+///
+/// Paths that only partly exist in the file point at the deepest part that does.
+pub(crate) fn locate(source: &str, errors: &mut [ConfigError]) {
+    let Ok(document) = ImDocument::parse(source) else {
+        return
+    };
+    for error in errors.iter_mut() {
+        error.location = span_of(&document, &error.path).map(|span| Location::from_offset(source, span.start));
+    }
+}
+
+enum Node<'a> {
+    Item(&'a Item),
+    Table(&'a dyn TableLike),
+    Value(&'a Value),
+}
+
+fn span_of(document: &ImDocument<&str>, path: &KeyPath) -> Option<Range<usize>> {
+    let mut node = Node::Item(document.as_item());
+    let mut best = None;
+    for segment in path.segments() {
+        let table = match node {
+            Node::Item(item) => item.as_table_like(),
+            Node::Table(table) => Some(table),
+            Node::Value(value) => value.as_inline_table().map(|t| t as &dyn TableLike),
+        };
+        let (span, next) = match segment {
+            PathSegment::Key(key) => {
+                let Some((k, next)) = table.and_then(|t| t.get_key_value(key)) else {
+                    break;
+                };
+                (next.span().or_else(|| k.span()), Node::Item(next))
+            }
+            PathSegment::Index(index) => match node {
+                Node::Item(Item::ArrayOfTables(tables)) => {
+                    let Some(table) = tables.get(*index) else {
+                        break;
+                    };
+                    (table.span(), Node::Table(table))
+                }
+                Node::Item(Item::Value(Value::Array(array))) | Node::Value(Value::Array(array)) => {
+                    let Some(value) = array.get(*index) else {
+                        break;
+                    };
+                    (value.span(), Node::Value(value))
+                }
+                _ => break,
+            },
+        };
+        best = span.or(best);
+        node = next;
+    }
+    best
+}
@@ -0,0 +1,91 @@
+//! The small glob syntax used by service `paths`, `artifacts` and `protect`.
+//!
+//! Patterns are matched against `/` separated paths relative to a root: the
+//! repo for `paths`, the service's workspaces for the others. `*` matches
+//! within one path segment, `?` matches one character and a `**` segment
+//! matches any number of segments. A pattern also matches everything below a
+//! directory it matches, so `libs/common` covers `libs/common/src/lib.rs`.
+
+/// Why a pattern can't be used, or `None` if it can.
+pub fn check(pattern: &str) -> Option<&'static str> {
+    if pattern.is_empty() {
+        return Some("is empty");
+    }
+    if pattern.starts_with('/') {
+        return Some("must be a relative path");
+    }
+    for segment in pattern.split('/') {
+        if segment == ".." {
+            return Some("can't go above its root with '..'");
+        }
+        if segment.contains("**") && segment != "**" {
+            return Some("'**' must be a whole path segment");
+        }
+    }
+    None
+}
+
+/// Whether `path`, or a directory above it, matches `pattern`.
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = segments(pattern).collect();
+    let path: Vec<&str> = segments(path).collect();
+    (1..=path.len()).any(|len| match_segments(&pattern, &path[..len]))
+}
+
+/// Path segments with empty and `.` parts dropped, so `./a//b/` is `a/b`.
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty() && *s != ".")
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((segment, path)) => match_segment(first.as_bytes(), segment.as_bytes()) && match_segments(rest, path),
+            None => false,
+        },
+    }
+}
+
+fn match_segment(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_segment(rest, &name[skip..])),
+        Some((b'?', rest)) => {
+            // ? stands for a whole character, not one byte of it
+            let len = std::str::from_utf8(name).ok().and_then(|s| s.chars().next()).map_or(0, char::len_utf8);
+            len > 0 && match_segment(rest, &name[len..])
+        }
+        Some((c, rest)) => name.first() == Some(c) && match_segment(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_segments_and_directories() {
+        assert!(matches("example_service_1", "example_service_1/src/main.rs"));
+        assert!(matches("example_service_1/**", "example_service_1/build.sh"));
+        assert!(!matches("example_service_1", "example_service_10/build.sh"));
+        assert!(matches("*.toml", "Cargo.toml"));
+        assert!(!matches("*.toml", "crates/db/Cargo.toml"));
+        assert!(matches("**/*.toml", "crates/db/Cargo.toml"));
+        assert!(matches("**/*.toml", "Cargo.toml"));
+        assert!(matches("crates/**/migrations", "crates/db/migrations/0001_init.sql"));
+        assert!(matches("src/?.rs", "src/a.rs"));
+        assert!(!matches("src/?.rs", "src/ab.rs"));
+        assert!(matches("./shared/", "shared/lib.rs"));
+    }
+
+    #[test]
+    fn rejects_patterns_outside_the_repo() {
+        assert_eq!(check("shared/**"), None);
+        assert!(check("").is_some());
+        assert!(check("/etc/**").is_some());
+        assert!(check("../other").is_some());
+        assert!(check("src/**.rs").is_some());
+    }
+}
@@ -3,16 +3,31 @@
 //! This crate exposes a single global configuration value backed by a `OnceLock`
 //! so callers can access settings without threading them through call stacks.
 
+mod error;
+pub mod glob;
+pub use error::{ConfigError, ConfigErrorKind, KeyPath, Location, PathSegment, Severity};
+
 use serde::Deserialize;
 use serde::de::{self, Deserializer};
 use std::collections::BTreeMap;
+use std::env;
 use std::fs;
+use std::process;
+use std::path::PathBuf;
 use std::sync::OnceLock;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Global configuration instance.
 static CONFIG: OnceLock<AppConfig> = OnceLock::new();
 
+/// Path chosen on the command line, read once when the config is first loaded.
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Environment variable naming the config file when no path was set explicitly.
+pub const CONFIG_PATH_ENV: &str = "PIPELINE_CONFIG";
+
+const DEFAULT_CONFIG_PATH: &str = "./config.toml";
+
 /// Top-level application configuration.
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct AppConfig {
@@ -24,19 +39,46 @@ pub struct AppConfig {
     pub database_path: String,
     pub migrations_dir: String,
     pub max_users: usize,
+    #[serde(default)]
+    pub server: ServerConfig,
     pub repos: BTreeMap<String, RepoCloneConfig>,
     pub nodes: BTreeMap<String, NodeConfig>,
     pub ci: CiConfig,
+    #[serde(default)]
+    pub artifacts: ArtifactsConfig,
     pub environments: BTreeMap<String, EnvironmentConfig>,
     pub services: BTreeMap<String, ServiceConfig>,
 }
 
+/// Listener and connection settings, all optional.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Addresses the HTTP server (and the websocket upgrade on it) binds.
+    pub listen: Vec<String>,
+    pub http_workers: usize,
+    /// Largest websocket message, in bytes, accepted from a browser.
+    pub ws_max_message_size: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec!["127.0.0.1:7878".to_string()],
+            http_workers: 4,
+            ws_max_message_size: 64 << 10,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct RepoCloneConfig {
     pub vcs: String,
     pub clone_url: String,
     pub dir: String,
     pub db_file: Option<String>,
+    /// Key pushes to `/hooks/<repo>` are signed with; the webhook is off without one.
+    pub webhook_secret: Option<String>,
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
@@ -45,43 +87,372 @@ pub struct NodeConfig {
     pub user: String,
     #[serde(deserialize_with = "deserialize_port")]
     pub port: usize,
+    /// How services with `deploy_as_root` get root on the node.
+    #[serde(default)]
+    pub escalation: Escalation,
+    /// Who to log in as for `escalation = "ssh"`.
+    #[serde(default = "default_root_user")]
+    pub root_user: String,
+    /// What the node's CPU runs, detected with `uname -m` when not given.
+    #[serde(default)]
+    pub arch: Option<Arch>,
+}
+
+/// Ways of running a deploy's node steps as root.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Escalation {
+    /// Passwordless `sudo` as the node's `user`.
+    #[default]
+    Sudo,
+    /// Log in as the node's `root_user` instead.
+    Ssh,
+}
+
+/// CPU architectures builds are made for.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Arch {
+    Amd64,
+    Arm64,
+    Armv7,
+}
+
+impl Arch {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Amd64 => "amd64",
+            Self::Arm64 => "arm64",
+            Self::Armv7 => "armv7",
+        }
+    }
+}
+
+impl std::fmt::Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn default_root_user() -> String {
+    "root".to_string()
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct EnvironmentConfig {
     pub nodes: Vec<String>,
+    /// The environment builds running here are promoted to.
+    #[serde(default)]
+    pub promote_to: Option<String>,
 }
 
+/// Where builds run.
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct CiConfig {
+    /// Nodes builds run on, the first one free taking the next build.
     pub nodes: Vec<String>,
+    /// Builds running on one node at the same time.
+    #[serde(default = "default_max_builds")]
+    pub max_builds: usize,
+}
+
+fn default_max_builds() -> usize {
+    1
+}
+
+/// Where builds are kept and for how long, all optional.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ArtifactsConfig {
+    /// Directory of the artifact store.
+    pub dir: String,
+    /// Builds kept per service and architecture.
+    pub keep: usize,
+    /// Days after which a build is removed however few there are, 0 keeps
+    /// them for good.
+    pub max_age_days: u64,
+}
+
+impl Default for ArtifactsConfig {
+    fn default() -> Self {
+        Self {
+            dir: "~/.local/share/pipeline/artifacts".to_string(),
+            keep: 10,
+            max_age_days: 0,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct ServiceConfig {
+    /// The `[repos]` entry the service is built from.
+    pub repo: String,
+    /// Globs, relative to the repo root, of the files the service is built
+    /// from. When empty, everything under `create_workspace` counts.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Seconds between checks of `repo` for new commits, 0 turns polling off
+    /// and leaves it to pushes to the webhook.
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    /// Environments deployed to, one after the other, when a new commit
+    /// touches the service.
+    #[serde(default = "default_auto_deploy")]
+    pub auto_deploy: Vec<String>,
     pub create_workspace: String,
     pub build_workspace: String,
     pub deploy_workspace: String,
+    /// Globs, relative to `build_workspace`, of the files synced to nodes.
+    /// When empty, everything in `build_workspace` is.
+    #[serde(default)]
+    pub artifacts: Vec<String>,
+    /// Globs, relative to `deploy_workspace`, of files a sync never deletes
+    /// from a node even though they aren't artifacts.
+    #[serde(default)]
+    pub protect: Vec<String>,
     pub deploy_as_root: bool,
+    /// Run the service on its nodes as a systemd unit, restarted after each sync.
+    pub unit: Option<UnitConfig>,
+    /// Checks every node has to pass after a deploy for it to succeed.
+    pub health: Option<HealthConfig>,
+    /// Releases kept in `deploy_workspace` on each node to roll back to. When
+    /// 0, deploys sync straight into `deploy_workspace`.
+    #[serde(default)]
+    pub keep_releases: usize,
+    /// Commands building, testing, packaging and deploying the service, in
+    /// place of its `build.sh`.
+    #[serde(default)]
+    pub commands: CommandsConfig,
     #[serde(flatten)]
     pub environments: BTreeMap<String, Vec<ServiceEnvironmentConfig>>,
 }
 
+fn default_poll_interval() -> u64 {
+    60
+}
+
+fn default_auto_deploy() -> Vec<String> {
+    vec!["development".to_string()]
+}
+
+/// The systemd unit a service runs as, `<service>.service`.
+///
+/// It is a user unit of the node's user, or a system unit when the service
+/// has `deploy_as_root`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct UnitConfig {
+    /// Command line of the service. A relative program is one of the
+    /// artifacts in `deploy_workspace`.
+    pub exec_start: String,
+    pub description: Option<String>,
+    /// systemd's `Restart=`, one of [`RESTART_POLICIES`].
+    #[serde(default = "default_restart")]
+    pub restart: String,
+    #[serde(default = "default_restart_sec")]
+    pub restart_sec: u64,
+    /// Defaults to `deploy_workspace`.
+    pub working_directory: Option<String>,
+    #[serde(default)]
+    pub environment: BTreeMap<String, String>,
+}
+
+pub const RESTART_POLICIES: &[&str] = &["no", "always", "on-success", "on-failure", "on-abnormal", "on-abort", "on-watchdog"];
+
+fn default_restart() -> String {
+    "on-failure".to_string()
+}
+
+fn default_restart_sec() -> u64 {
+    5
+}
+
+/// How a service is checked on a node after it was deployed.
+///
+/// Every check has to pass in the same attempt. A node that hasn't passed
+/// once `retries` are used up or `deadline` is reached is unhealthy.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct HealthConfig {
+    /// Attempts after the first before giving up.
+    #[serde(default = "default_health_retries")]
+    pub retries: u32,
+    /// Seconds between attempts.
+    #[serde(default = "default_health_interval")]
+    pub interval: u64,
+    /// Seconds from the first attempt after which the node is unhealthy.
+    #[serde(default = "default_health_deadline")]
+    pub deadline: u64,
+    pub checks: Vec<HealthCheck>,
+}
+
+/// One health check. `{host}` in a url or address is the node's `host_name`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum HealthCheck {
+    /// GET an `http://` url, expecting `status` and a body containing `body` when set.
+    Http {
+        url: String,
+        #[serde(default = "default_health_status")]
+        status: u16,
+        body: Option<String>,
+    },
+    /// Open a connection to a `host:port` address.
+    Tcp { address: String },
+    /// Run a `sh` command on the node, expecting it to exit 0.
+    Command { command: String },
+    /// `systemctl is-active` on the node, for the service's own unit unless `unit` is set.
+    Systemctl { unit: Option<String> },
+}
+
+/// Stands for the node's `host_name` in health check urls and addresses.
+pub const HOST_PLACEHOLDER: &str = "{host}";
+
+fn default_health_retries() -> u32 {
+    10
+}
+
+fn default_health_interval() -> u64 {
+    3
+}
+
+fn default_health_deadline() -> u64 {
+    60
+}
+
+fn default_health_status() -> u16 {
+    200
+}
+
+/// The commands of each stage of a service's pipeline, run in order.
+///
+/// Build, test and package run on the CI node, build and test in
+/// `create_workspace` and package in `build_workspace`. Without build
+/// commands the service's `build.sh` is run instead. Deploy commands run on
+/// every node after its sync, in what was synced to.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+pub struct CommandsConfig {
+    #[serde(default)]
+    pub build: Vec<CommandStep>,
+    #[serde(default)]
+    pub test: Vec<CommandStep>,
+    #[serde(default)]
+    pub package: Vec<CommandStep>,
+    #[serde(default)]
+    pub deploy: Vec<CommandStep>,
+}
+
+impl CommandsConfig {
+    /// Each stage's name with its commands, in the order the stages run.
+    pub fn stages(&self) -> [(&'static str, &[CommandStep]); 4] {
+        [("build", &self.build), ("test", &self.test), ("package", &self.package), ("deploy", &self.deploy)]
+    }
+}
+
+/// One command of a pipeline stage.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct CommandStep {
+    /// Shown for the step in the UI, defaults to the command.
+    pub name: Option<String>,
+    /// `sh` script to run, failing the step when it doesn't exit 0.
+    pub command: String,
+    /// Directory to run in, relative to the stage's workspace unless absolute.
+    pub working_dir: Option<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Seconds the command may run, defaults to the deploy's step timeout.
+    pub timeout: Option<u64>,
+    /// Carry on with the pipeline when the command fails.
+    #[serde(default)]
+    pub allow_failure: bool,
+}
+
+impl CommandStep {
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.command.trim())
+    }
+}
+
+/// A group of nodes a service deploys to in an environment.
+///
+/// The groups of an environment deploy one after the other as waves, the
+/// nodes of a group in parallel, at most `max_parallel` at a time.
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct ServiceEnvironmentConfig {
     pub nodes: Vec<String>,
+    #[serde(default = "default_max_parallel")]
+    pub max_parallel: usize,
+}
+
+fn default_max_parallel() -> usize {
+    4
+}
+
+/// Choose the file the global config is loaded from.
+///
+/// Has to happen before the first [`get_config`]; returns false if a path was
+/// already set.
+pub fn set_config_path(path: impl Into<PathBuf>) -> bool {
+    CONFIG_PATH.set(path.into()).is_ok()
+}
+
+/// The file the global config is loaded from: the path set with
+/// [`set_config_path`], else `$PIPELINE_CONFIG`, else `./config.toml`.
+pub fn config_path() -> PathBuf {
+    if let Some(path) = CONFIG_PATH.get() {
+        return path.clone();
+    }
+    match env::var_os(CONFIG_PATH_ENV) {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => PathBuf::from(DEFAULT_CONFIG_PATH),
+    }
+}
+
+/// A parsed config along with the problems that didn't stop it from loading.
+#[derive(Debug)]
+pub struct CheckedConfig {
+    pub config: AppConfig,
+    pub warnings: Vec<ConfigError>,
+}
+
+/// Parse and validate config file contents, collecting every problem found.
+///
+/// Fails if any problem is an error; the returned list then holds the
+/// warnings too, ordered by where they are in the file.
+pub fn check_config(contents: &str) -> Result<CheckedConfig, Vec<ConfigError>> {
+    let config = toml::from_str::<AppConfig>(contents)
+        .map_err(|e| vec![error::parse_error(contents, &e)])?;
+    let mut problems = validate_config(&config);
+    error::locate(contents, &mut problems);
+    problems.sort_by_key(|p| (p.location, p.severity));
+    if problems.iter().any(ConfigError::is_error) {
+        return Err(problems);
+    }
+    Ok(CheckedConfig { config, warnings: problems })
 }
 
 /// Load configuration from a TOML file and initialize the global config.
 ///
-/// Subsequent calls return an error to prevent accidental reconfiguration.
+/// Every problem with the file is printed before the process exits, so a bad
+/// config can be fixed in one go.
 fn load_config() -> AppConfig {
-    let contents = fs::read_to_string("./config.toml")
-        .unwrap_or_else(|e| panic!("error, when reading config contents. Error: {e}"));
-    let mut config = toml::from_str::<AppConfig>(&contents)
-        .unwrap_or_else(|e| panic!("error, config failed to load. Error: {e}"));
-    validate_config(&config)
-        .unwrap_or_else(|e| panic!("error, config failed validation. Error: {e}"));
+    let path = config_path();
+    let contents = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("error, when reading config contents from {}. Error: {e}", path.display()));
+    let mut config = match check_config(&contents) {
+        Ok(CheckedConfig { config, warnings }) => {
+            for warning in &warnings {
+                eprintln!("{}: {}", path.display(), warning);
+            }
+            config
+        }
+        Err(problems) => {
+            for problem in &problems {
+                eprintln!("{}: {}", path.display(), problem);
+            }
+            let errors = problems.iter().filter(|p| p.is_error()).count();
+            eprintln!("error, config failed validation with {errors} error(s)");
+            process::exit(1);
+        }
+    };
     if config.environment == "development" {
         let current_time = SystemTime::now();
         let duration_since_epoch = current_time
@@ -100,126 +471,318 @@ pub fn get_config() -> &'static AppConfig {
     CONFIG.get_or_init(load_config)
 }
 
-fn validate_config(config: &AppConfig) -> Result<(), String> {
+/// Check the parsed config for everything serde can't, without stopping at the first problem.
+fn validate_config(config: &AppConfig) -> Vec<ConfigError> {
+    use ConfigErrorKind::*;
+    let root = KeyPath::root();
+    let mut errors = Vec::new();
+
     if config.database_path.is_empty() {
-        return Err("database_path is required".to_string());
+        errors.push(ConfigError::error(root.key("database_path"), Required));
     }
     if config.migrations_dir.is_empty() {
-        return Err("migrations_dir is required".to_string());
+        errors.push(ConfigError::error(root.key("migrations_dir"), Required));
     }
     if config.max_users == 0 {
-        return Err("max_users must be greater than zero".to_string());
+        errors.push(ConfigError::error(root.key("max_users"), MustBePositive));
     }
 
-    if config.repos.is_empty() {
-        return Err("no repos provided".to_string());
+    let server = root.key("server");
+    if config.server.listen.is_empty() {
+        errors.push(ConfigError::error(server.key("listen"), Empty));
+    }
+    for (i, address) in config.server.listen.iter().enumerate() {
+        let has_port = address
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !has_port {
+            errors.push(ConfigError::error(server.key("listen").index(i), InvalidAddress(address.clone())));
+        }
+    }
+    if config.server.http_workers == 0 {
+        errors.push(ConfigError::error(server.key("http_workers"), MustBePositive));
+    }
+    if config.server.ws_max_message_size == 0 {
+        errors.push(ConfigError::error(server.key("ws_max_message_size"), MustBePositive));
     }
 
+    let artifacts = root.key("artifacts");
+    if config.artifacts.dir.is_empty() {
+        errors.push(ConfigError::error(artifacts.key("dir"), Required));
+    }
+    if config.artifacts.keep == 0 {
+        errors.push(ConfigError::error(artifacts.key("keep"), MustBePositive));
+    }
+
+    if config.repos.is_empty() {
+        errors.push(ConfigError::error(root.key("repos"), Empty));
+    }
     for (repo_name, repo_cfg) in &config.repos {
-        if repo_cfg.vcs.is_empty() {
-            return Err(format!("repo {repo_name} requires a vcs"));
-        }
-        if repo_cfg.clone_url.is_empty() {
-            return Err(format!("repo {repo_name} requires a clone_url"));
+        let repo = root.key("repos").key(repo_name);
+        for (key, value) in [("vcs", &repo_cfg.vcs), ("clone_url", &repo_cfg.clone_url), ("dir", &repo_cfg.dir)] {
+            if value.is_empty() {
+                errors.push(ConfigError::error(repo.key(key), Required));
+            }
         }
-        if repo_cfg.dir.is_empty() {
-            return Err(format!("repo {repo_name} requires a dir"));
+        if repo_cfg.webhook_secret.as_deref() == Some("") {
+            errors.push(ConfigError::error(repo.key("webhook_secret"), Required));
         }
         if repo_cfg.vcs == "fossil" && repo_cfg.db_file.is_none() {
-            return Err(format!("repo {repo_name} requires a db_file"));
+            errors.push(ConfigError::error(repo, MissingDbFile));
         }
     }
 
     if config.nodes.is_empty() {
-        return Err("no nodes provided".to_string());
+        errors.push(ConfigError::error(root.key("nodes"), Empty));
     }
-
     for (node_name, node_cfg) in &config.nodes {
+        let node = root.key("nodes").key(node_name);
         if node_cfg.host_name.is_empty() {
-            return Err(format!("node {} requires a host_name", node_name));
+            errors.push(ConfigError::error(node.key("host_name"), Required));
         }
         if node_cfg.user.is_empty() {
-            return Err(format!("node {} requires a user", node_name));
+            errors.push(ConfigError::error(node.key("user"), Required));
         }
         if node_cfg.port == 0 {
-            return Err(format!("node {} requires a port", node_name));
+            errors.push(ConfigError::error(node.key("port"), Required));
+        }
+        if node_cfg.escalation == Escalation::Ssh && node_cfg.root_user.is_empty() {
+            errors.push(ConfigError::error(node.key("root_user"), Required));
         }
     }
 
-    if config.ci.nodes.is_empty() {
-        return Err("no ci nodes defined".to_string());
+    check_nodes(config, &mut errors, root.key("ci").key("nodes"), &config.ci.nodes, &mut Vec::new());
+    if config.ci.max_builds == 0 {
+        errors.push(ConfigError::error(root.key("ci").key("max_builds"), MustBePositive));
     }
 
     if config.environments.is_empty() {
-        return Err("no environments defined".to_string());
+        errors.push(ConfigError::error(root.key("environments"), Empty));
     }
-
     for (env_name, env_cfg) in &config.environments {
-        if env_cfg.nodes.is_empty() {
-            return Err(format!("environment '{env_name}' requires at least one node"));
-        }
-        for node in &env_cfg.nodes {
-            if !config.nodes.contains_key(node) {
-                return Err(format!(
-                    "environment '{env_name}' references unknown node '{node}'"
-                ));
+        let env = root.key("environments").key(env_name);
+        check_nodes(config, &mut errors, env.key("nodes"), &env_cfg.nodes, &mut Vec::new());
+        if let Some(next) = &env_cfg.promote_to {
+            if !config.environments.contains_key(next) {
+                errors.push(ConfigError::error(env.key("promote_to"), UnknownEnvironment(next.clone())));
+            } else if promotes_back(config, env_name) {
+                errors.push(ConfigError::error(env.key("promote_to"), PromotionCycle(env_name.clone())));
             }
         }
     }
 
     if config.services.is_empty() {
-        return Err(format!("services requires at least one entry"));
+        errors.push(ConfigError::error(root.key("services"), Empty));
     }
     for (service_name, service_cfg) in &config.services {
-        if service_cfg.create_workspace.is_empty() {
-            return Err(format!(
-                "service '{service_name}' requires create_workspace"
-            ));
+        let service = root.key("services").key(service_name);
+        match config.repos.get(&service_cfg.repo) {
+            _ if service_cfg.repo.is_empty() => errors.push(ConfigError::error(service.key("repo"), Required)),
+            None => errors.push(ConfigError::error(service.key("repo"), UnknownRepo(service_cfg.repo.clone()))),
+            Some(repo) => {
+                if service_cfg.paths.is_empty() && !is_within(&service_cfg.create_workspace, &repo.dir) {
+                    // without paths the service would be rebuilt for every change to the repo
+                    errors.push(ConfigError::warning(service.key("create_workspace"), OutsideRepo(service_cfg.repo.clone())));
+                }
+            }
         }
-        if service_cfg.build_workspace.is_empty() {
-            return Err(format!(
-                "service '{service_name}' requires build_workspace"
-            ));
+        for (key, patterns) in [
+            ("paths", &service_cfg.paths),
+            ("artifacts", &service_cfg.artifacts),
+            ("protect", &service_cfg.protect),
+        ] {
+            for (i, pattern) in patterns.iter().enumerate() {
+                if let Some(reason) = glob::check(pattern) {
+                    errors.push(ConfigError::error(service.key(key).index(i), InvalidGlob(pattern.clone(), reason)));
+                }
+            }
+        }
+        for (key, workspace) in [
+            ("create_workspace", &service_cfg.create_workspace),
+            ("build_workspace", &service_cfg.build_workspace),
+            ("deploy_workspace", &service_cfg.deploy_workspace),
+        ] {
+            if workspace.is_empty() {
+                errors.push(ConfigError::error(service.key(key), Required));
+            }
+        }
+        if let Some(unit) = &service_cfg.unit {
+            let unit_path = service.key("unit");
+            if unit.exec_start.trim().is_empty() {
+                errors.push(ConfigError::error(unit_path.key("exec_start"), Required));
+            }
+            if !RESTART_POLICIES.contains(&unit.restart.as_str()) {
+                errors.push(ConfigError::error(unit_path.key("restart"), InvalidChoice(unit.restart.clone(), RESTART_POLICIES)));
+            }
+            for name in unit.environment.keys() {
+                if !is_env_name(name) {
+                    errors.push(ConfigError::error(unit_path.key("environment").key(name), InvalidEnvName(name.clone())));
+                }
+            }
         }
-        if service_cfg.deploy_workspace.is_empty() {
-            return Err(format!(
-                "service '{service_name}' requires deploy_workspace"
-            ));
+        for (stage, steps) in service_cfg.commands.stages() {
+            check_commands(&mut errors, service.key("commands").key(stage), steps);
+        }
+        if let Some(health) = &service_cfg.health {
+            check_health(&mut errors, service.key("health"), health, service_cfg.unit.is_some());
         }
         if service_cfg.environments.is_empty() {
-            return Err(format!(
-                "service '{service_name}' requires environments"
-            ));
+            errors.push(ConfigError::error(service.clone(), Empty));
+        }
+        for (i, env_name) in service_cfg.auto_deploy.iter().enumerate() {
+            if !service_cfg.environments.contains_key(env_name) {
+                errors.push(ConfigError::error(service.key("auto_deploy").index(i), UnknownEnvironment(env_name.clone())));
+            }
         }
         for (env_name, env_cfgs) in &service_cfg.environments {
-            config.environments.get(env_name).ok_or_else(|| {
-                format!(
-                    "service '{service_name}' references unknown environment '{env_name}'"
-                )
-            })?;
+            let env = service.key(env_name);
+            if !config.environments.contains_key(env_name) {
+                errors.push(ConfigError::error(env.clone(), UnknownEnvironment(env_name.clone())));
+            }
             if env_cfgs.is_empty() {
-                return Err(format!(
-                    "service '{service_name}' environment '{env_name}' requires at least one entry"
-                ));
+                errors.push(ConfigError::error(env.clone(), Empty));
             }
+            // a node belongs to one group of an environment, otherwise it would be deployed to twice
+            let mut seen = Vec::new();
             for (env_idx, env_cfg) in env_cfgs.iter().enumerate() {
-                if env_cfg.nodes.is_empty() {
-                    return Err(format!(
-                        "service '{service_name}' environment '{env_name}'[{env_idx}] requires at least one node"
-                    ));
+                check_nodes(config, &mut errors, env.index(env_idx).key("nodes"), &env_cfg.nodes, &mut seen);
+                if env_cfg.max_parallel == 0 {
+                    errors.push(ConfigError::error(env.index(env_idx).key("max_parallel"), MustBePositive));
+                }
+            }
+        }
+    }
+
+    let used_by_services = config.services.values()
+        .flat_map(|s| s.environments.values())
+        .flatten()
+        .flat_map(|group| group.nodes.iter());
+    let used_by_environments = config.environments.values().flat_map(|e| e.nodes.iter());
+    let used: Vec<&String> = config.ci.nodes.iter().chain(used_by_environments).chain(used_by_services).collect();
+    for node_name in config.nodes.keys() {
+        if !used.contains(&node_name) {
+            errors.push(ConfigError::warning(root.key("nodes").key(node_name), UnusedNode(node_name.clone())));
+        }
+    }
+
+    for repo_name in config.repos.keys() {
+        if !config.services.values().any(|s| &s.repo == repo_name) {
+            errors.push(ConfigError::warning(root.key("repos").key(repo_name), UnusedRepo(repo_name.clone())));
+        }
+    }
+
+    errors
+}
+
+/// Whether following `promote_to` from `environment` leads back to it.
+fn promotes_back(config: &AppConfig, environment: &str) -> bool {
+    let mut next = config.environments.get(environment).and_then(|env| env.promote_to.as_deref());
+    for _ in 0..config.environments.len() {
+        match next {
+            Some(name) if name == environment => return true,
+            Some(name) => next = config.environments.get(name).and_then(|env| env.promote_to.as_deref()),
+            None => return false,
+        }
+    }
+    false
+}
+
+/// Check a list of node names: it can't be empty, every name must be a
+/// configured node and none may already be in `seen`.
+fn check_nodes(config: &AppConfig, errors: &mut Vec<ConfigError>, path: KeyPath, nodes: &[String], seen: &mut Vec<String>) {
+    if nodes.is_empty() {
+        errors.push(ConfigError::error(path.clone(), ConfigErrorKind::Empty));
+    }
+    for (i, node) in nodes.iter().enumerate() {
+        if !config.nodes.contains_key(node) {
+            errors.push(ConfigError::error(path.index(i), ConfigErrorKind::UnknownNode(node.clone())));
+        } else if seen.contains(node) {
+            errors.push(ConfigError::error(path.index(i), ConfigErrorKind::DuplicateNode(node.clone())));
+        } else {
+            seen.push(node.clone());
+        }
+    }
+}
+
+/// Check the commands of one stage of a service's pipeline.
+fn check_commands(errors: &mut Vec<ConfigError>, path: KeyPath, steps: &[CommandStep]) {
+    use ConfigErrorKind::*;
+    let mut seen = Vec::new();
+    for (i, step) in steps.iter().enumerate() {
+        let step_path = path.index(i);
+        if step.command.trim().is_empty() {
+            errors.push(ConfigError::error(step_path.key("command"), Required));
+        } else if seen.contains(&step.display_name()) {
+            errors.push(ConfigError::error(step_path.key("name"), DuplicateStep(step.display_name().to_string())));
+        } else {
+            seen.push(step.display_name());
+        }
+        if step.working_dir.as_deref().is_some_and(|dir| dir.trim().is_empty()) {
+            errors.push(ConfigError::error(step_path.key("working_dir"), Required));
+        }
+        if step.timeout == Some(0) {
+            errors.push(ConfigError::error(step_path.key("timeout"), MustBePositive));
+        }
+        for name in step.env.keys() {
+            if !is_env_name(name) {
+                errors.push(ConfigError::error(step_path.key("env").key(name), InvalidEnvName(name.clone())));
+            }
+        }
+    }
+}
+
+fn is_env_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Check a service's health checks; `has_unit` is whether the service runs as a unit.
+fn check_health(errors: &mut Vec<ConfigError>, path: KeyPath, health: &HealthConfig, has_unit: bool) {
+    use ConfigErrorKind::*;
+    if health.deadline == 0 {
+        errors.push(ConfigError::error(path.key("deadline"), MustBePositive));
+    }
+    if health.checks.is_empty() {
+        errors.push(ConfigError::error(path.key("checks"), Empty));
+    }
+    for (i, check) in health.checks.iter().enumerate() {
+        let check_path = path.key("checks").index(i);
+        match check {
+            HealthCheck::Http { url, .. } => {
+                let authority = url
+                    .strip_prefix("http://")
+                    .map(|rest| rest.split('/').next().unwrap_or_default().replace(HOST_PLACEHOLDER, "host"));
+                if authority.is_none_or(|authority| authority.is_empty() || authority.starts_with(':')) {
+                    errors.push(ConfigError::error(check_path.key("url"), InvalidUrl(url.clone())));
                 }
-                for node in &env_cfg.nodes {
-                    if !config.nodes.contains_key(node) {
-                        return Err(format!(
-                            "service '{service_name}' environment '{env_name}'[{env_idx}] references unknown node '{node}'",
-                        ));
-                    }
+            }
+            HealthCheck::Tcp { address } => {
+                let has_port = address
+                    .replace(HOST_PLACEHOLDER, "host")
+                    .rsplit_once(':')
+                    .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+                if !has_port {
+                    errors.push(ConfigError::error(check_path.key("address"), InvalidAddress(address.clone())));
                 }
             }
+            HealthCheck::Command { command } if command.trim().is_empty() => {
+                errors.push(ConfigError::error(check_path.key("command"), Required));
+            }
+            HealthCheck::Systemctl { unit: None } if !has_unit => {
+                // without a unit of its own the service has nothing to check
+                errors.push(ConfigError::error(check_path.key("unit"), Required));
+            }
+            HealthCheck::Systemctl { unit: Some(unit) } if unit.is_empty() => {
+                errors.push(ConfigError::error(check_path.key("unit"), Required));
+            }
+            HealthCheck::Command { .. } | HealthCheck::Systemctl { .. } => {}
         }
     }
+}
 
-    Ok(())
+/// Whether `path` is `dir` or somewhere below it.
+fn is_within(path: &str, dir: &str) -> bool {
+    let dir = dir.trim_end_matches('/');
+    path.strip_prefix(dir).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
 }
 
 fn deserialize_port<'de, D>(deserializer: D) -> Result<usize, D::Error>
@@ -260,12 +823,147 @@ where
 mod tests {
     use super::*;
 
+    const EXAMPLE: &str = include_str!("../../../config/example.toml");
+
     #[test]
     fn example_config_parses_and_validates() {
-        let contents = include_str!("../../../config/example.toml");
-        let config = toml::from_str::<AppConfig>(contents)
-            .unwrap_or_else(|e| panic!("failed to parse example config: {e}"));
-        validate_config(&config)
-            .unwrap_or_else(|e| panic!("example config failed validation: {e}"));
+        let checked = check_config(EXAMPLE)
+            .unwrap_or_else(|e| panic!("example config failed validation: {e:?}"));
+        assert_eq!(checked.config.server.http_workers, 2);
+        assert!(checked.warnings.iter().all(|w| !w.is_error()));
+    }
+
+    #[test]
+    fn server_section_is_optional() {
+        let start = EXAMPLE.find("[server]").expect("example has a server section");
+        let end = start + EXAMPLE[start..].find("\n\n").expect("server section ends");
+        let without_server = format!("{}{}", &EXAMPLE[..start], &EXAMPLE[end..]);
+        let config = toml::from_str::<AppConfig>(&without_server)
+            .unwrap_or_else(|e| panic!("failed to parse config without server section: {e}"));
+        assert_eq!(config.server, ServerConfig::default());
+    }
+
+    #[test]
+    fn collects_every_problem_with_locations() {
+        let broken = EXAMPLE
+            .replace("listen = [\"127.0.0.1:7878\"]", "listen = [\"7878\"]")
+            .replace("[ci]\nnodes = [\"local\", \"pi2\"]", "[ci]\nnodes = [\"local\", \"local\"]")
+            .replace("[[services.example_service_1.staging]]\nnodes = [\"pi1\"]", "[[services.example_service_1.staging]]\nnodes = [\"pi9\"]")
+            .replace("build_workspace = \"~/build/example_service_2\"", "build_workspace = \"\"");
+        let problems = check_config(&broken).expect_err("broken config should fail");
+        let errors: Vec<String> = problems.iter().filter(|p| p.is_error()).map(|p| p.to_string()).collect();
+        assert_eq!(errors.len(), 4, "{errors:#?}");
+
+        let line_of = |needle: &str| broken.lines().position(|l| l.contains(needle)).unwrap() + 1;
+        let unknown = problems.iter().find(|p| p.kind == ConfigErrorKind::UnknownNode("pi9".to_string())).unwrap();
+        assert_eq!(unknown.path.to_string(), "services.example_service_1.staging[0].nodes[0]");
+        assert_eq!(unknown.location, Some(Location { line: line_of("\"pi9\""), column: 10 }));
+
+        let duplicate = problems.iter().find(|p| p.kind == ConfigErrorKind::DuplicateNode("local".to_string())).unwrap();
+        assert_eq!(duplicate.path.to_string(), "ci.nodes[1]");
+        assert_eq!(duplicate.location.map(|l| l.line), Some(line_of("nodes = [\"local\", \"local\"]")));
+
+        let workspace = problems.iter().find(|p| p.path.to_string() == "services.example_service_2.build_workspace").unwrap();
+        assert_eq!(workspace.location.map(|l| l.line), Some(line_of("build_workspace = \"\"")));
+
+        let unused = problems.iter().find(|p| p.kind == ConfigErrorKind::UnusedRepo("pipeline".to_string())).unwrap();
+        assert_eq!(unused.severity, Severity::Warning);
+        assert_eq!(unused.location.map(|l| l.line), Some(line_of("[repos.pipeline]")));
+    }
+
+    #[test]
+    fn promotions_go_to_known_environments_without_cycles() {
+        let broken = EXAMPLE
+            .replace("promote_to = \"production\"", "promote_to = \"development\"")
+            .replace("nodes = [\"pi3\", \"pi4\"]", "nodes = [\"pi3\", \"pi4\"]\npromote_to = \"prod\"");
+        let problems = check_config(&broken).expect_err("broken config should fail");
+        let found: Vec<(String, &ConfigErrorKind)> = problems.iter().map(|p| (p.path.to_string(), &p.kind)).collect();
+        assert!(found.contains(&("environments.development.promote_to".to_string(), &ConfigErrorKind::PromotionCycle("development".to_string()))));
+        assert!(found.contains(&("environments.staging.promote_to".to_string(), &ConfigErrorKind::PromotionCycle("staging".to_string()))));
+        assert!(found.contains(&("environments.production.promote_to".to_string(), &ConfigErrorKind::UnknownEnvironment("prod".to_string()))));
+    }
+
+    #[test]
+    fn services_reference_a_repo() {
+        let broken = EXAMPLE
+            .replacen("repo = \"create\"", "repo = \"creat\"", 1)
+            .replace("\"shared/**\"", "\"../shared/**\"")
+            .replace("create_workspace = \"~/create/example_service_2\"", "create_workspace = \"~/elsewhere/example_service_2\"");
+        let problems = check_config(&broken).expect_err("broken config should fail");
+        let kinds: Vec<&ConfigErrorKind> = problems.iter().map(|p| &p.kind).collect();
+        assert!(kinds.contains(&&ConfigErrorKind::UnknownRepo("creat".to_string())), "{kinds:#?}");
+        assert!(kinds.contains(&&ConfigErrorKind::InvalidGlob("../shared/**".to_string(), "can't go above its root with '..'")));
+        assert!(kinds.contains(&&ConfigErrorKind::OutsideRepo("create".to_string())));
+
+        let glob = problems.iter().find(|p| matches!(p.kind, ConfigErrorKind::InvalidGlob(..))).unwrap();
+        assert_eq!(glob.path.to_string(), "services.example_service_1.paths[1]");
+        assert_eq!(glob.location.map(|l| l.line), Some(broken.lines().position(|l| l.contains("../shared")).unwrap() + 1));
+    }
+
+    #[test]
+    fn checks_unit_settings() {
+        let broken = EXAMPLE
+            .replace("restart = \"on-failure\"", "restart = \"sometimes\"")
+            .replace("RUST_LOG = ", "\"RUST-LOG\" = ");
+        let problems = check_config(&broken).expect_err("broken config should fail");
+        let paths: Vec<String> = problems.iter().filter(|p| p.is_error()).map(|p| format!("{}: {}", p.path, p.kind)).collect();
+        assert_eq!(paths, [
+            "services.example_service_1.unit.restart: 'sometimes' must be one of no, always, on-success, on-failure, on-abnormal, on-abort, on-watchdog",
+            "services.example_service_1.unit.environment.RUST-LOG: environment variable 'RUST-LOG' may only contain letters, digits and '_'",
+        ]);
+    }
+
+    #[test]
+    fn checks_health_settings() {
+        let checked = check_config(EXAMPLE).expect("example config");
+        let health = checked.config.services["example_service_1"].health.clone().expect("health");
+        assert_eq!(health.checks[0], HealthCheck::Http {
+            url: "http://{host}:8080/health".to_string(),
+            status: 200,
+            body: Some("ok".to_string()),
+        });
+        assert_eq!(health.checks[3], HealthCheck::Systemctl { unit: None });
+
+        let broken = EXAMPLE
+            .replace("url = \"http://{host}:8080/health\"", "url = \"https://{host}/health\"")
+            .replace("address = \"{host}:8080\"", "address = \"{host}\"")
+            .replace("kind = \"systemctl\"\n", "kind = \"systemctl\"\nunit = \"\"\n");
+        let problems = check_config(&broken).expect_err("broken config should fail");
+        let paths: Vec<String> = problems.iter().filter(|p| p.is_error()).map(|p| format!("{}: {}", p.path, p.kind)).collect();
+        assert_eq!(paths, [
+            "services.example_service_1.health.checks[0].url: url 'https://{host}/health' must be http://host[:port][/path]",
+            "services.example_service_1.health.checks[1].address: address '{host}' must be host:port",
+            "services.example_service_1.health.checks[3].unit: is required",
+        ]);
+    }
+
+    #[test]
+    fn checks_service_commands() {
+        let checked = check_config(EXAMPLE).expect("example config");
+        let commands = &checked.config.services["example_service_2"].commands;
+        assert_eq!(commands.test[0].display_name(), "unit tests");
+        assert_eq!(commands.build[0].display_name(), "cargo build --release");
+        assert!(commands.test[1].allow_failure);
+
+        let broken = EXAMPLE
+            .replace("name = \"lint\"", "name = \"unit tests\"")
+            .replace("timeout = 900", "timeout = 0")
+            .replace("CARGO_TERM_COLOR", "\"BAD-NAME\"")
+            .replace("command = \"./example_service_2 --version\"", "command = \" \"");
+        let problems = check_config(&broken).expect_err("broken config should fail");
+        let paths: Vec<String> = problems.iter().filter(|p| p.is_error()).map(|p| format!("{}: {}", p.path, p.kind)).collect();
+        assert_eq!(paths, [
+            "services.example_service_2.commands.build[0].env.BAD-NAME: environment variable 'BAD-NAME' may only contain letters, digits and '_'",
+            "services.example_service_2.commands.build[0].timeout: must be greater than zero",
+            "services.example_service_2.commands.test[1].name: step 'unit tests' is already a step of this stage, give it another name",
+            "services.example_service_2.commands.deploy[0].command: is required",
+        ]);
+    }
+
+    #[test]
+    fn parse_errors_have_a_location() {
+        let problems = check_config("environment = \"development\"\nmax_users = \"many\"\n").unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].location.map(|l| l.line), Some(2));
     }
 }
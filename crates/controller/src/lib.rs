@@ -1,9 +1,13 @@
 //! Controller layer coordinating requests between models and views.
 
 use config::AppConfig;
-use model::{ModelResult, SqliteUserModel, User};
+use model::{Deployment, DeploymentFilter, DeploymentLog, DeploymentStatus, DeploymentStep, ModelResult, SqliteArtifactModel, SqliteDeploymentModel, SqliteUserModel, StoredArtifact, User};
 use std::collections::HashMap;
 use view::{get_landing_app, get_landing_page, get_landing_services_oob, get_settings_app, get_settings_page, get_service_app, get_service_page, get_not_found, get_not_found_app};
+use view::{DeploymentsQuery, get_deployments_app, get_deployments_page, get_deployment_app, get_deployment_page};
+
+/// Most deployments shown on the history page at once.
+const DEPLOYMENT_HISTORY_LIMIT: u32 = 200;
 
 /// Coordinates model operations for the view layer.
 pub struct UserController {
@@ -27,7 +31,11 @@ impl UserController {
 #[derive(Debug, PartialEq, Eq)]
 pub enum AppEvent {
     Ping,
-    Deploy(String),
+    Deploy { service: String, environment: String },
+    Rollback { service: String, environment: String },
+    /// Promote what `environment` runs to the environment it promotes to.
+    Promote { service: String, environment: String },
+    Subscribe(String),
     SearchServices(String),
     Navigate(String),
 }
@@ -47,8 +55,19 @@ pub fn parse_event(text: &str) -> Result<AppEvent, ParseEventError> {
         "ping" => {
             if rest.is_some() { Err(ParseEventError::ExtraData) } else { Ok(AppEvent::Ping) }
         }
-        "deploy" => match rest {
-            Some(service) if !service.is_empty() => Ok(AppEvent::Deploy(service.to_string())),
+        "deploy" | "rollback" | "promote" => match rest.and_then(|rest| rest.split_once(':')) {
+            Some((service, environment)) if !service.is_empty() && !environment.is_empty() => {
+                let (service, environment) = (service.to_string(), environment.to_string());
+                match kind {
+                    "deploy" => Ok(AppEvent::Deploy { service, environment }),
+                    "rollback" => Ok(AppEvent::Rollback { service, environment }),
+                    _ => Ok(AppEvent::Promote { service, environment }),
+                }
+            }
+            _ => Err(ParseEventError::MissingArg),
+        },
+        "subscribe" => match rest {
+            Some(service) => Ok(AppEvent::Subscribe(service.to_string())),
             _ => Err(ParseEventError::MissingArg),
         },
         "search_services" => match rest {
@@ -89,8 +108,10 @@ pub fn handle_nav(path: &str, query_params: HashMap<String, String>, config: &Ap
         },
         "/service" => match mode {
             UiMode::FullPage => UiResult::FullHtml(get_service_page(query_params, config)),
-            UiMode::Patch => UiResult::Patch(get_service_app(query_params)),
+            UiMode::Patch => UiResult::Patch(get_service_app(query_params, config)),
         },
+        "/deployments" => handle_deployments(&query_params, config, mode),
+        "/deployment" => handle_deployment(&query_params, config, mode),
         _ => match mode {
             UiMode::FullPage => UiResult::NotFound(get_not_found()),
             UiMode::Patch => UiResult::Patch(get_not_found_app()),
@@ -98,6 +119,63 @@ pub fn handle_nav(path: &str, query_params: HashMap<String, String>, config: &Ap
     }
 }
 
+fn handle_deployments(query_params: &HashMap<String, String>, config: &AppConfig, mode: UiMode) -> UiResult {
+    let query = DeploymentsQuery {
+        service: query_params.get("service").map(String::as_str).filter(|s| !s.is_empty()),
+        status: query_params.get("status").and_then(|s| DeploymentStatus::parse(s)),
+    };
+    let filter = DeploymentFilter {
+        service: query.service,
+        status: query.status,
+    };
+    let deployments = SqliteDeploymentModel::new()
+        .list_deployments(&filter, DEPLOYMENT_HISTORY_LIMIT)
+        .unwrap_or_else(|e| {
+            eprintln!("error, when listing deployments. Error: {e}");
+            Vec::new()
+        });
+    match mode {
+        UiMode::FullPage => UiResult::FullHtml(get_deployments_page(&deployments, &query, config)),
+        UiMode::Patch => UiResult::Patch(get_deployments_app(&deployments, &query, config)),
+    }
+}
+
+fn handle_deployment(query_params: &HashMap<String, String>, config: &AppConfig, mode: UiMode) -> UiResult {
+    let found = match query_params.get("id").and_then(|id| id.parse::<u64>().ok()) {
+        Some(id) => load_deployment(id).unwrap_or_else(|e| {
+            eprintln!("error, when loading deployment {id}. Error: {e}");
+            None
+        }),
+        None => None,
+    };
+    match (found, mode) {
+        (Some((deployment, steps, builds, logs)), UiMode::FullPage) => {
+            UiResult::FullHtml(get_deployment_page(&deployment, &steps, &builds, &logs, config))
+        }
+        (Some((deployment, steps, builds, logs)), UiMode::Patch) => {
+            UiResult::Patch(get_deployment_app(&deployment, &steps, &builds, &logs))
+        }
+        (None, UiMode::FullPage) => UiResult::NotFound(get_not_found()),
+        (None, UiMode::Patch) => UiResult::Patch(get_not_found_app()),
+    }
+}
+
+/// A deployment with the status of its steps, the builds it shipped and its output.
+type DeploymentDetails = (Deployment, Vec<DeploymentStep>, Vec<StoredArtifact>, Vec<DeploymentLog>);
+
+fn load_deployment(id: u64) -> ModelResult<Option<DeploymentDetails>> {
+    let history = SqliteDeploymentModel::new();
+    match history.find_deployment(id)? {
+        Some(deployment) => {
+            let steps = history.list_steps(deployment.id())?;
+            let builds = SqliteArtifactModel::new().deployment_artifacts(deployment.id())?;
+            let logs = history.list_logs(deployment.id())?;
+            Ok(Some((deployment, steps, builds, logs)))
+        }
+        None => Ok(None),
+    }
+}
+
 pub fn parse_query_params(query: &str) -> HashMap<String, String> {
     query
         .split('&')
@@ -109,11 +187,34 @@ pub fn parse_query_params(query: &str) -> HashMap<String, String> {
                 return None;
             }
             let value = parts.next().unwrap_or_default();
-            Some((key.to_string(), value.to_string()))
+            Some((percent_decode(key), percent_decode(value)))
         })
         .collect()
 }
 
+/// Decode a query string key or value, `+` standing for a space as in
+/// submitted forms. Malformed escapes are kept as they are.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match bytes.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()) {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
 pub fn get_filtered_landing_app(query: &str, config: &AppConfig) -> String {
     let query = query.trim();
     if query.is_empty() {
@@ -144,7 +245,7 @@ fn fuzzy_score(needle: &str, haystack: &str) -> Option<usize> {
 
     for needle_ch in needle.chars() {
         let mut found = None;
-        while let Some((idx, hay_ch)) = hay_iter.next() {
+        for (idx, hay_ch) in hay_iter.by_ref() {
             if hay_ch == needle_ch {
                 found = Some((idx, hay_ch.len_utf8()));
                 break;
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS deployment_logs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    deployment_id INTEGER NOT NULL REFERENCES deployments (id) ON DELETE CASCADE,
+    stream TEXT NOT NULL CHECK (stream IN ('stdout', 'stderr')),
+    line TEXT NOT NULL,
+    logged_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS deployment_logs_deployment_id ON deployment_logs (deployment_id, id);
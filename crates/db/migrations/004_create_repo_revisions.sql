@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS repo_revisions (
+    repo TEXT NOT NULL,
+    branch TEXT NOT NULL,
+    revision TEXT NOT NULL,
+    seen_at INTEGER NOT NULL,
+    PRIMARY KEY (repo, branch)
+);
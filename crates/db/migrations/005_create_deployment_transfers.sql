@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS deployment_transfers (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    deployment_id INTEGER NOT NULL REFERENCES deployments (id) ON DELETE CASCADE,
+    node TEXT NOT NULL,
+    path TEXT NOT NULL,
+    action TEXT NOT NULL CHECK (action IN ('upload', 'delete')),
+    hash TEXT,
+    size INTEGER NOT NULL DEFAULT 0,
+    transferred_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS deployment_transfers_deployment_id ON deployment_transfers (deployment_id, id);
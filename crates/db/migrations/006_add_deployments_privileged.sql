@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN privileged INTEGER NOT NULL DEFAULT 0;
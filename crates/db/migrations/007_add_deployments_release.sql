@@ -0,0 +1,2 @@
+ALTER TABLE deployments ADD COLUMN release TEXT;
+ALTER TABLE deployments ADD COLUMN rollback_of INTEGER REFERENCES deployments (id);
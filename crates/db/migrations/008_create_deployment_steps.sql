@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS deployment_steps (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    deployment_id INTEGER NOT NULL REFERENCES deployments (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    status TEXT NOT NULL CHECK (status IN ('running', 'succeeded', 'failed', 'allowed_failure')),
+    started_at INTEGER NOT NULL,
+    finished_at INTEGER,
+    UNIQUE (deployment_id, name)
+);
@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN promoted_from INTEGER REFERENCES deployments (id);
@@ -62,16 +62,7 @@ static DB_POOL: OnceLock<DbPool> = OnceLock::new();
 pub fn pool() -> &'static DbPool {
     DB_POOL.get_or_init(|| {
         let db_path = Path::new(&get_config().database_path);
-        if let Some(parent) = db_path.parent() {
-            fs::create_dir_all(parent)
-                .unwrap_or_else(|e| panic!("error, when creating db dir. Error: {e}"));
-        }
-
-        let mut connection = Connection::open(db_path)
-            .unwrap_or_else(|e| panic!("error, when opening db connection. Error: {e}"));
-        set_pragmas(&mut connection);
-        apply_migrations(&mut connection)
-            .unwrap_or_else(|e| panic!("error, when applying migrations. Error: {e}"));
+        migrate().unwrap_or_else(|e| panic!("error, when applying migrations. Error: {e}"));
 
         let manager = SqliteConnectionManager::file(db_path).with_init(|conn| {
             set_pragmas(conn);
@@ -87,6 +78,22 @@ pub fn pool() -> &'static DbPool {
     })
 }
 
+/// Create the database if needed and apply any pending migrations, returning
+/// the names of the ones applied.
+pub fn migrate() -> Result<Vec<String>, DbInitError> {
+    let db_path = Path::new(&get_config().database_path);
+    if let Some(parent) = db_path.parent() {
+        fs::create_dir_all(parent).map_err(|err| DbInitError::IoWithPath {
+            path: parent.to_path_buf(),
+            source: err,
+        })?;
+    }
+
+    let mut connection = Connection::open(db_path)?;
+    set_pragmas(&mut connection);
+    apply_migrations(&mut connection)
+}
+
 fn set_pragmas(connection: &mut Connection) {
     connection.pragma_update(None, "foreign_keys", "ON")
         .unwrap_or_else(|e| panic!("error, when setting foreign key pragma. Error: {e}"));
@@ -94,14 +101,14 @@ fn set_pragmas(connection: &mut Connection) {
         .unwrap_or_else(|e| panic!("error, when setting journal mode pragma. Error: {e}"));
 }
 
-fn apply_migrations(connection: &mut Connection) -> Result<(), DbInitError> {
+fn apply_migrations(connection: &mut Connection) -> Result<Vec<String>, DbInitError> {
     connection.execute(
         "CREATE TABLE IF NOT EXISTS schema_migrations (name TEXT PRIMARY KEY);",
         [],
     )?;
 
     let migrations_dir = Path::new(&get_config().migrations_dir);
-    let mut migrations: Vec<PathBuf> = fs::read_dir(&migrations_dir)
+    let mut migrations: Vec<PathBuf> = fs::read_dir(migrations_dir)
         .map_err(|err| DbInitError::IoWithPath {
             path: migrations_dir.to_path_buf(),
             source: err,
@@ -113,6 +120,7 @@ fn apply_migrations(connection: &mut Connection) -> Result<(), DbInitError> {
 
     migrations.sort();
 
+    let mut applied_now = Vec::new();
     for migration in migrations {
         let name = migration
             .file_name()
@@ -137,7 +145,8 @@ fn apply_migrations(connection: &mut Connection) -> Result<(), DbInitError> {
         tx.execute_batch(&sql)?;
         tx.execute("INSERT INTO schema_migrations (name) VALUES (?1);", [&name])?;
         tx.commit()?;
+        applied_now.push(name);
     }
 
-    Ok(())
+    Ok(applied_now)
 }
@@ -0,0 +1,71 @@
+//! Building for the CPU architectures of a deploy's nodes.
+//!
+//! A node's architecture is its configured `arch`, or what `uname -m` reports
+//! on the node the first time a deploy reaches it. A deploy builds once for
+//! every architecture among its nodes, into `build_workspace/<arch>`, with
+//! the build's env set up for cross compiling, and each node is synced from
+//! the build for its architecture. A node whose architecture isn't known gets
+//! a build for the CI node's own architecture, in `build_workspace` itself.
+
+use config::Arch;
+
+/// Script printing the machine hardware name of a node.
+pub const DETECT_SCRIPT: &str = "uname -m\n";
+
+/// The architecture of a node whose `uname -m` printed `machine`.
+pub fn from_uname(machine: &str) -> Option<Arch> {
+    match machine.trim() {
+        "x86_64" | "amd64" => Some(Arch::Amd64),
+        "aarch64" | "arm64" => Some(Arch::Arm64),
+        "armv7l" | "armv7" => Some(Arch::Armv7),
+        _ => None,
+    }
+}
+
+/// The Rust target triple of `arch`.
+pub fn rust_target(arch: Arch) -> &'static str {
+    match arch {
+        Arch::Amd64 => "x86_64-unknown-linux-gnu",
+        Arch::Arm64 => "aarch64-unknown-linux-gnu",
+        Arch::Armv7 => "armv7-unknown-linux-gnueabihf",
+    }
+}
+
+/// Env vars of a build for `arch`: `ARCH`, Go's `GOOS`, `GOARCH` and
+/// `GOARM`, and `RUST_TARGET` along with `CARGO_BUILD_TARGET`, which makes
+/// `cargo build` build for it.
+pub fn build_env(arch: Arch) -> Vec<(&'static str, &'static str)> {
+    let mut env = vec![("ARCH", arch.as_str()), ("GOOS", "linux")];
+    match arch {
+        Arch::Amd64 => env.push(("GOARCH", "amd64")),
+        Arch::Arm64 => env.push(("GOARCH", "arm64")),
+        Arch::Armv7 => env.extend([("GOARCH", "arm"), ("GOARM", "7")]),
+    }
+    env.extend([("RUST_TARGET", rust_target(arch)), ("CARGO_BUILD_TARGET", rust_target(arch))]);
+    env
+}
+
+/// Where the build for `arch` goes.
+pub fn build_dir(build_workspace: &str, arch: Option<Arch>) -> String {
+    match arch {
+        Some(arch) => format!("{}/{arch}", build_workspace.trim_end_matches('/')),
+        None => build_workspace.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_uname_to_build_env() {
+        assert_eq!(from_uname("aarch64\n"), Some(Arch::Arm64));
+        assert_eq!(from_uname("x86_64"), Some(Arch::Amd64));
+        assert_eq!(from_uname("riscv64"), None);
+        let env = build_env(Arch::Armv7);
+        assert!(env.contains(&("GOARCH", "arm")));
+        assert!(env.contains(&("CARGO_BUILD_TARGET", "armv7-unknown-linux-gnueabihf")));
+        assert_eq!(build_dir("~/build/", Some(Arch::Arm64)), "~/build/arm64");
+        assert_eq!(build_dir("~/build", None), "~/build");
+    }
+}
@@ -0,0 +1,401 @@
+//! Running builds on the CI nodes.
+//!
+//! Builds wait their turn in one [`BuildQueue`], first come first served. The
+//! build at the front goes to the first of its CI nodes, in the order `ci.nodes`
+//! lists them, that runs fewer than `max_builds` builds. A node that can't be
+//! reached is left out for a while and the build moves on to the next one.
+//!
+//! A build on a node other than this machine gets the service's
+//! `create_workspace` synced to the same path on the node first, see
+//! [`sync`], and the build's directory in `build_workspace` is copied back
+//! as a gzipped tar once the service's build, test and package commands ran,
+//! so the deploy syncs the artifacts from here as usual.
+
+use crate::sync::{self, Manifest, SyncError};
+use crate::{DeployNode, DeployPlan, shell_path};
+use exec::{Cancel, ExecError, Executor, Outcome, RunOptions, Stream};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How long a node that couldn't be reached is left out of the queue.
+pub const UNREACHABLE_FOR: Duration = Duration::from_secs(60);
+
+/// How often a waiting build checks whether it was cancelled.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// Directory of a repo's own metadata, which isn't sent to the CI nodes.
+const VCS_DIRS: [&str; 2] = [".git", ".fslckout"];
+
+/// Reasons a build didn't get a CI node.
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueueError {
+    Cancelled,
+    /// Every node the build could run on was unreachable.
+    Unreachable(Vec<String>),
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::Unreachable(nodes) => write!(f, "none of the CI nodes {} can be reached", nodes.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+/// Builds waiting for a CI node and the builds each node is running.
+pub struct BuildQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+struct QueueState {
+    next_ticket: u64,
+    waiting: VecDeque<u64>,
+    busy: BTreeMap<String, usize>,
+    /// Nodes left out, until when.
+    unreachable: BTreeMap<String, Instant>,
+}
+
+impl QueueState {
+    fn leave(&mut self, ticket: u64) {
+        self.waiting.retain(|waiting| *waiting != ticket);
+    }
+}
+
+impl Default for BuildQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BuildQueue {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                next_ticket: 0,
+                waiting: VecDeque::new(),
+                busy: BTreeMap::new(),
+                unreachable: BTreeMap::new(),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Wait for the first of `nodes` running fewer than `max_builds` builds.
+    ///
+    /// `on_wait` is told the build's place in the queue, counting from 1,
+    /// whenever it has to wait and each time that place changes.
+    pub fn acquire(
+        &self,
+        nodes: &[DeployNode],
+        max_builds: usize,
+        cancel: &Cancel,
+        on_wait: &mut dyn FnMut(usize),
+    ) -> Result<BuildLease<'_>, QueueError> {
+        let mut state = self.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back(ticket);
+        let mut reported = None;
+        loop {
+            let now = Instant::now();
+            state.unreachable.retain(|_, until| *until > now);
+            if nodes.iter().all(|node| state.unreachable.contains_key(&node.name)) {
+                state.leave(ticket);
+                self.changed.notify_all();
+                return Err(QueueError::Unreachable(nodes.iter().map(|node| node.name.clone()).collect()));
+            }
+            let position = state.waiting.iter().position(|waiting| *waiting == ticket).unwrap_or_default();
+            let free = nodes.iter().find(|node| {
+                !state.unreachable.contains_key(&node.name)
+                    && state.busy.get(&node.name).copied().unwrap_or_default() < max_builds
+            });
+            if let (0, Some(node)) = (position, free) {
+                state.waiting.pop_front();
+                *state.busy.entry(node.name.clone()).or_default() += 1;
+                // the build behind this one may fit on another node
+                self.changed.notify_all();
+                return Ok(BuildLease { queue: self, node: node.clone() });
+            }
+            if cancel.is_cancelled() {
+                state.leave(ticket);
+                self.changed.notify_all();
+                return Err(QueueError::Cancelled);
+            }
+            if reported != Some(position) {
+                reported = Some(position);
+                drop(state);
+                on_wait(position + 1);
+                state = self.lock();
+                continue;
+            }
+            state = self.changed.wait_timeout(state, CANCEL_POLL).expect("error, lock in poisoned state").0;
+        }
+    }
+
+    /// Builds running on each CI node that has any.
+    pub fn busy(&self) -> BTreeMap<String, usize> {
+        self.lock().busy.clone()
+    }
+
+    /// Builds waiting for a node.
+    pub fn waiting(&self) -> usize {
+        self.lock().waiting.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().expect("error, lock in poisoned state")
+    }
+}
+
+/// A build's claim on a CI node, given back when dropped.
+pub struct BuildLease<'a> {
+    queue: &'a BuildQueue,
+    node: DeployNode,
+}
+
+impl BuildLease<'_> {
+    pub fn node(&self) -> &DeployNode {
+        &self.node
+    }
+
+    /// Give the node back and leave it out for [`UNREACHABLE_FOR`].
+    pub fn unreachable(self) {
+        let until = Instant::now() + UNREACHABLE_FOR;
+        self.queue.lock().unreachable.insert(self.node.name.clone(), until);
+    }
+}
+
+impl Drop for BuildLease<'_> {
+    fn drop(&mut self) {
+        let mut state = self.queue.lock();
+        if let Some(busy) = state.busy.get_mut(&self.node.name) {
+            *busy -= 1;
+            if *busy == 0 {
+                state.busy.remove(&self.node.name);
+            }
+        }
+        self.queue.changed.notify_all();
+    }
+}
+
+/// Check a node can be reached, giving the reason when it can't. A check
+/// that was cancelled says nothing about the node and is handed back.
+pub fn reach(executor: &dyn Executor, options: &RunOptions) -> Result<Outcome, String> {
+    let mut last = None;
+    let outcome = executor
+        .run("exit 0\n", options, &mut |_, line| last = Some(line.to_string()))
+        .map_err(|err| err.to_string())?;
+    match (outcome, last) {
+        (outcome, _) if outcome.success() || outcome == Outcome::Cancelled => Ok(outcome),
+        (outcome, Some(line)) => Err(format!("{outcome}: {line}")),
+        (outcome, None) => Err(outcome.to_string()),
+    }
+}
+
+/// Reasons a build on a CI node didn't complete.
+#[derive(Debug)]
+pub enum BuildError {
+    Sources { create_workspace: String, source: io::Error },
+    Sync(SyncError),
+    Exec(ExecError),
+    /// The archive of the build workspace couldn't be kept here.
+    Archive { path: PathBuf, source: io::Error },
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sources { create_workspace, source } => {
+                write!(f, "unable to read create_workspace {create_workspace}: {source}")
+            }
+            Self::Sync(err) => write!(f, "unable to send sources: {err}"),
+            Self::Exec(err) => err.fmt(f),
+            Self::Archive { path, source } => {
+                write!(f, "unable to keep the archive of build_workspace in {}: {source}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Sync the plan's `create_workspace` to the same path on `node`, a CI node
+/// other than this machine.
+pub fn send_sources(
+    node: &dyn Executor,
+    plan: &DeployPlan,
+    options: &RunOptions,
+    on_line: &mut dyn FnMut(Stream, &str),
+) -> Result<Outcome, BuildError> {
+    let create_workspace = vcs::expand_home(&plan.create_workspace);
+    let mut sources = Manifest::collect(&create_workspace, &[]).map_err(|source| BuildError::Sources {
+        create_workspace: plan.create_workspace.clone(),
+        source,
+    })?;
+    sources.artifacts.retain(|file| !VCS_DIRS.iter().any(|dir| file.path.split('/').any(|part| part == *dir)));
+    on_line(Stream::Stdout, "syncing sources");
+    match sync::sync_node(node, &sources, &plan.create_workspace, &[], options, on_line) {
+        Ok(_) => Ok(Outcome::Exited(0)),
+        Err(SyncError::Failed { action, outcome }) => {
+            on_line(Stream::Stderr, &format!("{action} failed: {outcome}"));
+            Ok(outcome)
+        }
+        Err(err) => Err(BuildError::Sync(err)),
+    }
+}
+
+/// Copy `build_dir` on `node` back here, unpacking it with `local`.
+///
+/// The archive is kept next to the build's directory while it comes in and
+/// removed once it is unpacked.
+pub fn fetch_build(
+    node: &dyn Executor,
+    local: &dyn Executor,
+    build_dir: &str,
+    options: &RunOptions,
+    on_line: &mut dyn FnMut(Stream, &str),
+) -> Result<Outcome, BuildError> {
+    let build = vcs::expand_home(build_dir);
+    let archive = PathBuf::from(format!("{}.tar.gz", build.display().to_string().trim_end_matches('/')));
+    let fetched = fetch_archive(node, local, build_dir, &archive, options, on_line);
+    if let Err(err) = fs::remove_file(&archive)
+        && err.kind() != io::ErrorKind::NotFound
+    {
+        eprintln!("error, when removing build archive {}. Error: {}", archive.display(), err);
+    }
+    fetched
+}
+
+fn fetch_archive(
+    node: &dyn Executor,
+    local: &dyn Executor,
+    build_dir: &str,
+    archive: &Path,
+    options: &RunOptions,
+    on_line: &mut dyn FnMut(Stream, &str),
+) -> Result<Outcome, BuildError> {
+    let kept = |source| BuildError::Archive { path: archive.to_path_buf(), source };
+    if let Some(parent) = archive.parent() {
+        fs::create_dir_all(parent).map_err(kept)?;
+    }
+    let mut file = File::create(archive).map_err(kept)?;
+    let outcome = node.run_to_writer(&pack_script(build_dir), options, &mut file, on_line).map_err(BuildError::Exec)?;
+    if !outcome.success() {
+        return Ok(outcome);
+    }
+    let size = file.metadata().map_err(kept)?.len();
+    drop(file);
+    on_line(Stream::Stdout, &format!("copying back {build_dir} ({size} bytes)"));
+    local.run(&unpack_script(build_dir, archive), options, on_line).map_err(BuildError::Exec)
+}
+
+/// Script writing a gzipped tar of the build workspace to its stdout.
+fn pack_script(build_workspace: &str) -> String {
+    format!("set -eu\ncd {}\ntar -czf - .\n", shell_path(build_workspace))
+}
+
+/// Script unpacking `archive` into the build workspace.
+fn unpack_script(build_workspace: &str, archive: &Path) -> String {
+    let build = shell_path(build_workspace);
+    let archive = exec::shell_quote(&archive.display().to_string());
+    format!("set -eu\nmkdir -p {build}\ntar -xzf {archive} -C {build}\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn ci_node(name: &str) -> DeployNode {
+        DeployNode {
+            name: name.to_string(),
+            host_name: format!("{name}.lan"),
+            user: "ci".to_string(),
+            port: 22,
+            escalation: config::Escalation::Sudo,
+            root_user: "root".to_string(),
+            arch: None,
+        }
+    }
+
+    #[test]
+    fn builds_go_to_the_first_free_node_in_order() {
+        let queue = BuildQueue::new();
+        let nodes = [ci_node("a"), ci_node("b")];
+        let cancel = Cancel::new();
+        let first = queue.acquire(&nodes, 1, &cancel, &mut |_| {}).expect("first");
+        let second = queue.acquire(&nodes, 1, &cancel, &mut |_| {}).expect("second");
+        assert_eq!((first.node().name.as_str(), second.node().name.as_str()), ("a", "b"));
+        assert_eq!(queue.busy(), BTreeMap::from([("a".to_string(), 1), ("b".to_string(), 1)]));
+
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                let lease = queue.acquire(&nodes, 1, &cancel, &mut |position| tx.send(position).expect("send"));
+                lease.map(|lease| lease.node().name.clone())
+            });
+            assert_eq!(rx.recv().expect("position"), 1);
+            assert_eq!(queue.waiting(), 1);
+            drop(second);
+            assert_eq!(waiter.join().expect("join"), Ok("b".to_string()));
+        });
+        drop(first);
+        assert!(queue.busy().is_empty());
+    }
+
+    #[test]
+    fn unreachable_nodes_are_skipped() {
+        let queue = BuildQueue::new();
+        let nodes = [ci_node("a"), ci_node("b")];
+        let cancel = Cancel::new();
+        queue.acquire(&nodes, 1, &cancel, &mut |_| {}).expect("a").unreachable();
+        let lease = queue.acquire(&nodes, 1, &cancel, &mut |_| {}).expect("b");
+        assert_eq!(lease.node().name, "b");
+        lease.unreachable();
+        let none = queue.acquire(&nodes, 1, &cancel, &mut |_| {}).err();
+        assert_eq!(none, Some(QueueError::Unreachable(vec!["a".to_string(), "b".to_string()])));
+        assert_eq!(queue.waiting(), 0);
+    }
+
+    #[test]
+    fn a_cancelled_check_is_handed_back() {
+        let node = exec::FakeExecutor::new("ci@a.lan:22");
+        node.respond("", &[(Stream::Stderr, "Connection refused")], Outcome::Exited(255));
+        assert_eq!(reach(&node, &RunOptions::default()), Err("exit status: 255: Connection refused".to_string()));
+
+        let options = RunOptions::default();
+        options.cancel.cancel();
+        assert_eq!(reach(&node, &options), Ok(Outcome::Cancelled));
+    }
+
+    #[test]
+    fn fetches_builds_as_binary() {
+        let dir = std::env::temp_dir().join(format!("ci-fetch-{}", std::process::id()));
+        let build = dir.join("build");
+        fs::create_dir_all(&build).expect("build dir");
+        let bytes: Vec<u8> = (0..=255).collect();
+        fs::write(build.join("app"), &bytes).expect("artifact");
+        let build_dir = build.display().to_string();
+
+        let mut lines = Vec::new();
+        let outcome = fetch_build(&exec::LocalExecutor, &exec::LocalExecutor, &build_dir, &RunOptions::default(), &mut |_, line| {
+            lines.push(line.to_string())
+        })
+        .expect("fetch");
+        assert!(outcome.success(), "{lines:?}");
+        assert!(lines[0].starts_with(&format!("copying back {build_dir} (")));
+        assert_eq!(fs::read(build.join("app")).expect("artifact"), bytes);
+        assert!(!dir.join("build.tar.gz").exists());
+        fs::remove_dir_all(dir).expect("cleanup");
+    }
+}
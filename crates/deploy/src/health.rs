@@ -0,0 +1,241 @@
+//! Checking a service is healthy on a node after it was deployed.
+//!
+//! HTTP and TCP checks connect from this machine to the node, with its
+//! `host_name` in place of `{host}`. Command and systemctl checks run on the
+//! node through the deploy's executor for it. A node is healthy once every
+//! check passes in the same attempt; failed attempts are retried every
+//! `interval` until the retries run out or the `deadline` passes.
+
+use crate::systemd::{self, Scope};
+use config::{HOST_PLACEHOLDER, HealthCheck, HealthConfig};
+use exec::{Cancel, Executor, RunOptions, Stream, shell_quote};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest an HTTP or TCP check waits on the node.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most of an HTTP response read, the rest of the body is ignored.
+const MAX_RESPONSE: u64 = 64 << 10;
+
+/// How often a wait between attempts checks for cancellation.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// A check ready to run against one node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    Http { url: String, status: u16, body: Option<String> },
+    Tcp { address: String },
+    /// A script run on the node that passes by exiting 0.
+    Script { name: String, script: String },
+}
+
+impl Probe {
+    /// Resolve `check` for the node at `host`. `unit` is the service's own
+    /// unit, which a systemctl check without a unit of its own looks at.
+    pub fn new(check: &HealthCheck, host: &str, unit: Option<&str>, scope: Scope) -> Self {
+        match check {
+            HealthCheck::Http { url, status, body } => Self::Http {
+                url: url.replace(HOST_PLACEHOLDER, host),
+                status: *status,
+                body: body.clone(),
+            },
+            HealthCheck::Tcp { address } => Self::Tcp { address: address.replace(HOST_PLACEHOLDER, host) },
+            HealthCheck::Command { command } => Self::Script {
+                name: format!("'{command}'"),
+                script: format!("{command}\n"),
+            },
+            HealthCheck::Systemctl { unit: name } => {
+                let name = name.as_deref().or(unit).unwrap_or_default();
+                Self::Script {
+                    name: format!("{name} active"),
+                    script: format!("{} is-active {}\n", systemd::systemctl(scope), shell_quote(name)),
+                }
+            }
+        }
+    }
+
+    /// Run the check once, giving the reason it failed.
+    fn check(&self, executor: &dyn Executor, options: &RunOptions) -> Result<(), String> {
+        let timeout = options.timeout.map_or(CONNECT_TIMEOUT, |left| left.min(CONNECT_TIMEOUT));
+        match self {
+            Self::Http { url, status, body } => {
+                let (got, text) = http_get(url, timeout).map_err(|err| format!("{url}: {err}"))?;
+                if got != *status {
+                    return Err(format!("{url} returned {got}, expected {status}"));
+                }
+                match body {
+                    Some(body) if !text.contains(body.as_str()) => Err(format!("{url} body doesn't contain '{body}'")),
+                    _ => Ok(()),
+                }
+            }
+            Self::Tcp { address } => connect(address, timeout).map(drop).map_err(|err| format!("{address}: {err}")),
+            Self::Script { name, script } => {
+                let mut last = None;
+                let outcome = executor
+                    .run(script, options, &mut |_, line| last = Some(line.to_string()))
+                    .map_err(|err| format!("{name}: {err}"))?;
+                match (outcome.success(), last) {
+                    (true, _) => Ok(()),
+                    (false, Some(line)) => Err(format!("{name} failed: {outcome}: {line}")),
+                    (false, None) => Err(format!("{name} failed: {outcome}")),
+                }
+            }
+        }
+    }
+}
+
+/// How waiting for a node to become healthy ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Healthy { attempts: u32 },
+    /// Out of retries or time, with why the last attempt failed.
+    Unhealthy { attempts: u32, reason: String },
+    Cancelled,
+}
+
+/// Run `probes` until they all pass in one attempt, as `config` allows.
+///
+/// Each failed attempt is reported through `on_line`. Scripts run through
+/// `executor`, limited to the time left before the deadline.
+pub fn wait_healthy(
+    config: &HealthConfig,
+    probes: &[Probe],
+    executor: &dyn Executor,
+    cancel: &Cancel,
+    on_line: &mut dyn FnMut(Stream, &str),
+) -> Health {
+    let deadline = Instant::now() + Duration::from_secs(config.deadline);
+    let interval = Duration::from_secs(config.interval);
+    let total = config.retries.saturating_add(1);
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let options = RunOptions {
+            timeout: Some(deadline.saturating_duration_since(Instant::now())),
+            cancel: cancel.clone(),
+        };
+        let failed = probes.iter().find_map(|probe| probe.check(executor, &options).err());
+        if cancel.is_cancelled() {
+            return Health::Cancelled;
+        }
+        let Some(reason) = failed else {
+            return Health::Healthy { attempts };
+        };
+        on_line(Stream::Stdout, &format!("attempt {attempts}/{total}: {reason}"));
+        if attempts >= total || Instant::now() + interval >= deadline {
+            return Health::Unhealthy { attempts, reason };
+        }
+        let resume = Instant::now() + interval;
+        while Instant::now() < resume {
+            if cancel.is_cancelled() {
+                return Health::Cancelled;
+            }
+            thread::sleep(CANCEL_POLL.min(resume.saturating_duration_since(Instant::now())));
+        }
+    }
+}
+
+fn connect(address: &str, timeout: Duration) -> Result<TcpStream, String> {
+    let addrs: Vec<SocketAddr> = address.to_socket_addrs().map_err(|err| err.to_string())?.collect();
+    let mut last = String::from("no addresses");
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last = err.to_string(),
+        }
+    }
+    Err(last)
+}
+
+/// GET an `http://` url, returning the response's status and body.
+fn http_get(url: &str, timeout: Duration) -> Result<(u16, String), String> {
+    let rest = url.strip_prefix("http://").ok_or("only http:// urls are supported")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let address = match authority.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => authority.to_string(),
+        _ => format!("{authority}:80"),
+    };
+    let mut stream = connect(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout)).map_err(|err| err.to_string())?;
+    stream.set_write_timeout(Some(timeout)).map_err(|err| err.to_string())?;
+    // 1.0 so the body isn't chunked and ends with the connection
+    let request = format!("GET {path} HTTP/1.0\r\nHost: {authority}\r\nUser-Agent: pipeline\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).map_err(|err| err.to_string())?;
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE).read_to_end(&mut response).map_err(|err| err.to_string())?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .filter(|_| response.starts_with("HTTP/"))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or("not an HTTP response")?;
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    Ok((status, body.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exec::{FakeExecutor, Outcome};
+    use std::net::TcpListener;
+
+    fn config(retries: u32) -> HealthConfig {
+        HealthConfig { retries, interval: 0, deadline: 10, checks: Vec::new() }
+    }
+
+    /// Answer one request on a local port with `response`, returning the port.
+    fn serve(response: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            stream.write_all(response.as_bytes()).expect("respond");
+        });
+        port
+    }
+
+    #[test]
+    fn http_checks_status_and_body() {
+        let check = |body: &str, response| {
+            let check = HealthCheck::Http {
+                url: format!("http://{{host}}:{}/health", serve(response)),
+                status: 200,
+                body: Some(body.to_string()),
+            };
+            Probe::new(&check, "127.0.0.1", None, Scope::User).check(&FakeExecutor::new("local"), &RunOptions::default())
+        };
+        assert_eq!(check("ok", "HTTP/1.0 200 OK\r\nContent-Length: 2\r\n\r\nok"), Ok(()));
+        assert!(check("ok", "HTTP/1.1 503 Service Unavailable\r\n\r\n").unwrap_err().ends_with("returned 503, expected 200"));
+        assert!(check("ready", "HTTP/1.0 200 OK\r\n\r\nstarting").unwrap_err().ends_with("doesn't contain 'ready'"));
+    }
+
+    #[test]
+    fn retries_until_the_checks_pass_or_run_out() {
+        let node = FakeExecutor::new("pi@pi1:22");
+        node.respond("is-active", &[(Stream::Stdout, "activating")], Outcome::Exited(3));
+        let probes = [Probe::new(&HealthCheck::Systemctl { unit: None }, "pi1", Some("svc.service"), Scope::User)];
+        let mut lines = Vec::new();
+        let health = wait_healthy(&config(2), &probes, &node, &Cancel::new(), &mut |_, line| lines.push(line.to_string()));
+        assert_eq!(health, Health::Unhealthy {
+            attempts: 3,
+            reason: "svc.service active failed: exit status: 3: activating".to_string(),
+        });
+        assert_eq!(node.scripts(), vec!["systemctl --user is-active 'svc.service'\n"; 3]);
+        assert_eq!(lines[0], "attempt 1/3: svc.service active failed: exit status: 3: activating");
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let address = listener.local_addr().expect("addr").to_string();
+        let probes = [Probe::new(&HealthCheck::Tcp { address }, "127.0.0.1", None, Scope::User)];
+        let health = wait_healthy(&config(0), &probes, &node, &Cancel::new(), &mut |_, _| {});
+        assert_eq!(health, Health::Healthy { attempts: 1 });
+    }
+}
@@ -0,0 +1,1742 @@
+//! Runs each deploy on its own thread so it outlives the connection that
+//! started it.
+//!
+//! A deploy runs the steps of its plan through the executor for each step's
+//! target: first the build, on a CI node once one is free (see [`crate::ci`]),
+//! then the steps of each node, wave by wave. The nodes of a wave are
+//! deployed to in parallel, each running its steps in order. A step that
+//! fails, times out or is cancelled stops its node; no further nodes are
+//! started and later waves are skipped, unless the step is a command allowed
+//! to fail.
+//!
+//! Each step's status is recorded with the deployment as it changes and sent
+//! to subscribers, as are the files each sync transfers. Lines about a node
+//! are logged with its name in front, e.g. `[pi1] ==> sync pi1 on ...`.
+//!
+//! When a service keeps releases, a node failing its restart or health
+//! checks after being switched to the new release starts a rollback right
+//! away, recorded as a deployment of its own; [`DeployHub::rollback`] starts
+//! one by hand. [`DeployHub::redeploy`] and [`DeployHub::promote`] ship a
+//! stored build again instead of building.
+//!
+//! Subscribers follow a service by name. One that joins while a deploy of that
+//! service is running is first handed everything the deploy has printed so
+//! far, then live output as it arrives.
+
+use crate::arch;
+use crate::ci::{self, BuildQueue, QueueError};
+use crate::health::{self, Health};
+use crate::store::ArtifactStore;
+use crate::sync::{self, Manifest, SyncError};
+use crate::{Action, Command, Connect, DeployPlan, Step, Target, Wave};
+use crate::{releases, systemd};
+use config::Arch;
+use exec::{Cancel, Executor, Outcome, RunOptions, Stream};
+use model::{DeploymentStatus, LogStream, ModelError, NewDeployment, SqliteArtifactModel, SqliteDeploymentModel, StepStatus};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+
+static HUB: OnceLock<DeployHub> = OnceLock::new();
+
+/// The process wide hub, created on first use.
+pub fn hub() -> &'static DeployHub {
+    HUB.get_or_init(|| {
+        let store = ArtifactStore::new(&config::get_config().artifacts, SqliteArtifactModel::new());
+        DeployHub::new(SqliteDeploymentModel::new()).with_store(store)
+    })
+}
+
+/// Something that happened to a running deploy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeployEvent {
+    Started { deployment_id: u64, service: String, environment: String },
+    Line { deployment_id: u64, stream: LogStream, line: String },
+    Step { deployment_id: u64, name: String, status: StepStatus },
+    Finished { deployment_id: u64, status: DeploymentStatus, exit: String },
+}
+
+impl DeployEvent {
+    pub fn deployment_id(&self) -> u64 {
+        match self {
+            Self::Started { deployment_id, .. }
+            | Self::Line { deployment_id, .. }
+            | Self::Step { deployment_id, .. }
+            | Self::Finished { deployment_id, .. } => *deployment_id,
+        }
+    }
+}
+
+/// Receives deploy events; returning `false` drops the subscription.
+pub type Subscriber = Box<dyn FnMut(&DeployEvent) -> bool + Send>;
+
+/// Handle used to cancel a subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionId(u64);
+
+/// Reasons a deploy could not be started.
+#[derive(Debug)]
+pub enum StartError {
+    AlreadyRunning(String),
+    NothingToRollBack { service: String, environment: String },
+    /// The deployment to ship the build of doesn't exist or stored none.
+    NoStoredBuild(u64),
+    NothingToPromote { service: String, environment: String },
+    /// A deploy to the environment to promote from failed its health checks
+    /// since the last one that succeeded.
+    Unhealthy { deployment_id: u64, environment: String },
+    Record(ModelError),
+    Spawn(io::Error),
+}
+
+impl Display for StartError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyRunning(service) => write!(f, "a deploy of service '{service}' is already running"),
+            Self::NothingToRollBack { service, environment } => {
+                write!(f, "service '{service}' has no earlier release in '{environment}' to roll back to")
+            }
+            Self::NoStoredBuild(id) => write!(f, "deployment #{id} has no stored build to deploy"),
+            Self::NothingToPromote { service, environment } => {
+                write!(f, "service '{service}' hasn't been deployed to '{environment}', there is nothing to promote")
+            }
+            Self::Unhealthy { deployment_id, environment } => {
+                write!(f, "deployment #{deployment_id} to '{environment}' didn't pass its health checks")
+            }
+            Self::Record(err) => write!(f, "unable to record deployment: {err}"),
+            Self::Spawn(err) => write!(f, "unable to start deploy thread: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for StartError {}
+
+/// Owns every running deploy and fans its output out to subscribers.
+pub struct DeployHub {
+    state: Arc<Mutex<HubState>>,
+    history: SqliteDeploymentModel,
+    shared: Shared,
+    next_subscription: AtomicU64,
+}
+
+/// What every deploy of a hub uses besides its state and history.
+#[derive(Clone)]
+struct Shared {
+    connect: Connect,
+    builds: Arc<BuildQueue>,
+    arches: Arches,
+    store: Option<Arc<ArtifactStore>>,
+}
+
+/// Architectures detected on nodes, by node name.
+type Arches = Arc<Mutex<BTreeMap<String, Arch>>>;
+
+/// The artifacts of each build of a deploy, by architecture.
+type Manifests = Mutex<BTreeMap<Option<Arch>, Arc<Manifest>>>;
+
+struct HubState {
+    running: BTreeMap<u64, RunningDeploy>,
+    /// Services held for a deploy that is still being recorded.
+    reserved: BTreeSet<String>,
+    subscribers: Vec<Subscription>,
+}
+
+struct RunningDeploy {
+    service: String,
+    cancel: Cancel,
+    backlog: Vec<DeployEvent>,
+}
+
+struct Subscription {
+    id: SubscriptionId,
+    service: String,
+    subscriber: Subscriber,
+}
+
+impl HubState {
+    fn is_running(&self, service: &str) -> bool {
+        self.reserved.contains(service) || self.running.values().any(|r| r.service == service)
+    }
+
+    /// Track a recorded deploy as running and announce it.
+    fn begin(&mut self, deployment_id: u64, plan: &DeployPlan, cancel: Cancel) {
+        self.running.insert(deployment_id, RunningDeploy {
+            service: plan.service.clone(),
+            cancel,
+            backlog: Vec::new(),
+        });
+        self.publish(&plan.service, DeployEvent::Started {
+            deployment_id,
+            service: plan.service.clone(),
+            environment: plan.environment.clone(),
+        });
+    }
+
+    /// Hold `service` for a deploy about to be recorded; `state` is the mutex
+    /// this state is locked through.
+    fn reserve(&mut self, state: &Arc<Mutex<HubState>>, service: &str) -> Reservation {
+        self.reserved.insert(service.to_string());
+        Reservation { state: Arc::clone(state), service: Some(service.to_string()) }
+    }
+
+    fn publish(&mut self, service: &str, event: DeployEvent) {
+        self.subscribers
+            .retain_mut(|s| s.service != service || (s.subscriber)(&event));
+        if let Some(running) = self.running.get_mut(&event.deployment_id()) {
+            running.backlog.push(event);
+        }
+    }
+}
+
+impl DeployHub {
+    /// Recording deployments through `history`, running steps on nodes with [`crate::connect`].
+    pub fn new(history: SqliteDeploymentModel) -> Self {
+        Self::with_connect(history, Arc::new(crate::connect))
+    }
+
+    /// Like [`DeployHub::new`], picking each step's executor with `connect`.
+    pub fn with_connect(history: SqliteDeploymentModel, connect: Connect) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(HubState {
+                running: BTreeMap::new(),
+                reserved: BTreeSet::new(),
+                subscribers: Vec::new(),
+            })),
+            history,
+            shared: Shared {
+                connect,
+                builds: Arc::new(BuildQueue::new()),
+                arches: Arc::new(Mutex::new(BTreeMap::new())),
+                store: None,
+            },
+            next_subscription: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep every build in `store` and sync the nodes from there.
+    pub fn with_store(mut self, store: ArtifactStore) -> Self {
+        self.shared.store = Some(Arc::new(store));
+        self
+    }
+
+    /// Record and launch a deploy, returning its deployment id.
+    ///
+    /// Only one deploy of a service runs at a time.
+    pub fn start(&self, plan: &DeployPlan, initiated_by: &str) -> Result<u64, StartError> {
+        let reservation = self.reserve(&plan.service)?;
+        self.launch(reservation, plan.clone(), initiated_by)
+    }
+
+    /// Switch the nodes of the plan's service and environment back to the
+    /// release before the current one, as a deployment of its own.
+    pub fn rollback(&self, plan: &DeployPlan, initiated_by: &str) -> Result<u64, StartError> {
+        let reservation = self.reserve(&plan.service)?;
+        let nothing = || StartError::NothingToRollBack {
+            service: plan.service.clone(),
+            environment: plan.environment.clone(),
+        };
+        if plan.keep_releases == 0 {
+            return Err(nothing());
+        }
+        let deployments = self.history.list_releases(&plan.service, &plan.environment).map_err(StartError::Record)?;
+        let (Some(latest), Some(target)) = (deployments.last(), releases::rollback_target(&deployments)) else {
+            return Err(nothing());
+        };
+        self.launch(reservation, plan.rollback(latest.id(), target), initiated_by)
+    }
+
+    /// Deploy the build deployment `deployment_id` shipped with the plan,
+    /// instead of building, as a deployment of its own.
+    pub fn redeploy(&self, plan: &DeployPlan, deployment_id: u64, initiated_by: &str) -> Result<u64, StartError> {
+        let reservation = self.reserve(&plan.service)?;
+        match self.history.find_deployment(deployment_id).map_err(StartError::Record)? {
+            Some(source) if source.service() == plan.service && self.stored_build(deployment_id)? => {
+                self.launch(reservation, plan.redeploy(&source), initiated_by)
+            }
+            _ => Err(StartError::NoStoredBuild(deployment_id)),
+        }
+    }
+
+    /// Deploy what `from` runs with the plan, which is for the environment
+    /// `from` promotes to, provided it passed its health checks. The new
+    /// deployment records the one it was promoted from.
+    pub fn promote(&self, plan: &DeployPlan, from: &str, initiated_by: &str) -> Result<u64, StartError> {
+        let reservation = self.reserve(&plan.service)?;
+        let finished = self.history.finished_since_success(&plan.service, from).map_err(StartError::Record)?;
+        // a deploy that failed its health checks since may have left nodes on its release
+        for deployment in finished.iter().filter(|d| d.status() == DeploymentStatus::Failed) {
+            if self.failed_health(deployment)? {
+                return Err(StartError::Unhealthy { deployment_id: deployment.id(), environment: from.to_string() });
+            }
+        }
+        let source = finished
+            .into_iter()
+            .find(|d| d.status() == DeploymentStatus::Succeeded)
+            .ok_or_else(|| StartError::NothingToPromote {
+                service: plan.service.clone(),
+                environment: from.to_string(),
+            })?;
+        if !self.stored_build(source.id())? {
+            return Err(StartError::NoStoredBuild(source.id()));
+        }
+        self.launch(reservation, plan.promote(&source), initiated_by)
+    }
+
+    /// Whether a node of `deployment` failed its health checks.
+    fn failed_health(&self, deployment: &model::Deployment) -> Result<bool, StartError> {
+        let checks: Vec<String> = deployment.nodes().iter().map(|node| crate::health_step(node)).collect();
+        let steps = self.history.list_steps(deployment.id()).map_err(StartError::Record)?;
+        Ok(steps.iter().any(|step| step.status() == StepStatus::Failed && checks.iter().any(|name| name == step.name())))
+    }
+
+    /// Whether deployment `deployment_id` shipped a stored build for each
+    /// architecture the plan's nodes are known to run on, so
+    /// [`DeployHub::redeploy`] can ship it with the plan.
+    pub fn has_builds_for(&self, plan: &DeployPlan, deployment_id: u64) -> Result<bool, StartError> {
+        let Some(store) = &self.shared.store else {
+            return Ok(false);
+        };
+        let shipped = store.index().deployment_artifacts(deployment_id).map_err(StartError::Record)?;
+        let has_build = |arch: Option<Arch>| shipped.iter().any(|stored| stored.arch() == arch.map(|arch| arch.as_str()));
+        let detected = self.shared.arches.lock().expect("error, lock in poisoned state");
+        let mut nodes = plan.nodes().peekable();
+        if nodes.peek().is_none() {
+            return Ok(has_build(None));
+        }
+        Ok(nodes.all(|node| has_build(node.arch.or_else(|| detected.get(&node.name).copied()))))
+    }
+
+    /// Whether deployment `deployment_id` shipped builds kept in the store.
+    fn stored_build(&self, deployment_id: u64) -> Result<bool, StartError> {
+        match &self.shared.store {
+            Some(store) => {
+                let shipped = store.index().deployment_artifacts(deployment_id).map_err(StartError::Record)?;
+                Ok(!shipped.is_empty())
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Hold `service` while a deploy of it is looked up and recorded, unless
+    /// one is already running.
+    fn reserve(&self, service: &str) -> Result<Reservation, StartError> {
+        let mut state = lock(&self.state);
+        if state.is_running(service) {
+            return Err(StartError::AlreadyRunning(service.to_string()));
+        }
+        Ok(state.reserve(&self.state, service))
+    }
+
+    fn launch(&self, reservation: Reservation, plan: DeployPlan, initiated_by: &str) -> Result<u64, StartError> {
+        let run = DeployRun::record(plan, initiated_by, &self.state, &self.history, &self.shared)
+            .map_err(StartError::Record)?;
+        let (deployment_id, plan, cancel) = (run.deployment_id, run.plan.clone(), run.cancel.clone());
+        let mut state = lock(&self.state);
+        let launched = thread::Builder::new()
+            .name(format!("deploy-{deployment_id}"))
+            .spawn(move || run.run());
+        if let Err(err) = launched {
+            drop(state);
+            let line = format!("deploy failed: {err}");
+            record_line(&self.history, deployment_id, LogStream::Stderr, &line);
+            if let Err(err) = self.history.finish_deployment(deployment_id, DeploymentStatus::Failed, None) {
+                eprintln!("error, when recording deployment failure. Error: {err}");
+            }
+            return Err(StartError::Spawn(err));
+        }
+
+        // the deploy thread can't publish output before the lock is released, so Started is always first
+        state.begin(deployment_id, &plan, cancel);
+        reservation.release(&mut state);
+        Ok(deployment_id)
+    }
+
+    /// Stop a running deploy, killing its current step. Returns whether it was running.
+    pub fn cancel(&self, deployment_id: u64) -> bool {
+        match lock(&self.state).running.get(&deployment_id) {
+            Some(running) => {
+                running.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Follow every deploy of `service`, replaying output of one already running.
+    pub fn subscribe(&self, service: &str, mut subscriber: Subscriber) -> SubscriptionId {
+        let id = SubscriptionId(self.next_subscription.fetch_add(1, Ordering::Relaxed));
+        let mut state = lock(&self.state);
+        for running in state.running.values().filter(|r| r.service == service) {
+            for event in &running.backlog {
+                if !subscriber(event) {
+                    return id;
+                }
+            }
+        }
+        state.subscribers.push(Subscription {
+            id,
+            service: service.to_string(),
+            subscriber,
+        });
+        id
+    }
+
+    pub fn unsubscribe(&self, id: SubscriptionId) {
+        lock(&self.state).subscribers.retain(|s| s.id != id);
+    }
+
+    /// Whether a deploy of `service` is in progress.
+    pub fn is_running(&self, service: &str) -> bool {
+        lock(&self.state).is_running(service)
+    }
+
+    /// Whether deployment `deployment_id` is still running.
+    pub fn is_deployment_running(&self, deployment_id: u64) -> bool {
+        lock(&self.state).running.contains_key(&deployment_id)
+    }
+
+    /// The queue builds wait in for a CI node.
+    pub fn builds(&self) -> &BuildQueue {
+        &self.shared.builds
+    }
+}
+
+fn lock(state: &Mutex<HubState>) -> MutexGuard<'_, HubState> {
+    state.lock().expect("error, lock in poisoned state")
+}
+
+/// A service held for a deploy while it's recorded, so none other starts in
+/// between. Dropping it gives the service up again.
+struct Reservation {
+    state: Arc<Mutex<HubState>>,
+    service: Option<String>,
+}
+
+impl Reservation {
+    /// Hand the service over to the deploy `state` now tracks as running.
+    fn release(mut self, state: &mut HubState) {
+        if let Some(service) = self.service.take() {
+            state.reserved.remove(&service);
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(service) = self.service.take() {
+            lock(&self.state).reserved.remove(&service);
+        }
+    }
+}
+
+/// A deploy in progress on its own thread.
+struct DeployRun {
+    deployment_id: u64,
+    plan: DeployPlan,
+    cancel: Cancel,
+    state: Arc<Mutex<HubState>>,
+    history: SqliteDeploymentModel,
+    shared: Shared,
+    /// The nodes switched to the plan's release.
+    activated: Mutex<BTreeSet<String>>,
+}
+
+/// How the steps of a deploy went.
+struct Ran {
+    /// How the last step that ran ended.
+    outcome: Result<Outcome, String>,
+    /// What the step that failed did.
+    failed: Option<Action>,
+}
+
+impl DeployRun {
+    /// Record the start of a deployment of `plan`.
+    fn record(
+        mut plan: DeployPlan,
+        initiated_by: &str,
+        state: &Arc<Mutex<HubState>>,
+        history: &SqliteDeploymentModel,
+        shared: &Shared,
+    ) -> Result<Self, ModelError> {
+        let nodes: Vec<String> = plan.nodes().map(|node| node.name.clone()).collect();
+        let deployment = history.start_deployment(&NewDeployment {
+            service: &plan.service,
+            environment: &plan.environment,
+            nodes: &nodes,
+            revision: plan.revision.as_deref(),
+            initiated_by,
+            privileged: plan.deploy_as_root,
+            release: plan.release.as_deref(),
+            rollback_of: plan.rollback_of,
+            promoted_from: plan.promoted_from,
+        })?;
+        if let (Some(_), Some(restored), Some(store)) = (plan.rollback_of, plan.build_of, &shared.store) {
+            // a rollback ships the builds of the release it goes back to
+            let linked = store.index().deployment_artifacts(restored).and_then(|shipped| {
+                shipped.iter().try_for_each(|stored| store.index().link_deployment(deployment.id(), stored.id()))
+            });
+            if let Err(err) = linked {
+                eprintln!("error, when recording the builds a rollback restores. Error: {}", err);
+            }
+        }
+        {
+            // nodes detected by an earlier deploy aren't asked again
+            let detected = shared.arches.lock().expect("error, lock in poisoned state");
+            for node in plan.waves.iter_mut().flat_map(|wave| wave.nodes.iter_mut()) {
+                node.arch = node.arch.or_else(|| detected.get(&node.name).copied());
+            }
+        }
+        if plan.keep_releases > 0 && plan.release.is_none() {
+            plan.release = Some(releases::release_name(plan.revision.as_deref(), deployment.id()));
+        }
+        Ok(Self {
+            deployment_id: deployment.id(),
+            plan,
+            cancel: Cancel::new(),
+            state: Arc::clone(state),
+            history: history.clone(),
+            shared: shared.clone(),
+            activated: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    fn run(self) {
+        // a step that panics still finishes the deployment, so nobody waits on it forever
+        let Ran { outcome, failed } = panic::catch_unwind(AssertUnwindSafe(|| self.run_steps()))
+            .unwrap_or_else(|_| Ran { outcome: Err("the deploy stopped unexpectedly".to_string()), failed: None });
+        let rollback = match failed {
+            Some(Action::Restart | Action::Health) => self.rollback_plan(),
+            _ => None,
+        };
+        let status = match outcome {
+            Ok(outcome) if outcome.success() => DeploymentStatus::Succeeded,
+            _ => DeploymentStatus::Failed,
+        };
+        let exit_code = outcome.as_ref().ok().and_then(Outcome::code);
+        if let Err(err) = self.history.finish_deployment(self.deployment_id, status, exit_code) {
+            eprintln!("error, when recording deployment result. Error: {}", err);
+        }
+        let exit = match outcome {
+            Ok(outcome) => outcome.to_string(),
+            Err(err) => err,
+        };
+        let mut state = lock(&self.state);
+        state.publish(&self.plan.service, DeployEvent::Finished {
+            deployment_id: self.deployment_id,
+            status,
+            exit,
+        });
+        state.running.remove(&self.deployment_id);
+
+        // reserved before the lock is released, so the service never looks idle in between
+        let Some(plan) = rollback else {
+            return;
+        };
+        let reservation = state.reserve(&self.state, &self.plan.service);
+        drop(state);
+        match DeployRun::record(plan, "auto-rollback", &self.state, &self.history, &self.shared) {
+            Ok(next) => {
+                let mut state = lock(&self.state);
+                state.begin(next.deployment_id, &next.plan, next.cancel.clone());
+                reservation.release(&mut state);
+                drop(state);
+                next.run();
+            }
+            Err(err) => eprintln!("error, when recording rollback deployment. Error: {}", err),
+        }
+    }
+
+    /// The plan rolling the nodes this deploy switched to its release back, if
+    /// there is a release to go back to.
+    fn rollback_plan(&self) -> Option<DeployPlan> {
+        let activated = self.activated.lock().expect("error, lock in poisoned state").clone();
+        if self.plan.rollback_of.is_some() || activated.is_empty() {
+            return None;
+        }
+        let deployments = match self.history.list_releases(&self.plan.service, &self.plan.environment) {
+            Ok(deployments) => deployments,
+            Err(err) => {
+                eprintln!("error, when looking up releases to roll back to. Error: {}", err);
+                return None;
+            }
+        };
+        match releases::rollback_target(&deployments) {
+            Some(target) => {
+                let release = target.release().unwrap_or_default();
+                self.line(LogStream::Stdout, &format!("==> rolling back to release {release} of deployment #{}", target.id()));
+                let mut plan = self.plan.rollback(self.deployment_id, target);
+                for wave in &mut plan.waves {
+                    wave.nodes.retain(|node| activated.contains(&node.name));
+                }
+                plan.waves.retain(|wave| !wave.nodes.is_empty());
+                Some(plan)
+            }
+            None => {
+                self.line(LogStream::Stderr, "==> no earlier release to roll back to");
+                None
+            }
+        }
+    }
+
+    /// Run the preparing steps, then deploy to each wave in turn.
+    fn run_steps(&self) -> Ran {
+        let options = RunOptions {
+            timeout: Some(self.plan.step_timeout),
+            cancel: self.cancel.clone(),
+        };
+        // collected after the build step has produced the artifacts, once for all nodes of an architecture
+        let manifests = Mutex::new(BTreeMap::new());
+        for step in self.plan.prepare_steps() {
+            if let Err(ran) = self.run_step(step, &options, &manifests) {
+                return ran;
+            }
+        }
+        let waves = self.plan.waves.len();
+        for (i, wave) in self.plan.waves.iter().enumerate() {
+            if waves > 1 {
+                let nodes: Vec<&str> = wave.nodes.iter().map(|node| node.name.as_str()).collect();
+                self.line(LogStream::Stdout, &format!("==> wave {}/{waves}: {}", i + 1, nodes.join(", ")));
+            }
+            if let Some(ran) = self.run_wave(wave, &options, &manifests) {
+                let skipped = match waves - i - 1 {
+                    0 => None,
+                    1 => Some(format!("wave {waves}")),
+                    _ => Some(format!("waves {}-{waves}", i + 2)),
+                };
+                if let Some(skipped) = skipped {
+                    self.line(LogStream::Stderr, &format!("==> skipping {skipped}"));
+                }
+                return ran;
+            }
+        }
+        self.line(LogStream::Stdout, &format!("==> done {}", self.plan.service));
+        Ran { outcome: Ok(Outcome::Exited(0)), failed: None }
+    }
+
+    /// Deploy to the nodes of `wave`, at most `max_parallel` at once. Once a
+    /// node has failed no further nodes are started, the ones already going
+    /// finish their current step. Returns how the first failure went.
+    fn run_wave(&self, wave: &Wave, options: &RunOptions, manifests: &Manifests) -> Option<Ran> {
+        let next = AtomicUsize::new(0);
+        let failure: Mutex<Option<Ran>> = Mutex::new(None);
+        let deploy_nodes = || {
+            while let Some(node) = wave.nodes.get(next.fetch_add(1, Ordering::SeqCst)) {
+                if failure.lock().expect("error, lock in poisoned state").is_some() {
+                    break;
+                }
+                for step in self.plan.node_steps(node) {
+                    if let Err(ran) = self.run_step(step, options, manifests) {
+                        failure.lock().expect("error, lock in poisoned state").get_or_insert(ran);
+                        break;
+                    }
+                }
+            }
+        };
+        thread::scope(|scope| {
+            for _ in 1..wave.max_parallel.min(wave.nodes.len()) {
+                scope.spawn(deploy_nodes);
+            }
+            deploy_nodes();
+        });
+        failure.into_inner().expect("error, lock in poisoned state")
+    }
+
+    /// Run one step, logging how it failed.
+    fn run_step(&self, step: Step, options: &RunOptions, manifests: &Manifests) -> Result<(), Ran> {
+        let executor = (self.shared.connect)(&step.target);
+        let log = Log { run: self, node: step.target.node().map(|node| node.name.as_str()) };
+        log.line(LogStream::Stdout, &format!("==> {} on {}", step.name, executor.target()));
+        self.step(&step.name, StepStatus::Running);
+        let outcome = match &step.action {
+            Action::Build => self.build(options, manifests, log),
+            Action::Stored(deployment_id) => self.stored(*deployment_id, manifests, log),
+            Action::Command(command) => self.run_command(command, &*executor, log),
+            Action::CheckRoot => self.check_root(&step.target, &*executor, options, log),
+            Action::DetectArch => self.detect_arch(&step.target, &*executor, options, log),
+            Action::Sync => self.sync(&step.target, &*executor, options, manifests, log),
+            Action::Activate => self.activate(&step.target, &*executor, options, log),
+            Action::Restart => self.restart(&step.target, &*executor, options, log),
+            Action::Health => self.health(&step.target, &*executor, log),
+        };
+        let allow_failure = matches!(&step.action, Action::Command(command) if command.allow_failure);
+        self.settle(&step.name, outcome, allow_failure, log)
+            .map_err(|outcome| Ran { outcome, failed: Some(step.action) })
+    }
+
+    /// Record how the step called `name` ended, logging how it failed. A
+    /// failure the step is allowed doesn't stop the deploy, being cancelled
+    /// always does.
+    fn settle(
+        &self,
+        name: &str,
+        outcome: Result<Outcome, String>,
+        allow_failure: bool,
+        log: Log,
+    ) -> Result<(), Result<Outcome, String>> {
+        let (outcome, line) = match outcome {
+            Ok(outcome) if outcome.success() => {
+                self.step(name, StepStatus::Succeeded);
+                return Ok(());
+            }
+            Ok(outcome) => (Ok(outcome), format!("==> {name} failed: {outcome}")),
+            Err(err) => {
+                let line = format!("==> {name} failed: {err}");
+                (Err(line.clone()), line)
+            }
+        };
+        if allow_failure && !matches!(outcome, Ok(Outcome::Cancelled)) {
+            log.line(LogStream::Stderr, &format!("{line}, allowed to fail"));
+            self.step(name, StepStatus::AllowedFailure);
+            return Ok(());
+        }
+        log.line(LogStream::Stderr, &line);
+        self.step(name, StepStatus::Failed);
+        Err(outcome)
+    }
+
+    /// Run one of the service's commands under its own timeout.
+    fn run_command(&self, command: &Command, executor: &dyn Executor, log: Log) -> Result<Outcome, String> {
+        let options = RunOptions { timeout: Some(command.timeout), cancel: self.cancel.clone() };
+        executor
+            .run(&command.script, &options, &mut |stream, line| log.output(stream, line))
+            .map_err(|err| err.to_string())
+    }
+
+    /// Run the plan's build, test and package commands for `arch` in order,
+    /// each recorded as a step of its own. Stops at the first one that fails
+    /// without being allowed to.
+    fn run_commands(&self, arch: Option<Arch>, executor: &dyn Executor, log: Log) -> Result<Outcome, String> {
+        for command in self.plan.ci_commands(arch) {
+            log.line(LogStream::Stdout, &format!("==> {} on {}", command.name, executor.target()));
+            self.step(&command.name, StepStatus::Running);
+            let outcome = self.run_command(&command, executor, log);
+            if let Err(outcome) = self.settle(&command.name, outcome, command.allow_failure, log) {
+                return outcome;
+            }
+        }
+        Ok(Outcome::Exited(0))
+    }
+
+    /// The architectures among the plan's nodes, each with its nodes, in
+    /// the order the nodes are deployed to.
+    fn node_arches(&self) -> Vec<(Option<Arch>, Vec<&str>)> {
+        let mut arches: Vec<(Option<Arch>, Vec<&str>)> = Vec::new();
+        for node in self.plan.nodes() {
+            let arch = self.arch_of(node);
+            match arches.iter_mut().find(|(known, _)| *known == arch) {
+                Some((_, nodes)) => nodes.push(&node.name),
+                None => arches.push((arch, vec![&node.name])),
+            }
+        }
+        if arches.is_empty() {
+            arches.push((None, Vec::new()));
+        }
+        arches
+    }
+
+    /// Build once for each architecture among the plan's nodes and keep
+    /// each build, stopping at the first build that fails.
+    fn build(&self, options: &RunOptions, manifests: &Manifests, log: Log) -> Result<Outcome, String> {
+        let arches = self.node_arches();
+        for (arch, nodes) in &arches {
+            if let Some(arch) = arch {
+                log.line(LogStream::Stdout, &format!("==> building for {arch}: {}", nodes.join(", ")));
+            } else if arches.len() > 1 {
+                log.line(LogStream::Stdout, &format!("==> building for the CI node's architecture: {}", nodes.join(", ")));
+            }
+            let outcome = self.build_for(*arch, options, log)?;
+            if !outcome.success() {
+                return Ok(outcome);
+            }
+            self.keep_build(*arch, manifests, log)?;
+        }
+        if let Some(store) = &self.shared.store {
+            match store.prune(&self.plan.service) {
+                Ok(removed) => {
+                    for artifact in removed {
+                        log.line(LogStream::Stdout, &format!("removed stored build {}", short_hash(artifact.sha256())));
+                    }
+                }
+                Err(err) => log.line(LogStream::Stderr, &format!("unable to remove old builds: {err}")),
+            }
+        }
+        Ok(Outcome::Exited(0))
+    }
+
+    /// Keep the artifacts the build for `arch` left in the store, if there
+    /// is one, and sync the nodes from the stored copy.
+    fn keep_build(&self, arch: Option<Arch>, manifests: &Manifests, log: Log) -> Result<(), String> {
+        let Some(store) = &self.shared.store else {
+            return Ok(());
+        };
+        let build_dir = self.plan.build_dir(arch);
+        let manifest = Manifest::collect(&vcs::expand_home(&build_dir), &self.plan.artifacts)
+            .map_err(|source| SyncError::Manifest { build_workspace: build_dir, source }.to_string())?;
+        let stored = store
+            .put(&self.plan.service, self.plan.revision.as_deref(), arch, &manifest)
+            .map_err(|err| err.to_string())?;
+        log.line(LogStream::Stdout, &format!(
+            "stored build {} ({} files, {} bytes)",
+            short_hash(stored.sha256()),
+            manifest.artifacts.len(),
+            stored.size(),
+        ));
+        let manifest = self.ship(store, &stored)?;
+        manifests.lock().expect("error, lock in poisoned state").insert(arch, Arc::new(manifest));
+        Ok(())
+    }
+
+    /// Sync the nodes from the builds deployment `deployment_id` shipped, one
+    /// for the architecture of each node.
+    fn stored(&self, deployment_id: u64, manifests: &Manifests, log: Log) -> Result<Outcome, String> {
+        let store = self.shared.store.as_ref().ok_or("there is no artifact store to deploy from")?;
+        let shipped = store.index().deployment_artifacts(deployment_id).map_err(|err| err.to_string())?;
+        for (arch, nodes) in self.node_arches() {
+            let name = arch.map(|arch| arch.as_str());
+            let stored = shipped.iter().find(|artifact| artifact.arch() == name).ok_or_else(|| {
+                let arch = name.unwrap_or("the CI node's architecture");
+                format!("deployment #{deployment_id} has no build for {arch}, which {} need", nodes.join(", "))
+            })?;
+            log.line(LogStream::Stdout, &format!(
+                "using build {} of deployment #{deployment_id}{}",
+                short_hash(stored.sha256()),
+                name.map(|name| format!(" for {name}")).unwrap_or_default(),
+            ));
+            let manifest = self.ship(store, stored)?;
+            manifests.lock().expect("error, lock in poisoned state").insert(arch, Arc::new(manifest));
+        }
+        Ok(Outcome::Exited(0))
+    }
+
+    /// Record that this deployment ships a stored build, returning its files.
+    fn ship(&self, store: &ArtifactStore, stored: &model::StoredArtifact) -> Result<Manifest, String> {
+        if let Err(err) = store.index().link_deployment(self.deployment_id, stored.id()) {
+            eprintln!("error, when recording the build a deployment ships. Error: {}", err);
+        }
+        store.manifest(stored).map_err(|err| err.to_string())
+    }
+
+    /// Build for `arch` on the first free CI node, or here when the plan has
+    /// none, moving on to the next node when one can't be reached.
+    fn build_for(&self, arch: Option<Arch>, options: &RunOptions, log: Log) -> Result<Outcome, String> {
+        if self.plan.ci_nodes.is_empty() {
+            return self.run_commands(arch, &*(self.shared.connect)(&Target::Local), log);
+        }
+        loop {
+            let acquired = self.shared.builds.acquire(&self.plan.ci_nodes, self.plan.max_builds, &self.cancel, &mut |position| {
+                log.line(LogStream::Stdout, &format!("waiting for a CI node, position {position} in the queue"));
+            });
+            let lease = match acquired {
+                Ok(lease) => lease,
+                Err(QueueError::Cancelled) => return Ok(Outcome::Cancelled),
+                Err(err) => return Err(err.to_string()),
+            };
+            let node = lease.node().clone();
+            let executor = (self.shared.connect)(&Target::Node(node.clone()));
+            log.line(LogStream::Stdout, &format!("building on CI node {} ({})", node.name, executor.target()));
+            if node.is_local() {
+                return self.run_commands(arch, &*executor, log);
+            }
+            match ci::reach(&*executor, options) {
+                Ok(outcome) if !outcome.success() => return Ok(outcome),
+                Ok(_) => {}
+                Err(reason) => {
+                    log.line(LogStream::Stderr, &format!("CI node '{}' can't be reached, {reason}", node.name));
+                    lease.unreachable();
+                    continue;
+                }
+            }
+            let outcome = ci::send_sources(&*executor, &self.plan, options, &mut |stream, line| log.output(stream, line))
+                .map_err(|err| err.to_string())?;
+            if !outcome.success() {
+                return Ok(outcome);
+            }
+            let outcome = self.run_commands(arch, &*executor, log)?;
+            if !outcome.success() {
+                return Ok(outcome);
+            }
+            let local = (self.shared.connect)(&Target::Local);
+            let build_dir = self.plan.build_dir(arch);
+            return ci::fetch_build(&*executor, &*local, &build_dir, options, &mut |stream, line| log.output(stream, line))
+                .map_err(|err| err.to_string());
+        }
+    }
+
+    /// Find out what the step's node runs on and remember it for later deploys.
+    fn detect_arch(&self, target: &Target, executor: &dyn Executor, options: &RunOptions, log: Log) -> Result<Outcome, String> {
+        let mut machine = String::new();
+        let outcome = executor
+            .run(arch::DETECT_SCRIPT, options, &mut |stream, line| match stream {
+                Stream::Stdout => machine = line.trim().to_string(),
+                Stream::Stderr => log.output(stream, line),
+            })
+            .map_err(|err| err.to_string())?;
+        let Some(node) = target.node().filter(|_| outcome.success()) else {
+            return Ok(outcome);
+        };
+        match arch::from_uname(&machine) {
+            Some(arch) => {
+                log.line(LogStream::Stdout, &format!("{machine}, building for {arch}"));
+                self.shared.arches.lock().expect("error, lock in poisoned state").insert(node.name.clone(), arch);
+            }
+            None => log.line(LogStream::Stderr, &format!(
+                "unknown architecture '{machine}', the node gets a build for the CI node's architecture",
+            )),
+        }
+        Ok(outcome)
+    }
+
+    /// The architecture of `node`, as configured or detected.
+    fn arch_of(&self, node: &crate::DeployNode) -> Option<Arch> {
+        node.arch.or_else(|| self.shared.arches.lock().expect("error, lock in poisoned state").get(&node.name).copied())
+    }
+
+    /// Check the step's node runs scripts as root, explaining how to fix it when it doesn't.
+    fn check_root(&self, target: &Target, executor: &dyn Executor, options: &RunOptions, log: Log) -> Result<Outcome, String> {
+        let outcome = executor
+            .run("[ \"$(id -u)\" = 0 ]\n", options, &mut |stream, line| log.output(stream, line))
+            .map_err(|err| err.to_string())?;
+        if let (Some(node), Outcome::Exited(_)) = (target.node(), outcome)
+            && !outcome.success()
+        {
+            log.line(LogStream::Stderr, &node.root_check_failure());
+        }
+        Ok(outcome)
+    }
+
+    /// Sync the build's artifacts to the step's node and record what was transferred.
+    fn sync(
+        &self,
+        target: &Target,
+        executor: &dyn Executor,
+        options: &RunOptions,
+        manifests: &Manifests,
+        log: Log,
+    ) -> Result<Outcome, String> {
+        let node = target.node().map_or("local", |node| node.name.as_str());
+        let arch = target.node().and_then(|node| self.arch_of(node));
+        let manifest = {
+            // held while collecting, so nodes syncing at the same time wait for the one manifest
+            let mut collected = manifests.lock().expect("error, lock in poisoned state");
+            match collected.get(&arch) {
+                Some(manifest) => Arc::clone(manifest),
+                None => {
+                    let build_dir = self.plan.build_dir(arch);
+                    let manifest = Manifest::collect(&vcs::expand_home(&build_dir), &self.plan.artifacts)
+                        .map_err(|source| SyncError::Manifest { build_workspace: build_dir, source }.to_string())?;
+                    Arc::clone(collected.entry(arch).or_insert(Arc::new(manifest)))
+                }
+            }
+        };
+        if let Some(release) = self.plan.release.as_deref().filter(|_| self.plan.keep_releases > 0) {
+            let script = releases::prepare_script(&self.plan.deploy_workspace, release);
+            let outcome = executor
+                .run(&script, options, &mut |stream, line| log.output(stream, line))
+                .map_err(|err| err.to_string())?;
+            if !outcome.success() {
+                return Ok(outcome);
+            }
+        }
+        let synced = sync::sync_node(
+            executor,
+            &manifest,
+            &self.plan.sync_dir(),
+            &self.plan.sync_protect(),
+            options,
+            &mut |stream, line| log.output(stream, line),
+        );
+        match synced {
+            Ok(report) => {
+                if let Err(err) = self.history.record_transfers(self.deployment_id, node, &report.transfers()) {
+                    eprintln!("error, when recording transferred files. Error: {}", err);
+                }
+                Ok(Outcome::Exited(0))
+            }
+            Err(SyncError::Failed { action, outcome }) => {
+                log.line(LogStream::Stderr, &format!("{action} failed: {outcome}"));
+                Ok(outcome)
+            }
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    /// Point the step's node at the plan's release, recording the release
+    /// with the deployment once the first node is switched.
+    fn activate(&self, target: &Target, executor: &dyn Executor, options: &RunOptions, log: Log) -> Result<Outcome, String> {
+        let Some(release) = &self.plan.release else {
+            return Ok(Outcome::Exited(0));
+        };
+        let script = releases::activate_script(&self.plan.deploy_workspace, release, self.plan.keep_releases);
+        let outcome = executor
+            .run(&script, options, &mut |stream, line| log.output(stream, line))
+            .map_err(|err| err.to_string())?;
+        if !outcome.success() {
+            return Ok(outcome);
+        }
+        let node = target.node().map_or("local", |node| node.name.as_str());
+        let first = {
+            let mut activated = self.activated.lock().expect("error, lock in poisoned state");
+            activated.insert(node.to_string());
+            activated.len() == 1
+        };
+        if first && self.plan.rollback_of.is_none()
+            && let Err(err) = self.history.set_release(self.deployment_id, release)
+        {
+            eprintln!("error, when recording deployed release. Error: {}", err);
+        }
+        Ok(outcome)
+    }
+
+    /// Restart the service's unit on the step's node and report the state it settled in.
+    fn restart(&self, target: &Target, executor: &dyn Executor, options: &RunOptions, log: Log) -> Result<Outcome, String> {
+        let Some(unit) = self.plan.unit() else {
+            return Ok(Outcome::Exited(0));
+        };
+        let restarted = systemd::restart(executor, &unit, options, &mut |stream, line| log.output(stream, line))
+            .map_err(|err| err.to_string())?;
+        if let Some(state) = &restarted.state {
+            let node = target.node().map_or("local", |node| node.name.as_str());
+            log.line(LogStream::Stdout, &format!("==> {} on {node} is {state}", unit.name));
+        }
+        Ok(restarted.outcome)
+    }
+
+    /// Wait for the step's node to pass the service's health checks.
+    fn health(&self, target: &Target, executor: &dyn Executor, log: Log) -> Result<Outcome, String> {
+        let (Some(config), Some(node)) = (&self.plan.health, target.node()) else {
+            return Ok(Outcome::Exited(0));
+        };
+        let probes = self.plan.probes(node);
+        match health::wait_healthy(config, &probes, executor, &self.cancel, &mut |stream, line| log.output(stream, line)) {
+            Health::Healthy { attempts } => {
+                log.line(LogStream::Stdout, &format!(
+                    "==> {} on {} is healthy after {attempts} attempt(s)",
+                    self.plan.service, node.name,
+                ));
+                Ok(Outcome::Exited(0))
+            }
+            Health::Unhealthy { attempts, reason } => Err(format!(
+                "node '{}' never became healthy, {attempts} attempt(s): {reason}",
+                node.name,
+            )),
+            Health::Cancelled => Ok(Outcome::Cancelled),
+        }
+    }
+
+    /// Record the status of the step called `name` and tell subscribers.
+    fn step(&self, name: &str, status: StepStatus) {
+        if let Err(err) = self.history.record_step(self.deployment_id, name, status) {
+            eprintln!("error, when recording step status. Error: {}", err);
+        }
+        lock(&self.state).publish(&self.plan.service, DeployEvent::Step {
+            deployment_id: self.deployment_id,
+            name: name.to_string(),
+            status,
+        });
+    }
+
+    fn line(&self, stream: LogStream, line: &str) {
+        record_line(&self.history, self.deployment_id, stream, line);
+        lock(&self.state).publish(&self.plan.service, DeployEvent::Line {
+            deployment_id: self.deployment_id,
+            stream,
+            line: line.to_string(),
+        });
+    }
+}
+
+/// Where a step logs to: the deploy's output, with the step's node in front
+/// of each line so the output of nodes deployed in parallel can be told apart.
+#[derive(Clone, Copy)]
+struct Log<'a> {
+    run: &'a DeployRun,
+    node: Option<&'a str>,
+}
+
+impl Log<'_> {
+    fn output(&self, stream: Stream, line: &str) {
+        let stream = match stream {
+            Stream::Stdout => LogStream::Stdout,
+            Stream::Stderr => LogStream::Stderr,
+        };
+        self.line(stream, line);
+    }
+
+    fn line(&self, stream: LogStream, line: &str) {
+        match self.node {
+            Some(node) => self.run.line(stream, &format!("[{node}] {line}")),
+            None => self.run.line(stream, line),
+        }
+    }
+}
+
+/// The start of a hash, enough to tell builds apart in the log.
+fn short_hash(hash: &str) -> &str {
+    hash.get(..12).unwrap_or(hash)
+}
+
+fn record_line(history: &SqliteDeploymentModel, deployment_id: u64, stream: LogStream, line: &str) {
+    if let Err(err) = history.append_log(deployment_id, stream, line) {
+        eprintln!("error, when recording deploy output. Error: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    fn history() -> SqliteDeploymentModel {
+        SqliteDeploymentModel::new_with_pool(pool())
+    }
+
+    fn pool() -> Pool<SqliteConnectionManager> {
+        let manager = SqliteConnectionManager::memory();
+        let pool = Pool::builder().max_size(1).build(manager).expect("pool");
+        pool.get()
+            .expect("conn")
+            .execute_batch(concat!(
+                include_str!("../../db/migrations/002_create_deployments.sql"),
+                include_str!("../../db/migrations/003_create_deployment_logs.sql"),
+                include_str!("../../db/migrations/005_create_deployment_transfers.sql"),
+                include_str!("../../db/migrations/006_add_deployments_privileged.sql"),
+                include_str!("../../db/migrations/007_add_deployments_release.sql"),
+                include_str!("../../db/migrations/008_create_deployment_steps.sql"),
+                include_str!("../../db/migrations/009_create_artifacts.sql"),
+                include_str!("../../db/migrations/010_add_deployments_promoted_from.sql"),
+            ))
+            .expect("create tables");
+        pool
+    }
+
+    /// A plan with no nodes whose build script runs `build`.
+    fn plan(name: &str, build: &str) -> (DeployPlan, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("deploy-hub-{name}-{}", std::process::id()));
+        let create = dir.join("create");
+        fs::create_dir_all(&create).expect("create dir");
+        let script = create.join(crate::BUILD_SCRIPT);
+        fs::write(&script, format!("#!/bin/sh\n{build}\n")).expect("write build script");
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).expect("chmod");
+        let plan = DeployPlan {
+            service: name.to_string(),
+            environment: "development".to_string(),
+            create_workspace: create.display().to_string(),
+            build_workspace: dir.join("build").display().to_string(),
+            deploy_workspace: dir.join("deploy").display().to_string(),
+            artifacts: Vec::new(),
+            protect: Vec::new(),
+            deploy_as_root: false,
+            unit: None,
+            health: None,
+            ci_nodes: Vec::new(),
+            max_builds: 1,
+            commands: Default::default(),
+            keep_releases: 0,
+            waves: Vec::new(),
+            revision: None,
+            release: None,
+            rollback_of: None,
+            build_of: None,
+            promoted_from: None,
+            step_timeout: crate::STEP_TIMEOUT,
+        };
+        (plan, dir)
+    }
+
+    /// A node reached over ssh as `pi`.
+    fn pi(name: &str) -> crate::DeployNode {
+        crate::DeployNode {
+            name: name.to_string(),
+            host_name: "192.168.1.34".to_string(),
+            user: "pi".to_string(),
+            port: 22,
+            escalation: config::Escalation::Sudo,
+            root_user: "root".to_string(),
+            arch: None,
+        }
+    }
+
+    /// Fakes standing in for the nodes of a test, by name.
+    type Fakes = BTreeMap<&'static str, Arc<exec::FakeExecutor>>;
+
+    /// A hub recording through `history` whose steps run on fakes: the local
+    /// one returned first, and one reached as `pi@<name>:22` for each of `nodes`.
+    fn fake_hub(history: &SqliteDeploymentModel, nodes: &[&'static str]) -> (DeployHub, Arc<exec::FakeExecutor>, Fakes) {
+        let local = Arc::new(exec::FakeExecutor::new("local"));
+        let fakes: Fakes = nodes
+            .iter()
+            .map(|name| (*name, Arc::new(exec::FakeExecutor::new(&format!("pi@{name}:22")))))
+            .collect();
+        let (local_executor, executors) = (Arc::clone(&local), fakes.clone());
+        let connect: Connect = Arc::new(move |target: &Target| -> Arc<dyn Executor> {
+            match target.node() {
+                Some(node) => executors[node.name.as_str()].clone(),
+                None => local_executor.clone(),
+            }
+        });
+        (DeployHub::with_connect(history.clone(), connect), local, fakes)
+    }
+
+    fn collect(hub: &DeployHub, service: &str) -> mpsc::Receiver<DeployEvent> {
+        let (tx, rx) = mpsc::channel();
+        hub.subscribe(service, Box::new(move |event| tx.send(event.clone()).is_ok()));
+        rx
+    }
+
+    fn until_finished(rx: &mpsc::Receiver<DeployEvent>) -> Vec<DeployEvent> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut events = Vec::new();
+        while let Ok(event) = rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            let done = matches!(event, DeployEvent::Finished { .. });
+            events.push(event);
+            if done {
+                break;
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn late_subscriber_gets_backlog_then_live_output() {
+        let hub = DeployHub::new(history());
+        let (plan, dir) = plan("late-join", "echo first\nsleep 1\necho second");
+        let early = collect(&hub, "late-join");
+        let id = hub.start(&plan, "test").expect("start");
+
+        thread::sleep(Duration::from_millis(500));
+        let late = collect(&hub, "late-join");
+
+        let early_events = until_finished(&early);
+        let late_events = until_finished(&late);
+        assert_eq!(early_events, late_events);
+        assert_eq!(early_events.first(), Some(&DeployEvent::Started {
+            deployment_id: id,
+            service: "late-join".to_string(),
+            environment: "development".to_string(),
+        }));
+        assert!(early_events.contains(&DeployEvent::Line {
+            deployment_id: id,
+            stream: LogStream::Stdout,
+            line: "second".to_string(),
+        }));
+        assert!(matches!(
+            early_events.last(),
+            Some(DeployEvent::Finished { status: DeploymentStatus::Succeeded, .. })
+        ));
+        assert!(!hub.is_running("late-join"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejects_second_deploy_of_running_service() {
+        let hub = DeployHub::new(history());
+        let (plan, dir) = plan("single", "sleep 1");
+        let rx = collect(&hub, "single");
+        hub.start(&plan, "test").expect("start");
+        assert!(matches!(hub.start(&plan, "test"), Err(StartError::AlreadyRunning(_))));
+        until_finished(&rx);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn runs_each_step_through_the_executor_for_its_target() {
+        let history = history();
+        let (hub, local, nodes) = fake_hub(&history, &["pi1"]);
+        let node = &nodes["pi1"];
+        local.respond("build.sh", &[(Stream::Stdout, "built")], Outcome::Exited(0));
+        let stale = format!("{}  ./old.txt", "0".repeat(64));
+        node.respond("sha256sum", &[(Stream::Stdout, &stale)], Outcome::Exited(0));
+        node.respond("tar --no-same-owner -xf -", &[(Stream::Stderr, "unpacked")], Outcome::Exited(0));
+        node.respond("uname -m", &[(Stream::Stdout, "aarch64")], Outcome::Exited(0));
+        let (mut plan, dir) = plan("remote", "true");
+        fs::create_dir_all(dir.join("build/arm64")).expect("build dir");
+        fs::write(dir.join("build/arm64/app"), "binary").expect("artifact");
+        plan.waves.push(Wave { nodes: vec![pi("pi1")], max_parallel: 1 });
+        let rx = collect(&hub, "remote");
+        let id = hub.start(&plan, "test").expect("start");
+        let events = until_finished(&rx);
+
+        assert_eq!(local.scripts().len(), 1);
+        assert!(local.scripts()[0].contains("GOARCH=arm64"));
+        assert_eq!(node.scripts().len(), 3);
+        assert!(!node.inputs()[2].is_empty());
+        let lines: Vec<(LogStream, &str)> = events
+            .iter()
+            .filter_map(|event| match event {
+                DeployEvent::Line { stream, line, .. } => Some((*stream, line.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lines, [
+            (LogStream::Stdout, "[pi1] ==> arch pi1 on pi@pi1:22"),
+            (LogStream::Stdout, "[pi1] aarch64, building for arm64"),
+            (LogStream::Stdout, "==> build remote on local"),
+            (LogStream::Stdout, "==> building for arm64: pi1"),
+            (LogStream::Stdout, "==> build: build.sh (arm64) on local"),
+            (LogStream::Stdout, "built"),
+            (LogStream::Stdout, "[pi1] ==> sync pi1 on pi@pi1:22"),
+            (LogStream::Stdout, "[pi1] 1 to upload (6 bytes), 1 to delete, 0 unchanged"),
+            (LogStream::Stderr, "[pi1] unpacked"),
+            (LogStream::Stdout, "[pi1] uploaded app"),
+            (LogStream::Stdout, "[pi1] deleted old.txt"),
+            (LogStream::Stdout, "==> done remote"),
+        ]);
+        assert_eq!(events.last(), Some(&DeployEvent::Finished {
+            deployment_id: id,
+            status: DeploymentStatus::Succeeded,
+            exit: "exit status: 0".to_string(),
+        }));
+        let transfers = history.list_transfers(id).expect("transfers");
+        let recorded: Vec<(&str, &str)> = transfers.iter().map(|t| (t.node(), t.path())).collect();
+        assert_eq!(recorded, [("pi1", "app"), ("pi1", "old.txt")]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn commands_record_their_status_and_may_be_allowed_to_fail() {
+        let history = history();
+        let (hub, local, nodes) = fake_hub(&history, &["pi1"]);
+        local.respond("run-lint", &[(Stream::Stderr, "unused variable")], Outcome::Exited(1));
+        nodes["pi1"].respond("self-check", &[(Stream::Stderr, "missing config")], Outcome::Exited(2));
+        let (mut plan, dir) = plan("commands", "true");
+        fs::create_dir_all(dir.join("build")).expect("build dir");
+        let step = |name: &str, command: &str, allow_failure: bool| config::CommandStep {
+            name: Some(name.to_string()),
+            command: command.to_string(),
+            working_dir: None,
+            env: BTreeMap::new(),
+            timeout: None,
+            allow_failure,
+        };
+        plan.commands.build.push(step("compile", "make", false));
+        plan.commands.test.push(step("lint", "run-lint", true));
+        plan.commands.deploy.push(step("check", "self-check", false));
+        plan.waves.push(Wave { nodes: vec![pi("pi1")], max_parallel: 1 });
+        let rx = collect(&hub, "commands");
+        let id = hub.start(&plan, "test").expect("start");
+        let events = until_finished(&rx);
+
+        // the commands replace build.sh
+        assert!(local.scripts().iter().all(|script| !script.contains("build.sh")));
+        assert!(events.contains(&DeployEvent::Line {
+            deployment_id: id,
+            stream: LogStream::Stderr,
+            line: "==> test: lint failed: exit status: 1, allowed to fail".to_string(),
+        }));
+        assert!(events.contains(&DeployEvent::Step {
+            deployment_id: id,
+            name: "test: lint".to_string(),
+            status: StepStatus::Running,
+        }));
+        assert!(matches!(events.last(), Some(DeployEvent::Finished { status: DeploymentStatus::Failed, .. })));
+        let steps = history.list_steps(id).expect("steps");
+        let statuses: Vec<(&str, StepStatus)> = steps.iter().map(|step| (step.name(), step.status())).collect();
+        assert_eq!(statuses, [
+            ("arch pi1", StepStatus::Succeeded),
+            ("build commands", StepStatus::Succeeded),
+            ("build: compile", StepStatus::Succeeded),
+            ("test: lint", StepStatus::AllowedFailure),
+            ("sync pi1", StepStatus::Succeeded),
+            ("deploy pi1: check", StepStatus::Failed),
+        ]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn builds_once_per_architecture_and_syncs_each_node_its_own() {
+        let history = history();
+        let (hub, local, nodes) = fake_hub(&history, &["pi1", "pi2"]);
+        nodes["pi2"].respond("uname -m", &[(Stream::Stdout, "x86_64")], Outcome::Exited(0));
+        let (mut plan, dir) = plan("arches", "true");
+        for arch in ["arm64", "amd64"] {
+            fs::create_dir_all(dir.join("build").join(arch)).expect("build dir");
+            fs::write(dir.join("build").join(arch).join(arch), arch).expect("artifact");
+        }
+        let mut pi1 = pi("pi1");
+        pi1.arch = Some(Arch::Arm64);
+        plan.waves.push(Wave { nodes: vec![pi1, pi("pi2")], max_parallel: 1 });
+        let rx = collect(&hub, "arches");
+        let id = hub.start(&plan, "test").expect("start");
+        let events = until_finished(&rx);
+
+        assert!(matches!(events.last(), Some(DeployEvent::Finished { status: DeploymentStatus::Succeeded, .. })));
+        let builds = local.scripts();
+        assert_eq!(builds.len(), 2);
+        assert!(builds[0].contains("BUILD_WORKSPACE=") && builds[0].contains("/build/arm64' ARCH=arm64 "));
+        assert!(builds[1].contains("/build/amd64' ARCH=amd64 "));
+        let transfers = history.list_transfers(id).expect("transfers");
+        let recorded: Vec<(&str, &str)> = transfers.iter().map(|t| (t.node(), t.path())).collect();
+        assert_eq!(recorded, [("pi1", "arm64"), ("pi2", "amd64")]);
+
+        // the next deploy remembers what pi2 runs on
+        let rx = collect(&hub, "arches");
+        hub.start(&plan, "test").expect("start");
+        until_finished(&rx);
+        let asked = nodes["pi2"].scripts().iter().filter(|script| *script == arch::DETECT_SCRIPT).count();
+        assert_eq!(asked, 1);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn stored_builds_are_deployed_again_without_building() {
+        let pool = pool();
+        let history = SqliteDeploymentModel::new_with_pool(pool.clone());
+        let (hub, local, nodes) = fake_hub(&history, &["pi1"]);
+        let (mut plan, dir) = plan("stored", "true");
+        let config = config::ArtifactsConfig { dir: dir.join("store").display().to_string(), keep: 1, max_age_days: 0 };
+        let hub = hub.with_store(ArtifactStore::new(&config, SqliteArtifactModel::new_with_pool(pool)));
+        fs::create_dir_all(dir.join("build")).expect("build dir");
+        fs::write(dir.join("build").join("app"), "v1").expect("artifact");
+        plan.waves.push(Wave { nodes: vec![pi("pi1")], max_parallel: 1 });
+        let rx = collect(&hub, "stored");
+        let built = hub.start(&plan, "test").expect("start");
+        until_finished(&rx);
+        let index = hub.shared.store.as_ref().expect("store").index();
+        let shipped = index.deployment_artifacts(built).expect("artifacts");
+        assert_eq!(shipped.len(), 1);
+
+        // the build workspace moves on, the stored build doesn't
+        fs::write(dir.join("build").join("app"), "v2").expect("artifact");
+        let builds = local.scripts().len();
+        let production = DeployPlan { environment: "production".to_string(), ..plan.clone() };
+        let rx = collect(&hub, "stored");
+        let id = hub.redeploy(&production, built, "test").expect("redeploy");
+        let events = until_finished(&rx);
+
+        assert!(matches!(events.last(), Some(DeployEvent::Finished { status: DeploymentStatus::Succeeded, .. })));
+        assert_eq!(local.scripts().len(), builds);
+        let steps = history.list_steps(id).expect("steps");
+        assert!(steps.iter().any(|step| step.name() == format!("build of #{built}")));
+        assert_eq!(index.deployment_artifacts(id).expect("artifacts"), shipped);
+        let uploaded = nodes["pi1"].inputs().concat();
+        assert!(uploaded.windows(2).any(|bytes| bytes == b"v1"));
+        assert!(!uploaded.windows(2).any(|bytes| bytes == b"v2"));
+        assert!(matches!(hub.redeploy(&production, id + 1, "test"), Err(StartError::NoStoredBuild(_))));
+
+        // nodes of an architecture it wasn't built for need a build of their own
+        assert!(hub.has_builds_for(&production, built).expect("builds"));
+        let mut pi2 = pi("pi2");
+        pi2.arch = Some(Arch::Amd64);
+        let mixed = DeployPlan { waves: vec![Wave { nodes: vec![pi("pi1"), pi2], max_parallel: 1 }], ..production };
+        assert!(!hub.has_builds_for(&mixed, built).expect("builds"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn promotes_what_an_environment_runs_once_it_is_healthy() {
+        let pool = pool();
+        let history = SqliteDeploymentModel::new_with_pool(pool.clone());
+        let (plan, dir) = plan("promote", "test -e fail && exit 1; true");
+        let config = config::ArtifactsConfig { dir: dir.join("store").display().to_string(), keep: 1, max_age_days: 0 };
+        let store = ArtifactStore::new(&config, SqliteArtifactModel::new_with_pool(pool));
+        let hub = DeployHub::new(history.clone()).with_store(store);
+        fs::create_dir_all(dir.join("build")).expect("build dir");
+        fs::write(dir.join("build").join("app"), "v1").expect("artifact");
+        let production = DeployPlan { environment: "production".to_string(), ..plan.clone() };
+        assert!(matches!(
+            hub.promote(&production, "development", "test"),
+            Err(StartError::NothingToPromote { .. })
+        ));
+
+        let rx = collect(&hub, "promote");
+        let tested = hub.start(&plan, "test").expect("start");
+        until_finished(&rx);
+        let rx = collect(&hub, "promote");
+        let id = hub.promote(&production, "development", "test").expect("promote");
+        let events = until_finished(&rx);
+        assert!(matches!(events.last(), Some(DeployEvent::Finished { status: DeploymentStatus::Succeeded, .. })));
+        let promoted = history.find_deployment(id).expect("find").expect("recorded");
+        assert_eq!((promoted.environment(), promoted.promoted_from()), ("production", Some(tested)));
+
+        // a failed build left development running what it ran, so that's promoted again
+        fs::write(dir.join("create").join("fail"), "").expect("fail the build");
+        let rx = collect(&hub, "promote");
+        hub.start(&plan, "test").expect("start");
+        until_finished(&rx);
+        let rx = collect(&hub, "promote");
+        let id = hub.promote(&production, "development", "test").expect("promote");
+        until_finished(&rx);
+        let promoted = history.find_deployment(id).expect("find").expect("recorded");
+        assert_eq!(promoted.promoted_from(), Some(tested));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn promotes_the_release_a_rollback_went_back_to() {
+        let pool = pool();
+        let history = SqliteDeploymentModel::new_with_pool(pool.clone());
+        let (hub, _, nodes) = fake_hub(&history, &["pi1"]);
+        let (mut plan, dir) = plan("restored", "true");
+        let config = config::ArtifactsConfig { dir: dir.join("store").display().to_string(), keep: 3, max_age_days: 0 };
+        let hub = hub.with_store(ArtifactStore::new(&config, SqliteArtifactModel::new_with_pool(pool)));
+        plan.keep_releases = 3;
+        plan.waves.push(Wave { nodes: vec![pi("pi1")], max_parallel: 1 });
+        fs::create_dir_all(dir.join("build")).expect("build dir");
+        let deploy = |build: &str| {
+            fs::write(dir.join("build").join("app"), build).expect("artifact");
+            let rx = collect(&hub, "restored");
+            let id = hub.start(&plan, "test").expect("start");
+            until_finished(&rx);
+            id
+        };
+        let first = deploy("v1");
+        deploy("v2");
+        let rx = collect(&hub, "restored");
+        let rollback = hub.rollback(&plan, "test").expect("rollback");
+        until_finished(&rx);
+
+        let production = DeployPlan { environment: "production".to_string(), ..plan.clone() };
+        let rx = collect(&hub, "restored");
+        let id = hub.promote(&production, "development", "test").expect("promote");
+        let events = until_finished(&rx);
+        assert!(matches!(events.last(), Some(DeployEvent::Finished { status: DeploymentStatus::Succeeded, .. })));
+        let promoted = history.find_deployment(id).expect("find").expect("recorded");
+        assert_eq!(promoted.promoted_from(), Some(rollback));
+        let index = hub.shared.store.as_ref().expect("store").index();
+        assert_eq!(index.deployment_artifacts(id).expect("artifacts"), index.deployment_artifacts(first).expect("artifacts"));
+
+        // failing its health checks, and the rollback failing them too, leaves development unfit to promote
+        nodes["pi1"].respond("curl", &[(Stream::Stderr, "connection refused")], Outcome::Exited(7));
+        plan.health = Some(config::HealthConfig {
+            retries: 0,
+            interval: 0,
+            deadline: 10,
+            checks: vec![config::HealthCheck::Command { command: "curl -fs localhost:8080".to_string() }],
+        });
+        let rx = collect(&hub, "restored");
+        let unhealthy = hub.start(&plan, "test").expect("start");
+        until_finished(&rx);
+        until_finished(&rx);
+        assert!(matches!(
+            hub.promote(&production, "development", "test"),
+            Err(StartError::Unhealthy { deployment_id, .. }) if deployment_id == unhealthy + 1
+        ));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn a_failed_wave_stops_the_waves_after_it() {
+        let (hub, _, nodes) = fake_hub(&history(), &["pi1", "pi2", "pi3"]);
+        nodes["pi2"].respond("sha256sum", &[(Stream::Stderr, "disk full")], Outcome::Exited(1));
+        let (mut plan, dir) = plan("waves", "true");
+        fs::create_dir_all(dir.join("build")).expect("build dir");
+        plan.waves.push(Wave { nodes: vec![pi("pi1"), pi("pi2")], max_parallel: 2 });
+        plan.waves.push(Wave { nodes: vec![pi("pi3")], max_parallel: 2 });
+        let rx = collect(&hub, "waves");
+        hub.start(&plan, "test").expect("start");
+        let events = until_finished(&rx);
+
+        assert!(!nodes["pi1"].scripts().is_empty());
+        // pi3 was only asked for its architecture
+        assert_eq!(nodes["pi3"].scripts(), [arch::DETECT_SCRIPT]);
+        let lines: Vec<&str> = events
+            .iter()
+            .filter_map(|event| match event {
+                DeployEvent::Line { line, .. } => Some(line.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert!(lines.contains(&"==> wave 1/2: pi1, pi2"));
+        assert!(lines.contains(&"[pi2] disk full"));
+        assert!(lines.contains(&"[pi1] ==> sync pi1 on pi@pi1:22"));
+        assert_eq!(lines.last(), Some(&"==> skipping wave 2"));
+        assert!(matches!(events.last(), Some(DeployEvent::Finished { status: DeploymentStatus::Failed, .. })));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn builds_move_on_to_the_next_ci_node_when_one_is_unreachable() {
+        let (hub, local, nodes) = fake_hub(&history(), &["pi1", "pi2"]);
+        let (down, up) = (&nodes["pi1"], &nodes["pi2"]);
+        down.respond("", &[(Stream::Stderr, "ssh: connect to host pi1 port 22: Connection refused")], Outcome::Exited(255));
+        up.respond("tar -czf", &[(Stream::Stdout, "archive")], Outcome::Exited(0));
+        let (mut plan, dir) = plan("ci", "true");
+        plan.ci_nodes = vec![pi("pi1"), pi("pi2")];
+        let rx = collect(&hub, "ci");
+        hub.start(&plan, "test").expect("start");
+        let events = until_finished(&rx);
+
+        let lines: Vec<&str> = events
+            .iter()
+            .filter_map(|event| match event {
+                DeployEvent::Line { line, .. } => Some(line.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert!(lines.contains(&"CI node 'pi1' can't be reached, exit status: 255: ssh: connect to host pi1 port 22: Connection refused"));
+        assert!(lines.contains(&"building on CI node pi2 (pi@pi2:22)"));
+        assert!(matches!(events.last(), Some(DeployEvent::Finished { status: DeploymentStatus::Succeeded, .. })));
+        // the sources went up, the build ran there and its workspace came back
+        assert!(up.inputs().iter().any(|input| !input.is_empty()));
+        assert!(up.scripts().iter().any(|script| script.contains("./build.sh")));
+        assert!(lines.iter().any(|line| line.starts_with("copying back ") && line.ends_with(" (8 bytes)")));
+        assert!(local.scripts().iter().any(|script| script.contains("tar -xzf ")));
+        assert!(hub.builds().busy().is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn root_deploy_stops_before_the_build_without_passwordless_sudo() {
+        let history = history();
+        let (hub, _, nodes) = fake_hub(&history, &["pi1"]);
+        let node = &nodes["pi1"];
+        node.respond("id -u", &[(Stream::Stderr, "sudo: a password is required")], Outcome::Exited(1));
+        let (mut plan, dir) = plan("privileged", "true");
+        plan.deploy_as_root = true;
+        plan.waves.push(Wave { nodes: vec![pi("pi1")], max_parallel: 1 });
+        let rx = collect(&hub, "privileged");
+        let id = hub.start(&plan, "test").expect("start");
+        let events = until_finished(&rx);
+
+        assert_eq!(node.scripts().len(), 1);
+        assert!(events.contains(&DeployEvent::Line {
+            deployment_id: id,
+            stream: LogStream::Stderr,
+            line: format!("[pi1] {}", plan.waves[0].nodes[0].root_check_failure()),
+        }));
+        assert!(matches!(events.last(), Some(DeployEvent::Finished { status: DeploymentStatus::Failed, .. })));
+        assert!(history.find_deployment(id).expect("find").expect("recorded").privileged());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn unhealthy_node_fails_the_deploy() {
+        let (hub, _, nodes) = fake_hub(&history(), &["pi1"]);
+        let node = &nodes["pi1"];
+        node.respond("curl", &[(Stream::Stderr, "connection refused")], Outcome::Exited(7));
+        let (mut plan, dir) = plan("unhealthy", "true");
+        fs::create_dir_all(dir.join("build")).expect("build dir");
+        plan.health = Some(config::HealthConfig {
+            retries: 1,
+            interval: 0,
+            deadline: 10,
+            checks: vec![config::HealthCheck::Command { command: "curl -fs localhost:8080".to_string() }],
+        });
+        plan.waves.push(Wave { nodes: vec![pi("pi1")], max_parallel: 1 });
+        let rx = collect(&hub, "unhealthy");
+        let id = hub.start(&plan, "test").expect("start");
+        let events = until_finished(&rx);
+
+        let failure = "==> health pi1 failed: node 'pi1' never became healthy, 2 attempt(s): \
+                       'curl -fs localhost:8080' failed: exit status: 7: connection refused";
+        assert_eq!(events.last(), Some(&DeployEvent::Finished {
+            deployment_id: id,
+            status: DeploymentStatus::Failed,
+            exit: failure.to_string(),
+        }));
+        assert_eq!(node.scripts().iter().filter(|script| script.starts_with("curl")).count(), 2);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn unhealthy_release_is_rolled_back_as_its_own_deployment() {
+        let history = history();
+        let (hub, _, nodes) = fake_hub(&history, &["pi1", "pi2"]);
+        let node = &nodes["pi1"];
+        node.respond("curl", &[(Stream::Stderr, "connection refused")], Outcome::Exited(7));
+        let (mut plan, dir) = plan("rollback", "true");
+        fs::create_dir_all(dir.join("build")).expect("build dir");
+        plan.keep_releases = 3;
+        plan.revision = Some("r2".to_string());
+        plan.health = Some(config::HealthConfig {
+            retries: 0,
+            interval: 0,
+            deadline: 10,
+            checks: vec![config::HealthCheck::Command { command: "curl -fs localhost:8080".to_string() }],
+        });
+        plan.waves.push(Wave { nodes: vec![pi("pi1")], max_parallel: 1 });
+        plan.waves.push(Wave { nodes: vec![pi("pi2")], max_parallel: 1 });
+        let live = history
+            .start_deployment(&NewDeployment {
+                service: "rollback",
+                environment: "development",
+                nodes: &["pi1".to_string()],
+                revision: Some("r1"),
+                initiated_by: "test",
+                privileged: false,
+                release: Some("r1"),
+                rollback_of: None,
+                promoted_from: None,
+            })
+            .expect("record live release");
+        history.finish_deployment(live.id(), DeploymentStatus::Succeeded, Some(0)).expect("finish");
+
+        let rx = collect(&hub, "rollback");
+        let id = hub.start(&plan, "test").expect("start");
+        let mut events = until_finished(&rx);
+        events.extend(until_finished(&rx));
+
+        let finished: Vec<(u64, DeploymentStatus)> = events
+            .iter()
+            .filter_map(|event| match event {
+                DeployEvent::Finished { deployment_id, status, .. } => Some((*deployment_id, *status)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(finished, [(id, DeploymentStatus::Failed), (id + 1, DeploymentStatus::Failed)]);
+        assert!(events.contains(&DeployEvent::Line {
+            deployment_id: id,
+            stream: LogStream::Stdout,
+            line: format!("==> rolling back to release r1 of deployment #{}", live.id()),
+        }));
+        let scripts = node.scripts();
+        assert!(scripts.iter().any(|script| script.contains("mkdir 'releases/r2'")));
+        assert!(scripts.iter().any(|script| script.contains("ln -sfn 'releases/r1' current.tmp")));
+
+        let rollback = history.find_deployment(id + 1).expect("find").expect("recorded");
+        assert_eq!((rollback.release(), rollback.rollback_of()), (Some("r1"), Some(id)));
+        assert_eq!(rollback.initiated_by(), "auto-rollback");
+        // pi2 never got r2, so only pi1 is switched back
+        assert_eq!(rollback.nodes(), ["pi1".to_string()]);
+        assert!(!hub.is_running("rollback"));
+        assert_eq!(history.find_deployment(id).expect("find").expect("recorded").release(), Some("r2"));
+        // the rollback didn't get healthy either, and r1 is already what it went back to
+        assert!(matches!(hub.rollback(&plan, "test"), Err(StartError::NothingToRollBack { .. })));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn a_panicking_step_still_finishes_the_deploy() {
+        let connect: Connect = Arc::new(|_: &Target| -> Arc<dyn Executor> { panic!("no executor") });
+        let hub = DeployHub::with_connect(history(), connect);
+        let (plan, dir) = plan("panicking", "true");
+        let rx = collect(&hub, "panicking");
+        let id = hub.start(&plan, "test").expect("start");
+        let events = until_finished(&rx);
+        assert_eq!(events.last(), Some(&DeployEvent::Finished {
+            deployment_id: id,
+            status: DeploymentStatus::Failed,
+            exit: "the deploy stopped unexpectedly".to_string(),
+        }));
+        assert!(!hub.is_deployment_running(id));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn cancel_stops_the_running_step() {
+        let hub = DeployHub::new(history());
+        let (plan, dir) = plan("cancelled", "echo waiting\nsleep 30");
+        let rx = collect(&hub, "cancelled");
+        let id = hub.start(&plan, "test").expect("start");
+        thread::sleep(Duration::from_millis(300));
+        assert!(hub.cancel(id));
+        let events = until_finished(&rx);
+        assert_eq!(events.last(), Some(&DeployEvent::Finished {
+            deployment_id: id,
+            status: DeploymentStatus::Failed,
+            exit: "cancelled".to_string(),
+        }));
+        assert!(!hub.cancel(id));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
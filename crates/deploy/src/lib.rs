@@ -0,0 +1,801 @@
+//! Resolution of a service deployment into the commands that carry it out.
+//!
+//! A deploy walks the three workspaces declared on a service. The source in
+//! `create_workspace` is built into `build_workspace` by the service's
+//! `build.sh`, or by its build, test and package commands. The build runs on
+//! the first free CI node, see [`ci`], once for every architecture among the
+//! nodes, see [`arch`], and is kept in the artifact store, see [`store`].
+//!
+//! The artifacts of the build are synced into `deploy_workspace` on every
+//! node the target environment lists for the service, where the service's
+//! deploy commands run. A service with a `unit` is restarted as a systemd
+//! unit on each node, and a service with `health` checks has to pass them on
+//! every node for the deploy to succeed.
+//!
+//! A service that keeps releases syncs each deploy into a release of its own
+//! and switches to it, so a node that fails its restart or health checks can
+//! be rolled back, see [`releases`]. With `deploy_as_root` the node side runs
+//! as root, through the escalation each node is configured with, after
+//! checking up front that the node allows it.
+//!
+//! A plan is carried out as a list of [`Step`]s, each run by the executor for
+//! where it has to happen: this machine or a node over ssh. The groups of
+//! nodes an environment lists are deployed to one after the other as
+//! [`Wave`]s, the nodes of a wave in parallel.
+
+pub mod arch;
+pub mod ci;
+pub mod health;
+pub mod hub;
+pub mod poller;
+pub mod releases;
+pub mod store;
+pub mod sync;
+pub mod systemd;
+pub use hub::{DeployEvent, DeployHub, StartError, Subscriber, SubscriptionId, hub};
+pub use poller::PipelineRun;
+
+use config::{AppConfig, Arch, CommandStep, CommandsConfig, Escalation, HealthConfig, NodeConfig, UnitConfig};
+use exec::{Executor, LocalExecutor, SshExecutor, SudoExecutor, shell_quote};
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Script each service ships in its `create_workspace` to produce artifacts.
+pub const BUILD_SCRIPT: &str = "build.sh";
+
+/// Longest a single step may run before it is killed.
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Start of the name of the step checking a node's health.
+pub const HEALTH_STEP: &str = "health";
+
+/// Name of the step checking the health of the node called `node`.
+pub fn health_step(node: &str) -> String {
+    format!("{HEALTH_STEP} {node}")
+}
+
+/// Reasons a deploy request cannot be turned into a plan.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeployError {
+    UnknownService(String),
+    UnknownEnvironment { service: String, environment: String },
+    UnknownNode { service: String, node: String },
+    /// The environment has no `promote_to`.
+    NoPromotion(String),
+}
+
+impl Display for DeployError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownService(service) => write!(f, "unknown service '{service}'"),
+            Self::UnknownEnvironment { service, environment } => {
+                write!(f, "service '{service}' has no environment '{environment}'")
+            }
+            Self::UnknownNode { service, node } => {
+                write!(f, "service '{service}' references unknown node '{node}'")
+            }
+            Self::NoPromotion(environment) => write!(f, "environment '{environment}' doesn't promote to another"),
+        }
+    }
+}
+
+impl std::error::Error for DeployError {}
+
+/// A node a deploy targets, copied out of the config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeployNode {
+    pub name: String,
+    pub host_name: String,
+    pub user: String,
+    pub port: usize,
+    pub escalation: Escalation,
+    pub root_user: String,
+    /// The node's architecture, when configured or already detected.
+    pub arch: Option<Arch>,
+}
+
+impl DeployNode {
+    fn new(name: &str, config: &NodeConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            host_name: config.host_name.clone(),
+            user: config.user.clone(),
+            port: config.port,
+            escalation: config.escalation,
+            root_user: config.root_user.clone(),
+            arch: config.arch,
+        }
+    }
+
+    /// Whether the node is this machine, so no ssh hop is needed.
+    pub fn is_local(&self) -> bool {
+        matches!(self.host_name.as_str(), "127.0.0.1" | "localhost" | "::1")
+    }
+
+    /// Why a check that the node lets deploys run as root failed.
+    pub fn root_check_failure(&self) -> String {
+        match self.escalation {
+            Escalation::Sudo => format!(
+                "node '{}': user '{}' can't run sudo without a password, which deploy_as_root needs. \
+                 Allow it in sudoers, e.g. '{} ALL=(ALL) NOPASSWD: ALL', or set escalation = \"ssh\" on the node",
+                self.name, self.user, self.user,
+            ),
+            Escalation::Ssh => format!(
+                "node '{}': can't log in as '{}' over ssh, or '{}' isn't root, which deploy_as_root needs",
+                self.name, self.root_user, self.root_user,
+            ),
+        }
+    }
+}
+
+/// Nodes deployed to at the same time, one group of an environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wave {
+    pub nodes: Vec<DeployNode>,
+    /// Most nodes of the wave deployed to at once.
+    pub max_parallel: usize,
+}
+
+/// Where a step runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// The machine running the pipeline.
+    Local,
+    Node(DeployNode),
+    /// A node, as root through its escalation.
+    Root(DeployNode),
+}
+
+impl Target {
+    pub fn node(&self) -> Option<&DeployNode> {
+        match self {
+            Self::Local => None,
+            Self::Node(node) | Self::Root(node) => Some(node),
+        }
+    }
+}
+
+/// Picks the executor for a step's target.
+pub type Connect = Arc<dyn Fn(&Target) -> Arc<dyn Executor> + Send + Sync>;
+
+/// Run local steps and steps on local nodes here, and the rest over ssh.
+pub fn connect(target: &Target) -> Arc<dyn Executor> {
+    match target {
+        Target::Local => Arc::new(LocalExecutor),
+        Target::Node(node) => connect_as(node, &node.user),
+        Target::Root(node) => match node.escalation {
+            Escalation::Sudo => Arc::new(SudoExecutor::new(connect_as(node, &node.user))),
+            Escalation::Ssh => Arc::new(SshExecutor::new(&node.root_user, &node.host_name, node.port)),
+        },
+    }
+}
+
+fn connect_as(node: &DeployNode, user: &str) -> Arc<dyn Executor> {
+    if node.is_local() {
+        Arc::new(LocalExecutor)
+    } else {
+        Arc::new(SshExecutor::new(user, &node.host_name, node.port))
+    }
+}
+
+/// What a step does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Build the service on a CI node, see [`ci`].
+    Build,
+    /// Ship the builds a deployment shipped instead of building, see [`store`].
+    Stored(u64),
+    /// Run one of the service's commands.
+    Command(Command),
+    /// Find out the step's node's architecture, see [`arch`].
+    DetectArch,
+    /// Check the step's node lets the deploy run as root.
+    CheckRoot,
+    /// Sync the build's artifacts to the step's node, see [`sync`].
+    Sync,
+    /// Switch the step's node to the plan's release, see [`releases`].
+    Activate,
+    /// Install and restart the service's unit on the step's node, see [`systemd`].
+    Restart,
+    /// Wait for the service to pass its health checks on the step's node, see [`health`].
+    Health,
+}
+
+/// A command from the service's `commands`, ready to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    /// How the command's step is shown, e.g. `test: unit tests`.
+    pub name: String,
+    pub script: String,
+    pub timeout: Duration,
+    /// Whether the deploy carries on when the command fails.
+    pub allow_failure: bool,
+}
+
+/// One part of a deploy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub name: String,
+    pub target: Target,
+    pub action: Action,
+}
+
+/// Everything needed to run create → build → deploy for one service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeployPlan {
+    pub service: String,
+    pub environment: String,
+    pub create_workspace: String,
+    pub build_workspace: String,
+    pub deploy_workspace: String,
+    /// Globs picking the artifacts out of `build_workspace`, all files when empty.
+    pub artifacts: Vec<String>,
+    /// Globs of files in `deploy_workspace` a sync leaves alone.
+    pub protect: Vec<String>,
+    pub deploy_as_root: bool,
+    pub unit: Option<UnitConfig>,
+    pub health: Option<HealthConfig>,
+    /// Nodes the build may run on, in order of preference. Built on this
+    /// machine when empty.
+    pub ci_nodes: Vec<DeployNode>,
+    /// Builds running on one CI node at the same time.
+    pub max_builds: usize,
+    /// Releases kept on each node, 0 when the service is synced in place.
+    pub keep_releases: usize,
+    pub commands: CommandsConfig,
+    /// Groups of nodes deployed to one after the other.
+    pub waves: Vec<Wave>,
+    /// Commit being deployed, when it is known up front.
+    pub revision: Option<String>,
+    /// Release the nodes are switched to, named once the deployment is recorded.
+    pub release: Option<String>,
+    /// The deployment this plan rolls back. A rollback only switches the
+    /// nodes back to `release`, restarts and checks them.
+    pub rollback_of: Option<u64>,
+    /// The deployment whose stored builds are shipped instead of building,
+    /// or, for a rollback, whose release the nodes go back to.
+    pub build_of: Option<u64>,
+    /// Set along with `build_of` when the build is promoted from another environment.
+    pub promoted_from: Option<u64>,
+    pub step_timeout: Duration,
+}
+
+impl DeployPlan {
+    /// Look up `service` and the nodes it deploys to in `environment`.
+    pub fn resolve(config: &AppConfig, service: &str, environment: &str) -> Result<Self, DeployError> {
+        let service_cfg = config
+            .services
+            .get(service)
+            .ok_or_else(|| DeployError::UnknownService(service.to_string()))?;
+        let env_cfgs = service_cfg.environments.get(environment).ok_or_else(|| {
+            DeployError::UnknownEnvironment {
+                service: service.to_string(),
+                environment: environment.to_string(),
+            }
+        })?;
+
+        let mut waves: Vec<Wave> = Vec::new();
+        let mut seen: Vec<&String> = Vec::new();
+        for env_cfg in env_cfgs {
+            let mut wave = Wave { nodes: Vec::new(), max_parallel: env_cfg.max_parallel.max(1) };
+            for node_name in &env_cfg.nodes {
+                if seen.contains(&node_name) {
+                    continue;
+                }
+                seen.push(node_name);
+                wave.nodes.push(node(config, service, node_name)?);
+            }
+            if !wave.nodes.is_empty() {
+                waves.push(wave);
+            }
+        }
+
+        Ok(Self {
+            service: service.to_string(),
+            environment: environment.to_string(),
+            create_workspace: service_cfg.create_workspace.clone(),
+            build_workspace: service_cfg.build_workspace.clone(),
+            deploy_workspace: service_cfg.deploy_workspace.clone(),
+            artifacts: service_cfg.artifacts.clone(),
+            protect: service_cfg.protect.clone(),
+            deploy_as_root: service_cfg.deploy_as_root,
+            unit: service_cfg.unit.clone(),
+            health: service_cfg.health.clone(),
+            ci_nodes: config.ci.nodes.iter().map(|name| node(config, service, name)).collect::<Result<_, _>>()?,
+            max_builds: config.ci.max_builds.max(1),
+            keep_releases: service_cfg.keep_releases,
+            commands: service_cfg.commands.clone(),
+            waves,
+            revision: None,
+            release: None,
+            rollback_of: None,
+            build_of: None,
+            promoted_from: None,
+            step_timeout: STEP_TIMEOUT,
+        })
+    }
+
+    /// Every node of the plan, wave by wave.
+    pub fn nodes(&self) -> impl Iterator<Item = &DeployNode> {
+        self.waves.iter().flat_map(|wave| wave.nodes.iter())
+    }
+
+    /// The steps run before any node is deployed to, in order.
+    pub fn prepare_steps(&self) -> Vec<Step> {
+        let mut steps = Vec::new();
+        if self.deploy_as_root {
+            // before the build, so a node that can't do it doesn't cost a build first
+            steps.extend(self.nodes().map(|node| Step {
+                name: format!("check root {}", node.name),
+                target: self.node_target(node),
+                action: Action::CheckRoot,
+            }));
+        }
+        if self.rollback_of.is_none() {
+            steps.extend(self.nodes().filter(|node| node.arch.is_none()).map(|node| Step {
+                name: format!("arch {}", node.name),
+                target: Target::Node(node.clone()),
+                action: Action::DetectArch,
+            }));
+            steps.push(match self.build_of {
+                Some(deployment_id) => Step {
+                    name: format!("build of #{deployment_id}"),
+                    target: Target::Local,
+                    action: Action::Stored(deployment_id),
+                },
+                None => Step {
+                    name: format!("build {}", self.service),
+                    target: Target::Local,
+                    action: Action::Build,
+                },
+            });
+        }
+        steps
+    }
+
+    /// The steps that deploy to `node`, in order.
+    pub fn node_steps(&self, node: &DeployNode) -> Vec<Step> {
+        let mut steps = Vec::new();
+        if self.rollback_of.is_none() {
+            steps.push(Step {
+                name: format!("sync {}", node.name),
+                target: self.node_target(node),
+                action: Action::Sync,
+            });
+            steps.extend(self.commands.deploy.iter().map(|command| {
+                let command = self.command("deploy", command, &format!("deploy {}", node.name), node.arch);
+                Step { name: command.name.clone(), target: self.node_target(node), action: Action::Command(command) }
+            }));
+        }
+        if self.keep_releases > 0 {
+            steps.push(Step {
+                name: format!("activate {}", node.name),
+                target: self.node_target(node),
+                action: Action::Activate,
+            });
+        }
+        if self.unit.is_some() {
+            steps.push(Step {
+                name: format!("restart {}", node.name),
+                target: self.node_target(node),
+                action: Action::Restart,
+            });
+        }
+        if self.health.is_some() {
+            steps.push(Step {
+                name: health_step(&node.name),
+                target: self.node_target(node),
+                action: Action::Health,
+            });
+        }
+        steps
+    }
+
+    fn node_target(&self, node: &DeployNode) -> Target {
+        if self.deploy_as_root {
+            Target::Root(node.clone())
+        } else {
+            Target::Node(node.clone())
+        }
+    }
+
+    /// The systemd unit the service runs as, if it has one.
+    pub fn unit(&self) -> Option<systemd::Unit> {
+        let config = self.unit.as_ref()?;
+        Some(if self.keep_releases > 0 {
+            // every release is a fresh directory, so the running program is never overwritten
+            let current = releases::current_dir(&self.deploy_workspace);
+            systemd::Unit::new(&self.service, config, &current, self.scope(), false)
+        } else {
+            systemd::Unit::new(&self.service, config, &self.deploy_workspace, self.scope(), true)
+        })
+    }
+
+    /// Where a sync puts the artifacts on a node.
+    pub fn sync_dir(&self) -> String {
+        match &self.release {
+            Some(release) if self.keep_releases > 0 => releases::release_dir(&self.deploy_workspace, release),
+            _ => self.deploy_workspace.clone(),
+        }
+    }
+
+    /// A plan switching the nodes back to the release of `target`, rolling back `deployment_id`.
+    pub fn rollback(&self, deployment_id: u64, target: &model::Deployment) -> Self {
+        Self {
+            revision: target.revision().map(str::to_string),
+            release: target.release().map(str::to_string),
+            rollback_of: Some(deployment_id),
+            build_of: Some(target.id()),
+            promoted_from: None,
+            ..self.clone()
+        }
+    }
+
+    /// Look up `service` in the environment builds running in `environment`
+    /// are promoted to.
+    pub fn resolve_promotion(config: &AppConfig, service: &str, environment: &str) -> Result<Self, DeployError> {
+        let next = config
+            .environments
+            .get(environment)
+            .and_then(|env| env.promote_to.as_deref())
+            .ok_or_else(|| DeployError::NoPromotion(environment.to_string()))?;
+        Self::resolve(config, service, next)
+    }
+
+    /// A plan promoting the builds `source` shipped to the plan's environment.
+    pub fn promote(&self, source: &model::Deployment) -> Self {
+        Self { promoted_from: Some(source.id()), ..self.redeploy(source) }
+    }
+
+    /// A plan shipping the builds `source` shipped, to the plan's environment.
+    pub fn redeploy(&self, source: &model::Deployment) -> Self {
+        Self {
+            revision: source.revision().map(str::to_string),
+            build_of: Some(source.id()),
+            ..self.clone()
+        }
+    }
+
+    /// The service's health checks, resolved for `node`.
+    pub fn probes(&self, node: &DeployNode) -> Vec<health::Probe> {
+        let unit = self.unit().map(|unit| unit.name);
+        let checks = self.health.iter().flat_map(|health| health.checks.iter());
+        checks.map(|check| health::Probe::new(check, &node.host_name, unit.as_deref(), self.scope())).collect()
+    }
+
+    fn scope(&self) -> systemd::Scope {
+        if self.deploy_as_root { systemd::Scope::System } else { systemd::Scope::User }
+    }
+
+    /// Globs of files a sync leaves alone: the service's `protect` and the
+    /// copy of the program its unit runs.
+    pub fn sync_protect(&self) -> Vec<String> {
+        let mut protect = self.protect.clone();
+        protect.extend(self.unit().map(|unit| unit.protected()).unwrap_or_default());
+        protect
+    }
+
+    /// The commands a build for `arch` runs on its CI node: the service's
+    /// build commands, or its `build.sh` when it has none, then its test and
+    /// package commands. Their names end in the architecture when it is known.
+    pub fn ci_commands(&self, arch: Option<Arch>) -> Vec<Command> {
+        let mut commands = Vec::new();
+        if self.commands.build.is_empty() {
+            commands.push(Command {
+                name: format!("build: {BUILD_SCRIPT}"),
+                script: self.build_script(arch),
+                timeout: self.step_timeout,
+                allow_failure: false,
+            });
+        }
+        for (stage, steps) in self.commands.stages().into_iter().filter(|(stage, _)| *stage != "deploy") {
+            commands.extend(steps.iter().map(|step| self.command(stage, step, stage, arch)));
+        }
+        if let Some(arch) = arch {
+            for command in &mut commands {
+                command.name = format!("{} ({arch})", command.name);
+            }
+        }
+        commands
+    }
+
+    /// Where the build for `arch` goes, see [`arch::build_dir`].
+    pub fn build_dir(&self, arch: Option<Arch>) -> String {
+        arch::build_dir(&self.build_workspace, arch)
+    }
+
+    /// Resolve a command of `stage` for `arch`, shown as `<label>: <name>`.
+    fn command(&self, stage: &str, step: &CommandStep, label: &str, arch: Option<Arch>) -> Command {
+        let build_dir = self.build_dir(arch);
+        let workspace = match stage {
+            "build" | "test" => self.create_workspace.clone(),
+            "package" => build_dir.clone(),
+            _ => self.sync_dir(),
+        };
+        let mut script = String::from("set -eu\n");
+        if stage != "deploy" {
+            script.push_str(&format!("mkdir -p {}\n", shell_path(&build_dir)));
+        }
+        script.push_str(&format!("mkdir -p {0}\ncd {0}\n", shell_path(&workspace)));
+        if let Some(dir) = &step.working_dir {
+            script.push_str(&format!("cd {}\n", shell_path(dir)));
+        }
+        for (name, value) in [
+            ("SERVICE", shell_quote(&self.service)),
+            ("ENVIRONMENT", shell_quote(&self.environment)),
+            ("CREATE_WORKSPACE", shell_path(&self.create_workspace)),
+            ("BUILD_WORKSPACE", shell_path(&build_dir)),
+            ("DEPLOY_WORKSPACE", shell_path(&self.deploy_workspace)),
+        ] {
+            script.push_str(&format!("export {name}={value}\n"));
+        }
+        for (name, value) in arch.map(arch::build_env).unwrap_or_default() {
+            script.push_str(&format!("export {name}={value}\n"));
+        }
+        for (name, value) in &step.env {
+            script.push_str(&format!("export {name}={}\n", shell_quote(value)));
+        }
+        // the command runs as written, not under the strict options of the setup above
+        script.push_str(&format!("set +eu\n{}\n", step.command.trim_end()));
+        Command {
+            name: format!("{label}: {}", step.display_name()),
+            script,
+            timeout: step.timeout.map_or(self.step_timeout, Duration::from_secs),
+            allow_failure: step.allow_failure,
+        }
+    }
+
+    /// Check the create workspace and run its build script for `arch`,
+    /// stopping at the first failure.
+    pub(crate) fn build_script(&self, arch: Option<Arch>) -> String {
+        let create = shell_path(&self.create_workspace);
+        let build = shell_path(&self.build_dir(arch));
+        let mut script = String::from("set -eu\n");
+        script.push_str(&format!(
+            "if [ ! -d {create} ]; then echo {} >&2; exit 1; fi\n",
+            shell_quote(&format!("create_workspace {} does not exist", self.create_workspace)),
+        ));
+        script.push_str(&format!("mkdir -p {build}\n"));
+        script.push_str(&format!("cd {create}\n"));
+        script.push_str(&format!(
+            "if [ ! -x ./{BUILD_SCRIPT} ]; then echo {} >&2; exit 1; fi\n",
+            shell_quote(&format!("{} has no executable {BUILD_SCRIPT}", self.create_workspace)),
+        ));
+        let cross: String = arch
+            .map(arch::build_env)
+            .unwrap_or_default()
+            .iter()
+            .map(|(name, value)| format!("{name}={value} "))
+            .collect();
+        script.push_str(&format!(
+            "SERVICE={} ENVIRONMENT={} BUILD_WORKSPACE={build} {cross}./{BUILD_SCRIPT}\n",
+            shell_quote(&self.service),
+            shell_quote(&self.environment),
+        ));
+        script
+    }
+}
+
+fn node(config: &AppConfig, service: &str, name: &str) -> Result<DeployNode, DeployError> {
+    let node_cfg = config.nodes.get(name).ok_or_else(|| DeployError::UnknownNode {
+        service: service.to_string(),
+        node: name.to_string(),
+    })?;
+    Ok(DeployNode::new(name, node_cfg))
+}
+
+/// Quote a workspace path for `sh`, leaving a leading `~/` outside the quotes
+/// so the shell still expands it to the home directory.
+pub(crate) fn shell_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("~/{}", shell_quote(rest)),
+        None => shell_quote(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_config() -> AppConfig {
+        toml::from_str::<AppConfig>(include_str!("../../../config/example.toml"))
+            .unwrap_or_else(|e| panic!("failed to parse example config: {e}"))
+    }
+
+    /// Every step of the plan, wave by wave.
+    fn all_steps(plan: &DeployPlan) -> Vec<Step> {
+        let mut steps = plan.prepare_steps();
+        for node in plan.nodes() {
+            steps.extend(plan.node_steps(node));
+        }
+        steps
+    }
+
+    #[test]
+    fn resolves_service_nodes_for_environment() {
+        let config = example_config();
+        let plan = DeployPlan::resolve(&config, "example_service_1", "staging").expect("plan");
+        assert_eq!(plan.create_workspace, "~/create/example_service_1");
+        let nodes: Vec<&DeployNode> = plan.nodes().collect();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].name, "pi1");
+        assert!(!nodes[0].is_local());
+    }
+
+    #[test]
+    fn groups_of_an_environment_become_waves() {
+        let config = example_config();
+        let plan = DeployPlan::resolve(&config, "example_service_1", "production").expect("plan");
+        let waves: Vec<(Vec<&str>, usize)> = plan
+            .waves
+            .iter()
+            .map(|wave| (wave.nodes.iter().map(|node| node.name.as_str()).collect(), wave.max_parallel))
+            .collect();
+        assert_eq!(waves, [(vec!["pi2"], 4), (vec!["pi3", "pi4"], 2)]);
+    }
+
+    #[test]
+    fn rejects_unknown_service_and_environment() {
+        let config = example_config();
+        assert_eq!(
+            DeployPlan::resolve(&config, "nope", "staging"),
+            Err(DeployError::UnknownService("nope".to_string())),
+        );
+        assert_eq!(
+            DeployPlan::resolve(&config, "example_service_1", "qa"),
+            Err(DeployError::UnknownEnvironment {
+                service: "example_service_1".to_string(),
+                environment: "qa".to_string(),
+            }),
+        );
+    }
+
+    #[test]
+    fn steps_run_in_order() {
+        let config = example_config();
+        let plan = DeployPlan::resolve(&config, "example_service_1", "development").expect("plan");
+        let steps = all_steps(&plan);
+        let names: Vec<&str> = steps.iter().map(|step| step.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["arch local", "build example_service_1", "sync local", "activate local", "restart local", "health local"],
+        );
+        assert_eq!(steps[0].action, Action::DetectArch);
+        assert_eq!(steps[1].action, Action::Build);
+        assert!(plan.build_script(None).contains("./build.sh\n"));
+        let ci: Vec<&str> = plan.ci_nodes.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!((ci, plan.max_builds), (vec!["local", "pi2"], 1));
+        let local = &plan.waves[0].nodes[0];
+        assert_eq!(steps[2].target, Target::Node(local.clone()));
+        assert_eq!(steps[2].action, Action::Sync);
+        assert_eq!(steps[3].action, Action::Activate);
+        assert_eq!(steps[4].action, Action::Restart);
+        assert_eq!(steps[5].action, Action::Health);
+        assert_eq!(plan.probes(local)[3], health::Probe::Script {
+            name: "example_service_1.service active".to_string(),
+            script: "systemctl --user is-active 'example_service_1.service'\n".to_string(),
+        });
+        assert_eq!(plan.protect, ["data/**"]);
+        assert_eq!(plan.unit().map(|unit| unit.name), Some("example_service_1.service".to_string()));
+        assert!(plan.unit().unwrap().text.contains("ExecStart=%h/deploy/example_service_1/current/app --port 8080\n"));
+    }
+
+    #[test]
+    fn rollbacks_only_switch_restart_and_check() {
+        let config = example_config();
+        let mut plan = DeployPlan::resolve(&config, "example_service_1", "development").expect("plan");
+        plan.release = Some("r2".to_string());
+        assert_eq!(plan.sync_dir(), "~/deploy/example_service_1/releases/r2");
+        plan.rollback_of = Some(7);
+        let steps = all_steps(&plan);
+        let names: Vec<&str> = steps.iter().map(|step| step.name.as_str()).collect();
+        assert_eq!(names, ["activate local", "restart local", "health local"]);
+    }
+
+    #[test]
+    fn syncs_in_place_leave_the_running_copy_alone() {
+        let config = example_config();
+        let mut plan = DeployPlan::resolve(&config, "example_service_1", "development").expect("plan");
+        let dir = std::env::temp_dir().join(format!("deploy-in-place-{}", std::process::id()));
+        let (build, deploy) = (dir.join("build"), dir.join("deploy"));
+        std::fs::create_dir_all(&build).expect("build dir");
+        plan.keep_releases = 0;
+        plan.deploy_workspace = deploy.display().to_string();
+        assert_eq!(plan.sync_protect(), ["data/**", "app.new", "app.new.tmp"]);
+
+        let options = exec::RunOptions::default();
+        let sync = |version: &str| {
+            std::fs::write(build.join("app"), version).expect("artifact");
+            let manifest = sync::Manifest::collect(&build, &[]).expect("manifest");
+            sync::sync_node(&LocalExecutor, &manifest, &plan.sync_dir(), &plan.sync_protect(), &options, &mut |_, _| {})
+                .expect("sync")
+        };
+        sync("v1");
+        // what the unit's install script leaves next to the artifacts
+        std::fs::write(deploy.join("app.new"), "v1").expect("copy");
+        std::fs::write(deploy.join("app.new.tmp"), "v1").expect("copy");
+        let report = sync("v2");
+        assert!(report.deleted.is_empty());
+        assert_eq!(std::fs::read_to_string(deploy.join("app")).expect("app"), "v2");
+        assert_eq!(std::fs::read_to_string(deploy.join("app.new")).expect("copy"), "v1");
+        assert!(deploy.join("app.new.tmp").exists());
+        std::fs::remove_dir_all(dir).expect("cleanup");
+    }
+
+    #[test]
+    fn service_commands_run_in_their_stage_workspace() {
+        let config = example_config();
+        let plan = DeployPlan::resolve(&config, "example_service_2", "staging").expect("plan");
+        let commands = plan.ci_commands(None);
+        let names: Vec<&str> = commands.iter().map(|command| command.name.as_str()).collect();
+        assert_eq!(names, ["build: cargo build --release", "test: unit tests", "test: lint", "package: copy binary"]);
+        assert_eq!(commands[0].timeout, Duration::from_secs(900));
+        assert!(commands[0].script.starts_with(
+            "set -eu\nmkdir -p ~/'build/example_service_2'\nmkdir -p ~/'create/example_service_2'\ncd ~/'create/example_service_2'\ncd '.'\n"
+        ));
+        assert!(commands[0].script.ends_with("export CARGO_TERM_COLOR='never'\nset +eu\ncargo build --release\n"));
+        assert!(commands[2].allow_failure);
+        assert!(commands[3].script.contains("cd ~/'build/example_service_2'\n"));
+
+        let names: Vec<String> = all_steps(&plan).into_iter().map(|step| step.name).collect();
+        assert_eq!(names, ["arch pi3", "build example_service_2", "sync pi3", "deploy pi3: check binary"]);
+        let fallback = DeployPlan::resolve(&config, "example_service_1", "staging").expect("plan");
+        assert_eq!(fallback.ci_commands(None)[0].name, "build: build.sh");
+    }
+
+    #[test]
+    fn builds_for_an_architecture_go_to_its_own_directory() {
+        let config = example_config();
+        let plan = DeployPlan::resolve(&config, "example_service_1", "staging").expect("plan");
+        // pi1 declares its arch, so there is nothing to detect
+        assert_eq!(all_steps(&plan)[0].name, "build example_service_1");
+        assert_eq!(plan.waves[0].nodes[0].arch, Some(Arch::Arm64));
+        let build = &plan.ci_commands(Some(Arch::Arm64))[0];
+        assert_eq!(build.name, "build: build.sh (arm64)");
+        assert!(build.script.contains("mkdir -p ~/'build/example_service_1/arm64'\n"));
+        assert!(build.script.contains(
+            "BUILD_WORKSPACE=~/'build/example_service_1/arm64' ARCH=arm64 GOOS=linux GOARCH=arm64 \
+             RUST_TARGET=aarch64-unknown-linux-gnu CARGO_BUILD_TARGET=aarch64-unknown-linux-gnu ./build.sh\n"
+        ));
+
+        let plan = DeployPlan::resolve(&config, "example_service_2", "staging").expect("plan");
+        let package = &plan.ci_commands(Some(Arch::Armv7))[3];
+        assert!(package.script.contains("cd ~/'build/example_service_2/armv7'\n"));
+        assert!(package.script.contains("export GOARCH=arm\nexport GOARM=7\n"));
+    }
+
+    #[test]
+    fn remote_nodes_are_synced_over_ssh() {
+        let config = example_config();
+        let plan = DeployPlan::resolve(&config, "example_service_1", "staging").expect("plan");
+        let steps = all_steps(&plan);
+        assert_eq!(connect(&steps[0].target).target(), "local");
+        assert_eq!(connect(&steps[1].target).target(), "pi@192.168.1.34:22");
+    }
+
+    #[test]
+    fn root_deploys_check_nodes_first_and_escalate() {
+        let config = example_config();
+        let mut plan = DeployPlan::resolve(&config, "example_service_1", "staging").expect("plan");
+        plan.deploy_as_root = true;
+        let steps = all_steps(&plan);
+        let names: Vec<&str> = steps.iter().map(|step| step.name.as_str()).collect();
+        assert_eq!(names, ["check root pi1", "build example_service_1", "sync pi1", "activate pi1", "restart pi1", "health pi1"]);
+        assert_eq!(steps[0].action, Action::CheckRoot);
+        assert_eq!(connect(&steps[2].target).target(), "pi@192.168.1.34:22 (sudo)");
+        assert!(plan.waves[0].nodes[0].root_check_failure().contains("user 'pi' can't run sudo without a password"));
+
+        let mut plan = DeployPlan::resolve(&config, "example_service_1", "production").expect("plan");
+        plan.deploy_as_root = true;
+        let sync = all_steps(&plan).into_iter().find(|step| step.name == "sync pi2").expect("sync step");
+        assert_eq!(connect(&sync.target).target(), "root@192.168.1.53:22");
+        assert_eq!(plan.unit().map(|unit| unit.scope), Some(systemd::Scope::System));
+    }
+
+    #[test]
+    fn quotes_single_quotes() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_path("~/a b"), "~/'a b'");
+    }
+}
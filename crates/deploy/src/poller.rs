@@ -0,0 +1,441 @@
+//! Watches the configured repos for new commits and runs the pipeline of
+//! every service they touch.
+//!
+//! Each repo is pulled as often as the most eager service built from it asks
+//! for. When its branch moves past the revision recorded in SQLite, the
+//! services owning the changed files are built once and deployed to their
+//! `auto_deploy` environments, one after the other, stopping at the first
+//! failure. A repo seen for the first time only records where it is.
+//!
+//! A push reported through [`notify_push`] checks its repo straight away,
+//! whether or not the repo is polled.
+
+use crate::{DeployEvent, DeployHub, DeployPlan};
+use config::AppConfig;
+use model::{DeploymentStatus, ModelError, SqliteRevisionModel};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::sync::{OnceLock, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use vcs::{Repository, VcsError};
+
+/// How soon to look again at a repo whose services are still deploying.
+const BUSY_RECHECK: Duration = Duration::from_secs(1);
+
+static PUSHES: OnceLock<mpsc::Sender<Push>> = OnceLock::new();
+
+/// A push to a repo, reported by its host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Push {
+    pub repo: String,
+    pub branch: Option<String>,
+    pub revision: Option<String>,
+}
+
+/// Reasons a push could not be handed to the poller.
+#[derive(Debug, PartialEq, Eq)]
+pub enum NotifyError {
+    NotStarted,
+    Stopped,
+}
+
+impl Display for NotifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotStarted => write!(f, "the repo poller is not running"),
+            Self::Stopped => write!(f, "the repo poller has stopped"),
+        }
+    }
+}
+
+impl std::error::Error for NotifyError {}
+
+/// Have the poller check the pushed repo now instead of at its next poll.
+pub fn notify_push(push: Push) -> Result<(), NotifyError> {
+    let sender = PUSHES.get().ok_or(NotifyError::NotStarted)?;
+    sender.send(push).map_err(|_| NotifyError::Stopped)
+}
+
+/// A build and deploy of one service at one revision.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineRun {
+    pub repo: String,
+    pub service: String,
+    pub revision: String,
+    /// Deployed in order, each only if the one before succeeded.
+    pub environments: Vec<String>,
+}
+
+impl PipelineRun {
+    fn initiated_by(&self) -> String {
+        format!("poller:{}", self.repo)
+    }
+}
+
+/// Reasons a repo could not be checked for new commits.
+#[derive(Debug)]
+pub enum PollError {
+    Vcs(VcsError),
+    Record(ModelError),
+}
+
+impl Display for PollError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Vcs(err) => write!(f, "{err}"),
+            Self::Record(err) => write!(f, "unable to record last seen revision: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for PollError {}
+
+impl From<VcsError> for PollError {
+    fn from(value: VcsError) -> Self {
+        Self::Vcs(value)
+    }
+}
+
+impl From<ModelError> for PollError {
+    fn from(value: ModelError) -> Self {
+        Self::Record(value)
+    }
+}
+
+struct WatchedRepo {
+    repo: Box<dyn Repository>,
+    /// `None` when no service built from the repo polls, only pushes check it.
+    interval: Option<Duration>,
+    next_check: Option<Instant>,
+}
+
+/// Start watching every repo a service is built from.
+///
+/// Returns without starting a thread when there are none.
+pub fn start(config: &'static AppConfig, hub: &'static DeployHub) -> io::Result<()> {
+    let mut watched = Vec::new();
+    for (name, repo_cfg) in &config.repos {
+        if services_of(config, name).next().is_none() {
+            continue;
+        }
+        let interval = services_of(config, name)
+            .map(|(_, service)| service.poll_interval)
+            .filter(|interval| *interval > 0)
+            .min()
+            .map(Duration::from_secs);
+        match vcs::open(name, repo_cfg) {
+            Ok(repo) => watched.push(WatchedRepo {
+                repo,
+                interval,
+                next_check: interval.map(|_| Instant::now()),
+            }),
+            Err(err) => eprintln!("error, when opening repo {name} for polling. Error: {}", err),
+        }
+    }
+    if watched.is_empty() {
+        return Ok(());
+    }
+    let (sender, pushes) = mpsc::channel();
+    if PUSHES.set(sender).is_err() {
+        return Err(io::Error::other("the repo poller is already running"));
+    }
+    thread::Builder::new()
+        .name("repo-poller".to_string())
+        .spawn(move || run_poller(config, hub, watched, pushes, SqliteRevisionModel::new()))?;
+    Ok(())
+}
+
+/// Services built from `repo`.
+fn services_of<'a>(config: &'a AppConfig, repo: &'a str) -> impl Iterator<Item = (&'a String, &'a config::ServiceConfig)> {
+    config.services.iter().filter(move |(_, service)| service.repo == repo)
+}
+
+/// Pull `repo` and work out the pipelines its new commits call for.
+///
+/// The pulled revision is recorded as seen, so each commit is acted on once.
+pub fn check_repo(config: &AppConfig, repo: &dyn Repository, revisions: &SqliteRevisionModel) -> Result<Vec<PipelineRun>, PollError> {
+    repo.ensure_cloned()?;
+    let head = repo.pull()?;
+    let branch = repo.branch()?;
+    let last_seen = revisions.last_seen(repo.name(), &branch)?;
+
+    let affected: Vec<&str> = match last_seen {
+        None => Vec::new(),
+        Some(last) if last == head.id => Vec::new(),
+        Some(last) => match repo.changed_files(&last, &head.id) {
+            Ok(files) => vcs::affected_services(config, repo.name(), &files),
+            Err(err) => {
+                // e.g. the last seen commit was force pushed away, rebuilding everything is the safe choice
+                eprintln!("error, when listing changes to repo {} since {last}. Error: {}", repo.name(), err);
+                services_of(config, repo.name()).map(|(name, _)| name.as_str()).collect()
+            }
+        },
+    };
+    let runs = services_of(config, repo.name())
+        .filter(|(name, service)| affected.contains(&name.as_str()) && !service.auto_deploy.is_empty())
+        .map(|(name, service)| PipelineRun {
+            repo: repo.name().to_string(),
+            service: name.clone(),
+            revision: head.id.clone(),
+            environments: service.auto_deploy.clone(),
+        })
+        .collect();
+
+    revisions.record_seen(repo.name(), &branch, &head.id)?;
+    Ok(runs)
+}
+
+fn run_poller(
+    config: &'static AppConfig,
+    hub: &'static DeployHub,
+    mut watched: Vec<WatchedRepo>,
+    pushes: mpsc::Receiver<Push>,
+    revisions: SqliteRevisionModel,
+) {
+    let mut active: HashMap<String, JoinHandle<Option<DeploymentStatus>>> = HashMap::new();
+    // a newer commit replaces a run still waiting for its turn
+    let mut pending: BTreeMap<String, PipelineRun> = BTreeMap::new();
+
+    loop {
+        active.retain(|_, handle| !handle.is_finished());
+        let now = Instant::now();
+        for watched in watched.iter_mut().filter(|w| w.next_check.is_some_and(|next| next <= now)) {
+            // pulling would move the checkout out from under a build in progress
+            let busy = services_of(config, watched.repo.name())
+                .any(|(name, _)| active.contains_key(name) || pending.contains_key(name) || hub.is_running(name));
+            if busy {
+                watched.next_check = Some(now + BUSY_RECHECK);
+                continue;
+            }
+            watched.next_check = watched.interval.map(|interval| now + interval);
+            match check_repo(config, watched.repo.as_ref(), &revisions) {
+                Ok(runs) => {
+                    for run in runs {
+                        pending.insert(run.service.clone(), run);
+                    }
+                }
+                Err(err) => eprintln!("error, when polling repo {}. Error: {}", watched.repo.name(), err),
+            }
+        }
+
+        let ready: Vec<String> = pending
+            .keys()
+            .filter(|service| !active.contains_key(*service) && !hub.is_running(service))
+            .cloned()
+            .collect();
+        for service in ready {
+            let Some(run) = pending.remove(&service) else {
+                continue;
+            };
+            let spawned = thread::Builder::new()
+                .name(format!("pipeline-{service}"))
+                .spawn(move || run_pipeline(config, hub, &run));
+            match spawned {
+                Ok(handle) => {
+                    active.insert(service, handle);
+                }
+                Err(err) => eprintln!("error, when starting pipeline for service {service}. Error: {}", err),
+            }
+        }
+
+        let next_check = watched.iter().filter_map(|w| w.next_check).min();
+        let mut wait = next_check.map(|next| next.saturating_duration_since(Instant::now()));
+        if !active.is_empty() || !pending.is_empty() {
+            wait = Some(wait.map_or(BUSY_RECHECK, |wait| wait.min(BUSY_RECHECK)));
+        }
+        let push = match wait {
+            Some(wait) => pushes.recv_timeout(wait).ok(),
+            None => pushes.recv().ok(),
+        };
+        for push in push.into_iter().chain(pushes.try_iter()) {
+            match watched.iter_mut().find(|w| w.repo.name() == push.repo) {
+                Some(watched) => {
+                    let branch = push.branch.as_deref().unwrap_or("unknown branch");
+                    let revision = push.revision.as_deref().unwrap_or("unknown revision");
+                    println!("push to repo {} ({branch} at {revision}), checking it now", push.repo);
+                    watched.next_check = Some(Instant::now());
+                }
+                None => eprintln!("error, push to repo {} which no service is built from", push.repo),
+            }
+        }
+    }
+}
+
+/// Deploy `run` to each of its environments, stopping at the first that fails.
+///
+/// The build of the first environment is shipped to the others, unless one
+/// has nodes of an architecture it wasn't built for.
+pub fn run_pipeline(config: &AppConfig, hub: &DeployHub, run: &PipelineRun) -> Option<DeploymentStatus> {
+    let initiated_by = run.initiated_by();
+    let mut status = None;
+    let mut built = None;
+    for environment in &run.environments {
+        let mut plan = match DeployPlan::resolve(config, &run.service, environment) {
+            Ok(plan) => plan,
+            Err(err) => {
+                eprintln!("error, when planning deploy of {} to {environment}. Error: {}", run.service, err);
+                return Some(DeploymentStatus::Failed);
+            }
+        };
+        plan.revision = Some(run.revision.clone());
+
+        let (sender, receiver) = mpsc::channel();
+        let subscription = hub.subscribe(&run.service, Box::new(move |event| sender.send(event.clone()).is_ok()));
+        let stored = built.filter(|deployment_id| match hub.has_builds_for(&plan, *deployment_id) {
+            Ok(stored) => stored,
+            Err(err) => {
+                eprintln!("error, when looking up the builds of deployment #{deployment_id}. Error: {}", err);
+                false
+            }
+        });
+        let started = match stored {
+            Some(deployment_id) => hub.redeploy(&plan, deployment_id, &initiated_by),
+            None => hub.start(&plan, &initiated_by).inspect(|deployment_id| built = Some(*deployment_id)),
+        };
+        status = match started {
+            Ok(deployment_id) => Some(wait_for_finish(hub, &receiver, deployment_id)),
+            Err(err) => {
+                eprintln!("error, when starting deploy of {} to {environment}. Error: {}", run.service, err);
+                Some(DeploymentStatus::Failed)
+            }
+        };
+        hub.unsubscribe(subscription);
+        if status != Some(DeploymentStatus::Succeeded) {
+            break;
+        }
+    }
+    status
+}
+
+/// Wait for deployment `deployment_id` to finish, counting it as failed if
+/// the hub stops running it without saying how it ended.
+fn wait_for_finish(hub: &DeployHub, receiver: &mpsc::Receiver<DeployEvent>, deployment_id: u64) -> DeploymentStatus {
+    loop {
+        // the subscription follows the whole service, skip events of any other deploy
+        match receiver.recv_timeout(BUSY_RECHECK) {
+            Ok(DeployEvent::Finished { deployment_id: id, status, .. }) if id == deployment_id => return status,
+            Ok(_) => {}
+            Err(mpsc::RecvTimeoutError::Timeout) if hub.is_deployment_running(deployment_id) => {}
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // Finished is sent before the deploy stops counting as running, so it's queued by now if at all
+                let finished = receiver.try_iter().find_map(|event| match event {
+                    DeployEvent::Finished { deployment_id: id, status, .. } if id == deployment_id => Some(status),
+                    _ => None,
+                });
+                return finished.unwrap_or_else(|| {
+                    eprintln!("error, deployment #{deployment_id} stopped without finishing");
+                    DeploymentStatus::Failed
+                });
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => return DeploymentStatus::Failed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+    use vcs::GitRepository;
+
+    fn revisions() -> SqliteRevisionModel {
+        let manager = SqliteConnectionManager::memory();
+        let pool = Pool::builder().max_size(1).build(manager).expect("pool");
+        pool.get()
+            .expect("conn")
+            .execute_batch(include_str!("../../db/migrations/004_create_repo_revisions.sql"))
+            .expect("create table");
+        SqliteRevisionModel::new_with_pool(pool)
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .expect("run git")
+            .status;
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    fn commit(seed: &Path, file: &str) {
+        let path = seed.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, file).unwrap();
+        git(seed, &["add", file]);
+        git(seed, &["commit", "--quiet", "-m", file]);
+        git(seed, &["push", "--quiet", "origin", "HEAD:main"]);
+    }
+
+    #[test]
+    fn waiting_gives_up_on_a_deploy_the_hub_no_longer_runs() {
+        let manager = SqliteConnectionManager::memory();
+        let hub = DeployHub::new(model::SqliteDeploymentModel::new_with_pool(Pool::new(manager).expect("pool")));
+        let finished = |deployment_id| DeployEvent::Finished {
+            deployment_id,
+            status: DeploymentStatus::Succeeded,
+            exit: "exit status: 0".to_string(),
+        };
+        let (sender, receiver) = mpsc::channel();
+        sender.send(finished(6)).unwrap();
+        sender.send(finished(7)).unwrap();
+        assert_eq!(wait_for_finish(&hub, &receiver, 7), DeploymentStatus::Succeeded);
+        // e.g. the deploy thread died before saying how it went
+        sender.send(finished(8)).unwrap();
+        assert_eq!(wait_for_finish(&hub, &receiver, 9), DeploymentStatus::Failed);
+    }
+
+    fn services(runs: &[PipelineRun]) -> Vec<&str> {
+        runs.iter().map(|run| run.service.as_str()).collect()
+    }
+
+    #[test]
+    fn new_commits_run_pipelines_of_affected_services() {
+        let root = std::env::temp_dir().join(format!("deploy-poller-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let origin = root.join("origin.git");
+        let seed = root.join("seed");
+        fs::create_dir_all(&seed).unwrap();
+        git(&root, &["init", "--quiet", "--bare", "-b", "main", origin.to_str().unwrap()]);
+        git(&root, &["clone", "--quiet", origin.to_str().unwrap(), seed.to_str().unwrap()]);
+        commit(&seed, "README.md");
+
+        let work = root.join("work");
+        let example = include_str!("../../../config/example.toml")
+            .replace("vcs = \"fossil\"", "vcs = \"git\"")
+            .replace("ssh://example.com//home/pi/museum/create.fossil", origin.to_str().unwrap())
+            .replace("\"~/create", &format!("\"{}", work.display()));
+        let config = config::check_config(&example).expect("example config").config;
+        let repo = GitRepository::new("create", origin.to_str().unwrap(), work);
+        let revisions = revisions();
+
+        // the first look only records where the branch is
+        assert!(check_repo(&config, &repo, &revisions).unwrap().is_empty());
+
+        commit(&seed, "example_service_1/build.sh");
+        let runs = check_repo(&config, &repo, &revisions).unwrap();
+        assert_eq!(services(&runs), ["example_service_1"]);
+        assert_eq!(runs[0].environments, ["development", "staging"]);
+        assert_eq!(runs[0].revision, repo.current().unwrap().id);
+        assert!(check_repo(&config, &repo, &revisions).unwrap().is_empty());
+
+        commit(&seed, "example_service_2/build.sh");
+        commit(&seed, "shared/lib.sh");
+        let runs = check_repo(&config, &repo, &revisions).unwrap();
+        assert_eq!(services(&runs), ["example_service_1", "example_service_2"]);
+        assert_eq!(runs[1].environments, ["development"]);
+
+        commit(&seed, "docs/notes.md");
+        assert!(check_repo(&config, &repo, &revisions).unwrap().is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+//! Keeping the last few releases of a service on its nodes.
+//!
+//! With `keep_releases` a deploy syncs into `releases/<release>` under
+//! `deploy_workspace`, starting from a copy of the current release so only
+//! changed files are sent, then points the `current` symlink at it and prunes
+//! the oldest releases. A release is named after the revision deployed, or
+//! `deploy-<id>` when the revision isn't known. Rolling back points `current`
+//! at an earlier release that is still on the node.
+
+use crate::shell_path;
+use exec::shell_quote;
+use model::{Deployment, DeploymentStatus};
+
+/// Directory under `deploy_workspace` holding one directory per release.
+pub const RELEASES_DIR: &str = "releases";
+
+/// Symlink under `deploy_workspace` to the release the service runs.
+pub const CURRENT_LINK: &str = "current";
+
+/// Name of the release a deployment of `revision` syncs into.
+pub fn release_name(revision: Option<&str>, deployment_id: u64) -> String {
+    match revision {
+        Some(revision) => revision.to_string(),
+        None => format!("deploy-{deployment_id}"),
+    }
+}
+
+/// Where `release` lives on a node.
+pub fn release_dir(deploy_workspace: &str, release: &str) -> String {
+    format!("{}/{RELEASES_DIR}/{release}", deploy_workspace.trim_end_matches('/'))
+}
+
+/// Where the running release is reached on a node.
+pub fn current_dir(deploy_workspace: &str) -> String {
+    format!("{}/{CURRENT_LINK}", deploy_workspace.trim_end_matches('/'))
+}
+
+/// Script that creates the release's directory from a copy of the current
+/// release, unless the release is the current one already.
+pub fn prepare_script(deploy_workspace: &str, release: &str) -> String {
+    let linked = shell_quote(&format!("{RELEASES_DIR}/{release}"));
+    let mut script = format!(
+        "set -eu\nmkdir -p {}\ncd {}\n",
+        shell_path(&format!("{}/{RELEASES_DIR}", deploy_workspace.trim_end_matches('/'))),
+        shell_path(deploy_workspace),
+    );
+    script.push_str(&format!("if [ \"$(readlink {CURRENT_LINK} || true)\" != {linked} ]; then\n"));
+    script.push_str(&format!("    rm -rf {linked}\n    mkdir {linked}\n"));
+    script.push_str(&format!("    if [ -d {CURRENT_LINK}/ ]; then cp -a {CURRENT_LINK}/. {linked}/; fi\nfi\n"));
+    script
+}
+
+/// Script that points `current` at the release and removes all but the
+/// `keep` most recently activated releases.
+pub fn activate_script(deploy_workspace: &str, release: &str, keep: usize) -> String {
+    let linked = shell_quote(&format!("{RELEASES_DIR}/{release}"));
+    let mut script = format!("set -eu\ncd {}\n", shell_path(deploy_workspace));
+    script.push_str(&format!(
+        "if [ ! -d {linked} ]; then echo {} >&2; exit 1; fi\n",
+        shell_quote(&format!("release {release} is no longer on the node")),
+    ));
+    // the directory's time orders releases by when they were last activated
+    script.push_str(&format!("touch {linked}\nln -sfn {linked} {CURRENT_LINK}.tmp\nmv -fT {CURRENT_LINK}.tmp {CURRENT_LINK}\n"));
+    script.push_str(&format!("echo \"{CURRENT_LINK} -> {RELEASES_DIR}/{release}\"\n"));
+    script.push_str(&format!("ls -1t {RELEASES_DIR} | tail -n +{} | while read -r old; do\n", keep.max(1) + 1));
+    script.push_str(&format!("    [ \"{RELEASES_DIR}/$old\" = {linked} ] && continue\n"));
+    script.push_str(&format!("    rm -rf \"{RELEASES_DIR}/$old\"\n    echo \"removed release $old\"\ndone\n"));
+    script
+}
+
+/// The deployment whose release a rollback goes back to, given every
+/// deployment of a service to an environment that switched to a release,
+/// oldest first.
+///
+/// When the newest of them didn't succeed its nodes may be left on its
+/// release, so the rollback is to the last release that went out fine.
+/// Otherwise it is to the release before that one. Rollbacks take the
+/// releases they went back over out of the line, so repeated rollbacks keep
+/// going further back.
+pub fn rollback_target(deployments: &[Deployment]) -> Option<&Deployment> {
+    let latest = deployments.last()?;
+    let mut line: Vec<&Deployment> = Vec::new();
+    for deployment in deployments.iter().filter(|d| d.status() == DeploymentStatus::Succeeded) {
+        let earlier = line.iter().rposition(|d| d.release() == deployment.release());
+        match earlier {
+            Some(i) if deployment.rollback_of().is_some() => line.truncate(i + 1),
+            Some(i) if i + 1 == line.len() => {}
+            _ => line.push(deployment),
+        }
+    }
+    let target = if latest.status() == DeploymentStatus::Succeeded {
+        line.len().checked_sub(2).map(|i| line[i])
+    } else {
+        line.last().copied()
+    };
+    target.filter(|target| target.release() != latest.release())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exec::{Executor, LocalExecutor, RunOptions};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn releases_are_switched_and_pruned_on_the_node() {
+        let dir = std::env::temp_dir().join(format!("deploy-releases-{}", std::process::id()));
+        let workspace = dir.display().to_string();
+        let run = |script: &str| {
+            let mut lines = Vec::new();
+            let outcome = LocalExecutor
+                .run(script, &RunOptions::default(), &mut |_, line| lines.push(line.to_string()))
+                .expect("run");
+            assert!(outcome.success(), "{lines:?}");
+            lines
+        };
+        for release in ["a", "b", "c"] {
+            run(&prepare_script(&workspace, release));
+            fs::write(dir.join(RELEASES_DIR).join(release).join(release), release).expect("artifact");
+            run(&activate_script(&workspace, release, 2));
+        }
+        assert_eq!(fs::read_link(dir.join(CURRENT_LINK)).expect("link"), Path::new("releases/c"));
+        // each release started out as a copy of the one before it
+        assert!(dir.join("releases/c/b").exists());
+        assert!(!dir.join("releases/a").exists());
+
+        assert_eq!(run(&activate_script(&workspace, "b", 2)), ["current -> releases/b"]);
+        assert!(dir.join("current/b").exists());
+        let gone = LocalExecutor.run(&activate_script(&workspace, "a", 2), &RunOptions::default(), &mut |_, _| {});
+        assert!(!gone.expect("run").success());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,263 @@
+//! Keeping every build in a content addressed artifact store.
+//!
+//! Each file of a build is stored once under `objects/<aa>/<sha256>` in the
+//! store's `dir`, however many builds share it, and an index in SQLite lists
+//! the files of each build along with its service, revision and
+//! architecture. A build is known by the sha256 of its file listing, so the
+//! same files make the same build. Nodes are synced from the stored copy,
+//! which lets a later deploy ship the very same bytes to another environment,
+//! or again, without building.
+//!
+//! After storing a build the store removes the builds of that service beyond
+//! the newest `keep` per architecture, and those older than `max_age_days`,
+//! except the ones still live: shipped by the last successful deployment of
+//! a service to an environment.
+
+use crate::sync::{self, Artifact, Manifest};
+use config::{Arch, ArtifactsConfig};
+use model::{ModelError, NewArtifact, NewArtifactFile, SqliteArtifactModel, StoredArtifact};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Directory under the store's `dir` holding the files, by sha256.
+pub const OBJECTS_DIR: &str = "objects";
+
+/// Tells apart the temporary files of objects being stored at the same time.
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// Reasons a build couldn't be stored or read back.
+#[derive(Debug)]
+pub enum StoreError {
+    Io { path: PathBuf, source: io::Error },
+    Index(ModelError),
+    /// A file changed between hashing it and storing it.
+    Changed(PathBuf),
+    /// The index lists a file the store no longer has.
+    Missing(String),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => write!(f, "unable to store {}: {source}", path.display()),
+            Self::Index(err) => write!(f, "artifact index error: {err}"),
+            Self::Changed(path) => write!(f, "{} changed while it was being stored", path.display()),
+            Self::Missing(hash) => write!(f, "the artifact store has lost file {hash}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<ModelError> for StoreError {
+    fn from(value: ModelError) -> Self {
+        Self::Index(value)
+    }
+}
+
+/// The artifact store of this machine.
+pub struct ArtifactStore {
+    dir: PathBuf,
+    index: SqliteArtifactModel,
+    keep: usize,
+    max_age: Option<Duration>,
+    /// Held while storing or removing, so a file being stored isn't removed
+    /// as unused in between.
+    writing: Mutex<()>,
+}
+
+impl ArtifactStore {
+    pub fn new(config: &ArtifactsConfig, index: SqliteArtifactModel) -> Self {
+        Self {
+            dir: vcs::expand_home(&config.dir),
+            index,
+            keep: config.keep.max(1),
+            max_age: (config.max_age_days > 0).then(|| Duration::from_secs(config.max_age_days * 24 * 60 * 60)),
+            writing: Mutex::new(()),
+        }
+    }
+
+    pub fn index(&self) -> &SqliteArtifactModel {
+        &self.index
+    }
+
+    /// Store the files of `manifest` as a build of `service` for `arch`.
+    pub fn put(
+        &self,
+        service: &str,
+        revision: Option<&str>,
+        arch: Option<Arch>,
+        manifest: &Manifest,
+    ) -> Result<StoredArtifact, StoreError> {
+        let _writing = self.writing.lock().expect("error, lock in poisoned state");
+        for artifact in &manifest.artifacts {
+            self.put_object(artifact)?;
+        }
+        let files: Vec<NewArtifactFile> = manifest
+            .artifacts
+            .iter()
+            .map(|artifact| NewArtifactFile {
+                path: &artifact.path,
+                sha256: &artifact.hash,
+                size: artifact.size,
+                mode: artifact.mode,
+            })
+            .collect();
+        let stored = self.index.add_artifact(&NewArtifact {
+            service,
+            revision,
+            arch: arch.map(|arch| arch.as_str()),
+            sha256: &digest(manifest),
+            files: &files,
+        })?;
+        Ok(stored)
+    }
+
+    fn put_object(&self, artifact: &Artifact) -> Result<(), StoreError> {
+        let path = self.object_path(&artifact.hash);
+        if path.is_file() {
+            return Ok(());
+        }
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| StoreError::Io { path, source }
+        };
+        let dir = path.parent().expect("objects are in a directory");
+        fs::create_dir_all(dir).map_err(io_error(dir))?;
+        let temp = dir.join(format!(
+            "{}.{}-{}.tmp",
+            artifact.hash,
+            process::id(),
+            NEXT_TEMP.fetch_add(1, Ordering::Relaxed),
+        ));
+        fs::copy(&artifact.source, &temp).map_err(io_error(&artifact.source))?;
+        let (hash, _) = sync::hash_file(&temp).map_err(io_error(&temp))?;
+        if hash != artifact.hash {
+            let _ = fs::remove_file(&temp);
+            return Err(StoreError::Changed(artifact.source.clone()));
+        }
+        fs::rename(&temp, &path).map_err(io_error(&path))
+    }
+
+    /// A stored build as a manifest nodes can be synced from.
+    pub fn manifest(&self, artifact: &StoredArtifact) -> Result<Manifest, StoreError> {
+        let mut artifacts = Vec::new();
+        for file in self.index.list_files(artifact.id())? {
+            let source = self.object_path(file.sha256());
+            if !source.is_file() {
+                return Err(StoreError::Missing(file.sha256().to_string()));
+            }
+            artifacts.push(Artifact {
+                path: file.path().to_string(),
+                source,
+                hash: file.sha256().to_string(),
+                size: file.size(),
+                mode: file.mode(),
+            });
+        }
+        Ok(Manifest { artifacts })
+    }
+
+    /// Remove the builds of `service` the retention rules no longer keep,
+    /// returning them.
+    pub fn prune(&self, service: &str) -> Result<Vec<StoredArtifact>, StoreError> {
+        let _writing = self.writing.lock().expect("error, lock in poisoned state");
+        let live = self.index.live_artifact_ids()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut seen: BTreeMap<Option<String>, usize> = BTreeMap::new();
+        let mut removed = Vec::new();
+        for artifact in self.index.list_artifacts(service)? {
+            let newer = seen.entry(artifact.arch().map(str::to_string)).or_default();
+            *newer += 1;
+            let age = now.saturating_sub(Duration::from_millis(artifact.created_at()));
+            let expired = *newer > self.keep || self.max_age.is_some_and(|max_age| age > max_age);
+            if !expired || live.contains(&artifact.id()) {
+                continue;
+            }
+            for hash in self.index.remove_artifact(artifact.id())? {
+                let path = self.object_path(&hash);
+                match fs::remove_file(&path) {
+                    Err(source) if source.kind() != io::ErrorKind::NotFound => {
+                        return Err(StoreError::Io { path, source });
+                    }
+                    _ => {}
+                }
+            }
+            removed.push(artifact);
+        }
+        Ok(removed)
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(OBJECTS_DIR).join(hash.get(..2).unwrap_or(hash)).join(hash)
+    }
+}
+
+/// The sha256 a build is known by, over the mode, hash and path of each file.
+pub fn digest(manifest: &Manifest) -> String {
+    let mut hasher = Sha256::new();
+    for artifact in &manifest.artifacts {
+        hasher.update(format!("{:o} {} {}\n", artifact.mode, artifact.hash, artifact.path));
+    }
+    sync::hex(&hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn stores_builds_once_and_prunes_beyond_keep() {
+        let dir = std::env::temp_dir().join(format!("deploy-store-{}", process::id()));
+        let build = dir.join("build");
+        fs::create_dir_all(&build).expect("build dir");
+        let pool = Pool::builder().max_size(1).build(SqliteConnectionManager::memory()).expect("pool");
+        pool.get()
+            .expect("conn")
+            .execute_batch(concat!(
+                include_str!("../../db/migrations/002_create_deployments.sql"),
+                include_str!("../../db/migrations/006_add_deployments_privileged.sql"),
+                include_str!("../../db/migrations/007_add_deployments_release.sql"),
+                include_str!("../../db/migrations/009_create_artifacts.sql"),
+                include_str!("../../db/migrations/010_add_deployments_promoted_from.sql"),
+            ))
+            .expect("create tables");
+        let config = ArtifactsConfig { dir: dir.join("store").display().to_string(), keep: 1, max_age_days: 0 };
+        let store = ArtifactStore::new(&config, SqliteArtifactModel::new_with_pool(pool));
+
+        fs::write(build.join("app"), "v1").expect("artifact");
+        fs::set_permissions(build.join("app"), fs::Permissions::from_mode(0o755)).expect("mode");
+        fs::write(build.join("README"), "same").expect("artifact");
+        let manifest = Manifest::collect(&build, &[]).expect("manifest");
+        let first = store.put("svc", Some("r1"), Some(Arch::Arm64), &manifest).expect("put");
+        assert_eq!(first.sha256(), digest(&manifest));
+        assert_eq!(store.put("svc", Some("r1"), Some(Arch::Arm64), &manifest).expect("put again"), first);
+        let stored = store.manifest(&first).expect("stored manifest");
+        let files = |manifest: &Manifest| -> Vec<(String, String, u32)> {
+            manifest.artifacts.iter().map(|a| (a.path.clone(), a.hash.clone(), a.mode)).collect()
+        };
+        assert_eq!(files(&stored), files(&manifest));
+        assert_eq!(fs::read_to_string(&stored.artifacts[1].source).expect("object"), "v1");
+
+        fs::write(build.join("app"), "v2").expect("artifact");
+        let manifest = Manifest::collect(&build, &[]).expect("manifest");
+        let second = store.put("svc", Some("r2"), Some(Arch::Arm64), &manifest).expect("put");
+        assert_eq!(store.prune("svc").expect("prune"), [first]);
+        // README is still part of the second build, v1 of app is gone
+        assert!(stored.artifacts[0].source.is_file());
+        assert!(!stored.artifacts[1].source.exists());
+        assert!(store.manifest(&second).is_ok());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
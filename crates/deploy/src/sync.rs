@@ -0,0 +1,350 @@
+//! Syncing a service's build artifacts into `deploy_workspace` on a node.
+//!
+//! The artifacts are the files in `build_workspace` matching the service's
+//! `artifacts` globs. Each is hashed and compared with the hashes of the files
+//! the node already has, so only changed files are sent, as a tar archive the
+//! node unpacks. Files on the node that aren't artifacts are deleted unless
+//! they match one of the service's `protect` globs.
+
+use crate::shell_path;
+use config::glob;
+use exec::{ExecError, Executor, Outcome, RunOptions, Stream};
+use model::{NewTransfer, TransferAction};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// A file in `build_workspace` that is deployed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    /// `/` separated path relative to the workspace.
+    pub path: String,
+    pub source: PathBuf,
+    /// Hex sha256 of the content.
+    pub hash: String,
+    pub size: u64,
+    /// Unix permission bits the file is deployed with.
+    pub mode: u32,
+}
+
+/// Every artifact of a build, ordered by path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub artifacts: Vec<Artifact>,
+}
+
+impl Manifest {
+    /// Hash the files under `build_workspace` that match `patterns`, or all of
+    /// them when there are no patterns.
+    pub fn collect(build_workspace: &Path, patterns: &[String]) -> io::Result<Self> {
+        let mut artifacts = Vec::new();
+        collect_dir(build_workspace, "", patterns, &mut artifacts)?;
+        artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Self { artifacts })
+    }
+
+    /// Total size of the artifacts in bytes.
+    pub fn size(&self) -> u64 {
+        self.artifacts.iter().map(|artifact| artifact.size).sum()
+    }
+}
+
+fn collect_dir(dir: &Path, prefix: &str, patterns: &[String], artifacts: &mut Vec<Artifact>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = if prefix.is_empty() { name } else { format!("{prefix}/{name}") };
+        let source = entry.path();
+        // links to directories aren't followed so a link back up can't loop forever
+        if entry.file_type()?.is_dir() {
+            collect_dir(&source, &path, patterns, artifacts)?;
+            continue;
+        }
+        let metadata = fs::metadata(&source)?;
+        if !metadata.is_file() {
+            continue;
+        }
+        if !patterns.is_empty() && !patterns.iter().any(|pattern| glob::matches(pattern, &path)) {
+            continue;
+        }
+        let (hash, size) = hash_file(&source)?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        artifacts.push(Artifact { path, source, hash, size, mode });
+    }
+    Ok(())
+}
+
+pub(crate) fn hash_file(path: &Path) -> io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok((hex(&hasher.finalize()), size))
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// What a sync has to do to bring a node up to date with a manifest.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SyncPlan<'a> {
+    pub upload: Vec<&'a Artifact>,
+    pub delete: Vec<String>,
+    pub unchanged: usize,
+}
+
+/// Compare a manifest with the hashes of the files on a node, keyed by path.
+pub fn plan_sync<'a>(manifest: &'a Manifest, remote: &BTreeMap<String, String>, protect: &[String]) -> SyncPlan<'a> {
+    let (unchanged, upload): (Vec<&Artifact>, Vec<&Artifact>) = manifest
+        .artifacts
+        .iter()
+        .partition(|artifact| remote.get(&artifact.path) == Some(&artifact.hash));
+    let delete = remote
+        .keys()
+        .filter(|path| manifest.artifacts.binary_search_by(|a| a.path.as_str().cmp(path)).is_err())
+        .filter(|path| !protect.iter().any(|pattern| glob::matches(pattern, path)))
+        .cloned()
+        .collect();
+    SyncPlan { upload, delete, unchanged: unchanged.len() }
+}
+
+/// Reasons a sync didn't complete.
+#[derive(Debug)]
+pub enum SyncError {
+    Manifest { build_workspace: String, source: io::Error },
+    Archive(io::Error),
+    Exec(ExecError),
+    /// A script on the node didn't succeed.
+    Failed { action: &'static str, outcome: Outcome },
+}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Manifest { build_workspace, source } => {
+                write!(f, "unable to read artifacts in {build_workspace}: {source}")
+            }
+            Self::Archive(err) => write!(f, "unable to pack artifacts: {err}"),
+            Self::Exec(err) => write!(f, "{err}"),
+            Self::Failed { action, outcome } => write!(f, "{action} failed: {outcome}"),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+/// Everything a sync changed on a node.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub uploaded: Vec<Artifact>,
+    pub deleted: Vec<String>,
+    pub unchanged: usize,
+}
+
+impl SyncReport {
+    /// The report as rows for the deployment's transfer record.
+    pub fn transfers(&self) -> Vec<NewTransfer<'_>> {
+        let uploaded = self.uploaded.iter().map(|artifact| NewTransfer {
+            path: &artifact.path,
+            action: TransferAction::Upload,
+            hash: Some(&artifact.hash),
+            size: artifact.size,
+        });
+        let deleted = self.deleted.iter().map(|path| NewTransfer {
+            path,
+            action: TransferAction::Delete,
+            hash: None,
+            size: 0,
+        });
+        uploaded.chain(deleted).collect()
+    }
+}
+
+/// Bring `deploy_workspace` on the executor's node up to date with `manifest`.
+///
+/// Progress is reported through `on_line`, as is anything the node prints on
+/// stderr.
+pub fn sync_node(
+    executor: &dyn Executor,
+    manifest: &Manifest,
+    deploy_workspace: &str,
+    protect: &[String],
+    options: &RunOptions,
+    on_line: &mut dyn FnMut(Stream, &str),
+) -> Result<SyncReport, SyncError> {
+    let remote = list_remote(executor, deploy_workspace, options, on_line)?;
+    let plan = plan_sync(manifest, &remote, protect);
+    let upload_size: u64 = plan.upload.iter().map(|artifact| artifact.size).sum();
+    on_line(
+        Stream::Stdout,
+        &format!(
+            "{} to upload ({upload_size} bytes), {} to delete, {} unchanged",
+            plan.upload.len(),
+            plan.delete.len(),
+            plan.unchanged,
+        ),
+    );
+    if plan.upload.is_empty() && plan.delete.is_empty() {
+        return Ok(SyncReport { unchanged: plan.unchanged, ..SyncReport::default() });
+    }
+
+    let mut script = format!("set -eu\nmkdir -p {workspace}\ncd {workspace}\n", workspace = shell_path(deploy_workspace));
+    let mut archive = Vec::new();
+    if !plan.upload.is_empty() {
+        archive = pack(&plan.upload).map_err(SyncError::Archive)?;
+        // root would otherwise give the files the owner they have on this machine
+        script.push_str("tar --no-same-owner -xf -\n");
+    }
+    for path in &plan.delete {
+        script.push_str(&format!("rm -f -- {}\n", exec::shell_quote(path)));
+    }
+    let outcome = executor
+        .run_with_input(&script, &archive, options, on_line)
+        .map_err(SyncError::Exec)?;
+    if !outcome.success() {
+        return Err(SyncError::Failed { action: "transfer", outcome });
+    }
+
+    for artifact in &plan.upload {
+        on_line(Stream::Stdout, &format!("uploaded {}", artifact.path));
+    }
+    for path in &plan.delete {
+        on_line(Stream::Stdout, &format!("deleted {path}"));
+    }
+    Ok(SyncReport {
+        uploaded: plan.upload.into_iter().cloned().collect(),
+        deleted: plan.delete,
+        unchanged: plan.unchanged,
+    })
+}
+
+/// Hashes of the files already in `deploy_workspace` on the node.
+fn list_remote(
+    executor: &dyn Executor,
+    deploy_workspace: &str,
+    options: &RunOptions,
+    on_line: &mut dyn FnMut(Stream, &str),
+) -> Result<BTreeMap<String, String>, SyncError> {
+    // a node that was never deployed to has no workspace yet
+    let script = format!(
+        "cd {} 2>/dev/null || exit 0\nfind . -type f -exec sha256sum {{}} +\n",
+        shell_path(deploy_workspace),
+    );
+    let mut remote = BTreeMap::new();
+    let outcome = executor
+        .run(&script, options, &mut |stream, line| match stream {
+            Stream::Stdout => {
+                if let Some((hash, path)) = parse_sha256sum(line) {
+                    remote.insert(path.to_string(), hash.to_string());
+                }
+            }
+            Stream::Stderr => on_line(stream, line),
+        })
+        .map_err(SyncError::Exec)?;
+    if !outcome.success() {
+        return Err(SyncError::Failed { action: "listing deployed files", outcome });
+    }
+    Ok(remote)
+}
+
+/// Split a `sha256sum` line, `<hash>  ./<path>`, into hash and path.
+fn parse_sha256sum(line: &str) -> Option<(&str, &str)> {
+    // names with a newline or backslash are escaped and the line starts with `\`,
+    // skipping them only means they are sent again and never deleted
+    let (hash, path) = line.split_once("  ")?;
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((hash, path.strip_prefix("./").unwrap_or(path)))
+}
+
+fn pack(artifacts: &[&Artifact]) -> io::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for artifact in artifacts {
+        let file = File::open(&artifact.source)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&file.metadata()?);
+        // a stored file's own mode isn't the one it was built with
+        header.set_mode(artifact.mode);
+        builder.append_data(&mut header, &artifact.path, file)?;
+    }
+    builder.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exec::LocalExecutor;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn plans_only_changed_files_and_spares_protected_ones() {
+        let dir = std::env::temp_dir().join(format!("deploy-sync-plan-{}", std::process::id()));
+        write(&dir.join("app"), "binary");
+        write(&dir.join("migrations/001.sql"), "create");
+        write(&dir.join("notes.txt"), "not deployed");
+        let patterns = vec!["app".to_string(), "migrations/**".to_string()];
+        let manifest = Manifest::collect(&dir, &patterns).unwrap();
+        let paths: Vec<&str> = manifest.artifacts.iter().map(|a| a.path.as_str()).collect();
+        assert_eq!(paths, ["app", "migrations/001.sql"]);
+        assert_eq!(manifest.size(), 12);
+
+        let remote = BTreeMap::from([
+            ("app".to_string(), manifest.artifacts[0].hash.clone()),
+            ("migrations/000.sql".to_string(), "stale".to_string()),
+            ("app.new".to_string(), "running".to_string()),
+        ]);
+        let plan = plan_sync(&manifest, &remote, &["app.new".to_string()]);
+        assert_eq!(plan.upload, [&manifest.artifacts[1]]);
+        assert_eq!(plan.delete, ["migrations/000.sql"]);
+        assert_eq!(plan.unchanged, 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn syncs_incrementally_into_the_deploy_workspace() {
+        let dir = std::env::temp_dir().join(format!("deploy-sync-node-{}", std::process::id()));
+        let build = dir.join("build");
+        let deploy = dir.join("deploy");
+        write(&build.join("app"), "v1");
+        fs::set_permissions(build.join("app"), fs::Permissions::from_mode(0o755)).unwrap();
+        write(&build.join("static/index.html"), "<p>hi</p>");
+        write(&deploy.join("app.new"), "running");
+        write(&deploy.join("leftover.txt"), "old");
+        let protect = vec!["app.new".to_string()];
+        let workspace = deploy.display().to_string();
+        let options = RunOptions::default();
+
+        let manifest = Manifest::collect(&build, &[]).unwrap();
+        let mut lines = Vec::new();
+        let first = sync_node(&LocalExecutor, &manifest, &workspace, &protect, &options, &mut |_, line| {
+            lines.push(line.to_string())
+        })
+        .unwrap();
+        assert_eq!(first.uploaded.len(), 2);
+        assert_eq!(first.deleted, ["leftover.txt"]);
+        assert_eq!(fs::read_to_string(deploy.join("static/index.html")).unwrap(), "<p>hi</p>");
+        assert_eq!(fs::metadata(deploy.join("app")).unwrap().permissions().mode() & 0o777, 0o755);
+        assert!(deploy.join("app.new").exists());
+        assert!(!deploy.join("leftover.txt").exists());
+        assert!(lines.contains(&"2 to upload (11 bytes), 1 to delete, 0 unchanged".to_string()));
+
+        write(&build.join("app"), "v2");
+        let manifest = Manifest::collect(&build, &[]).unwrap();
+        let second = sync_node(&LocalExecutor, &manifest, &workspace, &protect, &options, &mut |_, _| {}).unwrap();
+        let uploaded: Vec<&str> = second.uploaded.iter().map(|a| a.path.as_str()).collect();
+        assert_eq!(uploaded, ["app"]);
+        assert_eq!(second.unchanged, 1);
+        assert_eq!(second.transfers()[0].hash, Some(manifest.artifacts[0].hash.as_str()));
+        assert_eq!(fs::read_to_string(deploy.join("app")).unwrap(), "v2");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+//! Running a service as a systemd unit on its nodes.
+//!
+//! The unit file is generated from the service's `unit` config and written,
+//! enabled and restarted on each node after a sync. A user unit goes in the
+//! node user's `~/.local/share/systemd/user`, a system unit for a service with
+//! `deploy_as_root` goes in `/etc/systemd/system`. The scripts for a system
+//! unit expect to already run as root.
+
+use crate::shell_path;
+use config::UnitConfig;
+use exec::{ExecError, Executor, Outcome, RunOptions, Stream, shell_quote};
+
+/// Seconds a restarted unit gets to settle before its state is read.
+const SETTLE_SECS: u32 = 2;
+
+/// Longest a unit may stay `activating` before its state is reported anyway.
+const ACTIVATING_SECS: u32 = 10;
+
+/// Which systemd instance manages a unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// The node user's own `systemctl --user`.
+    User,
+    /// The system manager, for root deploys.
+    System,
+}
+
+/// A service's unit as it is installed on a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unit {
+    /// File name of the unit, `<service>.service`.
+    pub name: String,
+    pub scope: Scope,
+    /// Contents of the unit file.
+    pub text: String,
+    deploy_workspace: String,
+    /// Program in `deploy_workspace` the unit runs through a `.new` copy.
+    program: Option<String>,
+}
+
+impl Unit {
+    /// The unit of `service` run from `deploy_workspace`. With `copy_program`
+    /// a relative program runs through a `.new` copy, so a sync in place can
+    /// replace the program while the service is still running.
+    pub fn new(service: &str, config: &UnitConfig, deploy_workspace: &str, scope: Scope, copy_program: bool) -> Self {
+        // %h is the home of the user the systemd instance belongs to, root's for the system manager
+        let unit_path = |path: &str| match path.strip_prefix("~/") {
+            Some(rest) => format!("%h/{}", escape_specifiers(rest)),
+            None => escape_specifiers(path),
+        };
+
+        let exec_start = config.exec_start.trim();
+        let (program, args) = exec_start.split_once(char::is_whitespace).unwrap_or((exec_start, ""));
+        let relative = !program.starts_with('/') && !program.starts_with("~/");
+        let command = if relative && copy_program {
+            let copy = format!("{}/{program}.new", deploy_workspace.trim_end_matches('/'));
+            format!("{} {}", unit_path(&copy), escape_specifiers(args))
+        } else if relative {
+            let path = format!("{}/{program}", deploy_workspace.trim_end_matches('/'));
+            format!("{} {}", unit_path(&path), escape_specifiers(args))
+        } else {
+            format!("{} {}", unit_path(program), escape_specifiers(args))
+        };
+
+        let description = config.description.clone().unwrap_or_else(|| format!("{service}, deployed by pipeline"));
+        let working_directory = config.working_directory.as_deref().unwrap_or(deploy_workspace);
+        let mut text = format!(
+            "[Unit]\nDescription={}\nAfter=network.target\n\n[Service]\nType=simple\nWorkingDirectory={}\n",
+            escape_specifiers(&description),
+            unit_path(working_directory),
+        );
+        for (name, value) in &config.environment {
+            text.push_str(&format!("Environment=\"{name}={}\"\n", escape_value(value)));
+        }
+        text.push_str(&format!(
+            "ExecStart={}\nRestart={}\nRestartSec={}\n\n[Install]\nWantedBy={}\n",
+            command.trim_end(),
+            config.restart,
+            config.restart_sec,
+            match scope {
+                Scope::User => "default.target",
+                Scope::System => "multi-user.target",
+            },
+        ));
+
+        Self {
+            name: format!("{service}.service"),
+            scope,
+            text,
+            deploy_workspace: deploy_workspace.to_string(),
+            program: (relative && copy_program).then(|| program.to_string()),
+        }
+    }
+
+    /// Script that installs the unit file it reads on stdin, then enables and
+    /// restarts the unit.
+    pub fn install_script(&self) -> String {
+        let name = shell_quote(&self.name);
+        let systemctl = systemctl(self.scope);
+        let mut script = String::from("set -eu\n");
+        if let Some(program) = &self.program {
+            // the running copy is swapped by rename, so the program can be synced while the service runs
+            let copy = shell_quote(&format!("{program}.new"));
+            let program = shell_quote(program);
+            script.push_str(&format!(
+                "cd {}\ncp -f {program} {copy}.tmp\nmv -f {copy}.tmp {copy}\n",
+                shell_path(&self.deploy_workspace),
+            ));
+        }
+        match self.scope {
+            Scope::User => script.push_str(&format!(
+                "mkdir -p ~/.local/share/systemd/user\ncat > ~/.local/share/systemd/user/{name}\n",
+            )),
+            Scope::System => script.push_str(&format!("cat > /etc/systemd/system/{name}\n")),
+        }
+        script.push_str(&format!(
+            "{systemctl} daemon-reload\n{systemctl} enable {name}\n{systemctl} restart {name}\n",
+        ));
+        script
+    }
+
+    /// Files in `deploy_workspace` the unit runs from that aren't artifacts,
+    /// which a sync has to leave alone.
+    pub fn protected(&self) -> Vec<String> {
+        self.program.iter().flat_map(|program| [format!("{program}.new"), format!("{program}.new.tmp")]).collect()
+    }
+
+    /// Script that waits for the unit to settle, prints `state=<state>` and
+    /// the unit's status, and fails unless the unit is active.
+    pub fn status_script(&self) -> String {
+        let name = shell_quote(&self.name);
+        let systemctl = systemctl(self.scope);
+        let mut script = format!("sleep {SETTLE_SECS}\ni=0\n");
+        script.push_str(&format!(
+            "while state=$({systemctl} is-active {name}); [ \"$state\" = activating ] && [ $i -lt {ACTIVATING_SECS} ]; do\n",
+        ));
+        script.push_str("    i=$((i + 1))\n    sleep 1\ndone\necho \"state=$state\"\n");
+        script.push_str(&format!("{systemctl} status --no-pager --lines=10 {name} || true\n"));
+        script.push_str("[ \"$state\" = active ]\n");
+        script
+    }
+}
+
+/// How a restart went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Restarted {
+    pub outcome: Outcome,
+    /// What `systemctl is-active` said after the restart, when it got that far.
+    pub state: Option<String>,
+}
+
+/// Install and restart `unit` on the executor's node, then read back its state.
+pub fn restart(
+    executor: &dyn Executor,
+    unit: &Unit,
+    options: &RunOptions,
+    on_line: &mut dyn FnMut(Stream, &str),
+) -> Result<Restarted, ExecError> {
+    let outcome = executor.run_with_input(&unit.install_script(), unit.text.as_bytes(), options, on_line)?;
+    if !outcome.success() {
+        return Ok(Restarted { outcome, state: None });
+    }
+    let mut state = None;
+    let outcome = executor.run(&unit.status_script(), options, &mut |stream, line| {
+        match line.strip_prefix("state=") {
+            Some(value) if stream == Stream::Stdout => state = Some(value.to_string()),
+            _ => on_line(stream, line),
+        }
+    })?;
+    Ok(Restarted { outcome, state })
+}
+
+/// The `systemctl` command for units of `scope`.
+pub(crate) fn systemctl(scope: Scope) -> &'static str {
+    match scope {
+        Scope::User => "systemctl --user",
+        Scope::System => "systemctl",
+    }
+}
+
+/// Escape `%` so systemd doesn't read it as a specifier.
+fn escape_specifiers(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+/// Escape a value for a double quoted unit setting.
+fn escape_value(value: &str) -> String {
+    escape_specifiers(value).replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exec::FakeExecutor;
+    use std::collections::BTreeMap;
+
+    fn config() -> UnitConfig {
+        UnitConfig {
+            exec_start: "app --port 8080".to_string(),
+            description: None,
+            restart: "on-failure".to_string(),
+            restart_sec: 45,
+            working_directory: None,
+            environment: BTreeMap::from([("GREETING".to_string(), "say \"100%\"".to_string())]),
+        }
+    }
+
+    #[test]
+    fn renders_user_and_system_units() {
+        let user = Unit::new("svc", &config(), "~/deploy/svc", Scope::User, true);
+        assert_eq!(user.name, "svc.service");
+        assert_eq!(user.text, "[Unit]\n\
+            Description=svc, deployed by pipeline\n\
+            After=network.target\n\n\
+            [Service]\n\
+            Type=simple\n\
+            WorkingDirectory=%h/deploy/svc\n\
+            Environment=\"GREETING=say \\\"100%%\\\"\"\n\
+            ExecStart=%h/deploy/svc/app.new --port 8080\n\
+            Restart=on-failure\n\
+            RestartSec=45\n\n\
+            [Install]\n\
+            WantedBy=default.target\n");
+        assert!(user.install_script().contains("cp -f 'app' 'app.new'.tmp\n"));
+        assert!(user.install_script().contains("systemctl --user restart 'svc.service'\n"));
+
+        let absolute = UnitConfig { exec_start: "/usr/bin/svc".to_string(), ..config() };
+        let system = Unit::new("svc", &absolute, "~/deploy/svc", Scope::System, true);
+        assert!(system.text.contains("WorkingDirectory=%h/deploy/svc\n"));
+        assert!(system.text.contains("ExecStart=/usr/bin/svc\n"));
+        assert!(system.text.contains("WantedBy=multi-user.target\n"));
+        assert!(!system.install_script().contains("cp -f"));
+        assert!(system.install_script().contains("cat > /etc/systemd/system/'svc.service'\nsystemctl daemon-reload\n"));
+
+        let release = Unit::new("svc", &config(), "~/deploy/svc/current", Scope::User, false);
+        assert!(release.text.contains("ExecStart=%h/deploy/svc/current/app --port 8080\n"));
+        assert!(!release.install_script().contains("cp -f"));
+    }
+
+    #[test]
+    fn restart_reports_the_unit_state() {
+        let unit = Unit::new("svc", &config(), "~/deploy/svc", Scope::User, true);
+        let node = FakeExecutor::new("pi@pi1:22");
+        node.respond("is-active", &[(Stream::Stdout, "state=failed"), (Stream::Stdout, "● svc.service")], Outcome::Exited(1));
+        let mut lines = Vec::new();
+        let restarted = restart(&node, &unit, &RunOptions::default(), &mut |_, line| lines.push(line.to_string())).unwrap();
+        assert_eq!(restarted, Restarted { outcome: Outcome::Exited(1), state: Some("failed".to_string()) });
+        assert_eq!(lines, ["● svc.service"]);
+        assert_eq!(node.inputs()[0], unit.text.as_bytes());
+    }
+}
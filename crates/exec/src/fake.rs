@@ -0,0 +1,105 @@
+use crate::{ExecError, Executor, Outcome, RunOptions, Stream};
+use std::io::Write;
+use std::sync::Mutex;
+
+struct Response {
+    pattern: String,
+    lines: Vec<(Stream, String)>,
+    outcome: Outcome,
+}
+
+/// An executor that runs nothing, for tests.
+///
+/// Scripts and their input are recorded, and answered by the first response whose pattern
+/// they contain, or with a quiet success. Written stdout is the response's stdout lines,
+/// each ending in a newline.
+pub struct FakeExecutor {
+    target: String,
+    responses: Mutex<Vec<Response>>,
+    scripts: Mutex<Vec<(String, Vec<u8>)>>,
+}
+
+impl FakeExecutor {
+    pub fn new(target: &str) -> Self {
+        Self {
+            target: target.to_string(),
+            responses: Mutex::new(Vec::new()),
+            scripts: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Answer scripts containing `pattern` with `lines` and `outcome`.
+    pub fn respond(&self, pattern: &str, lines: &[(Stream, &str)], outcome: Outcome) {
+        self.responses.lock().expect("error, lock in poisoned state").push(Response {
+            pattern: pattern.to_string(),
+            lines: lines.iter().map(|(stream, line)| (*stream, line.to_string())).collect(),
+            outcome,
+        });
+    }
+
+    /// Every script run so far, in order.
+    pub fn scripts(&self) -> Vec<String> {
+        let scripts = self.scripts.lock().expect("error, lock in poisoned state");
+        scripts.iter().map(|(script, _)| script.clone()).collect()
+    }
+
+    fn answer(
+        &self,
+        script: &str,
+        input: &[u8],
+        options: &RunOptions,
+        mut stdout: Option<&mut dyn Write>,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError> {
+        self.scripts
+            .lock()
+            .expect("error, lock in poisoned state")
+            .push((script.to_string(), input.to_vec()));
+        if options.cancel.is_cancelled() {
+            return Ok(Outcome::Cancelled);
+        }
+        let responses = self.responses.lock().expect("error, lock in poisoned state");
+        let Some(response) = responses.iter().find(|r| script.contains(&r.pattern)) else {
+            return Ok(Outcome::Exited(0));
+        };
+        for (stream, line) in &response.lines {
+            match (stream, stdout.as_mut()) {
+                (Stream::Stdout, Some(stdout)) => writeln!(stdout, "{line}").map_err(ExecError::Output)?,
+                _ => on_line(*stream, line),
+            }
+        }
+        Ok(response.outcome)
+    }
+
+    /// What each script run so far was given on stdin, in order.
+    pub fn inputs(&self) -> Vec<Vec<u8>> {
+        let scripts = self.scripts.lock().expect("error, lock in poisoned state");
+        scripts.iter().map(|(_, input)| input.clone()).collect()
+    }
+}
+
+impl Executor for FakeExecutor {
+    fn target(&self) -> String {
+        self.target.clone()
+    }
+
+    fn run_with_input(
+        &self,
+        script: &str,
+        input: &[u8],
+        options: &RunOptions,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError> {
+        self.answer(script, input, options, None, on_line)
+    }
+
+    fn run_to_writer(
+        &self,
+        script: &str,
+        options: &RunOptions,
+        stdout: &mut dyn Write,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError> {
+        self.answer(script, &[], options, Some(stdout), on_line)
+    }
+}
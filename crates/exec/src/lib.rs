@@ -0,0 +1,404 @@
+//! Running shell scripts on this machine or on a node.
+//!
+//! An [`Executor`] runs a `sh` script somewhere and hands its output back a
+//! line at a time as it is printed. A run can be given a timeout and be
+//! cancelled from another thread; either kills everything the script started.
+//! [`SudoExecutor`] wraps another executor to run its scripts as root.
+
+pub mod fake;
+pub mod local;
+pub mod ssh;
+pub mod sudo;
+
+pub use fake::FakeExecutor;
+pub use local::LocalExecutor;
+pub use ssh::SshExecutor;
+pub use sudo::SudoExecutor;
+
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a run checks for cancellation while its script is quiet.
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long output is still read after the script exits, for anything it
+/// left running in the background that holds the pipes open.
+const EXIT_GRACE: Duration = Duration::from_millis(500);
+
+/// Which pipe of the script a line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Shared flag to stop a run early; clones stop the same run.
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits on a single run.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Kill the script if it is still running after this long.
+    pub timeout: Option<Duration>,
+    pub cancel: Cancel,
+}
+
+/// How a run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Exited(i32),
+    Signaled(i32),
+    TimedOut(Duration),
+    Cancelled,
+}
+
+impl Outcome {
+    pub fn success(&self) -> bool {
+        *self == Self::Exited(0)
+    }
+
+    /// Exit code of a script that ran to completion.
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            Self::Exited(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    fn from_status(status: ExitStatus) -> Self {
+        match (status.code(), status.signal()) {
+            (Some(code), _) => Self::Exited(code),
+            (None, Some(signal)) => Self::Signaled(signal),
+            (None, None) => Self::Exited(-1),
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "exit status: {code}"),
+            Self::Signaled(signal) => write!(f, "killed by signal {signal}"),
+            Self::TimedOut(after) => write!(f, "timed out after {}s", after.as_secs()),
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// Reasons a script could not be run at all.
+#[derive(Debug)]
+pub enum ExecError {
+    Spawn { program: String, source: io::Error },
+    Wait(io::Error),
+    /// The script's stdout couldn't be written where it was asked to go.
+    Output(io::Error),
+}
+
+impl Display for ExecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Spawn { program, source } => write!(f, "unable to start {program}: {source}"),
+            Self::Wait(err) => write!(f, "unable to wait for script: {err}"),
+            Self::Output(err) => write!(f, "unable to write script output: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+/// Somewhere scripts run.
+pub trait Executor: Send + Sync {
+    /// Where scripts run, for log lines, e.g. `local` or `pi@192.168.1.34:22`.
+    fn target(&self) -> String;
+
+    /// Run `script` with `sh`, calling `on_line` for each line it prints.
+    fn run(&self, script: &str, options: &RunOptions, on_line: &mut dyn FnMut(Stream, &str)) -> Result<Outcome, ExecError> {
+        self.run_with_input(script, &[], options, on_line)
+    }
+
+    /// Like [`Executor::run`], with `input` on the script's stdin.
+    fn run_with_input(
+        &self,
+        script: &str,
+        input: &[u8],
+        options: &RunOptions,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError>;
+
+    /// Like [`Executor::run`], writing the script's stdout to `stdout` as is
+    /// rather than line by line, e.g. for an archive. `on_line` gets stderr.
+    fn run_to_writer(
+        &self,
+        script: &str,
+        options: &RunOptions,
+        stdout: &mut dyn Write,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError>;
+}
+
+/// Quote a value for `sh`.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+enum Piped {
+    Line(Stream, String),
+    Chunk(Vec<u8>),
+    Eof,
+}
+
+/// Run a process to completion, feeding it `input` and streaming its output,
+/// within `options`. Its stdout goes to `stdout` as is when given, and line
+/// by line to `on_line` otherwise.
+///
+/// The process leads its own process group, so a timeout or cancel kills
+/// whatever it started too.
+pub(crate) fn run_process(
+    mut command: Command,
+    input: &[u8],
+    mut stdout: Option<&mut dyn Write>,
+    options: &RunOptions,
+    on_line: &mut dyn FnMut(Stream, &str),
+) -> Result<Outcome, ExecError> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let stdin = if input.is_empty() { Stdio::null() } else { Stdio::piped() };
+    let mut child = command
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(|source| ExecError::Spawn { program, source })?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        let input = input.to_vec();
+        // a script that exits without reading all of its input closes the pipe early, that's its business
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+
+    let (sender, lines) = mpsc::channel();
+    let mut open = 0;
+    if let Some(pipe) = child.stdout.take() {
+        match stdout {
+            Some(_) => forward_chunks(pipe, sender.clone()),
+            None => forward_lines(pipe, Stream::Stdout, sender.clone()),
+        }
+        open += 1;
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_lines(stderr, Stream::Stderr, sender);
+        open += 1;
+    }
+
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let mut exited: Option<(ExitStatus, Instant)> = None;
+    let outcome = loop {
+        let quiet = match lines.recv_timeout(CHECK_INTERVAL) {
+            Ok(Piped::Line(stream, line)) => {
+                on_line(stream, &line);
+                false
+            }
+            Ok(Piped::Chunk(bytes)) => {
+                if let Some(stdout) = stdout.as_mut()
+                    && let Err(err) = stdout.write_all(&bytes)
+                {
+                    kill_group(&mut child);
+                    return Err(ExecError::Output(err));
+                }
+                false
+            }
+            Ok(Piped::Eof) => {
+                open -= 1;
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => true,
+            Err(RecvTimeoutError::Disconnected) => {
+                open = 0;
+                true
+            }
+        };
+        if options.cancel.is_cancelled() {
+            kill_group(&mut child);
+            break Outcome::Cancelled;
+        }
+        if let (Some(deadline), Some(timeout)) = (deadline, options.timeout)
+            && Instant::now() >= deadline
+        {
+            kill_group(&mut child);
+            break Outcome::TimedOut(timeout);
+        }
+        if exited.is_none() {
+            exited = child.try_wait().map_err(ExecError::Wait)?.map(|status| (status, Instant::now()));
+        }
+        if let Some((status, at)) = exited
+            && (open == 0 || (quiet && at.elapsed() >= EXIT_GRACE))
+        {
+            break Outcome::from_status(status);
+        }
+    };
+    // lines already read before the kill are still worth showing
+    for read in lines.try_iter() {
+        match (read, stdout.as_mut()) {
+            (Piped::Line(stream, line), _) => on_line(stream, &line),
+            (Piped::Chunk(bytes), Some(stdout)) => stdout.write_all(&bytes).map_err(ExecError::Output)?,
+            _ => {}
+        }
+    }
+    Ok(outcome)
+}
+
+fn forward_chunks(mut pipe: impl Read + Send + 'static, sender: mpsc::Sender<Piped>) {
+    thread::spawn(move || {
+        let mut buf = [0; 64 * 1024];
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => {
+                    if sender.send(Piped::Chunk(buf[..read].to_vec())).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        let _ = sender.send(Piped::Eof);
+    });
+}
+
+fn forward_lines(pipe: impl Read + Send + 'static, stream: Stream, sender: mpsc::Sender<Piped>) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if buf.last() == Some(&b'\n') {
+                        buf.pop();
+                    }
+                    if buf.last() == Some(&b'\r') {
+                        buf.pop();
+                    }
+                    let line = String::from_utf8_lossy(&buf).into_owned();
+                    if sender.send(Piped::Line(stream, line)).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        let _ = sender.send(Piped::Eof);
+    });
+}
+
+fn kill_group(child: &mut Child) {
+    // the group id is the child's pid, see process_group(0)
+    let group = child.id() as libc::pid_t;
+    unsafe {
+        libc::kill(-group, libc::SIGKILL);
+    }
+    if let Err(err) = child.wait() {
+        eprintln!("error, when reaping killed script. Error: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(script: &str, options: &RunOptions) -> (Outcome, Vec<(Stream, String)>) {
+        let mut lines = Vec::new();
+        let outcome = LocalExecutor
+            .run(script, options, &mut |stream, line| lines.push((stream, line.to_string())))
+            .expect("run");
+        (outcome, lines)
+    }
+
+    #[test]
+    fn streams_lines_and_exit_code() {
+        let (outcome, lines) = run("echo one; echo two >&2; printf 'no newline'; exit 3", &RunOptions::default());
+        assert_eq!(outcome, Outcome::Exited(3));
+        assert!(!outcome.success());
+        assert!(lines.contains(&(Stream::Stdout, "one".to_string())));
+        assert!(lines.contains(&(Stream::Stderr, "two".to_string())));
+        assert!(lines.contains(&(Stream::Stdout, "no newline".to_string())));
+    }
+
+    #[test]
+    fn times_out_and_kills_children() {
+        let options = RunOptions { timeout: Some(Duration::from_millis(300)), ..RunOptions::default() };
+        let started = Instant::now();
+        let (outcome, lines) = run("echo started; sleep 30 & sleep 30", &options);
+        assert_eq!(outcome, Outcome::TimedOut(Duration::from_millis(300)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(lines, [(Stream::Stdout, "started".to_string())]);
+    }
+
+    #[test]
+    fn cancels_from_another_thread() {
+        let options = RunOptions::default();
+        let cancel = options.cancel.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            cancel.cancel();
+        });
+        let (outcome, _) = run("sleep 30", &options);
+        canceller.join().unwrap();
+        assert_eq!(outcome, Outcome::Cancelled);
+    }
+
+    #[test]
+    fn feeds_input_to_stdin() {
+        let mut lines = Vec::new();
+        let outcome = LocalExecutor
+            .run_with_input("tr a-z A-Z", b"one\ntwo\n", &RunOptions::default(), &mut |_, line| lines.push(line.to_string()))
+            .expect("run");
+        assert!(outcome.success());
+        assert_eq!(lines, ["ONE", "TWO"]);
+    }
+
+    #[test]
+    fn writes_stdout_as_is() {
+        let mut stdout = Vec::new();
+        let mut lines = Vec::new();
+        let outcome = LocalExecutor
+            .run_to_writer("printf 'a\\000b\\r\\n'; echo note >&2", &RunOptions::default(), &mut stdout, &mut |stream, line| {
+                lines.push((stream, line.to_string()))
+            })
+            .expect("run");
+        assert!(outcome.success());
+        assert_eq!(stdout, b"a\0b\r\n");
+        assert_eq!(lines, [(Stream::Stderr, "note".to_string())]);
+    }
+
+    #[test]
+    fn background_processes_do_not_hold_the_run_open() {
+        let started = Instant::now();
+        let (outcome, _) = run("sleep 30 &\necho done", &RunOptions::default());
+        assert!(outcome.success());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
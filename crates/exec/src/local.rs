@@ -0,0 +1,39 @@
+use crate::{ExecError, Executor, Outcome, RunOptions, Stream, run_process};
+use std::io::Write;
+use std::process::Command;
+
+/// Runs scripts on this machine.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalExecutor;
+
+impl Executor for LocalExecutor {
+    fn target(&self) -> String {
+        "local".to_string()
+    }
+
+    fn run_with_input(
+        &self,
+        script: &str,
+        input: &[u8],
+        options: &RunOptions,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError> {
+        run_process(command(script), input, None, options, on_line)
+    }
+
+    fn run_to_writer(
+        &self,
+        script: &str,
+        options: &RunOptions,
+        stdout: &mut dyn Write,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError> {
+        run_process(command(script), &[], Some(stdout), options, on_line)
+    }
+}
+
+fn command(script: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(script);
+    command
+}
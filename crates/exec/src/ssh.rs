@@ -0,0 +1,220 @@
+use crate::{ExecError, Executor, Outcome, RunOptions, Stream, run_process, shell_quote};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+/// Seconds ssh waits for a node to answer before giving up.
+const CONNECT_TIMEOUT: u32 = 10;
+
+/// Starts the stderr line where the remote script reports its process group.
+const GROUP_MARKER: &str = "ssh-executor-group ";
+
+/// How long killing what is left of a stopped script on the node may take.
+const KILL_TIMEOUT: Duration = Duration::from_secs(CONNECT_TIMEOUT as u64 + 5);
+
+/// Runs scripts on a node through the `ssh` client.
+///
+/// Authentication has to work without a prompt, e.g. with a key in the
+/// agent, since nobody is there to type a password.
+///
+/// Killing the local `ssh` doesn't stop the script on the node, so scripts
+/// run in a session of their own there, and a timeout or cancel kills that
+/// session's process group through a second connection.
+#[derive(Debug, Clone)]
+pub struct SshExecutor {
+    user: String,
+    host_name: String,
+    port: usize,
+    program: PathBuf,
+}
+
+impl SshExecutor {
+    pub fn new(user: &str, host_name: &str, port: usize) -> Self {
+        Self {
+            user: user.to_string(),
+            host_name: host_name.to_string(),
+            port,
+            program: PathBuf::from("ssh"),
+        }
+    }
+
+    /// Use another program in place of `ssh`. It is called with ssh's
+    /// arguments, so a stand-in can run the command locally instead.
+    pub fn with_program(mut self, program: impl Into<PathBuf>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// `ssh` running `command_line` on the node.
+    fn command(&self, command_line: &str) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .arg("-p")
+            .arg(self.port.to_string())
+            .args(["-o", "BatchMode=yes"])
+            .args(["-o", &format!("ConnectTimeout={CONNECT_TIMEOUT}")])
+            .arg(format!("{}@{}", self.user, self.host_name))
+            // ssh joins its arguments into one command line for the remote shell
+            .arg(command_line);
+        command
+    }
+
+    /// Run `script` in a session of its own on the node, killing the session
+    /// when the run times out or is cancelled.
+    fn run_session(
+        &self,
+        script: &str,
+        input: &[u8],
+        stdout: Option<&mut dyn Write>,
+        options: &RunOptions,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError> {
+        let mut group = None;
+        let outcome = run_process(self.command(&session_command(script)), input, stdout, options, &mut |stream, line| {
+            if group.is_none()
+                && stream == Stream::Stderr
+                && let Some(id) = line.strip_prefix(GROUP_MARKER)
+            {
+                group = id.parse::<u32>().ok();
+                return;
+            }
+            on_line(stream, line);
+        })?;
+        if matches!(outcome, Outcome::Cancelled | Outcome::TimedOut(_))
+            && let Some(group) = group
+        {
+            self.kill_remote(group);
+        }
+        Ok(outcome)
+    }
+
+    /// Kill process group `group` on the node, left behind by a stopped run.
+    fn kill_remote(&self, group: u32) {
+        let options = RunOptions { timeout: Some(KILL_TIMEOUT), ..RunOptions::default() };
+        let script = format!("kill -KILL -{group}");
+        match run_process(self.command(&format!("sh -c {}", shell_quote(&script))), &[], None, &options, &mut |_, _| {}) {
+            Ok(outcome) if outcome.success() => {}
+            Ok(outcome) => eprintln!("error, when killing process group {group} on {}. Error: {}", self.target(), outcome),
+            Err(e) => eprintln!("error, when killing process group {group} on {}. Error: {}", self.target(), e),
+        }
+    }
+}
+
+/// The remote command line running `script` as the leader of a new session,
+/// which first reports its process group on stderr.
+fn session_command(script: &str) -> String {
+    let leader = format!("echo \"{GROUP_MARKER}$$\" >&2; exec sh -c \"$1\"");
+    format!("setsid -w sh -c {} sh {}", shell_quote(&leader), shell_quote(script))
+}
+
+impl Executor for SshExecutor {
+    fn target(&self) -> String {
+        format!("{}@{}:{}", self.user, self.host_name, self.port)
+    }
+
+    fn run_with_input(
+        &self,
+        script: &str,
+        input: &[u8],
+        options: &RunOptions,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError> {
+        self.run_session(script, input, None, options, on_line)
+    }
+
+    fn run_to_writer(
+        &self,
+        script: &str,
+        options: &RunOptions,
+        stdout: &mut dyn Write,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError> {
+        self.run_session(script, &[], Some(stdout), options, on_line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::thread;
+    use std::time::Instant;
+
+    /// An `ssh` that checks the destination and runs the command here.
+    fn stand_in(dir: &std::path::Path) -> PathBuf {
+        let path = dir.join("ssh");
+        let script = r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -p|-o) shift 2 ;;
+        *) break ;;
+    esac
+done
+[ "$1" = "pi@node1" ] || { echo "unexpected destination $1" >&2; exit 255; }
+shift
+exec sh -c "$*"
+"#;
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn runs_scripts_through_ssh() {
+        let dir = std::env::temp_dir().join(format!("exec-ssh-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ssh = SshExecutor::new("pi", "node1", 2222).with_program(stand_in(&dir));
+        assert_eq!(ssh.target(), "pi@node1:2222");
+
+        let mut lines = Vec::new();
+        let outcome = ssh
+            .run("echo \"it's $((1 + 1))\"; exit 4", &RunOptions::default(), &mut |_, line| lines.push(line.to_string()))
+            .unwrap();
+        assert_eq!(outcome, Outcome::Exited(4));
+        assert_eq!(lines, ["it's 2"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Whether process `pid` is gone, or dead and waiting to be reaped.
+    fn is_gone(pid: u32) -> bool {
+        match fs::read_to_string(format!("/proc/{pid}/stat")) {
+            Ok(stat) => stat.rsplit_once(')').is_some_and(|(_, rest)| rest.trim_start().starts_with('Z')),
+            Err(_) => true,
+        }
+    }
+
+    #[test]
+    fn cancel_kills_the_script_on_the_node() {
+        let dir = std::env::temp_dir().join(format!("exec-ssh-cancel-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ssh = SshExecutor::new("pi", "node1", 22).with_program(stand_in(&dir));
+        let pid_file = dir.join("pid");
+
+        let options = RunOptions::default();
+        let cancel = options.cancel.clone();
+        let watched = pid_file.clone();
+        let canceller = thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !watched.exists() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(20));
+            }
+            cancel.cancel();
+        });
+        let script = format!("sleep 30 & echo $! > {}; wait", shell_quote(&pid_file.display().to_string()));
+        let mut lines = Vec::new();
+        let outcome = ssh.run(&script, &options, &mut |_, line| lines.push(line.to_string())).unwrap();
+        canceller.join().unwrap();
+
+        assert_eq!(outcome, Outcome::Cancelled);
+        assert!(lines.is_empty(), "{lines:?}");
+        let pid: u32 = fs::read_to_string(&pid_file).unwrap().trim().parse().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !is_gone(pid) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(is_gone(pid), "sleep {pid} outlived the cancel");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
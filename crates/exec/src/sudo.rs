@@ -0,0 +1,65 @@
+use crate::{ExecError, Executor, Outcome, RunOptions, Stream, shell_quote};
+use std::io::Write;
+use std::sync::Arc;
+
+/// Runs scripts as root through passwordless `sudo` on another executor.
+///
+/// `sudo -n` fails straight away rather than waiting for a password nobody
+/// can type. `-H` points `HOME` at root's home, so `~` means the same as it
+/// does for a root login.
+#[derive(Clone)]
+pub struct SudoExecutor {
+    inner: Arc<dyn Executor>,
+}
+
+impl SudoExecutor {
+    pub fn new(inner: Arc<dyn Executor>) -> Self {
+        Self { inner }
+    }
+}
+
+impl Executor for SudoExecutor {
+    fn target(&self) -> String {
+        format!("{} (sudo)", self.inner.target())
+    }
+
+    fn run_with_input(
+        &self,
+        script: &str,
+        input: &[u8],
+        options: &RunOptions,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError> {
+        self.inner.run_with_input(&as_root(script), input, options, on_line)
+    }
+
+    fn run_to_writer(
+        &self,
+        script: &str,
+        options: &RunOptions,
+        stdout: &mut dyn Write,
+        on_line: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Outcome, ExecError> {
+        self.inner.run_to_writer(&as_root(script), options, stdout, on_line)
+    }
+}
+
+fn as_root(script: &str) -> String {
+    format!("sudo -n -H sh -c {}", shell_quote(script))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FakeExecutor;
+
+    #[test]
+    fn wraps_scripts_in_sudo() {
+        let node = Arc::new(FakeExecutor::new("pi@pi1:22"));
+        let sudo = SudoExecutor::new(node.clone());
+        assert_eq!(sudo.target(), "pi@pi1:22 (sudo)");
+        sudo.run_with_input("cat > '/etc/x'", b"x", &RunOptions::default(), &mut |_, _| {}).unwrap();
+        assert_eq!(node.scripts(), [r"sudo -n -H sh -c 'cat > '\''/etc/x'\'''"]);
+        assert_eq!(node.inputs(), [b"x".to_vec()]);
+    }
+}
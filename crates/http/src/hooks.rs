@@ -0,0 +1,227 @@
+//! `POST /hooks/<repo>`: push notifications from the host of a repo.
+//!
+//! The body is either the generic `{"repo": .., "branch": .., "revision": ..}`
+//! or the payload GitHub sends for a push. Either way it has to be signed with
+//! the repo's `webhook_secret`, GitHub style: `X-Hub-Signature-256:
+//! sha256=<hex hmac of the body>`. A verified push makes the repo poller check
+//! the repo right away.
+
+use config::AppConfig;
+use deploy::poller::{self, NotifyError, Push};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+pub const PREFIX: &str = "/hooks/";
+
+/// Largest body accepted, GitHub caps its payloads at 25MB but a push is far smaller.
+pub const MAX_BODY: usize = 1 << 20;
+
+const SIGNATURE_HEADER: &str = "x-hub-signature-256";
+const EVENT_HEADER: &str = "x-github-event";
+
+/// What was done with an accepted notification.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Accepted {
+    /// The host checking that the webhook works.
+    Pong,
+    /// The repo poller will check the repo now.
+    Queued,
+}
+
+impl Accepted {
+    pub fn status_line(&self) -> &'static str {
+        match self {
+            Self::Pong => "HTTP/1.1 200 OK",
+            Self::Queued => "HTTP/1.1 202 ACCEPTED",
+        }
+    }
+}
+
+impl Display for Accepted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pong => write!(f, "pong"),
+            Self::Queued => write!(f, "queued"),
+        }
+    }
+}
+
+/// Reasons a push notification was turned away.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HookError {
+    UnknownRepo(String),
+    NotEnabled(String),
+    MissingSignature,
+    BadSignature,
+    InvalidBody(String),
+    RepoMismatch { path: String, body: String },
+    Unavailable(NotifyError),
+}
+
+impl HookError {
+    pub fn status_line(&self) -> &'static str {
+        match self {
+            Self::UnknownRepo(_) => "HTTP/1.1 404 NOT FOUND",
+            Self::NotEnabled(_) => "HTTP/1.1 403 FORBIDDEN",
+            Self::MissingSignature | Self::BadSignature => "HTTP/1.1 401 UNAUTHORIZED",
+            Self::InvalidBody(_) | Self::RepoMismatch { .. } => "HTTP/1.1 400 BAD REQUEST",
+            Self::Unavailable(_) => "HTTP/1.1 503 SERVICE UNAVAILABLE",
+        }
+    }
+}
+
+impl Display for HookError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownRepo(repo) => write!(f, "unknown repo '{repo}'"),
+            Self::NotEnabled(repo) => write!(f, "repo '{repo}' has no webhook_secret"),
+            Self::MissingSignature => write!(f, "missing X-Hub-Signature-256 header"),
+            Self::BadSignature => write!(f, "signature does not match the body"),
+            Self::InvalidBody(reason) => write!(f, "invalid push body: {reason}"),
+            Self::RepoMismatch { path, body } => write!(f, "body is for repo '{body}', not '{path}'"),
+            Self::Unavailable(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for HookError {}
+
+/// Check and act on a push to `repo`.
+pub fn handle_hook(config: &AppConfig, repo: &str, headers: &HashMap<String, String>, body: &[u8]) -> Result<Accepted, HookError> {
+    let repo_cfg = config.repos.get(repo).ok_or_else(|| HookError::UnknownRepo(repo.to_string()))?;
+    let secret = repo_cfg
+        .webhook_secret
+        .as_deref()
+        .ok_or_else(|| HookError::NotEnabled(repo.to_string()))?;
+    let signature = headers.get(SIGNATURE_HEADER).ok_or(HookError::MissingSignature)?;
+    verify_signature(secret, body, signature)?;
+
+    // GitHub sends a ping when the webhook is created
+    if headers.get(EVENT_HEADER).is_some_and(|event| event == "ping") {
+        return Ok(Accepted::Pong);
+    }
+    let push = parse_push(repo, body)?;
+    poller::notify_push(push).map_err(HookError::Unavailable)?;
+    Ok(Accepted::Queued)
+}
+
+/// Check `header`, `sha256=<hex>`, is the HMAC-SHA256 of `body` under `secret`.
+fn verify_signature(secret: &str, body: &[u8], header: &str) -> Result<(), HookError> {
+    let signature = header
+        .strip_prefix("sha256=")
+        .and_then(decode_hex)
+        .ok_or(HookError::BadSignature)?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any length");
+    mac.update(body);
+    // compares in constant time
+    mac.verify_slice(&signature).map_err(|_| HookError::BadSignature)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Read a push out of either body shape.
+fn parse_push(repo: &str, body: &[u8]) -> Result<Push, HookError> {
+    let value: Value = serde_json::from_slice(body).map_err(|e| HookError::InvalidBody(e.to_string()))?;
+    if !value.is_object() {
+        return Err(HookError::InvalidBody("expected a JSON object".to_string()));
+    }
+    let text = |value: &Value, key: &str| value.get(key).and_then(Value::as_str).map(str::to_string);
+
+    if let Some(git_ref) = text(&value, "ref") {
+        // GitHub: the ref is refs/heads/<branch>, `after` the new head, unless the branch was deleted
+        let deleted = value.get("deleted").and_then(Value::as_bool).unwrap_or(false);
+        return Ok(Push {
+            repo: repo.to_string(),
+            branch: Some(git_ref.strip_prefix("refs/heads/").unwrap_or(&git_ref).to_string()),
+            revision: text(&value, "after").filter(|_| !deleted),
+        });
+    }
+    if let Some(body_repo) = text(&value, "repo")
+        && body_repo != repo
+    {
+        return Err(HookError::RepoMismatch { path: repo.to_string(), body: body_repo });
+    }
+    Ok(Push {
+        repo: repo.to_string(),
+        branch: text(&value, "branch"),
+        revision: text(&value, "revision"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let hex: String = mac.finalize().into_bytes().iter().map(|b| format!("{b:02x}")).collect();
+        format!("sha256={hex}")
+    }
+
+    #[test]
+    fn verifies_hmac_signature() {
+        let body = br#"{"repo":"create"}"#;
+        assert_eq!(verify_signature("secret", body, &sign("secret", body)), Ok(()));
+        assert_eq!(verify_signature("other", body, &sign("secret", body)), Err(HookError::BadSignature));
+        assert_eq!(verify_signature("secret", b"{}", &sign("secret", body)), Err(HookError::BadSignature));
+        assert_eq!(verify_signature("secret", body, "sha256=zz"), Err(HookError::BadSignature));
+        assert_eq!(verify_signature("secret", body, "sha1=abcd"), Err(HookError::BadSignature));
+    }
+
+    #[test]
+    fn parses_generic_and_github_pushes() {
+        let generic = parse_push("create", br#"{"repo":"create","branch":"trunk","revision":"5e3d4b"}"#).unwrap();
+        assert_eq!(generic, Push {
+            repo: "create".to_string(),
+            branch: Some("trunk".to_string()),
+            revision: Some("5e3d4b".to_string()),
+        });
+        assert!(matches!(
+            parse_push("create", br#"{"repo":"pipeline"}"#),
+            Err(HookError::RepoMismatch { .. })
+        ));
+
+        let github = br#"{"ref":"refs/heads/main","before":"1a2b","after":"3c4d","repository":{"name":"example"}}"#;
+        let push = parse_push("pipeline", github).unwrap();
+        assert_eq!(push.branch.as_deref(), Some("main"));
+        assert_eq!(push.revision.as_deref(), Some("3c4d"));
+
+        let deleted = parse_push("pipeline", br#"{"ref":"refs/heads/old","after":"0000","deleted":true}"#).unwrap();
+        assert_eq!(deleted.revision, None);
+
+        assert!(matches!(parse_push("create", b"[1]"), Err(HookError::InvalidBody(_))));
+        assert!(matches!(parse_push("create", b"not json"), Err(HookError::InvalidBody(_))));
+    }
+
+    #[test]
+    fn rejects_unknown_unsigned_and_disabled_repos() {
+        let config = config::check_config(include_str!("../../../config/example.toml")).unwrap().config;
+        let body = br#"{"branch":"trunk"}"#;
+        let mut headers = HashMap::new();
+        assert_eq!(handle_hook(&config, "nope", &headers, body), Err(HookError::UnknownRepo("nope".to_string())));
+        assert_eq!(handle_hook(&config, "pipeline", &headers, body), Err(HookError::NotEnabled("pipeline".to_string())));
+        assert_eq!(handle_hook(&config, "create", &headers, body), Err(HookError::MissingSignature));
+
+        headers.insert(SIGNATURE_HEADER.to_string(), sign("change-me", body));
+        headers.insert(EVENT_HEADER.to_string(), "ping".to_string());
+        assert_eq!(handle_hook(&config, "create", &headers, body), Ok(Accepted::Pong));
+        headers.remove(EVENT_HEADER);
+        // the poller isn't running in tests
+        assert_eq!(
+            handle_hook(&config, "create", &headers, body),
+            Err(HookError::Unavailable(NotifyError::NotStarted)),
+        );
+    }
+}
@@ -3,9 +3,12 @@ use controller::{handle_nav, parse_query_params, UiMode, UiResult};
 use std::{
     backtrace::Backtrace,
     collections::HashMap,
-    io::{BufRead, BufReader, Write},
+    io::{self, BufRead, BufReader, Read, Write},
     net::TcpStream,
 };
+
+pub mod hooks;
+
 // importing like this is nice because all files end up in the binary and stay in RAM for quick
 // access. Also means you just ship the binary instead of files.
 static CUSTOM_HTMX_JS: &[u8] = include_bytes!("../../../static/custom_htmx.js");
@@ -17,35 +20,82 @@ static LANDING_PAGE_CSS: &[u8] = include_bytes!("../../../static/landing_page.cs
 static LANDING_PAGE_JS: &[u8] = include_bytes!("../../../static/landing_page.js");
 static SETTINGS_PAGE_CSS: &[u8] = include_bytes!("../../../static/settings_page.css");
 static SERVICE_PAGE_CSS: &[u8] = include_bytes!("../../../static/service_page.css");
+static DEPLOYMENTS_PAGE_CSS: &[u8] = include_bytes!("../../../static/deployments_page.css");
 static INTERNAL_ERROR_HTML: &[u8] = b"<html><body><h1>Internal Server Error</h1></body></html>";
 // static WASM_HELLO: &[u8] = include_bytes!("../wasm-hello/pkg/wasm_hello.js");
 // static WASM_HELLO_RUST: &[u8] = include_bytes!("../wasm-hello/pkg/wasm_hello_bg.wasm");
 
+/// Most bytes the request line and headers may take together.
+pub const MAX_HEAD: usize = 16 * 1024;
+
 pub struct RequestLine {
-    method: String,
-    path: String,
-    version: String,
+    pub method: String,
+    pub path: String,
+    pub version: String,
 }
 
 impl RequestLine {
 }
 
+/// Request line plus the headers that follow it, names lowercased.
+pub struct RequestHead {
+    pub line: RequestLine,
+    pub query_params: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+}
+
 pub fn handle_http_connection(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&stream);
-    let request_line = buf_reader.lines().next();
-    let (request_line, query_params) = match request_line {
-        Some(request_line) => match request_line {
-            Ok(request_line) => parse_request_line(request_line),
-            Err(err) => {
-                println!("error, when reading request line {err}");
-                return;
-            }
-        },
-        None => {
+    let mut buf_reader = BufReader::new(&stream);
+    let RequestHead { line: request_line, query_params, headers } = match read_request_head(&mut buf_reader) {
+        Ok(Some(head)) => head,
+        Ok(None) => {
             println!("error, no request line found");
             return;
         }
+        Err(HeadError::TooLarge) => {
+            let message = format!("request line and headers are over the {MAX_HEAD} byte limit");
+            let status_line = "HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE";
+            write_response(&mut stream, status_line, "text/plain; charset=utf-8", false, message.as_bytes());
+            return;
+        }
+        Err(HeadError::Io(err)) => {
+            println!("error, when reading request line {err}");
+            return;
+        }
     };
+    if request_line.method == "GET" && request_line.path == ws::PATH && ws::is_upgrade_request(&headers) {
+        // the client waits for our 101 before sending frames, but keep anything it sent early
+        let read_ahead = buf_reader.buffer().to_vec();
+        if let Err(err) = ws::upgrade(stream, &headers, read_ahead) {
+            eprintln!("error, when upgrading connection to websocket. Error: {}", err);
+        }
+        return;
+    }
+    if request_line.method == "POST"
+        && let Some(repo) = request_line.path.strip_prefix(hooks::PREFIX)
+    {
+        let body = read_body(&mut buf_reader, &headers, hooks::MAX_BODY);
+        let (status_line, message) = match body {
+            Ok(body) => match hooks::handle_hook(get_config(), repo, &headers, &body) {
+                Ok(accepted) => (accepted.status_line(), accepted.to_string()),
+                Err(err) => {
+                    eprintln!("error, when handling push to repo {repo}. Error: {}", err);
+                    (err.status_line(), err.to_string())
+                }
+            },
+            Err(BodyError::LengthRequired) => ("HTTP/1.1 411 LENGTH REQUIRED", "Content-Length is required".to_string()),
+            Err(BodyError::TooLarge(length)) => (
+                "HTTP/1.1 413 PAYLOAD TOO LARGE",
+                format!("body of {length} bytes is over the {} byte limit", hooks::MAX_BODY),
+            ),
+            Err(BodyError::Io(err)) => {
+                eprintln!("error, when reading webhook body. Error: {}", err);
+                return;
+            }
+        };
+        write_response(&mut stream, status_line, "text/plain; charset=utf-8", false, message.as_bytes());
+        return;
+    }
     let config = &get_config();
     let method = request_line.method.as_str();
     let path = request_line.path.as_str();
@@ -80,6 +130,12 @@ pub fn handle_http_connection(mut stream: TcpStream) {
             "text/css",
             true,
         ),
+        ("GET", "/static/deployments_page.css") => (
+            "HTTP/1.1 200 OK",
+            DEPLOYMENTS_PAGE_CSS,
+            "text/css",
+            true,
+        ),
         ("GET", "/static/animation.css") => (
             "HTTP/1.1 200 OK",
             ANIMATION_CSS,
@@ -152,7 +208,6 @@ pub fn handle_http_connection(mut stream: TcpStream) {
         Err(err) => {
             let bt = Backtrace::capture();
             eprintln!("error, when streaming content to client. Error: {}. Stack: {:?}", err, bt);
-            return
         }
     }
 }
@@ -194,6 +249,73 @@ fn parse_request_line(request_line: String) -> (RequestLine, HashMap<String, Str
     (request_line, query_params)
 }
 
+#[derive(Debug)]
+enum HeadError {
+    /// The head went over [`MAX_HEAD`] bytes.
+    TooLarge,
+    Io(io::Error),
+}
+
+/// Read the request line and headers, leaving any body in the reader.
+///
+/// Header names are lowercased. Returns `None` if the client sent nothing.
+fn read_request_head(
+    reader: &mut impl BufRead,
+) -> Result<Option<RequestHead>, HeadError> {
+    let mut left = MAX_HEAD;
+    let mut line = String::new();
+    if read_head_line(reader, &mut line, &mut left)? == 0 {
+        return Ok(None);
+    }
+    let (request_line, query_params) = parse_request_line(line.trim_end().to_string());
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if read_head_line(reader, &mut line, &mut left)? == 0 {
+            break;
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    Ok(Some(RequestHead { line: request_line, query_params, headers }))
+}
+
+/// Read one line of the head, taking what it used from `left`.
+fn read_head_line(reader: &mut impl BufRead, line: &mut String, left: &mut usize) -> Result<usize, HeadError> {
+    let read = reader.by_ref().take(*left as u64).read_line(line).map_err(HeadError::Io)?;
+    *left -= read;
+    if *left == 0 && !line.ends_with('\n') {
+        return Err(HeadError::TooLarge);
+    }
+    Ok(read)
+}
+
+enum BodyError {
+    LengthRequired,
+    TooLarge(usize),
+    Io(io::Error),
+}
+
+/// Read the body announced by `Content-Length`, refusing anything over `max` bytes.
+fn read_body(reader: &mut impl BufRead, headers: &HashMap<String, String>, max: usize) -> Result<Vec<u8>, BodyError> {
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .ok_or(BodyError::LengthRequired)?;
+    if length > max {
+        return Err(BodyError::TooLarge(length));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(BodyError::Io)?;
+    Ok(body)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -220,4 +342,45 @@ mod tests {
         expected.insert("name".to_string(), "hello".to_string());
         assert_eq!(qp, expected);
     }
+
+    #[test]
+    fn decodes_query_params() {
+        let rl = "GET /deployments?service=a%26b+c%2Bd&status=failed&bad=%zz%4 HTTP/1.1".to_string();
+        let (_, qp) = parse_request_line(rl);
+        assert_eq!(qp.get("service").map(String::as_str), Some("a&b c+d"));
+        assert_eq!(qp.get("status").map(String::as_str), Some("failed"));
+        assert_eq!(qp.get("bad").map(String::as_str), Some("%zz%4"));
+    }
+
+    #[test]
+    fn reads_request_head_headers() {
+        let raw = "GET /ws HTTP/1.1\r\nHost: pi\r\nUpgrade: websocket\r\nSec-WebSocket-Key: abc==\r\n\r\nrest";
+        let mut reader = BufReader::new(raw.as_bytes());
+        let head = read_request_head(&mut reader).unwrap().unwrap();
+        assert_eq!(head.line.path, "/ws");
+        assert!(head.query_params.is_empty());
+        let headers = head.headers;
+        assert_eq!(headers.get("upgrade").map(String::as_str), Some("websocket"));
+        assert_eq!(headers.get("sec-websocket-key").map(String::as_str), Some("abc=="));
+        assert_eq!(reader.buffer(), b"rest");
+    }
+
+    #[test]
+    fn reads_body_by_content_length() {
+        let raw = "POST /hooks/create HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello, and more";
+        let mut reader = BufReader::new(raw.as_bytes());
+        let head = read_request_head(&mut reader).unwrap().unwrap();
+        assert!(matches!(read_body(&mut reader, &head.headers, 5), Ok(body) if body == b"hello"));
+        assert!(matches!(read_body(&mut reader, &head.headers, 4), Err(BodyError::TooLarge(5))));
+        assert!(matches!(read_body(&mut reader, &HashMap::new(), 5), Err(BodyError::LengthRequired)));
+    }
+
+    #[test]
+    fn refuses_request_heads_over_the_limit() {
+        let raw = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "x".repeat(MAX_HEAD));
+        let mut reader = BufReader::new(raw.as_bytes());
+        assert!(matches!(read_request_head(&mut reader), Err(HeadError::TooLarge)));
+        let raw = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "x".repeat(MAX_HEAD / 2));
+        assert!(read_request_head(&mut BufReader::new(raw.as_bytes())).unwrap().is_some());
+    }
 }
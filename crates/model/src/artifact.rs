@@ -0,0 +1,367 @@
+//! Index of the builds kept in the artifact store.
+//!
+//! The files themselves live on disk, named after their sha256. The index
+//! records which files make up each stored build, keyed by service,
+//! revision, architecture and the sha256 of the build's file listing, and
+//! which builds each deployment shipped.
+
+use crate::ModelResult;
+use crate::deployment::now_millis;
+use db::{self, DbPool};
+use r2d2_sqlite::rusqlite::{self, OptionalExtension, Row, named_params};
+
+/// A build kept in the artifact store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredArtifact {
+    id: u64,
+    service: String,
+    revision: Option<String>,
+    arch: Option<String>,
+    sha256: String,
+    size: u64,
+    created_at: u64,
+}
+
+impl StoredArtifact {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    /// Revision the build was made from, when it is known.
+    pub fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
+
+    /// Architecture the build is for, `None` for the CI node's own.
+    pub fn arch(&self) -> Option<&str> {
+        self.arch.as_deref()
+    }
+
+    /// Hex sha256 of the build's file listing.
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    /// Total size of the build's files in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+}
+
+/// A file of a stored build.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactFile {
+    path: String,
+    sha256: String,
+    size: u64,
+    mode: u32,
+}
+
+impl ArtifactFile {
+    /// `/` separated path relative to the build's directory.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Unix permission bits the file is deployed with.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+}
+
+/// A build about to be added to the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NewArtifact<'a> {
+    pub service: &'a str,
+    pub revision: Option<&'a str>,
+    pub arch: Option<&'a str>,
+    pub sha256: &'a str,
+    pub files: &'a [NewArtifactFile<'a>],
+}
+
+/// A file of a build about to be added to the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NewArtifactFile<'a> {
+    pub path: &'a str,
+    pub sha256: &'a str,
+    pub size: u64,
+    pub mode: u32,
+}
+
+/// SQLite-backed index of the artifact store.
+#[derive(Clone)]
+pub struct SqliteArtifactModel {
+    pool: DbPool,
+}
+
+impl Default for SqliteArtifactModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const ARTIFACT_COLUMNS: &str = "id, service, revision, arch, sha256, size, created_at";
+
+impl SqliteArtifactModel {
+    pub fn new() -> Self {
+        Self {
+            pool: db::pool().clone(),
+        }
+    }
+
+    pub fn new_with_pool(pool: DbPool) -> Self {
+        Self { pool }
+    }
+
+    /// Add a build to the index, or return the one already there with the
+    /// same service, revision, architecture and content.
+    pub fn add_artifact(&self, new: &NewArtifact) -> ModelResult<StoredArtifact> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let existing = tx
+            .prepare_cached(&format!(
+                "SELECT {ARTIFACT_COLUMNS} FROM artifacts \
+                 WHERE service = :service AND revision IS :revision AND arch IS :arch AND sha256 = :sha256;"
+            ))?
+            .query_row(
+                named_params! {
+                    ":service": new.service,
+                    ":revision": new.revision,
+                    ":arch": new.arch,
+                    ":sha256": new.sha256,
+                },
+                artifact_from_row,
+            )
+            .optional()?;
+        if let Some(existing) = existing {
+            return Ok(existing);
+        }
+        let size: u64 = new.files.iter().map(|file| file.size).sum();
+        let created_at = now_millis();
+        tx.execute(
+            "INSERT INTO artifacts (service, revision, arch, sha256, size, created_at) \
+             VALUES (:service, :revision, :arch, :sha256, :size, :created_at);",
+            named_params! {
+                ":service": new.service,
+                ":revision": new.revision,
+                ":arch": new.arch,
+                ":sha256": new.sha256,
+                ":size": size as i64,
+                ":created_at": created_at as i64,
+            },
+        )?;
+        let id = tx.last_insert_rowid() as u64;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO artifact_files (artifact_id, path, sha256, size, mode) \
+                 VALUES (:artifact_id, :path, :sha256, :size, :mode);",
+            )?;
+            for file in new.files {
+                stmt.execute(named_params! {
+                    ":artifact_id": id as i64,
+                    ":path": file.path,
+                    ":sha256": file.sha256,
+                    ":size": file.size as i64,
+                    ":mode": file.mode,
+                })?;
+            }
+        }
+        tx.commit()?;
+        Ok(StoredArtifact {
+            id,
+            service: new.service.to_string(),
+            revision: new.revision.map(str::to_string),
+            arch: new.arch.map(str::to_string),
+            sha256: new.sha256.to_string(),
+            size,
+            created_at,
+        })
+    }
+
+    /// Fetch a stored build by id.
+    pub fn find_artifact(&self, id: u64) -> ModelResult<Option<StoredArtifact>> {
+        let conn = self.pool.get()?;
+        conn.prepare_cached(&format!("SELECT {ARTIFACT_COLUMNS} FROM artifacts WHERE id = ?1;"))?
+            .query_row([id as i64], artifact_from_row)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// The stored builds of `service`, newest first.
+    pub fn list_artifacts(&self, service: &str) -> ModelResult<Vec<StoredArtifact>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {ARTIFACT_COLUMNS} FROM artifacts WHERE service = ?1 ORDER BY id DESC;"
+        ))?;
+        let rows = stmt.query_map([service], artifact_from_row)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// The files of a stored build, ordered by path.
+    pub fn list_files(&self, artifact_id: u64) -> ModelResult<Vec<ArtifactFile>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT path, sha256, size, mode FROM artifact_files WHERE artifact_id = ?1 ORDER BY path;",
+        )?;
+        let rows = stmt.query_map([artifact_id as i64], |row| {
+            Ok(ArtifactFile {
+                path: row.get(0)?,
+                sha256: row.get(1)?,
+                size: row.get::<_, i64>(2)? as u64,
+                mode: row.get(3)?,
+            })
+        })?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Record that a deployment shipped a stored build.
+    pub fn link_deployment(&self, deployment_id: u64, artifact_id: u64) -> ModelResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT OR IGNORE INTO deployment_artifacts (deployment_id, artifact_id) VALUES (?1, ?2);",
+            [deployment_id as i64, artifact_id as i64],
+        )?;
+        Ok(())
+    }
+
+    /// The stored builds a deployment shipped, one per architecture.
+    pub fn deployment_artifacts(&self, deployment_id: u64) -> ModelResult<Vec<StoredArtifact>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {ARTIFACT_COLUMNS} FROM artifacts \
+             WHERE id IN (SELECT artifact_id FROM deployment_artifacts WHERE deployment_id = ?1) \
+             ORDER BY arch, id;"
+        ))?;
+        let rows = stmt.query_map([deployment_id as i64], artifact_from_row)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Ids of the builds running somewhere: those the last successful
+    /// deployment of each service to each environment shipped.
+    pub fn live_artifact_ids(&self) -> ModelResult<Vec<u64>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT DISTINCT artifact_id FROM deployment_artifacts WHERE deployment_id IN ( \
+                 SELECT MAX(id) FROM deployments WHERE status = 'succeeded' GROUP BY service, environment \
+             ) ORDER BY artifact_id;",
+        )?;
+        let rows = stmt.query_map([], |row| Ok(row.get::<_, i64>(0)? as u64))?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Drop a build from the index, returning the hashes of its files no
+    /// other stored build has.
+    pub fn remove_artifact(&self, id: u64) -> ModelResult<Vec<String>> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let hashes: Vec<String> = {
+            let mut stmt = tx.prepare_cached("SELECT DISTINCT sha256 FROM artifact_files WHERE artifact_id = ?1;")?;
+            let rows = stmt.query_map([id as i64], |row| row.get(0))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        tx.execute("DELETE FROM artifacts WHERE id = ?1;", [id as i64])?;
+        let mut orphaned = Vec::new();
+        {
+            let mut stmt = tx.prepare_cached("SELECT EXISTS (SELECT 1 FROM artifact_files WHERE sha256 = ?1);")?;
+            for hash in hashes {
+                if !stmt.query_row([&hash], |row| row.get::<_, bool>(0))? {
+                    orphaned.push(hash);
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(orphaned)
+    }
+}
+
+fn artifact_from_row(row: &Row) -> rusqlite::Result<StoredArtifact> {
+    Ok(StoredArtifact {
+        id: row.get::<_, i64>(0)? as u64,
+        service: row.get(1)?,
+        revision: row.get(2)?,
+        arch: row.get(3)?,
+        sha256: row.get(4)?,
+        size: row.get::<_, i64>(5)? as u64,
+        created_at: row.get::<_, i64>(6)? as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeploymentStatus, NewDeployment, SqliteDeploymentModel};
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    #[test]
+    fn indexes_builds_and_what_deployments_shipped() {
+        let manager = SqliteConnectionManager::memory().with_init(|conn| conn.pragma_update(None, "foreign_keys", "ON"));
+        let pool = Pool::builder().max_size(1).build(manager).expect("pool");
+        pool.get()
+            .expect("conn")
+            .execute_batch(concat!(
+                include_str!("../../db/migrations/002_create_deployments.sql"),
+                include_str!("../../db/migrations/006_add_deployments_privileged.sql"),
+                include_str!("../../db/migrations/007_add_deployments_release.sql"),
+                include_str!("../../db/migrations/009_create_artifacts.sql"),
+                include_str!("../../db/migrations/010_add_deployments_promoted_from.sql"),
+            ))
+            .expect("create tables");
+        let model = SqliteArtifactModel::new_with_pool(pool.clone());
+        let deployments = SqliteDeploymentModel::new_with_pool(pool);
+
+        let app = NewArtifactFile { path: "app", sha256: "aa", size: 6, mode: 0o755 };
+        let config = NewArtifactFile { path: "config.toml", sha256: "bb", size: 4, mode: 0o644 };
+        let new = NewArtifact { service: "svc", revision: Some("r1"), arch: Some("arm64"), sha256: "11", files: &[app, config] };
+        let first = model.add_artifact(&new).expect("add");
+        assert_eq!(first.size(), 10);
+        assert_eq!(model.add_artifact(&new).expect("add again"), first);
+        let files = model.list_files(first.id()).expect("files");
+        assert_eq!((files[0].path(), files[0].mode()), ("app", 0o755));
+        let second = model
+            .add_artifact(&NewArtifact { revision: Some("r2"), sha256: "22", files: &[app], ..new })
+            .expect("add");
+
+        let nodes = vec!["pi1".to_string()];
+        let deployment = deployments
+            .start_deployment(&NewDeployment {
+                service: "svc",
+                environment: "staging",
+                nodes: &nodes,
+                revision: Some("r1"),
+                initiated_by: "test",
+                privileged: false,
+                release: None,
+                rollback_of: None,
+                promoted_from: None,
+            })
+            .expect("start");
+        model.link_deployment(deployment.id(), first.id()).expect("link");
+        assert_eq!(model.deployment_artifacts(deployment.id()).expect("shipped"), std::slice::from_ref(&first));
+        assert!(model.live_artifact_ids().expect("live").is_empty());
+        deployments.finish_deployment(deployment.id(), DeploymentStatus::Succeeded, Some(0)).expect("finish");
+        assert_eq!(model.live_artifact_ids().expect("live"), [first.id()]);
+
+        // app is still part of the first build
+        assert!(model.remove_artifact(second.id()).expect("remove").is_empty());
+        assert_eq!(model.remove_artifact(first.id()).expect("remove"), ["aa", "bb"]);
+        assert!(model.list_artifacts("svc").expect("list").is_empty());
+        assert!(model.deployment_artifacts(deployment.id()).expect("shipped").is_empty());
+    }
+}
@@ -0,0 +1,912 @@
+//! Deployment history: one row per deploy plus every line of output it produced
+//! and every file it transferred to a node.
+
+use crate::ModelResult;
+use db::{self, DbPool};
+use r2d2_sqlite::rusqlite::{self, OptionalExtension, Row, named_params};
+use std::fmt::{self, Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Lifecycle of a deployment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeploymentStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl DeploymentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "running" => Some(Self::Running),
+            "succeeded" => Some(Self::Succeeded),
+            "failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
+
+impl Display for DeploymentStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How one step of a deployment is going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepStatus {
+    Running,
+    Succeeded,
+    Failed,
+    /// Failed, but the step is allowed to, so the deployment carried on.
+    AllowedFailure,
+}
+
+impl StepStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+            Self::AllowedFailure => "allowed_failure",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "running" => Some(Self::Running),
+            "succeeded" => Some(Self::Succeeded),
+            "failed" => Some(Self::Failed),
+            "allowed_failure" => Some(Self::AllowedFailure),
+            _ => None,
+        }
+    }
+}
+
+impl Display for StepStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Which pipe of the deploy process a log line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+impl LogStream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stdout => "stdout",
+            Self::Stderr => "stderr",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "stderr" => Self::Stderr,
+            _ => Self::Stdout,
+        }
+    }
+}
+
+/// What a sync did to a file on a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferAction {
+    Upload,
+    Delete,
+}
+
+impl TransferAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Upload => "upload",
+            Self::Delete => "delete",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "delete" => Self::Delete,
+            _ => Self::Upload,
+        }
+    }
+}
+
+/// A file a deploy is about to record as transferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NewTransfer<'a> {
+    /// Path relative to the node's `deploy_workspace`.
+    pub path: &'a str,
+    pub action: TransferAction,
+    /// Hex sha256 of an uploaded file.
+    pub hash: Option<&'a str>,
+    pub size: u64,
+}
+
+/// Details known when a deployment starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewDeployment<'a> {
+    pub service: &'a str,
+    pub environment: &'a str,
+    pub nodes: &'a [String],
+    pub revision: Option<&'a str>,
+    pub initiated_by: &'a str,
+    /// Whether the deploy runs as root on its nodes.
+    pub privileged: bool,
+    /// Release directory the deploy switches its nodes to, when the service keeps releases.
+    pub release: Option<&'a str>,
+    /// The deployment this one rolls back.
+    pub rollback_of: Option<u64>,
+    /// The deployment whose builds this one promotes from another environment.
+    pub promoted_from: Option<u64>,
+}
+
+/// Narrows a deployment listing; `None` fields match everything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeploymentFilter<'a> {
+    pub service: Option<&'a str>,
+    pub status: Option<DeploymentStatus>,
+}
+
+/// A recorded deployment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deployment {
+    id: u64,
+    service: String,
+    environment: String,
+    nodes: Vec<String>,
+    revision: Option<String>,
+    initiated_by: String,
+    privileged: bool,
+    release: Option<String>,
+    rollback_of: Option<u64>,
+    promoted_from: Option<u64>,
+    status: DeploymentStatus,
+    exit_code: Option<i32>,
+    started_at: u64,
+    finished_at: Option<u64>,
+}
+
+impl Deployment {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn environment(&self) -> &str {
+        &self.environment
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    /// Revision that was deployed, when it is known.
+    pub fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
+
+    /// Who or what started the deployment.
+    pub fn initiated_by(&self) -> &str {
+        &self.initiated_by
+    }
+
+    /// Whether the deploy ran as root on its nodes.
+    pub fn privileged(&self) -> bool {
+        self.privileged
+    }
+
+    /// Release directory the deploy switched its nodes to.
+    pub fn release(&self) -> Option<&str> {
+        self.release.as_deref()
+    }
+
+    /// The deployment this one rolled back, if it is a rollback.
+    pub fn rollback_of(&self) -> Option<u64> {
+        self.rollback_of
+    }
+
+    pub fn promoted_from(&self) -> Option<u64> {
+        self.promoted_from
+    }
+
+    pub fn status(&self) -> DeploymentStatus {
+        self.status
+    }
+
+    /// Exit code of the deploy process, `None` while running or when killed by a signal.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Start time in milliseconds since the unix epoch.
+    pub fn started_at(&self) -> u64 {
+        self.started_at
+    }
+
+    /// End time in milliseconds since the unix epoch.
+    pub fn finished_at(&self) -> Option<u64> {
+        self.finished_at
+    }
+
+    /// Wall time of a finished deployment in milliseconds.
+    pub fn duration_ms(&self) -> Option<u64> {
+        self.finished_at.map(|end| end.saturating_sub(self.started_at))
+    }
+}
+
+/// A single line of deploy output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeploymentLog {
+    stream: LogStream,
+    line: String,
+    logged_at: u64,
+}
+
+impl DeploymentLog {
+    pub fn stream(&self) -> LogStream {
+        self.stream
+    }
+
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Time the line was read in milliseconds since the unix epoch.
+    pub fn logged_at(&self) -> u64 {
+        self.logged_at
+    }
+}
+
+/// A file a deploy uploaded to or deleted from a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    node: String,
+    path: String,
+    action: TransferAction,
+    hash: Option<String>,
+    size: u64,
+    transferred_at: u64,
+}
+
+impl Transfer {
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Path relative to the node's `deploy_workspace`.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn action(&self) -> TransferAction {
+        self.action
+    }
+
+    /// Hex sha256 of an uploaded file.
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    /// Size of an uploaded file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Time of the transfer in milliseconds since the unix epoch.
+    pub fn transferred_at(&self) -> u64 {
+        self.transferred_at
+    }
+}
+
+/// A step of a deployment and how it went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeploymentStep {
+    name: String,
+    status: StepStatus,
+    started_at: u64,
+    finished_at: Option<u64>,
+}
+
+impl DeploymentStep {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn status(&self) -> StepStatus {
+        self.status
+    }
+
+    /// Time the step started in milliseconds since the unix epoch.
+    pub fn started_at(&self) -> u64 {
+        self.started_at
+    }
+
+    pub fn finished_at(&self) -> Option<u64> {
+        self.finished_at
+    }
+
+    /// How long the step ran, once it finished.
+    pub fn duration_ms(&self) -> Option<u64> {
+        self.finished_at.map(|finished| finished.saturating_sub(self.started_at))
+    }
+}
+
+/// SQLite-backed deployment history.
+#[derive(Clone)]
+pub struct SqliteDeploymentModel {
+    pool: DbPool,
+}
+
+impl Default for SqliteDeploymentModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SqliteDeploymentModel {
+    pub fn new() -> Self {
+        Self {
+            pool: db::pool().clone(),
+        }
+    }
+
+    pub fn new_with_pool(pool: DbPool) -> Self {
+        Self { pool }
+    }
+
+    /// Record a deployment as running and return it.
+    pub fn start_deployment(&self, new: &NewDeployment) -> ModelResult<Deployment> {
+        let conn = self.pool.get()?;
+        let started_at = now_millis();
+        let nodes = new.nodes.join(",");
+        conn.execute(
+            "INSERT INTO deployments \
+             (service, environment, nodes, revision, initiated_by, privileged, release, rollback_of, promoted_from, \
+             status, started_at) \
+             VALUES (:service, :environment, :nodes, :revision, :initiated_by, :privileged, :release, :rollback_of, \
+             :promoted_from, :status, :started_at);",
+            named_params! {
+                ":service": new.service,
+                ":environment": new.environment,
+                ":nodes": nodes,
+                ":revision": new.revision,
+                ":initiated_by": new.initiated_by,
+                ":privileged": new.privileged,
+                ":release": new.release,
+                ":rollback_of": new.rollback_of.map(|id| id as i64),
+                ":promoted_from": new.promoted_from.map(|id| id as i64),
+                ":status": DeploymentStatus::Running.as_str(),
+                ":started_at": started_at as i64,
+            },
+        )?;
+
+        Ok(Deployment {
+            id: conn.last_insert_rowid() as u64,
+            service: new.service.to_string(),
+            environment: new.environment.to_string(),
+            nodes: new.nodes.to_vec(),
+            revision: new.revision.map(str::to_string),
+            initiated_by: new.initiated_by.to_string(),
+            privileged: new.privileged,
+            release: new.release.map(str::to_string),
+            rollback_of: new.rollback_of,
+            promoted_from: new.promoted_from,
+            status: DeploymentStatus::Running,
+            exit_code: None,
+            started_at,
+            finished_at: None,
+        })
+    }
+
+    /// Append one line of output to a deployment.
+    pub fn append_log(&self, deployment_id: u64, stream: LogStream, line: &str) -> ModelResult<()> {
+        let conn = self.pool.get()?;
+        conn.prepare_cached(
+            "INSERT INTO deployment_logs (deployment_id, stream, line, logged_at) \
+             VALUES (:deployment_id, :stream, :line, :logged_at);",
+        )?
+        .execute(named_params! {
+            ":deployment_id": deployment_id as i64,
+            ":stream": stream.as_str(),
+            ":line": line,
+            ":logged_at": now_millis() as i64,
+        })?;
+        Ok(())
+    }
+
+    /// Record the files a sync of a deployment transferred to `node`.
+    pub fn record_transfers(&self, deployment_id: u64, node: &str, transfers: &[NewTransfer]) -> ModelResult<()> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO deployment_transfers (deployment_id, node, path, action, hash, size, transferred_at) \
+                 VALUES (:deployment_id, :node, :path, :action, :hash, :size, :transferred_at);",
+            )?;
+            let transferred_at = now_millis();
+            for transfer in transfers {
+                stmt.execute(named_params! {
+                    ":deployment_id": deployment_id as i64,
+                    ":node": node,
+                    ":path": transfer.path,
+                    ":action": transfer.action.as_str(),
+                    ":hash": transfer.hash,
+                    ":size": transfer.size as i64,
+                    ":transferred_at": transferred_at as i64,
+                })?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Files a deployment transferred, in the order they were recorded.
+    pub fn list_transfers(&self, deployment_id: u64) -> ModelResult<Vec<Transfer>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT node, path, action, hash, size, transferred_at FROM deployment_transfers \
+             WHERE deployment_id = ?1 ORDER BY id;",
+        )?;
+        let rows = stmt.query_map([deployment_id as i64], |row| {
+            Ok(Transfer {
+                node: row.get(0)?,
+                path: row.get(1)?,
+                action: TransferAction::parse(&row.get::<_, String>(2)?),
+                hash: row.get(3)?,
+                size: row.get::<_, i64>(4)? as u64,
+                transferred_at: row.get::<_, i64>(5)? as u64,
+            })
+        })?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Record that a step of a deployment started or ended. A step is known
+    /// by its name within the deployment; starting it again starts it over.
+    pub fn record_step(&self, deployment_id: u64, name: &str, status: StepStatus) -> ModelResult<()> {
+        let conn = self.pool.get()?;
+        let now = now_millis() as i64;
+        if status == StepStatus::Running {
+            conn.execute(
+                "INSERT INTO deployment_steps (deployment_id, name, status, started_at) \
+                 VALUES (:deployment_id, :name, :status, :now) \
+                 ON CONFLICT (deployment_id, name) DO UPDATE SET status = :status, started_at = :now, finished_at = NULL;",
+                named_params! { ":deployment_id": deployment_id as i64, ":name": name, ":status": status.as_str(), ":now": now },
+            )?;
+        } else {
+            conn.execute(
+                "INSERT INTO deployment_steps (deployment_id, name, status, started_at, finished_at) \
+                 VALUES (:deployment_id, :name, :status, :now, :now) \
+                 ON CONFLICT (deployment_id, name) DO UPDATE SET status = :status, finished_at = :now;",
+                named_params! { ":deployment_id": deployment_id as i64, ":name": name, ":status": status.as_str(), ":now": now },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Steps of a deployment in the order they started.
+    pub fn list_steps(&self, deployment_id: u64) -> ModelResult<Vec<DeploymentStep>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT name, status, started_at, finished_at FROM deployment_steps \
+             WHERE deployment_id = ?1 ORDER BY started_at, id;",
+        )?;
+        let rows = stmt.query_map([deployment_id as i64], |row| {
+            Ok(DeploymentStep {
+                name: row.get(0)?,
+                status: StepStatus::parse(&row.get::<_, String>(1)?).unwrap_or(StepStatus::Failed),
+                started_at: row.get::<_, i64>(2)? as u64,
+                finished_at: row.get::<_, Option<i64>>(3)?.map(|at| at as u64),
+            })
+        })?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Mark a deployment finished with its final status and exit code.
+    pub fn finish_deployment(
+        &self,
+        deployment_id: u64,
+        status: DeploymentStatus,
+        exit_code: Option<i32>,
+    ) -> ModelResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "UPDATE deployments SET status = :status, exit_code = :exit_code, finished_at = :finished_at \
+             WHERE id = :id;",
+            named_params! {
+                ":status": status.as_str(),
+                ":exit_code": exit_code,
+                ":finished_at": now_millis() as i64,
+                ":id": deployment_id as i64,
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Name the release a running deployment switches its nodes to, once it
+    /// is known.
+    pub fn set_release(&self, deployment_id: u64, release: &str) -> ModelResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "UPDATE deployments SET release = :release WHERE id = :id;",
+            named_params! { ":release": release, ":id": deployment_id as i64 },
+        )?;
+        Ok(())
+    }
+
+    /// Deployments of `service` to `environment` that switched to a release,
+    /// oldest first.
+    pub fn list_releases(&self, service: &str, environment: &str) -> ModelResult<Vec<Deployment>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {DEPLOYMENT_COLUMNS} FROM deployments \
+             WHERE service = ?1 AND environment = ?2 AND release IS NOT NULL ORDER BY id;"
+        ))?;
+        let rows = stmt.query_map([service, environment], deployment_from_row)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// The finished deployments of `service` to `environment`, newest first,
+    /// back to the last one that succeeded. All of them when none did.
+    pub fn finished_since_success(&self, service: &str, environment: &str) -> ModelResult<Vec<Deployment>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {DEPLOYMENT_COLUMNS} FROM deployments \
+             WHERE service = ?1 AND environment = ?2 AND status != ?3 AND id >= COALESCE(( \
+                 SELECT MAX(id) FROM deployments WHERE service = ?1 AND environment = ?2 AND status = ?4 \
+             ), 0) ORDER BY id DESC;"
+        ))?;
+        let params = [service, environment, DeploymentStatus::Running.as_str(), DeploymentStatus::Succeeded.as_str()];
+        let rows = stmt.query_map(params, deployment_from_row)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Fetch a deployment by id.
+    pub fn find_deployment(&self, id: u64) -> ModelResult<Option<Deployment>> {
+        let conn = self.pool.get()?;
+        conn.prepare_cached(&format!("SELECT {DEPLOYMENT_COLUMNS} FROM deployments WHERE id = ?1;"))?
+            .query_row([id as i64], deployment_from_row)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Most recent deployments first, narrowed by `filter`.
+    pub fn list_deployments(&self, filter: &DeploymentFilter, limit: u32) -> ModelResult<Vec<Deployment>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {DEPLOYMENT_COLUMNS} FROM deployments \
+             WHERE (:service IS NULL OR service = :service) AND (:status IS NULL OR status = :status) \
+             ORDER BY started_at DESC, id DESC LIMIT :limit;"
+        ))?;
+        let rows = stmt.query_map(
+            named_params! {
+                ":service": filter.service,
+                ":status": filter.status.map(|status| status.as_str()),
+                ":limit": limit,
+            },
+            deployment_from_row,
+        )?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Output of a deployment in the order it was produced.
+    pub fn list_logs(&self, deployment_id: u64) -> ModelResult<Vec<DeploymentLog>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT stream, line, logged_at FROM deployment_logs WHERE deployment_id = ?1 ORDER BY id;",
+        )?;
+        let rows = stmt.query_map([deployment_id as i64], |row| {
+            Ok(DeploymentLog {
+                stream: LogStream::parse(&row.get::<_, String>(0)?),
+                line: row.get(1)?,
+                logged_at: row.get::<_, i64>(2)? as u64,
+            })
+        })?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+}
+
+const DEPLOYMENT_COLUMNS: &str =
+    "id, service, environment, nodes, revision, initiated_by, status, exit_code, started_at, finished_at, privileged, \
+     release, rollback_of, promoted_from";
+
+fn deployment_from_row(row: &Row) -> rusqlite::Result<Deployment> {
+    let nodes: String = row.get(3)?;
+    let status: String = row.get(6)?;
+    Ok(Deployment {
+        id: row.get::<_, i64>(0)? as u64,
+        service: row.get(1)?,
+        environment: row.get(2)?,
+        nodes: nodes
+            .split(',')
+            .filter(|node| !node.is_empty())
+            .map(str::to_string)
+            .collect(),
+        revision: row.get(4)?,
+        initiated_by: row.get(5)?,
+        status: DeploymentStatus::parse(&status).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(
+                6,
+                rusqlite::types::Type::Text,
+                format!("unknown deployment status '{status}'").into(),
+            )
+        })?,
+        exit_code: row.get(7)?,
+        started_at: row.get::<_, i64>(8)? as u64,
+        finished_at: row.get::<_, Option<i64>>(9)?.map(|t| t as u64),
+        privileged: row.get(10)?,
+        release: row.get(11)?,
+        rollback_of: row.get::<_, Option<i64>>(12)?.map(|id| id as u64),
+        promoted_from: row.get::<_, Option<i64>>(13)?.map(|id| id as u64),
+    })
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("SystemTime set to a time before UNIX EPOCH!")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    fn model() -> SqliteDeploymentModel {
+        let manager = SqliteConnectionManager::memory();
+        let pool = Pool::builder().max_size(1).build(manager).expect("pool");
+        pool.get()
+            .expect("conn")
+            .execute_batch(concat!(
+                include_str!("../../db/migrations/002_create_deployments.sql"),
+                include_str!("../../db/migrations/003_create_deployment_logs.sql"),
+                include_str!("../../db/migrations/005_create_deployment_transfers.sql"),
+                include_str!("../../db/migrations/006_add_deployments_privileged.sql"),
+                include_str!("../../db/migrations/007_add_deployments_release.sql"),
+                include_str!("../../db/migrations/010_add_deployments_promoted_from.sql"),
+                include_str!("../../db/migrations/008_create_deployment_steps.sql"),
+            ))
+            .expect("create tables");
+        SqliteDeploymentModel::new_with_pool(pool)
+    }
+
+    #[test]
+    fn records_deployment_lifecycle() {
+        let model = model();
+        let nodes = vec!["pi1".to_string(), "pi2".to_string()];
+        let started = model
+            .start_deployment(&NewDeployment {
+                service: "svc",
+                environment: "staging",
+                nodes: &nodes,
+                revision: Some("abc123"),
+                initiated_by: "web:127.0.0.1",
+                privileged: true,
+                release: None,
+                rollback_of: None,
+                promoted_from: None,
+            })
+            .expect("start");
+        assert_eq!(started.status(), DeploymentStatus::Running);
+
+        model.finish_deployment(started.id(), DeploymentStatus::Failed, Some(2)).expect("finish");
+        let fetched = model.find_deployment(started.id()).expect("find").unwrap();
+        assert_eq!(fetched.nodes(), nodes.as_slice());
+        assert_eq!(fetched.revision(), Some("abc123"));
+        assert!(fetched.privileged());
+        assert_eq!(fetched.status(), DeploymentStatus::Failed);
+        assert_eq!(fetched.exit_code(), Some(2));
+        assert!(fetched.finished_at().is_some());
+        assert_eq!(model.list_deployments(&DeploymentFilter::default(), 10).expect("list"), vec![fetched]);
+    }
+
+    #[test]
+    fn filters_deployments_by_service_and_status() {
+        let model = model();
+        let nodes = vec!["local".to_string()];
+        for service in ["api", "web", "api"] {
+            model
+                .start_deployment(&NewDeployment {
+                    service,
+                    environment: "development",
+                    nodes: &nodes,
+                    revision: None,
+                    initiated_by: "cli",
+                    privileged: false,
+                    release: None,
+                    rollback_of: None,
+                    promoted_from: None,
+                })
+                .expect("start");
+        }
+        model.finish_deployment(1, DeploymentStatus::Succeeded, Some(0)).expect("finish");
+
+        let api = DeploymentFilter { service: Some("api"), status: None };
+        assert_eq!(model.list_deployments(&api, 10).expect("list").len(), 2);
+        let running_api = DeploymentFilter { service: Some("api"), status: Some(DeploymentStatus::Running) };
+        let running = model.list_deployments(&running_api, 10).expect("list");
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].id(), 3);
+    }
+
+    #[test]
+    fn keeps_log_lines_in_order() {
+        let model = model();
+        let deployment = model
+            .start_deployment(&NewDeployment {
+                service: "svc",
+                environment: "development",
+                nodes: &["local".to_string()],
+                revision: None,
+                initiated_by: "cli",
+                privileged: false,
+                release: None,
+                rollback_of: None,
+                promoted_from: None,
+            })
+            .expect("start");
+        model.append_log(deployment.id(), LogStream::Stdout, "building").expect("log");
+        model.append_log(deployment.id(), LogStream::Stderr, "warning: unused").expect("log");
+
+        let logs = model.list_logs(deployment.id()).expect("logs");
+        let lines: Vec<(LogStream, &str)> = logs.iter().map(|l| (l.stream(), l.line())).collect();
+        assert_eq!(
+            lines,
+            vec![(LogStream::Stdout, "building"), (LogStream::Stderr, "warning: unused")],
+        );
+    }
+
+    #[test]
+    fn records_transfers_per_node() {
+        let model = model();
+        let deployment = model
+            .start_deployment(&NewDeployment {
+                service: "svc",
+                environment: "staging",
+                nodes: &["pi1".to_string()],
+                revision: None,
+                initiated_by: "cli",
+                privileged: false,
+                release: None,
+                rollback_of: None,
+                promoted_from: None,
+            })
+            .expect("start");
+        model
+            .record_transfers(deployment.id(), "pi1", &[
+                NewTransfer { path: "app", action: TransferAction::Upload, hash: Some("ab12"), size: 42 },
+                NewTransfer { path: "old.txt", action: TransferAction::Delete, hash: None, size: 0 },
+            ])
+            .expect("record");
+
+        let transfers = model.list_transfers(deployment.id()).expect("transfers");
+        let recorded: Vec<(&str, &str, TransferAction, Option<&str>, u64)> = transfers
+            .iter()
+            .map(|t| (t.node(), t.path(), t.action(), t.hash(), t.size()))
+            .collect();
+        assert_eq!(recorded, [
+            ("pi1", "app", TransferAction::Upload, Some("ab12"), 42),
+            ("pi1", "old.txt", TransferAction::Delete, None, 0),
+        ]);
+    }
+
+    #[test]
+    fn lists_releases_of_a_service_environment() {
+        let model = model();
+        let nodes = ["pi1".to_string()];
+        let deploy = |environment, release, rollback_of| {
+            model
+                .start_deployment(&NewDeployment {
+                    service: "svc",
+                    environment,
+                    nodes: &nodes,
+                    revision: None,
+                    initiated_by: "cli",
+                    privileged: false,
+                    release,
+                    rollback_of,
+                    promoted_from: None,
+                })
+                .expect("start")
+        };
+        let first = deploy("staging", Some("abc123"), None);
+        let unnamed = deploy("staging", None, None);
+        deploy("production", Some("abc123"), None);
+        model.set_release(unnamed.id(), &format!("deploy-{}", unnamed.id())).expect("name release");
+        deploy("staging", Some("abc123"), Some(unnamed.id()));
+
+        let releases = model.list_releases("svc", "staging").expect("releases");
+        let listed: Vec<(Option<&str>, Option<u64>)> = releases.iter().map(|d| (d.release(), d.rollback_of())).collect();
+        assert_eq!(listed, [
+            (Some("abc123"), None),
+            (Some("deploy-2"), None),
+            (Some("abc123"), Some(unnamed.id())),
+        ]);
+        assert_eq!(releases[0], model.find_deployment(first.id()).expect("find").expect("recorded"));
+    }
+
+    #[test]
+    fn finds_what_an_environment_runs_and_where_it_came_from() {
+        let model = model();
+        let nodes = ["pi1".to_string()];
+        let deploy = |environment, promoted_from| {
+            model
+                .start_deployment(&NewDeployment {
+                    service: "svc",
+                    environment,
+                    nodes: &nodes,
+                    revision: Some("abc123"),
+                    initiated_by: "cli",
+                    privileged: false,
+                    release: None,
+                    rollback_of: None,
+                    promoted_from,
+                })
+                .expect("start")
+        };
+        let finished = |model: &SqliteDeploymentModel| -> Vec<u64> {
+            let since = model.finished_since_success("svc", "staging").expect("finished");
+            since.iter().map(Deployment::id).collect()
+        };
+        let failed = deploy("staging", None);
+        model.finish_deployment(failed.id(), DeploymentStatus::Failed, Some(1)).expect("finish");
+        let staging = deploy("staging", None);
+        assert_eq!(finished(&model), [failed.id()]);
+        model.finish_deployment(staging.id(), DeploymentStatus::Succeeded, Some(0)).expect("finish");
+        let broken = deploy("staging", None);
+        model.finish_deployment(broken.id(), DeploymentStatus::Failed, Some(1)).expect("finish");
+        deploy("staging", None);
+        let production = deploy("production", Some(staging.id()));
+
+        assert_eq!(finished(&model), [broken.id(), staging.id()]);
+        let promoted = model.find_deployment(production.id()).expect("find").expect("recorded");
+        assert_eq!(promoted.promoted_from(), Some(staging.id()));
+    }
+
+    #[test]
+    fn records_step_statuses() {
+        let model = model();
+        let deployment = model
+            .start_deployment(&NewDeployment {
+                service: "svc",
+                environment: "staging",
+                nodes: &["pi1".to_string()],
+                revision: None,
+                initiated_by: "test",
+                privileged: false,
+                release: None,
+                rollback_of: None,
+                promoted_from: None,
+            })
+            .expect("start");
+        model.record_step(deployment.id(), "build svc", StepStatus::Running).expect("start build");
+        model.record_step(deployment.id(), "build svc", StepStatus::Succeeded).expect("finish build");
+        model.record_step(deployment.id(), "test: lint", StepStatus::AllowedFailure).expect("lint");
+        model.record_step(deployment.id(), "sync pi1", StepStatus::Running).expect("start sync");
+
+        let steps = model.list_steps(deployment.id()).expect("steps");
+        let listed: Vec<(&str, StepStatus, bool)> =
+            steps.iter().map(|step| (step.name(), step.status(), step.finished_at().is_some())).collect();
+        assert_eq!(listed, [
+            ("build svc", StepStatus::Succeeded, true),
+            ("test: lint", StepStatus::AllowedFailure, true),
+            ("sync pi1", StepStatus::Running, false),
+        ]);
+    }
+}
@@ -8,6 +8,16 @@ use r2d2_sqlite::rusqlite::{OptionalExtension, named_params};
 use std::fmt::{self, Display, Formatter};
 use db::{self, DbPool};
 
+pub mod artifact;
+pub use artifact::{ArtifactFile, NewArtifact, NewArtifactFile, SqliteArtifactModel, StoredArtifact};
+pub mod deployment;
+pub use deployment::{
+    Deployment, DeploymentFilter, DeploymentLog, DeploymentStatus, DeploymentStep, LogStream, NewDeployment,
+    NewTransfer, SqliteDeploymentModel, StepStatus, Transfer, TransferAction,
+};
+pub mod revision;
+pub use revision::SqliteRevisionModel;
+
 /// Errors that can occur during model operations.
 #[derive(Debug)]
 pub enum ModelError {
@@ -81,6 +91,12 @@ pub struct SqliteUserModel {
     pool: DbPool,
 }
 
+impl Default for SqliteUserModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SqliteUserModel {
     pub fn new() -> Self {
         Self {
@@ -0,0 +1,82 @@
+//! The newest revision seen on each repo branch, so a restart doesn't
+//! rebuild commits that were already handled.
+
+use crate::ModelResult;
+use crate::deployment::now_millis;
+use db::{self, DbPool};
+use r2d2_sqlite::rusqlite::{OptionalExtension, named_params};
+
+/// SQLite-backed record of the last revision seen per repo and branch.
+#[derive(Clone)]
+pub struct SqliteRevisionModel {
+    pool: DbPool,
+}
+
+impl Default for SqliteRevisionModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SqliteRevisionModel {
+    pub fn new() -> Self {
+        Self {
+            pool: db::pool().clone(),
+        }
+    }
+
+    pub fn new_with_pool(pool: DbPool) -> Self {
+        Self { pool }
+    }
+
+    /// The revision last recorded for `branch` of `repo`, if any.
+    pub fn last_seen(&self, repo: &str, branch: &str) -> ModelResult<Option<String>> {
+        let conn = self.pool.get()?;
+        conn.prepare_cached("SELECT revision FROM repo_revisions WHERE repo = ?1 AND branch = ?2;")?
+            .query_row([repo, branch], |row| row.get(0))
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Remember `revision` as the newest one on `branch` of `repo`.
+    pub fn record_seen(&self, repo: &str, branch: &str, revision: &str) -> ModelResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO repo_revisions (repo, branch, revision, seen_at) \
+             VALUES (:repo, :branch, :revision, :seen_at) \
+             ON CONFLICT(repo, branch) DO UPDATE SET revision = excluded.revision, seen_at = excluded.seen_at;",
+            named_params! {
+                ":repo": repo,
+                ":branch": branch,
+                ":revision": revision,
+                ":seen_at": now_millis() as i64,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    #[test]
+    fn keeps_newest_revision_per_branch() {
+        let manager = SqliteConnectionManager::memory();
+        let pool = Pool::builder().max_size(1).build(manager).expect("pool");
+        pool.get()
+            .expect("conn")
+            .execute_batch(include_str!("../../db/migrations/004_create_repo_revisions.sql"))
+            .expect("create table");
+        let model = SqliteRevisionModel::new_with_pool(pool);
+
+        assert_eq!(model.last_seen("create", "trunk").unwrap(), None);
+        model.record_seen("create", "trunk", "a1").unwrap();
+        model.record_seen("create", "trunk", "b2").unwrap();
+        model.record_seen("create", "release", "c3").unwrap();
+        assert_eq!(model.last_seen("create", "trunk").unwrap().as_deref(), Some("b2"));
+        assert_eq!(model.last_seen("create", "release").unwrap().as_deref(), Some("c3"));
+    }
+}
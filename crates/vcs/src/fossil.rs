@@ -0,0 +1,267 @@
+use crate::{Repository, Revision, VcsError, run};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A fossil repository file cloned to `db_file` with a checkout opened in `dir`.
+pub struct FossilRepository {
+    name: String,
+    clone_url: String,
+    dir: PathBuf,
+    db_file: PathBuf,
+}
+
+impl FossilRepository {
+    pub fn new(name: &str, clone_url: &str, dir: PathBuf, db_file: PathBuf) -> Self {
+        Self {
+            name: name.to_string(),
+            clone_url: clone_url.to_string(),
+            dir,
+            db_file,
+        }
+    }
+
+    /// A fossil command run inside the checkout.
+    fn fossil(&self) -> Command {
+        let mut command = Command::new("fossil");
+        command.current_dir(&self.dir);
+        command
+    }
+
+    fn is_open(&self) -> bool {
+        self.dir.join(".fslckout").exists() || self.dir.join("_FOSSIL_").exists()
+    }
+
+    fn has_commit(&self, revision: &str) -> bool {
+        run(self.fossil().args(["info", revision])).is_ok()
+    }
+}
+
+impl Repository for FossilRepository {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn ensure_cloned(&self) -> Result<bool, VcsError> {
+        let mut changed = false;
+        if !self.db_file.exists() {
+            create_dir(self.db_file.parent())?;
+            let mut clone = Command::new("fossil");
+            clone.args(["clone", &self.clone_url]).arg(&self.db_file);
+            run(&mut clone)?;
+            changed = true;
+        }
+        if !self.is_open() {
+            create_dir(Some(&self.dir))?;
+            // --force because the directory may already hold files from an earlier checkout
+            run(self.fossil().arg("open").arg(&self.db_file).arg("--force"))?;
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    fn pull(&self) -> Result<Revision, VcsError> {
+        run(self.fossil().arg("pull"))?;
+        run(self.fossil().arg("update"))?;
+        self.current()
+    }
+
+    fn checkout(&self, revision: &str) -> Result<Revision, VcsError> {
+        if !self.has_commit(revision) {
+            run(self.fossil().arg("pull"))?;
+        }
+        run(self.fossil().args(["checkout", "--force", revision]))?;
+        self.current()
+    }
+
+    fn current(&self) -> Result<Revision, VcsError> {
+        let output = run(self.fossil().arg("info"))?;
+        parse_info(&output).ok_or(VcsError::UnexpectedOutput {
+            command: "fossil info".to_string(),
+            output,
+        })
+    }
+
+    fn branch(&self) -> Result<String, VcsError> {
+        let output = run(self.fossil().args(["branch", "current"]))?;
+        parse_branch(&output).ok_or(VcsError::UnexpectedOutput {
+            command: "fossil branch current".to_string(),
+            output,
+        })
+    }
+
+    fn changed_files(&self, from: &str, to: &str) -> Result<Vec<String>, VcsError> {
+        let output = run(self.fossil().args(["diff", "--brief", "--from", from, "--to", to]))?;
+        Ok(parse_brief_diff(&output))
+    }
+}
+
+fn create_dir(dir: Option<&Path>) -> Result<(), VcsError> {
+    let Some(dir) = dir else {
+        return Ok(());
+    };
+    fs::create_dir_all(dir).map_err(|source| VcsError::Io {
+        command: format!("mkdir -p {}", dir.display()),
+        source,
+    })
+}
+
+/// Pull the checkout hash and comment out of `fossil info`.
+///
+/// Long comments wrap onto indented lines, and end with `(user: name)`.
+fn parse_info(output: &str) -> Option<Revision> {
+    let mut id = None;
+    let mut comment: Option<String> = None;
+    let mut in_comment = false;
+    for line in output.lines() {
+        if let Some(rest) = line.strip_prefix("checkout:") {
+            id = rest.split_whitespace().next().map(str::to_string);
+            in_comment = false;
+        } else if let Some(rest) = line.strip_prefix("comment:") {
+            comment = Some(rest.trim().to_string());
+            in_comment = true;
+        } else if in_comment && line.starts_with(char::is_whitespace) {
+            if let Some(comment) = comment.as_mut() {
+                comment.push(' ');
+                comment.push_str(line.trim());
+            }
+        } else {
+            in_comment = false;
+        }
+    }
+    let comment = comment?;
+    let message = match comment.rfind(" (user: ") {
+        Some(end) if comment.ends_with(')') => &comment[..end],
+        _ => comment.as_str(),
+    };
+    Some(Revision {
+        id: id?,
+        message: message.to_string(),
+    })
+}
+
+/// The branch name `fossil branch current` prints on a line of its own.
+fn parse_branch(output: &str) -> Option<String> {
+    Some(output.trim()).filter(|branch| !branch.is_empty()).map(str::to_string)
+}
+
+/// File names out of `fossil diff --brief`, one `STATUS  name` per line.
+fn parse_brief_diff(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.trim_start().split_once(char::is_whitespace))
+        .map(|(_, name)| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fossil_info() {
+        let output = "\
+project-name: create
+repository:   /home/pi/museum/create.fossil
+local-root:   /home/pi/create/
+checkout:     5e3d4b9a1c2f 2026-10-17 12:00:00 UTC
+parent:       1a2b3c4d5e6f 2026-10-16 09:30:00 UTC
+tags:         trunk
+comment:      Move the build script into the service directory so each
+              service owns its own (user: pi)
+check-ins:    42
+";
+        assert_eq!(
+            parse_info(output),
+            Some(Revision {
+                id: "5e3d4b9a1c2f".to_string(),
+                message: "Move the build script into the service directory so each service owns its own".to_string(),
+            }),
+        );
+        assert_eq!(parse_info("project-name: create\n"), None);
+    }
+
+    #[test]
+    fn parses_fossil_info_without_a_user() {
+        let output = "checkout:     5e3d4b9a1c2f 2026-10-17 12:00:00 UTC\ncomment:      Fix (the) build\ntags:         trunk\n";
+        assert_eq!(
+            parse_info(output),
+            Some(Revision { id: "5e3d4b9a1c2f".to_string(), message: "Fix (the) build".to_string() }),
+        );
+        // lines after the comment that aren't indented don't belong to it
+        let output = "comment:      Fix the build (user: pi)\nchecked-in:   yes\ncheckout:     9f8e7d6c5b4a 2026-10-17\n";
+        assert_eq!(parse_info(output).map(|revision| revision.message), Some("Fix the build".to_string()));
+        assert_eq!(parse_info("checkout:     5e3d4b9a1c2f 2026-10-17\n"), None);
+    }
+
+    #[test]
+    fn parses_current_branch() {
+        assert_eq!(parse_branch("trunk\n"), Some("trunk".to_string()));
+        assert_eq!(parse_branch("  release-2\n\n"), Some("release-2".to_string()));
+        assert_eq!(parse_branch("\n"), None);
+    }
+
+    #[test]
+    fn parses_brief_diff() {
+        let output = "CHANGED  example_service_1/build.sh\nADDED    shared/with space.sh\nDELETED  old.txt\n";
+        assert_eq!(
+            parse_brief_diff(output),
+            ["example_service_1/build.sh", "shared/with space.sh", "old.txt"],
+        );
+        assert!(parse_brief_diff("").is_empty());
+        assert_eq!(parse_brief_diff("\n  EDITED   a.txt\nUNKNOWN\n"), ["a.txt"]);
+    }
+
+    fn fossil(dir: &Path, args: &[&str]) -> String {
+        let mut command = Command::new("fossil");
+        command.current_dir(dir).args(args);
+        run(&mut command).unwrap_or_else(|e| panic!("fossil {args:?} failed: {e}"))
+    }
+
+    fn commit(seed: &Path, file: &str, message: &str) -> String {
+        fs::write(seed.join(file), message).unwrap();
+        fossil(seed, &["add", file]);
+        fossil(seed, &["commit", "--no-prompt", "--user-override", "test", "-m", message]);
+        parse_info(&fossil(seed, &["info"])).unwrap().id
+    }
+
+    #[test]
+    #[ignore = "needs the fossil binary, run with --ignored where it is installed"]
+    fn clones_pulls_and_checks_out() {
+        let root = std::env::temp_dir().join(format!("vcs-fossil-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let seed = root.join("seed");
+        fs::create_dir_all(&seed).unwrap();
+        let origin = root.join("origin.fossil");
+        fossil(&root, &["init", "--admin-user", "test", origin.to_str().unwrap()]);
+        fossil(&seed, &["open", origin.to_str().unwrap()]);
+        let first = commit(&seed, "a.txt", "first");
+
+        let repo = FossilRepository::new(
+            "test",
+            origin.to_str().unwrap(),
+            root.join("work"),
+            root.join("museum").join("test.fossil"),
+        );
+        assert!(repo.ensure_cloned().unwrap());
+        assert!(!repo.ensure_cloned().unwrap());
+        assert_eq!(repo.current().unwrap(), Revision { id: first.clone(), message: "first".to_string() });
+
+        let second = commit(&seed, "b.txt", "second");
+        assert_eq!(repo.pull().unwrap().id, second);
+
+        assert_eq!(repo.checkout(&first).unwrap().id, first);
+        assert!(!repo.dir().join("b.txt").exists());
+
+        let third = commit(&seed, "c.txt", "third");
+        assert_eq!(repo.checkout(&third).unwrap().message, "third");
+        assert_eq!(repo.changed_files(&first, &third).unwrap(), ["b.txt", "c.txt"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{Repository, Revision, VcsError, run};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A git clone that tracks the remote's default branch.
+pub struct GitRepository {
+    name: String,
+    clone_url: String,
+    dir: PathBuf,
+}
+
+impl GitRepository {
+    pub fn new(name: &str, clone_url: &str, dir: PathBuf) -> Self {
+        Self {
+            name: name.to_string(),
+            clone_url: clone_url.to_string(),
+            dir,
+        }
+    }
+
+    fn git(&self) -> Command {
+        let mut command = Command::new("git");
+        // never block on a credential prompt, there is nobody to answer it
+        command.arg("-C").arg(&self.dir).env("GIT_TERMINAL_PROMPT", "0");
+        command
+    }
+
+    fn fetch(&self) -> Result<(), VcsError> {
+        run(self.git().args(["fetch", "--quiet", "--prune", "origin"])).map(|_| ())
+    }
+
+    /// The branch `origin/HEAD` points at, e.g. `main`.
+    fn default_branch(&self) -> Result<String, VcsError> {
+        let head = || run(self.git().args(["rev-parse", "--abbrev-ref", "origin/HEAD"]));
+        let head = match head() {
+            Ok(head) => head,
+            // clones of an empty repo don't get origin/HEAD, ask the remote once it has commits
+            Err(_) => {
+                run(self.git().args(["remote", "set-head", "origin", "--auto"]))?;
+                head()?
+            }
+        };
+        let head = head.trim();
+        head.strip_prefix("origin/")
+            .map(str::to_string)
+            .ok_or_else(|| VcsError::UnexpectedOutput {
+                command: "git rev-parse --abbrev-ref origin/HEAD".to_string(),
+                output: head.to_string(),
+            })
+    }
+
+    fn has_commit(&self, revision: &str) -> bool {
+        run(self.git().args(["cat-file", "-e", &format!("{revision}^{{commit}}")])).is_ok()
+    }
+}
+
+impl Repository for GitRepository {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn ensure_cloned(&self) -> Result<bool, VcsError> {
+        if self.dir.join(".git").exists() {
+            return Ok(false);
+        }
+        if let Some(parent) = self.dir.parent() {
+            fs::create_dir_all(parent).map_err(|source| VcsError::Io {
+                command: format!("mkdir -p {}", parent.display()),
+                source,
+            })?;
+        }
+        let mut clone = Command::new("git");
+        clone
+            .args(["clone", "--quiet", &self.clone_url])
+            .arg(&self.dir)
+            .env("GIT_TERMINAL_PROMPT", "0");
+        run(&mut clone)?;
+        Ok(true)
+    }
+
+    fn pull(&self) -> Result<Revision, VcsError> {
+        self.fetch()?;
+        let branch = self.default_branch()?;
+        // -B resets the local branch to the remote one, a deploy checkout has no work of its own
+        run(self.git().args(["checkout", "--quiet", "--force", "-B", &branch, &format!("origin/{branch}")]))?;
+        self.current()
+    }
+
+    fn checkout(&self, revision: &str) -> Result<Revision, VcsError> {
+        if !self.has_commit(revision) {
+            self.fetch()?;
+        }
+        run(self.git().args(["checkout", "--quiet", "--force", "--detach", revision]))?;
+        self.current()
+    }
+
+    fn current(&self) -> Result<Revision, VcsError> {
+        let output = run(self.git().args(["log", "-1", "--format=%H%n%B"]))?;
+        match output.split_once('\n') {
+            Some((id, message)) => Ok(Revision {
+                id: id.trim().to_string(),
+                message: message.trim().to_string(),
+            }),
+            None => Err(VcsError::UnexpectedOutput {
+                command: "git log -1".to_string(),
+                output,
+            }),
+        }
+    }
+
+    fn branch(&self) -> Result<String, VcsError> {
+        self.default_branch()
+    }
+
+    fn changed_files(&self, from: &str, to: &str) -> Result<Vec<String>, VcsError> {
+        // --no-renames lists both sides of a rename, the old path may belong to another service
+        let output = run(self.git().args(["diff", "--name-only", "--no-renames", from, to]))?;
+        Ok(output.lines().filter(|line| !line.is_empty()).map(str::to_string).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let mut command = Command::new("git");
+        command
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args);
+        run(&mut command).unwrap_or_else(|e| panic!("git {args:?} failed: {e}"))
+    }
+
+    fn commit(seed: &Path, file: &str, message: &str) -> String {
+        fs::write(seed.join(file), message).unwrap();
+        git(seed, &["add", file]);
+        git(seed, &["commit", "--quiet", "-m", message]);
+        git(seed, &["push", "--quiet", "origin", "HEAD:main"]);
+        git(seed, &["rev-parse", "HEAD"]).trim().to_string()
+    }
+
+    #[test]
+    fn clones_pulls_and_checks_out() {
+        let root = std::env::temp_dir().join(format!("vcs-git-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let origin = root.join("origin.git");
+        let seed = root.join("seed");
+        fs::create_dir_all(&seed).unwrap();
+        git(&root, &["init", "--quiet", "--bare", "-b", "main", origin.to_str().unwrap()]);
+        git(&root, &["clone", "--quiet", origin.to_str().unwrap(), seed.to_str().unwrap()]);
+        let first = commit(&seed, "a.txt", "first");
+
+        let repo = GitRepository::new("test", origin.to_str().unwrap(), root.join("work"));
+        assert!(repo.ensure_cloned().unwrap());
+        assert!(!repo.ensure_cloned().unwrap());
+        assert_eq!(repo.current().unwrap(), Revision { id: first.clone(), message: "first".to_string() });
+        assert_eq!(repo.branch().unwrap(), "main");
+
+        let second = commit(&seed, "b.txt", "second\n\nwith a body");
+        let pulled = repo.pull().unwrap();
+        assert_eq!(pulled.id, second);
+        assert_eq!(pulled.message, "second\n\nwith a body");
+
+        assert_eq!(repo.checkout(&first).unwrap().id, first);
+        assert!(!repo.dir().join("b.txt").exists());
+        assert_eq!(repo.pull().unwrap().id, second);
+
+        // a revision pushed after the last pull is fetched on demand
+        let third = commit(&seed, "c.txt", "third");
+        assert_eq!(repo.checkout(&third).unwrap().message, "third");
+        assert_eq!(repo.changed_files(&first, &third).unwrap(), ["b.txt", "c.txt"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
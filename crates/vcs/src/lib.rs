@@ -0,0 +1,204 @@
+//! Working copies of the repos declared under `[repos]` in the config.
+//!
+//! Git and fossil sit behind the [`Repository`] trait, so callers can keep a
+//! checkout current without caring which one a repo uses. Everything shells
+//! out to the `git` and `fossil` binaries.
+
+pub mod fossil;
+pub mod git;
+
+pub use fossil::FossilRepository;
+pub use git::GitRepository;
+
+use config::{AppConfig, RepoCloneConfig, ServiceConfig, glob};
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+
+/// Reasons a repository operation failed.
+#[derive(Debug)]
+pub enum VcsError {
+    UnknownVcs { repo: String, vcs: String },
+    MissingDbFile(String),
+    Io { command: String, source: io::Error },
+    Command { command: String, status: ExitStatus, stderr: String },
+    UnexpectedOutput { command: String, output: String },
+}
+
+impl Display for VcsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownVcs { repo, vcs } => write!(f, "repo '{repo}' uses unknown vcs '{vcs}'"),
+            Self::MissingDbFile(repo) => write!(f, "fossil repo '{repo}' requires a db_file"),
+            Self::Io { command, source } => write!(f, "unable to run `{command}`: {source}"),
+            Self::Command { command, status, stderr } => {
+                write!(f, "`{command}` failed with {status}: {}", stderr.trim())
+            }
+            Self::UnexpectedOutput { command, output } => {
+                write!(f, "unexpected output from `{command}`: {}", output.trim())
+            }
+        }
+    }
+}
+
+impl std::error::Error for VcsError {}
+
+/// A commit as reported by the vcs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Revision {
+    pub id: String,
+    pub message: String,
+}
+
+/// A local working copy of a remote repository.
+pub trait Repository: Send + Sync {
+    /// Name of the repo in the config.
+    fn name(&self) -> &str;
+
+    /// Directory holding the working copy.
+    fn dir(&self) -> &Path;
+
+    /// Clone the repo unless a working copy is already there. Returns whether
+    /// anything was cloned.
+    fn ensure_cloned(&self) -> Result<bool, VcsError>;
+
+    /// Fetch from the remote and move the working copy to the newest commit
+    /// of its branch.
+    fn pull(&self) -> Result<Revision, VcsError>;
+
+    /// Move the working copy to `revision`, discarding local changes.
+    fn checkout(&self, revision: &str) -> Result<Revision, VcsError>;
+
+    /// The commit the working copy is at.
+    fn current(&self) -> Result<Revision, VcsError>;
+
+    /// The branch [`Repository::pull`] follows.
+    fn branch(&self) -> Result<String, VcsError>;
+
+    /// Paths, relative to the repo root, of the files that differ between
+    /// two commits.
+    fn changed_files(&self, from: &str, to: &str) -> Result<Vec<String>, VcsError>;
+}
+
+/// Build the repository for a `[repos.<name>]` config entry.
+pub fn open(name: &str, config: &RepoCloneConfig) -> Result<Box<dyn Repository>, VcsError> {
+    match config.vcs.as_str() {
+        "git" => Ok(Box::new(GitRepository::new(name, &config.clone_url, expand_home(&config.dir)))),
+        "fossil" => {
+            let db_file = config
+                .db_file
+                .as_deref()
+                .ok_or_else(|| VcsError::MissingDbFile(name.to_string()))?;
+            Ok(Box::new(FossilRepository::new(
+                name,
+                &config.clone_url,
+                expand_home(&config.dir),
+                expand_home(db_file),
+            )))
+        }
+        other => Err(VcsError::UnknownVcs { repo: name.to_string(), vcs: other.to_string() }),
+    }
+}
+
+/// Services built from `repo` that any of `changed_files` belong to, in
+/// config order.
+///
+/// A service owns the files matching its `paths`, or everything under its
+/// `create_workspace` when it has none.
+pub fn affected_services<'a>(config: &'a AppConfig, repo: &str, changed_files: &[String]) -> Vec<&'a str> {
+    let Some(repo_cfg) = config.repos.get(repo) else {
+        return Vec::new();
+    };
+    config
+        .services
+        .iter()
+        .filter(|(_, service)| service.repo == repo)
+        .filter(|(_, service)| changed_files.iter().any(|file| owns(repo_cfg, service, file)))
+        .map(|(name, _)| name.as_str())
+        .collect()
+}
+
+fn owns(repo: &RepoCloneConfig, service: &ServiceConfig, file: &str) -> bool {
+    if !service.paths.is_empty() {
+        return service.paths.iter().any(|pattern| glob::matches(pattern, file));
+    }
+    let dir = repo.dir.trim_end_matches('/');
+    match service.create_workspace.strip_prefix(dir) {
+        Some("") => true,
+        Some(rest) if rest.starts_with('/') => glob::matches(rest.trim_start_matches('/'), file),
+        // validation warns about this, rebuilding too often beats never rebuilding
+        _ => true,
+    }
+}
+
+/// Resolve a leading `~/` against `$HOME`, the way the config writes paths.
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Run a vcs command to completion, returning its stdout.
+fn run(command: &mut Command) -> Result<String, VcsError> {
+    let display = describe(command);
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|source| VcsError::Io { command: display.clone(), source })?;
+    if !output.status.success() {
+        return Err(VcsError::Command {
+            command: display,
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn describe(command: &Command) -> String {
+    let mut parts = vec![command.get_program().to_string_lossy().into_owned()];
+    parts.extend(command.get_args().map(|arg| arg.to_string_lossy().into_owned()));
+    parts.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_repos_from_config() {
+        let git = RepoCloneConfig {
+            vcs: "git".to_string(),
+            clone_url: "https://example.com/a.git".to_string(),
+            dir: "/srv/a".to_string(),
+            db_file: None,
+            webhook_secret: None,
+        };
+        assert_eq!(open("a", &git).unwrap().dir(), Path::new("/srv/a"));
+
+        let fossil = RepoCloneConfig { vcs: "fossil".to_string(), ..git };
+        assert!(matches!(open("b", &fossil), Err(VcsError::MissingDbFile(_))));
+
+        let svn = RepoCloneConfig { vcs: "svn".to_string(), ..fossil };
+        assert!(matches!(open("c", &svn), Err(VcsError::UnknownVcs { .. })));
+    }
+
+    #[test]
+    fn finds_services_affected_by_changes() {
+        let config = config::check_config(include_str!("../../../config/example.toml")).unwrap().config;
+        let changed = |files: &[&str]| files.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+
+        // example_service_1 lists its paths, example_service_2 falls back to its create_workspace
+        assert_eq!(affected_services(&config, "create", &changed(&["shared/util.sh"])), ["example_service_1"]);
+        assert_eq!(
+            affected_services(&config, "create", &changed(&["example_service_2/build.sh", "example_service_1/a"])),
+            ["example_service_1", "example_service_2"],
+        );
+        assert!(affected_services(&config, "create", &changed(&["README.md"])).is_empty());
+        assert!(affected_services(&config, "pipeline", &changed(&["shared/util.sh"])).is_empty());
+        assert!(affected_services(&config, "missing", &changed(&["shared/util.sh"])).is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+use hypertext::{ Raw, maud, prelude::* };
+use config::AppConfig;
+use model::{Deployment, DeploymentLog, DeploymentStatus, DeploymentStep, LogStream, StoredArtifact};
+
+static WEBSOCKET_CLIENT: &str = include_str!("../../../static/ws.js");
+
+const STATUSES: [DeploymentStatus; 3] = [
+    DeploymentStatus::Running,
+    DeploymentStatus::Succeeded,
+    DeploymentStatus::Failed,
+];
+
+/// Filter values currently applied to the deployment history listing.
+pub struct DeploymentsQuery<'a> {
+    pub service: Option<&'a str>,
+    pub status: Option<DeploymentStatus>,
+}
+
+pub fn get_deployments_app(deployments: &[Deployment], query: &DeploymentsQuery, config: &AppConfig) -> String {
+    let selected_service = query.service.unwrap_or("");
+    let selected_status = query.status.map(|s| s.as_str()).unwrap_or("");
+    maud! {
+        div #app data-page="deployments" data-css="/static/deployments_page.css" {
+            h1 { "Deployments" }
+            img.firetruck src="/static/firetruck.svg" loading="lazy" alt="firetruck" width="96" height="96";
+            img.ambulance src="/static/ambulance.svg" loading="lazy" alt="ambulance" width="96" height="96";
+            img.police src="/static/police.svg" loading="lazy" alt="police" width="50" height="50";
+
+            form #deployments-filter action="/deployments" method="get" {
+                label {
+                    "Service:"
+                    select name="service" {
+                        option value="" { "all" }
+                        @for name in config.services.keys() {
+                            option value=(name) selected[name == selected_service] { (name) }
+                        }
+                    }
+                }
+                label {
+                    "Status:"
+                    select name="status" {
+                        option value="" { "all" }
+                        @for status in &STATUSES {
+                            option value=(status.as_str()) selected[status.as_str() == selected_status] {
+                                (status.as_str())
+                            }
+                        }
+                    }
+                }
+                button type="submit" { "Filter" }
+            }
+
+            @if deployments.is_empty() {
+                p.empty { "No deployments found" }
+            } @else {
+                table.deployments {
+                    thead {
+                        tr {
+                            th { "Started" }
+                            th { "Service" }
+                            th { "Environment" }
+                            th { "Nodes" }
+                            th { "Revision" }
+                            th { "Status" }
+                            th { "Duration" }
+                            th { "Initiated by" }
+                        }
+                    }
+                    tbody {
+                        @for deployment in deployments {
+                            tr {
+                                td {
+                                    a href=(format!("/deployment?id={}", deployment.id())) {
+                                        (format_timestamp(deployment.started_at()))
+                                    }
+                                }
+                                td { (deployment.service()) }
+                                td {
+                                    (deployment.environment())
+                                    @if let Some(promoted) = deployment.promoted_from() {
+                                        " "
+                                        a.promoted href=(format!("/deployment?id={promoted}")) title="promoted from" { "from #" (promoted) }
+                                    }
+                                }
+                                td { (deployment.nodes().join(", ")) }
+                                td { (deployment.revision().unwrap_or("-")) }
+                                td class=(format!("status {}", deployment.status())) { (deployment.status().as_str()) }
+                                td { (deployment.duration_ms().map(format_duration).unwrap_or_else(|| "-".to_string())) }
+                                td { (deployment.initiated_by()) }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    .render()
+    .into_inner()
+}
+
+pub fn get_deployment_app(
+    deployment: &Deployment,
+    steps: &[DeploymentStep],
+    builds: &[StoredArtifact],
+    logs: &[DeploymentLog],
+) -> String {
+    maud! {
+        div #app data-page="deployment" data-css="/static/deployments_page.css" {
+            h1 { "Deployment #" (deployment.id()) }
+            a href=(format!("/deployments?service={}", crate::query_encode(deployment.service()))) { "Back to deployments" }
+            dl.summary {
+                dt { "Service" } dd { (deployment.service()) }
+                dt { "Environment" } dd { (deployment.environment()) }
+                dt { "Nodes" } dd { (deployment.nodes().join(", ")) }
+                dt { "Revision" } dd { (deployment.revision().unwrap_or("-")) }
+                dt { "Release" } dd { (deployment.release().unwrap_or("-")) }
+                @if let Some(rolled_back) = deployment.rollback_of() {
+                    dt { "Rollback of" }
+                    dd { a href=(format!("/deployment?id={rolled_back}")) { "#" (rolled_back) } }
+                }
+                @if let Some(promoted) = deployment.promoted_from() {
+                    dt { "Promoted from" }
+                    dd { a href=(format!("/deployment?id={promoted}")) { "#" (promoted) } }
+                }
+                dt { "Status" } dd class=(format!("status {}", deployment.status())) { (deployment.status().as_str()) }
+                dt { "Exit code" } dd { (deployment.exit_code().map(|c| c.to_string()).unwrap_or_else(|| "-".to_string())) }
+                dt { "Started" } dd { (format_timestamp(deployment.started_at())) }
+                dt { "Duration" } dd { (deployment.duration_ms().map(format_duration).unwrap_or_else(|| "-".to_string())) }
+                dt { "Initiated by" } dd { (deployment.initiated_by()) }
+                dt { "Ran as root" } dd { (if deployment.privileged() { "yes" } else { "no" }) }
+                @if !builds.is_empty() {
+                    dt { "Builds" }
+                    dd {
+                        ul.builds {
+                            @for build in builds {
+                                li {
+                                    (build.arch().unwrap_or("ci node")) ": "
+                                    code title=(build.sha256()) { (build.sha256().get(..12).unwrap_or(build.sha256())) }
+                                    " (" (format_size(build.size())) ")"
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            @if !steps.is_empty() {
+                h2 { "Steps" }
+                table.steps {
+                    thead {
+                        tr {
+                            th { "Step" }
+                            th { "Status" }
+                            th { "Duration" }
+                        }
+                    }
+                    tbody {
+                        @for step in steps {
+                            tr {
+                                td { (step.name()) }
+                                td class=(format!("status {}", step.status())) { (step.status().as_str().replace('_', " ")) }
+                                td { (step.duration_ms().map(format_duration).unwrap_or_else(|| "-".to_string())) }
+                            }
+                        }
+                    }
+                }
+            }
+            h2 { "Output" }
+            pre.log {
+                @for log in logs {
+                    span class=(match log.stream() { LogStream::Stdout => "stdout", LogStream::Stderr => "stderr" }) {
+                        (log.line()) "\n"
+                    }
+                }
+            }
+        }
+    }
+    .render()
+    .into_inner()
+}
+
+pub fn get_deployments_page(deployments: &[Deployment], query: &DeploymentsQuery, config: &AppConfig) -> Vec<u8> {
+    let app_html = get_deployments_app(deployments, query, config);
+    wrap_page(&app_html, "deployments", config)
+}
+
+pub fn get_deployment_page(
+    deployment: &Deployment,
+    steps: &[DeploymentStep],
+    builds: &[StoredArtifact],
+    logs: &[DeploymentLog],
+    config: &AppConfig,
+) -> Vec<u8> {
+    let app_html = get_deployment_app(deployment, steps, builds, logs);
+    wrap_page(&app_html, "deployment", config)
+}
+
+fn wrap_page(app_html: &str, page: &str, config: &AppConfig) -> Vec<u8> {
+    maud! {
+        html {
+            head {
+                meta charset="utf-8";
+                title { "Axe" }
+                meta name="app-version" content=(&config.app_version);
+                script type="module" src="/static/custom_htmx.js" defer {}
+                link rel="stylesheet" href="/static/animation.css";
+                link rel="stylesheet" href="/static/deployments_page.css";
+                script {
+                    (Raw::dangerously_create(WEBSOCKET_CLIENT))
+                }
+            }
+            body data-page=(page) {
+                (Raw::dangerously_create(app_html))
+            }
+        }
+    }.render().into_inner().as_bytes().to_vec()
+}
+
+/// Render a duration compactly, e.g. `450ms`, `4.2s` or `1m 23s`.
+pub fn format_duration(ms: u64) -> String {
+    let secs = ms / 1000;
+    match secs {
+        0 => format!("{}ms", ms),
+        1..=59 => format!("{}.{}s", secs, (ms % 1000) / 100),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, (secs % 3600) / 60),
+    }
+}
+
+/// Render a byte count compactly, e.g. `512 B`, `3.4 KiB` or `12.0 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Render milliseconds since the unix epoch as a UTC date and time.
+pub fn format_timestamp(ms: u64) -> String {
+    let secs = ms / 1000;
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_duration, format_size, format_timestamp};
+
+    #[test]
+    fn formats_times() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_792_238_400_000), "2026-10-17 12:00:00 UTC");
+        assert_eq!(format_duration(450), "450ms");
+        assert_eq!(format_duration(4_250), "4.2s");
+        assert_eq!(format_duration(83_000), "1m 23s");
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(3_500), "3.4 KiB");
+    }
+}
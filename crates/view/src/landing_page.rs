@@ -32,7 +32,7 @@ where
             ul #messages {
                 @for name in &services {
                     li.item {
-                        a.item-link href=(format!("/service?name={}", name)) {
+                        a.item-link href=(format!("/service?name={}", crate::query_encode(name))) {
                             (name)
                         }
                     }
@@ -53,7 +53,7 @@ where
         ul #messages hx-swap-oob="true" {
             @for name in &services {
                 li.item {
-                    a.item-link href=(format!("/service?name={}", name)) {
+                    a.item-link href=(format!("/service?name={}", crate::query_encode(name))) {
                         (name)
                     }
                 }
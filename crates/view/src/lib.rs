@@ -6,11 +6,25 @@ pub mod settings_page;
 pub use settings_page::{get_settings_page, get_settings_app};
 pub mod service_page;
 pub use service_page::{get_service_page, get_service_app};
+pub mod deployments_page;
+pub use deployments_page::{DeploymentsQuery, format_duration, format_timestamp, get_deployments_page, get_deployments_app, get_deployment_page, get_deployment_app};
 pub mod not_found;
 pub use not_found::{get_not_found, get_not_found_app};
 
 use model::User;
 
+/// Percent-encode `value` for use as a query string key or value.
+pub fn query_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
 /// Render a user profile into a simple string representation.
 pub fn render_user_profile(user: &User) -> String {
     format!(
@@ -23,7 +37,7 @@ pub fn render_user_profile(user: &User) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::render_user_profile;
+    use super::{query_encode, render_user_profile};
     use model::User;
 
     #[test]
@@ -34,4 +48,10 @@ mod tests {
         assert!(rendered.contains("User #7"));
         assert!(rendered.contains("rendered@example.com"));
     }
+
+    #[test]
+    fn encodes_query_values() {
+        assert_eq!(query_encode("api-v2_x.y"), "api-v2_x.y");
+        assert_eq!(query_encode("a&b c+d%"), "a%26b%20c%2Bd%25");
+    }
 }
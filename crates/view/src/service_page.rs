@@ -4,30 +4,55 @@ use std::collections::HashMap;
 
 static WEBSOCKET_CLIENT: &str = include_str!("../../../static/ws.js"); 
 
-pub fn get_service_app(query_params: HashMap<String, String>) -> String {
+pub fn get_service_app(query_params: HashMap<String, String>, config: &AppConfig) -> String {
     let service_name = match query_params.get("name") {
         Some(sn) => sn.as_str(),
         None => "unknown", // todo handle error with validation and feedback to user
     };
+    let environments: Vec<&str> = match config.services.get(service_name) {
+        Some(service) => service.environments.keys().map(String::as_str).collect(),
+        None => Vec::new(),
+    };
+    let keeps_releases = config.services.get(service_name).is_some_and(|service| service.keep_releases > 0);
+    // promoting takes what the selected environment runs to the one it promotes to
+    let promotes = environments.iter().any(|env| {
+        config.environments.get(*env).and_then(|env| env.promote_to.as_ref()).is_some_and(|next| environments.contains(&next.as_str()))
+    });
     maud! {
-        div #app data-page="service" data-css="/static/service_page.css" {
+        div #app data-page="service" data-css="/static/service_page.css" data-subscribe=(service_name) {
             h1 { "Service " (service_name) }
+            a href=(format!("/deployments?service={}", crate::query_encode(service_name))) { "Deployment history" }
             img.firetruck src="/static/firetruck.svg" loading="lazy" alt="firetruck" width="96" height="96";
             img.ambulance src="/static/ambulance.svg" loading="lazy" alt="ambulance" width="96" height="96";
             img.police src="/static/police.svg" loading="lazy" alt="police" width="50" height="50";
 
-            form #publish-form {
+            form #deploy-form {
                 label {
-                    "Message:"
-                    input #publish-body 
-                          type="text" 
-                          placeholder="Write a message for subject 'demo'";
+                    "Environment:"
+                    select #deploy-environment {
+                        @for env in &environments {
+                            option value=(env) { (env) }
+                        }
+                    }
+                }
+                button type="button" hx-patch=(format!("deploy:{}", service_name)) {
+                    "Deploy"
                 }
-                button type="submit" {
-                    "Publish"
+                @if keeps_releases {
+                    button type="button" hx-patch=(format!("rollback:{}", service_name)) {
+                        "Roll back"
+                    }
                 }
+                @if promotes {
+                    button type="button" hx-patch=(format!("promote:{}", service_name)) title="deploy what the selected environment runs to the next one" {
+                        "Promote"
+                    }
+                }
+            }
+            h2 { "Steps" }
+            ul #steps {
             }
-            h2 { "Messages on 'demo'" }
+            h2 { "Deployment output" }
             ul #messages {
             }
         }
@@ -37,7 +62,7 @@ pub fn get_service_app(query_params: HashMap<String, String>) -> String {
 }
 
 pub fn get_service_page(query_params: HashMap<String, String>, config: &AppConfig) -> Vec<u8> {
-    let app_html = get_service_app(query_params.clone());
+    let app_html = get_service_app(query_params, config);
     maud! {
         html {
             head {
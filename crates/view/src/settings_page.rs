@@ -13,7 +13,7 @@ pub fn get_settings_app(config: &AppConfig) -> String {
 
             h2 { "Services" }
             ul {
-                @for (name, _) in &config.services {
+                @for name in config.services.keys() {
                     li {
                         (name)
                     }
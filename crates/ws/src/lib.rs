@@ -1,288 +1,621 @@
+//! Websocket server running every browser connection on one mio event loop.
+//!
+//! The HTTP server answers the upgrade request on [`PATH`] through [`upgrade`],
+//! which hands the socket over to the event loop started by [`start`]. Each
+//! connection is registered with its own token, so open sockets are bounded by
+//! file descriptors rather than threads. `max_users` is an admission limit:
+//! connections past it are closed with a "try again later" close frame.
+//!
+//! Requests that read the database or start a deploy are carried out by a
+//! small pool of worker threads, which hand the replies back to the event
+//! loop through its waker, so a slow one holds up no socket. A connection's
+//! requests are carried out one at a time, in order, while other connections'
+//! run next to them.
+
 use controller::{AppEvent, ParseEventError, UiMode, UiResult, handle_nav, parse_event, parse_query_params};
 use config::get_config;
+use deploy::{DeployError, DeployEvent, DeployPlan, StartError, SubscriptionId, hub};
 use std::{
     backtrace::Backtrace,
-    collections::VecDeque,
-    io::{self, ErrorKind, Read},
-    net::TcpStream,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Display, Formatter},
+    io::{self, ErrorKind, Write},
+    net,
     os::unix::io::{AsRawFd, RawFd},
-    process::{Child, ChildStderr, ChildStdout, Command, Stdio},
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, mpsc},
+    thread,
     time::{Duration, Instant},
 };
-use mio::{Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::net::TcpStream;
 use mio::unix::SourceFd;
-use tungstenite::{accept, Bytes, Message, WebSocket};
+use tungstenite::{
+    Bytes, Message, WebSocket,
+    handshake::derive_accept_key,
+    protocol::{CloseFrame, Role, WebSocketConfig, frame::coding::CloseCode},
+};
 
-const SOCKET: Token = Token(0);
-const STDOUT: Token = Token(2);
-const STDERR: Token = Token(3);
+/// Path the browser client opens its websocket on.
+pub const PATH: &str = "/ws";
 
-pub fn handle_websocket_connection(stream: TcpStream) {
-    let config = get_config();
-    let websocket = accept(stream);
-    let mut websocket = match websocket {
-        Ok(w) => w,
-        Err(err) => {
-            let bt = Backtrace::capture();
-            eprintln!("error, when accepting websocket connection. Error: {}. Stack: {:?}", err, bt);
-            return
+const WAKE: Token = Token(0);
+const FIRST_CONNECTION: usize = 1;
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Threads carrying out requests for the connections.
+const WORKERS: usize = 4;
+
+/// Global handle to the running event loop.
+static REACTOR: OnceLock<ReactorHandle> = OnceLock::new();
+
+/// A socket whose upgrade has been answered, on its way into the event loop.
+struct Adopted {
+    stream: net::TcpStream,
+    read_ahead: Vec<u8>,
+}
+
+struct ReactorHandle {
+    sender: mpsc::Sender<Adopted>,
+    waker: Arc<Waker>,
+}
+
+/// Reasons an upgrade request is turned away.
+#[derive(Debug)]
+pub enum UpgradeError {
+    MissingKey,
+    UnsupportedVersion(String),
+    NotStarted,
+    Io(io::Error),
+}
+
+impl Display for UpgradeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingKey => write!(f, "missing Sec-WebSocket-Key header"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported websocket version '{version}'"),
+            Self::NotStarted => write!(f, "websocket server is not running"),
+            Self::Io(err) => write!(f, "{err}"),
         }
-    };
+    }
+}
+
+impl std::error::Error for UpgradeError {}
 
-    let ping_interval = Duration::from_secs(rand::random_range(20..=30));
-    let pong_timeout = Duration::from_secs(rand::random_range(7..=10));
+impl UpgradeError {
+    /// Status line the request is answered with, if it can still be answered.
+    pub fn status_line(&self) -> Option<&'static str> {
+        match self {
+            Self::MissingKey | Self::UnsupportedVersion(_) => Some("HTTP/1.1 400 BAD REQUEST"),
+            Self::NotStarted => Some("HTTP/1.1 503 SERVICE UNAVAILABLE"),
+            Self::Io(_) => None,
+        }
+    }
+}
 
-    let mut last_rx = Instant::now();
-    let mut ping_in_flight: Option<Instant> = None;
+/// Whether the request head asks to switch this connection to a websocket.
+///
+/// `headers` are keyed by lowercased header name.
+pub fn is_upgrade_request(headers: &HashMap<String, String>) -> bool {
+    headers
+        .get("upgrade")
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
 
-    if let Err(err) = websocket.get_mut().set_nonblocking(true) {
-        let bt = Backtrace::capture();
-        eprintln!("error, unable to set non-blocking for websocket. Error: {}. Stack: {:?}", err, bt);
-        return
+/// Start the event loop on its own thread. Calling it again is a no-op.
+///
+/// Messages larger than `max_message_size` bytes fail the connection.
+pub fn start(max_users: usize, max_message_size: usize) -> io::Result<()> {
+    if REACTOR.get().is_some() {
+        return Ok(())
     }
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKE)?);
+    let (sender, receiver) = mpsc::channel();
+    let limits = WebSocketConfig::default()
+        .max_message_size(Some(max_message_size))
+        .max_frame_size(Some(max_message_size));
+    let worker = Worker::start()?;
+    let app_version = get_config().app_version.clone();
+    let mut reactor = Reactor::new(poll, Arc::clone(&waker), receiver, worker, max_users, limits, app_version);
+    thread::Builder::new()
+        .name("ws-reactor".to_string())
+        .spawn(move || {
+            if let Err(err) = reactor.run() {
+                let bt = Backtrace::capture();
+                eprintln!("error, when polling websockets. Error: {}. Stack: {:?}", err, bt);
+            }
+        })?;
+    let _ = REACTOR.set(ReactorHandle { sender, waker });
+    Ok(())
+}
 
-    let mut poll = match Poll::new() {
-        Ok(p) => p,
+/// Answer a websocket upgrade request and hand the socket to the event loop.
+///
+/// `read_ahead` holds any bytes the caller buffered past the request head.
+/// A request that is turned away is answered with the error's
+/// [`UpgradeError::status_line`] before returning the error.
+pub fn upgrade(
+    mut stream: net::TcpStream,
+    headers: &HashMap<String, String>,
+    read_ahead: Vec<u8>,
+) -> Result<(), UpgradeError> {
+    let checked = check_upgrade(headers).and_then(|key| Ok((key, REACTOR.get().ok_or(UpgradeError::NotStarted)?)));
+    let (key, handle) = match checked {
+        Ok(checked) => checked,
         Err(err) => {
-            let bt = Backtrace::capture();
-            eprintln!("error, when creating websocket poll. Error: {}. Stack: {:?}", err, bt);
-            return
+            if let Some(status_line) = err.status_line() {
+                let _ = write!(stream, "{status_line}\r\nContent-Length: 0\r\n\r\n");
+            }
+            return Err(err)
         }
     };
-    let mut events = Events::with_capacity(64);
-
-    let raw_fd = websocket.get_ref().as_raw_fd();
-    let mut socket_source = SourceFd(&raw_fd);
-    if let Err(err) = poll.registry().register(
-            &mut socket_source,
-            SOCKET,
-            Interest::READABLE,
-        ) {
-        let bt = Backtrace::capture();
-        eprintln!("error, when registering websocket socket with poll. Error: {}. Stack: {:?}", err, bt);
-        return
-    }
-
-    let ready_message = format!("ready:{}", config.app_version);
-    let mut outbox: VecDeque<Message> = VecDeque::from([Message::Text(ready_message.into())]);
-    let mut deploy: Option<DeployChild> = None;
-    let mut want_write = false;
-    if drain_outbound(&mut outbox, &mut websocket, &mut ping_in_flight).is_err() {
-        return
-    }
-    let write_work_needed = !outbox.is_empty();
-    if want_write != write_work_needed {
-        want_write = write_work_needed;
-        if update_socket_interest(&mut poll, &mut socket_source, want_write).is_err() {
-            return
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes()),
+    );
+    stream.write_all(response.as_bytes()).map_err(UpgradeError::Io)?;
+    stream.set_nonblocking(true).map_err(UpgradeError::Io)?;
+    handle
+        .sender
+        .send(Adopted { stream, read_ahead })
+        .map_err(|_| UpgradeError::NotStarted)?;
+    handle.waker.wake().map_err(UpgradeError::Io)
+}
+
+fn check_upgrade(headers: &HashMap<String, String>) -> Result<&str, UpgradeError> {
+    match headers.get("sec-websocket-version").map(String::as_str) {
+        Some("13") => {}
+        other => return Err(UpgradeError::UnsupportedVersion(other.unwrap_or_default().to_string())),
+    }
+    headers
+        .get("sec-websocket-key")
+        .map(|key| key.trim())
+        .filter(|key| !key.is_empty())
+        .ok_or(UpgradeError::MissingKey)
+}
+
+struct Reactor {
+    poll: Poll,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    max_users: usize,
+    limits: WebSocketConfig,
+    app_version: String,
+    waker: Arc<Waker>,
+    adopted_rx: mpsc::Receiver<Adopted>,
+    worker: Worker,
+    delivery_tx: mpsc::Sender<(Token, Delivery)>,
+    delivery_rx: mpsc::Receiver<(Token, Delivery)>,
+}
+
+impl Reactor {
+    fn new(
+        poll: Poll,
+        waker: Arc<Waker>,
+        adopted_rx: mpsc::Receiver<Adopted>,
+        worker: Worker,
+        max_users: usize,
+        limits: WebSocketConfig,
+        app_version: String,
+    ) -> Self {
+        let (delivery_tx, delivery_rx) = mpsc::channel();
+        Self {
+            poll,
+            connections: HashMap::new(),
+            next_token: FIRST_CONNECTION,
+            max_users,
+            limits,
+            app_version,
+            waker,
+            adopted_rx,
+            worker,
+            delivery_tx,
+            delivery_rx,
         }
     }
 
-    loop {
-        let now = Instant::now();
-        let ping_deadline = last_rx + ping_interval;
-        let timeout = match ping_in_flight {
-            Some(t0) => {
-                let pong_deadline = t0 + pong_timeout;
-                let deadline = if ping_deadline < pong_deadline { ping_deadline } else { pong_deadline };
-                deadline.saturating_duration_since(now)
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(256);
+        loop {
+            let now = Instant::now();
+            let timeout = self
+                .connections
+                .values()
+                .map(Connection::deadline)
+                .min()
+                .map(|deadline| deadline.saturating_duration_since(now));
+            match self.poll.poll(&mut events, timeout) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
             }
-            None => ping_deadline.saturating_duration_since(now),
-        };
-        if let Err(err) = poll.poll(&mut events, Some(timeout)) {
-            let bt = Backtrace::capture();
-            eprintln!("error, when polling websocket. Error: {}. Stack: {:?}", err, bt);
-            return
-        }
 
-        for event in events.iter() {
-            match event.token() {
-                SOCKET => {
-                    if event.is_readable() {
-                        loop {
-                            match websocket.read() {
-                                Ok(msg) => {
-                                    // Any inbound traffic counts as "alive"
-                                    last_rx = Instant::now();
-                                    ping_in_flight = None;
-                                    match msg {
-                                        Message::Ping(_) => {
-                                            // tungstenite will auto-reply to ping/pongs but we still list them in this
-                                            // match statement so application logic handling doesn't get handed control
-                                            // logic messages
-                                        }
-                                        Message::Pong(_) => {
-                                            // good, client is alive, but we already cleared ping_in_flight because any
-                                            // traffic counts as proof of life
-                                        }
-                                        Message::Close(frame) => {
-                                            websocket.send(Message::Close(frame))
-                                                .unwrap_or_else(|e| eprintln!("error, when sending close response in response to close request. Error: {}", e));
-                                            return
-                                        }
-                                        other => {
-                                                handle_app_message(
-                                                    &mut poll,
-                                                    &mut outbox,
-                                                    &mut deploy,
-                                                    &mut socket_source,
-                                                    other,
-                                                    &mut want_write,
-                                                    config,
-                                                );
-                                        }
-                                    }
-                                }
-                                Err(e) if is_timeout(&e) => break,
-                                Err(err) => {
-                                    let bt = Backtrace::capture();
-                                    eprintln!("error, when reading websocket message. Error: {}. Stack: {:?}", err, bt);
-                                    return
-                                }
-                            }
+            let mut touched: Vec<Token> = Vec::new();
+            for event in events.iter() {
+                match event.token() {
+                    // one waker per poll, so it covers handed over sockets, deploy output and worker replies
+                    WAKE => {
+                        while let Ok(adopted) = self.adopted_rx.try_recv() {
+                            self.adopt(adopted, &mut touched);
                         }
+                        self.deliver(&mut touched);
                     }
-                    if event.is_writable() {
-                        if drain_outbound(&mut outbox, &mut websocket, &mut ping_in_flight).is_err() {
-                            return
+                    token => {
+                        if let Some(conn) = self.connections.get_mut(&token) {
+                            conn.on_ready(event.is_readable(), event.is_writable());
+                            touched.push(token);
                         }
                     }
                 }
-                STDOUT => {
-                    if let Some(dep) = deploy.as_mut() {
-                        if handle_child_readable(dep, &mut outbox, ChildStream::Stdout).is_err() {
-                            return
-                        }
-                    }
+            }
+
+            let now = Instant::now();
+            for (token, conn) in self.connections.iter_mut() {
+                if conn.deadline() <= now {
+                    conn.on_timer(now);
+                    touched.push(*token);
                 }
-                STDERR => {
-                    if let Some(dep) = deploy.as_mut() {
-                        if handle_child_readable(dep, &mut outbox, ChildStream::Stderr).is_err() {
-                            return
-                        }
-                    }
+            }
+
+            touched.sort_unstable();
+            touched.dedup();
+            for token in touched {
+                let Some(conn) = self.connections.get_mut(&token) else {
+                    continue;
+                };
+                if !conn.done {
+                    conn.flush();
+                }
+                if !conn.done {
+                    conn.update_interest(&self.poll, token);
+                }
+                if conn.done {
+                    // dropping the stream closes the fd, which also takes it out of the poll
+                    self.connections.remove(&token);
                 }
-                _ => {}
             }
         }
+    }
+
+    /// Hand what subscribers and the worker sent to their connections.
+    fn deliver(&mut self, touched: &mut Vec<Token>) {
+        // tokens are never reused, so deliveries for a connection that already went away are dropped
+        while let Ok((token, delivery)) = self.delivery_rx.try_recv() {
+            if let Some(conn) = self.connections.get_mut(&token) {
+                match delivery {
+                    Delivery::Text(text) => conn.outbox.push_back(Message::Text(text.into())),
+                    Delivery::Follow(service) => conn.watch.follow(&service),
+                }
+                touched.push(token);
+            }
+        }
+    }
 
-        if drain_outbound(&mut outbox, &mut websocket, &mut ping_in_flight).is_err() {
+    fn adopt(&mut self, adopted: Adopted, touched: &mut Vec<Token>) {
+        let initiated_by = match adopted.stream.peer_addr() {
+            Ok(addr) => format!("web:{}", addr.ip()),
+            Err(_) => "web".to_string(),
+        };
+        let mut stream = TcpStream::from_std(adopted.stream);
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        if let Err(err) = self.poll.registry().register(
+            &mut stream,
+            token,
+            Interest::READABLE.add(Interest::WRITABLE),
+        ) {
+            let bt = Backtrace::capture();
+            eprintln!("error, when registering websocket socket with poll. Error: {}. Stack: {:?}", err, bt);
             return
         }
+        let admitted = self.connections.values().filter(|c| c.admitted).count() < self.max_users;
+        if !admitted {
+            eprintln!("websocket connection from {} refused, max_users of {} reached", initiated_by, self.max_users);
+        }
+        let websocket = WebSocket::from_partially_read(stream, adopted.read_ahead, Role::Server, Some(self.limits));
+        let outbound = Outbound { token, sender: self.delivery_tx.clone(), waker: Arc::clone(&self.waker) };
+        let mut conn = Connection::new(websocket, admitted, initiated_by, Watch::new(outbound), self.worker.clone());
+        if admitted {
+            let ready_message = format!("ready:{}", self.app_version);
+            conn.outbox.push_back(Message::Text(ready_message.into()));
+        }
+        // frames may have arrived before registration, edge triggered readiness won't report them again
+        conn.on_ready(true, true);
+        self.connections.insert(token, conn);
+        touched.push(token);
+    }
+}
 
-        let write_work_needed = !outbox.is_empty();
-        if want_write != write_work_needed {
-            want_write = write_work_needed;
-            if update_socket_interest(&mut poll, &mut socket_source, want_write).is_err() {
-                return
-            }
+struct Connection {
+    websocket: WebSocket<TcpStream>,
+    fd: RawFd,
+    admitted: bool,
+    outbox: VecDeque<Message>,
+    flush_pending: bool,
+    interest: Interest,
+    last_rx: Instant,
+    ping_in_flight: Option<Instant>,
+    ping_interval: Duration,
+    pong_timeout: Duration,
+    closing_since: Option<Instant>,
+    done: bool,
+    initiated_by: String,
+    watch: Watch,
+    worker: Worker,
+}
+
+impl Connection {
+    fn new(websocket: WebSocket<TcpStream>, admitted: bool, initiated_by: String, watch: Watch, worker: Worker) -> Self {
+        let mut conn = Self {
+            fd: websocket.get_ref().as_raw_fd(),
+            websocket,
+            admitted,
+            outbox: VecDeque::new(),
+            flush_pending: false,
+            interest: Interest::READABLE.add(Interest::WRITABLE),
+            last_rx: Instant::now(),
+            ping_in_flight: None,
+            ping_interval: Duration::from_secs(rand::random_range(20..=30)),
+            pong_timeout: Duration::from_secs(rand::random_range(7..=10)),
+            closing_since: None,
+            done: false,
+            initiated_by,
+            watch,
+            worker,
+        };
+        if !admitted {
+            conn.begin_close(CloseFrame {
+                code: CloseCode::Again,
+                reason: "too many users connected, try again later".into(),
+            });
         }
+        conn
+    }
 
-        if let Some(dep) = deploy.as_mut() {
-            if dep.is_done() {
-                if finalize_deploy(&mut poll, dep, &mut outbox).is_err() {
-                    return
+    /// When this connection next needs attention without any socket activity.
+    fn deadline(&self) -> Instant {
+        match (self.closing_since, self.ping_in_flight) {
+            (Some(t0), _) => t0 + CLOSE_TIMEOUT,
+            (None, Some(t0)) => t0 + self.pong_timeout,
+            (None, None) => self.last_rx + self.ping_interval,
+        }
+    }
+
+    fn on_ready(&mut self, readable: bool, writable: bool) {
+        if readable && !self.done {
+            self.read_messages();
+        }
+        if writable && !self.done {
+            self.flush();
+        }
+    }
+
+    /// Queue a close frame and give the client `CLOSE_TIMEOUT` to answer it.
+    fn begin_close(&mut self, frame: CloseFrame) {
+        self.outbox.clear();
+        self.closing_since = Some(Instant::now());
+        match self.websocket.close(Some(frame)) {
+            Ok(()) => {}
+            Err(e) if is_timeout(&e) => self.flush_pending = true,
+            Err(_) => self.done = true,
+        }
+    }
+
+    fn read_messages(&mut self) {
+        loop {
+            match self.websocket.read() {
+                Ok(msg) => {
+                    // Any inbound traffic counts as "alive"
+                    self.last_rx = Instant::now();
+                    self.ping_in_flight = None;
+                    match msg {
+                        Message::Ping(_) => {
+                            // tungstenite will auto-reply to ping/pongs but we still list them in this
+                            // match statement so application logic handling doesn't get handed control
+                            // logic messages
+                        }
+                        Message::Pong(_) => {
+                            // good, client is alive, but we already cleared ping_in_flight because any
+                            // traffic counts as proof of life
+                        }
+                        Message::Close(_) => {
+                            // tungstenite queued the close reply on read, send it on its way and hang up
+                            if let Err(e) = self.websocket.flush()
+                                && !matches!(e, tungstenite::Error::ConnectionClosed) {
+                                eprintln!("error, when sending close response in response to close request. Error: {}", e);
+                            }
+                            self.done = true;
+                            return
+                        }
+                        other => {
+                            if self.closing_since.is_none() {
+                                self.handle_app_message(&other.to_string());
+                            }
+                        }
+                    }
                 }
-                deploy = None;
-                if drain_outbound(&mut outbox, &mut websocket, &mut ping_in_flight).is_err() {
+                Err(e) if is_timeout(&e) => return,
+                Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                    self.done = true;
                     return
                 }
-                let write_work_needed = !outbox.is_empty();
-                if want_write != write_work_needed {
-                    want_write = write_work_needed;
-                    if update_socket_interest(&mut poll, &mut socket_source, want_write).is_err() {
-                        return
-                    }
+                Err(err) => {
+                    let bt = Backtrace::capture();
+                    eprintln!("error, when reading websocket message. Error: {}. Stack: {:?}", err, bt);
+                    self.done = true;
+                    return
                 }
             }
         }
+    }
+
+    /// Answer a message from the client, leaving anything that reads the
+    /// database or starts a deploy to the worker.
+    fn handle_app_message(&mut self, text: &str) {
+        match dispatch(text) {
+            Handling::Reply(reply) => self.outbox.push_back(Message::Text(reply.into())),
+            Handling::Follow(service) => self.watch.follow(&service),
+            Handling::Work(event) => {
+                let job = Job { event, initiated_by: self.initiated_by.clone(), outbound: self.watch.outbound.clone() };
+                self.worker.submit(job);
+            }
+        }
+    }
 
-        let now = Instant::now();
+    fn on_timer(&mut self, now: Instant) {
+        if self.closing_since.is_some() {
+            self.done = true;
+            return
+        }
+        match self.ping_in_flight {
+            // If we pinged and still didn't get anything back in time, close.
+            Some(t0) => {
+                if now.duration_since(t0) >= self.pong_timeout {
+                    self.begin_close(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "ping timeout".into(),
+                    });
+                }
+            }
+            // If we've been idle long enough, ping.
+            None => {
+                if now.duration_since(self.last_rx) >= self.ping_interval {
+                    self.outbox.push_back(Message::Ping(Bytes::new()));
+                    self.ping_in_flight = Some(now);
+                }
+            }
+        }
+    }
 
-        // 1) If we've been idle long enough, ping.
-        if now.duration_since(last_rx) >= ping_interval && ping_in_flight.is_none() {
-            match websocket.send(Message::Ping(Bytes::new())) {
-                Ok(()) => ping_in_flight = Some(now),
-                Err(err) if is_timeout(&err) => outbox.push_back(Message::Ping(Bytes::new())),
+    /// Move queued messages into tungstenite's write buffer and push it at the socket.
+    fn flush(&mut self) {
+        while let Some(msg) = self.outbox.pop_front() {
+            match self.websocket.write(msg) {
+                Ok(()) => {}
+                Err(tungstenite::Error::WriteBufferFull(msg)) => {
+                    self.outbox.push_front(*msg);
+                    break;
+                }
+                // the frame is buffered already and goes out with a later flush
+                Err(e) if is_timeout(&e) => {}
                 Err(err) => {
-                    eprintln!("error, when writing ping websocket message. Error: {}", err);
+                    eprintln!("error, when writing to websocket connection. Error: {}", err);
+                    self.done = true;
                     return
                 }
             }
         }
-
-        // 2) If we pinged and still didn't get anything back in time, close.
-        if let Some(t0) = ping_in_flight {
-            if now.duration_since(t0) >= pong_timeout {
-                // you can also send Close first if you want
-                let _ = websocket.send(Message::Close(None));
-                break;
+        match self.websocket.flush() {
+            Ok(()) => self.flush_pending = false,
+            Err(e) if is_timeout(&e) => self.flush_pending = true,
+            Err(tungstenite::Error::ConnectionClosed) => self.done = true,
+            Err(err) => {
+                eprintln!("error, when writing to websocket connection. Error: {}", err);
+                self.done = true;
             }
         }
     }
+
+    fn update_interest(&mut self, poll: &Poll, token: Token) {
+        let interest = if !self.outbox.is_empty() || self.flush_pending {
+            Interest::READABLE.add(Interest::WRITABLE)
+        } else {
+            Interest::READABLE
+        };
+        if self.interest == interest {
+            return
+        }
+        if let Err(err) = poll.registry().reregister(&mut SourceFd(&self.fd), token, interest) {
+            let bt = Backtrace::capture();
+            eprintln!("error, when updating websocket socket interest. Error: {}. Stack: {:?}", err, bt);
+            self.done = true;
+            return
+        }
+        self.interest = interest;
+    }
+}
+
+/// How a connection answers one message from its client.
+#[derive(Debug, PartialEq, Eq)]
+enum Handling {
+    /// Send this text back right away.
+    Reply(String),
+    /// Follow the deploy output of a service.
+    Follow(String),
+    /// Hand the request to the worker.
+    Work(AppEvent),
 }
 
-fn handle_app_message(
-    poll: &mut Poll,
-    outbox: &mut VecDeque<Message>,
-    deploy: &mut Option<DeployChild>,
-    socket_source: &mut SourceFd,
-    msg: Message,
-    want_write: &mut bool,
-    config: &'static config::AppConfig,
-) {
+fn dispatch(text: &str) -> Handling {
     // custom ping / pong started by client since the client doesn't know when it can reconnect due to no
     // access to control frames
-    match parse_event(&msg.to_string()) {
-        Ok(AppEvent::Ping) => outbox.push_back(Message::Text("pong".into())),
-        Ok(AppEvent::SearchServices(s)) => {
+    match parse_event(text) {
+        Ok(AppEvent::Ping) => Handling::Reply("pong".to_string()),
+        Ok(AppEvent::Subscribe(service)) => Handling::Follow(service),
+        Ok(event) => Handling::Work(event),
+        Err(ParseEventError::UnknownKind) => Handling::Reply("error, unknown event kind".to_string()),
+        Err(ParseEventError::MissingArg) => Handling::Reply("error, missing event arg".to_string()),
+        Err(ParseEventError::ExtraData) => Handling::Reply("error, excess data in event call".to_string()),
+    }
+}
+
+/// Carry out a request handed to the worker, returning what goes back to
+/// the connection.
+fn work(event: AppEvent, initiated_by: &str) -> Vec<Delivery> {
+    let config = get_config();
+    match event {
+        AppEvent::SearchServices(s) => {
             let html = controller::get_filtered_landing_app(&s, config);
-            outbox.push_back(Message::Text(format!("patch:{}", html).into()));
+            vec![Delivery::Text(format!("patch:{}", html))]
         }
-        Ok(AppEvent::Navigate(path)) => {
+        AppEvent::Navigate(path) => {
             let (path_only, query) = split_path_query(&path);
             let query_params = parse_query_params(query);
             match handle_nav(path_only, query_params, config, UiMode::Patch) {
                 UiResult::Patch(html) => {
-                    outbox.push_back(Message::Text(format!("patch:{}", html).into()));
-                    outbox.push_back(Message::Text(format!("location:{}", path).into()));
-                }
-                UiResult::Redirect(location) => {
-                    outbox.push_back(Message::Text(format!("location:{}", location).into()));
+                    vec![Delivery::Text(format!("patch:{}", html)), Delivery::Text(format!("location:{}", path))]
                 }
+                UiResult::Redirect(location) => vec![Delivery::Text(format!("location:{}", location))],
                 UiResult::FullHtml(_) | UiResult::NotFound(_) => {
-                    outbox.push_back(Message::Text("error: invalid navigation result".into()));
+                    vec![Delivery::Text("error: invalid navigation result".to_string())]
                 }
             }
         }
-        Ok(AppEvent::Deploy(s)) => {
-            outbox.push_back(Message::Text(format!("new_deployment: {}", s).into()));
-            if deploy.is_some() {
-                outbox.push_back(Message::Text("deploy already running".into()));
-                return
-            }
-            match spawn_deploy() {
-                Ok(mut dep) => {
-                    if register_child_fds(poll, &mut dep).is_err() {
-                        return
-                    }
-                    *deploy = Some(dep);
-                }
-                Err(err) => {
-                    let msg = format!("deploy failed: {}", err);
-                    eprintln!("error, when running deploy: {}", msg);
-                    outbox.push_back(Message::Text(msg.into()));
-                }
-            }
+        AppEvent::Deploy { service, environment } => {
+            let plan = DeployPlan::resolve(config, &service, &environment);
+            vec![start_deploy(plan, |plan| hub().start(plan, initiated_by))]
         }
-        Err(ParseEventError::UnknownKind) => outbox.push_back(Message::Text("error, unknown event kind".into())),
-        Err(ParseEventError::MissingArg) => outbox.push_back(Message::Text("error, missing event arg".into())),
-        Err(ParseEventError::ExtraData) => outbox.push_back(Message::Text("error, excess data in event call".into())),
+        AppEvent::Rollback { service, environment } => {
+            let plan = DeployPlan::resolve(config, &service, &environment);
+            vec![start_deploy(plan, |plan| hub().rollback(plan, initiated_by))]
+        }
+        AppEvent::Promote { service, environment } => {
+            let plan = DeployPlan::resolve_promotion(config, &service, &environment);
+            vec![start_deploy(plan, |plan| hub().promote(plan, &environment, initiated_by))]
+        }
+        // answered on the event loop, see dispatch
+        AppEvent::Ping | AppEvent::Subscribe(_) => Vec::new(),
     }
-    let write_work_needed = !outbox.is_empty();
-    if *want_write != write_work_needed {
-        *want_write = write_work_needed;
-        let _ = update_socket_interest(poll, socket_source, *want_write);
+}
+
+/// Hand a planned deploy to `start`, following its output on success.
+fn start_deploy(
+    plan: Result<DeployPlan, DeployError>,
+    start: impl FnOnce(&DeployPlan) -> Result<u64, StartError>,
+) -> Delivery {
+    let plan = match plan {
+        Ok(plan) => plan,
+        Err(err) => return Delivery::Text(format!("deploy_log:deploy rejected: {}", err)),
+    };
+    match start(&plan) {
+        // a fresh subscription replays the deploy from its start, an existing one already saw it begin
+        Ok(_) => Delivery::Follow(plan.service),
+        Err(err) => {
+            let msg = format!("deploy failed: {}", err);
+            eprintln!("error, when running deploy: {}", msg);
+            Delivery::Text(format!("deploy_log:{}", msg))
+        }
     }
 }
 
@@ -293,46 +626,6 @@ fn split_path_query(path: &str) -> (&str, &str) {
     }
 }
 
-fn update_socket_interest(poll: &mut Poll, socket_source: &mut SourceFd, want_write: bool) -> Result<(), ()> {
-    let interest = if want_write {
-        Interest::READABLE.add(Interest::WRITABLE)
-    } else {
-        Interest::READABLE
-    };
-    if let Err(err) = poll.registry().reregister(socket_source, SOCKET, interest) {
-        let bt = Backtrace::capture();
-        eprintln!("error, when updating websocket socket interest. Error: {}. Stack: {:?}", err, bt);
-        return Err(())
-    }
-    Ok(())
-}
-
-fn drain_outbound(
-    outbox: &mut VecDeque<Message>,
-    websocket: &mut WebSocket<TcpStream>,
-    ping_in_flight: &mut Option<Instant>,
-) -> Result<(), ()> {
-    while let Some(msg) = outbox.pop_front() {
-        let is_ping = matches!(msg, Message::Ping(_));
-        match websocket.send(msg.clone()) {
-            Ok(()) => {
-                if is_ping && ping_in_flight.is_none() {
-                    *ping_in_flight = Some(Instant::now());
-                }
-            }
-            Err(err) if is_timeout(&err) => {
-                outbox.push_front(msg);
-                return Ok(())
-            }
-            Err(err) => {
-                eprintln!("error, when writing to websocket connection. Error: {}", err);
-                return Err(())
-            }
-        }
-    }
-    Ok(())
-}
-
 fn is_timeout(e: &tungstenite::Error) -> bool {
     use tungstenite::Error::Io;
     match e {
@@ -344,181 +637,319 @@ fn is_timeout(e: &tungstenite::Error) -> bool {
     }
 }
 
-enum ChildStream {
-    Stdout,
-    Stderr,
+/// What the event loop does for a connection on behalf of a subscriber or
+/// the worker.
+#[derive(Debug, PartialEq, Eq)]
+enum Delivery {
+    /// Send a text message to the client.
+    Text(String),
+    /// Follow the deploy output of a service.
+    Follow(String),
 }
 
-struct DeployChild {
-    child: Child,
-    stdout: ChildStdout,
-    stderr: ChildStderr,
-    stdout_buf: Vec<u8>,
-    stderr_buf: Vec<u8>,
-    stdout_done: bool,
-    stderr_done: bool,
-    stdout_fd: RawFd,
-    stderr_fd: RawFd,
+/// Hands deliveries for one connection to the event loop.
+#[derive(Clone)]
+struct Outbound {
+    token: Token,
+    sender: mpsc::Sender<(Token, Delivery)>,
+    waker: Arc<Waker>,
 }
 
-impl DeployChild {
-    fn is_done(&self) -> bool {
-        self.stdout_done && self.stderr_done
+impl Outbound {
+    /// Returns `false` once the event loop is gone.
+    fn send(&self, delivery: Delivery) -> bool {
+        self.sender.send((self.token, delivery)).is_ok() && self.waker.wake().is_ok()
     }
 }
 
-fn spawn_deploy() -> Result<DeployChild, std::io::Error> {
-    let mut child = Command::new("ls")
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn().map_err(|e| {
-            io::Error::new(
-                e.kind(),
-                format!("error, when writing to websocket connection for starting child process. Error: {}", e),
-            )
-        })?;
+/// A request of a connection for the worker.
+struct Job {
+    event: AppEvent,
+    initiated_by: String,
+    outbound: Outbound,
+}
 
-    let stdout = child.stdout.take().ok_or_else(|| {
-        io::Error::new(
-            ErrorKind::Other,
-            "stdout wasn't piped or was already taken",
-        )
-    })?;
-    let stderr = child.stderr.take().ok_or_else(|| {
-        io::Error::new(
-            ErrorKind::Other,
-            "stderr wasn't piped or was already taken",
-        )
-    })?;
-
-    let stdout_fd = stdout.as_raw_fd();
-    let stderr_fd = stderr.as_raw_fd();
-    set_nonblocking_fd(stdout_fd)?;
-    set_nonblocking_fd(stderr_fd)?;
-
-    Ok(DeployChild {
-        child,
-        stdout,
-        stderr,
-        stdout_buf: Vec::new(),
-        stderr_buf: Vec::new(),
-        stdout_done: false,
-        stderr_done: false,
-        stdout_fd,
-        stderr_fd,
-    })
-}
-
-fn register_child_fds(poll: &mut Poll, deploy: &mut DeployChild) -> Result<(), ()> {
-    let mut stdout_source = SourceFd(&deploy.stdout_fd);
-    if let Err(err) = poll.registry().register(&mut stdout_source, STDOUT, Interest::READABLE) {
-        let bt = Backtrace::capture();
-        eprintln!("error, when registering deploy stdout. Error: {}. Stack: {:?}", err, bt);
-        return Err(())
-    }
-    let mut stderr_source = SourceFd(&deploy.stderr_fd);
-    if let Err(err) = poll.registry().register(&mut stderr_source, STDERR, Interest::READABLE) {
-        let bt = Backtrace::capture();
-        eprintln!("error, when registering deploy stderr. Error: {}. Stack: {:?}", err, bt);
-        return Err(())
-    }
-    Ok(())
+/// Threads carrying out the requests that read the database or start a
+/// deploy.
+#[derive(Clone)]
+struct Worker {
+    queue: Arc<JobQueue>,
 }
 
-fn handle_child_readable(deploy: &mut DeployChild, outbox: &mut VecDeque<Message>, which: ChildStream) -> Result<(), ()> {
-    match which {
-        ChildStream::Stdout => {
-            match read_child_stream(&mut deploy.stdout, &mut deploy.stdout_buf, outbox) {
-                Ok(ChildRead::Progress) => Ok(()),
-                Ok(ChildRead::Eof) => {
-                    deploy.stdout_done = true;
-                    Ok(())
-                }
-                Err(err) => {
-                    eprintln!("error, when reading deploy output: {}", err);
-                    Err(())
-                }
-            }
-        }
-        ChildStream::Stderr => {
-            match read_child_stream(&mut deploy.stderr, &mut deploy.stderr_buf, outbox) {
-                Ok(ChildRead::Progress) => Ok(()),
-                Ok(ChildRead::Eof) => {
-                    deploy.stderr_done = true;
-                    Ok(())
-                }
-                Err(err) => {
-                    eprintln!("error, when reading deploy output: {}", err);
-                    Err(())
+impl Worker {
+    fn start() -> io::Result<Self> {
+        let queue = Arc::new(JobQueue::default());
+        for n in 0..WORKERS {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new().name(format!("ws-worker-{n}")).spawn(move || {
+                loop {
+                    let job = queue.take();
+                    let token = job.outbound.token;
+                    // a request that panics mustn't take its worker, or its connection's later requests, along
+                    let deliveries = panic::catch_unwind(AssertUnwindSafe(|| work(job.event, &job.initiated_by)))
+                        .unwrap_or_else(|_| vec![Delivery::Text("error, request failed unexpectedly".to_string())]);
+                    for delivery in deliveries {
+                        job.outbound.send(delivery);
+                    }
+                    queue.finish(token);
                 }
-            }
+            })?;
         }
+        Ok(Self { queue })
+    }
+
+    fn submit(&self, job: Job) {
+        self.queue.push(job);
     }
 }
 
-fn finalize_deploy(poll: &mut Poll, deploy: &mut DeployChild, outbox: &mut VecDeque<Message>) -> Result<(), ()> {
-    let status = match deploy.child.try_wait() {
-        Ok(Some(status)) => status,
-        Ok(None) => return Ok(()),
-        Err(err) => {
-            eprintln!("error, when waiting for child process. Error: {}", err);
-            return Err(())
-        }
-    };
-    outbox.push_back(Message::Text(format!("child process exited: {status}").into()));
-    let mut stdout_source = SourceFd(&deploy.stdout_fd);
-    let _ = poll.registry().deregister(&mut stdout_source);
-    let mut stderr_source = SourceFd(&deploy.stderr_fd);
-    let _ = poll.registry().deregister(&mut stderr_source);
-    Ok(())
+/// Requests waiting for a worker.
+#[derive(Default)]
+struct JobQueue {
+    state: Mutex<Queued>,
+    ready: Condvar,
 }
 
-enum ChildRead {
-    Progress,
-    Eof,
+#[derive(Default)]
+struct Queued {
+    /// Waiting requests of each connection, in the order they came.
+    jobs: HashMap<Token, VecDeque<Job>>,
+    /// Connections with waiting requests and no worker on them, in turn.
+    turns: VecDeque<Token>,
+    /// Connections a worker is carrying out a request of.
+    busy: HashSet<Token>,
 }
 
-fn read_child_stream(stream: &mut impl Read, buf: &mut Vec<u8>, outbox: &mut VecDeque<Message>) -> io::Result<ChildRead> {
-    let mut tmp = [0u8; 4096];
-    loop {
-        match stream.read(&mut tmp) {
-            Ok(0) => {
-                flush_lines(buf, outbox);
-                return Ok(ChildRead::Eof);
-            }
-            Ok(n) => {
-                buf.extend_from_slice(&tmp[..n]);
-                flush_lines(buf, outbox);
+impl Queued {
+    /// The next request no other worker's request is ahead of.
+    fn next(&mut self) -> Option<Job> {
+        let token = self.turns.pop_front()?;
+        let jobs = self.jobs.get_mut(&token)?;
+        let job = jobs.pop_front();
+        if jobs.is_empty() {
+            self.jobs.remove(&token);
+        }
+        self.busy.insert(token);
+        job
+    }
+}
+
+impl JobQueue {
+    fn push(&self, job: Job) {
+        let token = job.outbound.token;
+        let mut state = self.lock();
+        let jobs = state.jobs.entry(token).or_default();
+        jobs.push_back(job);
+        if jobs.len() == 1 && !state.busy.contains(&token) {
+            state.turns.push_back(token);
+            self.ready.notify_one();
+        }
+    }
+
+    /// Wait for the next request to carry out.
+    fn take(&self) -> Job {
+        let mut state = self.lock();
+        loop {
+            if let Some(job) = state.next() {
+                return job;
             }
-            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(ChildRead::Progress),
-            Err(e) => return Err(e),
+            state = self.ready.wait(state).expect("error, lock in poisoned state");
         }
     }
+
+    /// A worker is done with the request of `token` it took.
+    fn finish(&self, token: Token) {
+        let mut state = self.lock();
+        state.busy.remove(&token);
+        if state.jobs.contains_key(&token) {
+            state.turns.push_back(token);
+            self.ready.notify_one();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queued> {
+        self.state.lock().expect("error, lock in poisoned state")
+    }
+}
+
+/// The service whose deploy output a connection is following.
+struct Watch {
+    outbound: Outbound,
+    current: Option<(String, SubscriptionId)>,
 }
 
-fn flush_lines(buf: &mut Vec<u8>, outbox: &mut VecDeque<Message>) {
-    while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
-        let mut line = buf.drain(..=pos).collect::<Vec<u8>>();
-        if matches!(line.last(), Some(b'\n')) {
-            line.pop();
+impl Watch {
+    fn new(outbound: Outbound) -> Self {
+        Self { outbound, current: None }
+    }
+
+    /// Switch to following `service`; an empty name stops following.
+    fn follow(&mut self, service: &str) {
+        if self.current.as_ref().is_some_and(|(current, _)| current == service) {
+            return
         }
-        if matches!(line.last(), Some(b'\r')) {
-            line.pop();
+        self.stop();
+        if service.is_empty() {
+            return
+        }
+        let outbound = self.outbound.clone();
+        let id = hub().subscribe(service, Box::new(move |event| {
+            outbound.send(Delivery::Text(deploy_event_message(event)))
+        }));
+        self.current = Some((service.to_string(), id));
+    }
+
+    fn stop(&mut self) {
+        if let Some((_, id)) = self.current.take() {
+            hub().unsubscribe(id);
         }
-        let text = String::from_utf8_lossy(&line).to_string();
-        outbox.push_back(Message::Text(text.into()));
     }
 }
 
-fn set_nonblocking_fd(fd: RawFd) -> io::Result<()> {
-    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
-    if flags < 0 {
-        return Err(io::Error::last_os_error());
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.stop();
     }
-    let new_flags = flags | libc::O_NONBLOCK;
-    let res = unsafe { libc::fcntl(fd, libc::F_SETFL, new_flags) };
-    if res < 0 {
-        return Err(io::Error::last_os_error());
+}
+
+fn deploy_event_message(event: &DeployEvent) -> String {
+    match event {
+        DeployEvent::Started { service, environment, .. } => format!("new_deployment: {} ({})", service, environment),
+        DeployEvent::Line { line, .. } => format!("deploy_log:{}", line),
+        DeployEvent::Step { name, status, .. } => format!("deploy_step:{}:{}", status, name),
+        DeployEvent::Finished { exit, .. } => format!("deploy_log:deploy finished: {}", exit),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    /// A reactor that isn't polled, with its own end of a socket pair per adopted client.
+    fn reactor(max_users: usize) -> (Reactor, Arc<JobQueue>) {
+        let poll = Poll::new().expect("poll");
+        let waker = Arc::new(Waker::new(poll.registry(), WAKE).expect("waker"));
+        let (_adopt, adopted_rx) = mpsc::channel();
+        let queue = Arc::new(JobQueue::default());
+        let reactor = Reactor::new(
+            poll,
+            waker,
+            adopted_rx,
+            Worker { queue: Arc::clone(&queue) },
+            max_users,
+            WebSocketConfig::default(),
+            "test".to_string(),
+        );
+        (reactor, queue)
+    }
+
+    /// Connect a client to `reactor` and flush what the reactor queued for it.
+    fn connect(reactor: &mut Reactor) -> (Token, WebSocket<net::TcpStream>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").expect("listen");
+        let client = net::TcpStream::connect(listener.local_addr().expect("addr")).expect("connect");
+        client.set_read_timeout(Some(Duration::from_secs(5))).expect("timeout");
+        let (server, _) = listener.accept().expect("accept");
+        server.set_nonblocking(true).expect("nonblocking");
+        let mut touched = Vec::new();
+        reactor.adopt(Adopted { stream: server, read_ahead: Vec::new() }, &mut touched);
+        flush(reactor, &touched);
+        (touched[0], WebSocket::from_raw_socket(client, Role::Client, None))
+    }
+
+    fn flush(reactor: &mut Reactor, touched: &[Token]) {
+        for token in touched {
+            if let Some(conn) = reactor.connections.get_mut(token) {
+                conn.flush();
+            }
+        }
+    }
+
+    #[test]
+    fn checks_upgrade_requests() {
+        let good = headers(&[("sec-websocket-version", "13"), ("sec-websocket-key", " abc== ")]);
+        assert_eq!(check_upgrade(&good).expect("upgrade"), "abc==");
+        let old = headers(&[("sec-websocket-version", "8"), ("sec-websocket-key", "abc==")]);
+        assert!(matches!(check_upgrade(&old), Err(UpgradeError::UnsupportedVersion(version)) if version == "8"));
+        let keyless = headers(&[("sec-websocket-version", "13"), ("sec-websocket-key", "")]);
+        let err = check_upgrade(&keyless).expect_err("missing key");
+        assert!(matches!(err, UpgradeError::MissingKey));
+        assert_eq!(err.status_line(), Some("HTTP/1.1 400 BAD REQUEST"));
+        assert_eq!(UpgradeError::NotStarted.status_line(), Some("HTTP/1.1 503 SERVICE UNAVAILABLE"));
+    }
+
+    #[test]
+    fn answers_control_messages_and_hands_the_rest_to_the_worker() {
+        assert_eq!(dispatch("ping"), Handling::Reply("pong".to_string()));
+        assert_eq!(dispatch("subscribe:api"), Handling::Follow("api".to_string()));
+        assert_eq!(
+            dispatch("promote:api:staging"),
+            Handling::Work(AppEvent::Promote { service: "api".to_string(), environment: "staging".to_string() }),
+        );
+        assert_eq!(dispatch("navigate:/deployments"), Handling::Work(AppEvent::Navigate("/deployments".to_string())));
+        assert_eq!(dispatch("deploy:api"), Handling::Reply("error, missing event arg".to_string()));
+        assert_eq!(dispatch("explode"), Handling::Reply("error, unknown event kind".to_string()));
+    }
+
+    #[test]
+    fn requests_of_a_connection_wait_for_each_other_only() {
+        let poll = Poll::new().expect("poll");
+        let waker = Arc::new(Waker::new(poll.registry(), WAKE).expect("waker"));
+        let (sender, _deliveries) = mpsc::channel();
+        let job = |token: usize, path: &str| Job {
+            event: AppEvent::Navigate(path.to_string()),
+            initiated_by: "test".to_string(),
+            outbound: Outbound { token: Token(token), sender: sender.clone(), waker: Arc::clone(&waker) },
+        };
+        let queue = JobQueue::default();
+        queue.push(job(1, "/a1"));
+        queue.push(job(1, "/a2"));
+        queue.push(job(2, "/b1"));
+
+        assert_eq!(queue.take().event, AppEvent::Navigate("/a1".to_string()));
+        // the second request of a busy connection waits, another connection's doesn't
+        assert_eq!(queue.take().event, AppEvent::Navigate("/b1".to_string()));
+        assert!(queue.lock().next().is_none());
+        queue.finish(Token(1));
+        assert_eq!(queue.take().event, AppEvent::Navigate("/a2".to_string()));
+        queue.finish(Token(2));
+        queue.finish(Token(1));
+        let state = queue.lock();
+        assert!(state.jobs.is_empty() && state.turns.is_empty() && state.busy.is_empty());
+    }
+
+    #[test]
+    fn connections_past_max_users_are_told_to_try_again() {
+        let (mut reactor, jobs) = reactor(1);
+        let (token, mut admitted) = connect(&mut reactor);
+        assert_eq!(admitted.read().expect("ready"), Message::Text("ready:test".into()));
+        let (_, mut refused) = connect(&mut reactor);
+        match refused.read().expect("close") {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Again),
+            other => panic!("expected a close frame, got {other:?}"),
+        }
+
+        // requests go to the worker, its replies come back through the event loop
+        admitted.send(Message::Text("navigate:/deployments".into())).expect("send");
+        let conn = reactor.connections.get_mut(&token).expect("connection");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let job = loop {
+            conn.on_ready(true, false);
+            if let Some(job) = jobs.lock().next() {
+                break job;
+            }
+            assert!(Instant::now() < deadline, "request never reached the worker");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(job.event, AppEvent::Navigate("/deployments".to_string()));
+        assert!(job.outbound.send(Delivery::Text("patch:<div></div>".to_string())));
+        let mut touched = Vec::new();
+        reactor.deliver(&mut touched);
+        assert_eq!(touched, [token]);
+        flush(&mut reactor, &touched);
+        assert_eq!(admitted.read().expect("reply"), Message::Text("patch:<div></div>".into()));
     }
-    Ok(())
 }
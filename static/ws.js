@@ -1,7 +1,7 @@
 (function () {
-  const host = location.hostname || "localhost";
+  const host = location.host || "localhost:7878";
   const protocol = location.protocol === "https:" ? "wss" : "ws";
-  const url = `${protocol}://${host}:8787`;
+  const url = `${protocol}://${host}/ws`;
 
   function randomInt(min, max) {
         return Math.floor(Math.random() * (max - min + 1)) + min;
@@ -19,6 +19,7 @@
   let attempts = 0;
   let last_server_contact = Date.now();
   let last_contact_timeout = 120000;
+  let subscribedTo = "";
 
   const log = (...args) => console.log("[ws-demo]", ...args);
 
@@ -91,11 +92,19 @@
     if (page) {
       document.body.dataset.page = page;
     }
+    syncSubscription(app.dataset.subscribe || "");
     ensurePageCss(page, css);
     ensurePageScript(page, js);
     mountCurrentPage();
   }
 
+  // follow deploy output for the service the current page is about
+  function syncSubscription(topic) {
+    if (topic === subscribedTo) return;
+    subscribedTo = topic;
+    send(`subscribe:${topic}`);
+  }
+
   function watchPageAssets() {
     syncPageAssets();
     let scheduled = false;
@@ -125,6 +134,9 @@
         log("connected", url);
         attempts = 0;
         startHeartbeat();
+        if (subscribedTo) {
+            send(`subscribe:${subscribedTo}`);
+        }
       });
 
 
@@ -155,10 +167,14 @@
                   }
                   break;
               case "new_deployment":
-                  const ul = document.querySelector('#messages');
-                  const li = document.createElement('li');
-                  li.textContent = event.data;
-                  ul.appendChild(li);
+                  clearSteps();
+                  appendMessage(event.data);
+                  break;
+              case "deploy_log":
+                  appendMessage(event_data);
+                  break;
+              case "deploy_step":
+                  showStep(event_data);
                   break;
               default:
                   console.error("server sent unknown event event_name: '%s'. event_data: '%s'", event_name, event_data);
@@ -185,6 +201,36 @@
       Object.freeze(window.WS);
   }
 
+  function appendMessage(text) {
+    const ul = document.querySelector('#messages');
+    if (!ul) return;
+    const li = document.createElement('li');
+    li.textContent = text;
+    ul.appendChild(li);
+  }
+
+  function clearSteps() {
+    const ul = document.querySelector('#steps');
+    if (ul) ul.replaceChildren();
+  }
+
+  // data is "<status>:<step name>", one list item per step showing its latest status
+  function showStep(data) {
+    const ul = document.querySelector('#steps');
+    if (!ul) return;
+    const index = data.indexOf(":");
+    const status = data.slice(0, index);
+    const name = data.slice(index + 1);
+    let li = Array.from(ul.children).find((item) => item.dataset.step === name);
+    if (!li) {
+      li = document.createElement('li');
+      li.dataset.step = name;
+      ul.appendChild(li);
+    }
+    li.className = status;
+    li.textContent = `${name}: ${status.replace("_", " ")}`;
+  }
+
   const pendingPatches = [];
 
   function flushPendingPatches() {